@@ -1,35 +1,516 @@
 // Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
 // SPDX-License-Identifier: MIT
 
-use cfg_if::cfg_if;
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
 
 fn main() {
     // println!(r"cargo:rustc-link-search=C:\Libs\_SDKs\OpenCL-SDK\lib");
 
-    if let Some(path) = option_env!("OPENCL_SDK") {
-        eprintln!("Using KhronosGroup OpenCL-SDK");
+    let target_os = env::var("CARGO_CFG_TARGET_OS").unwrap_or_default();
+    let target_arch = env::var("CARGO_CFG_TARGET_ARCH").unwrap_or_default();
 
-        dbg!(&path);
-        println!(r"cargo:rustc-link-search={}/lib", path);
-    } else if let Some(path) = option_env!("OCL_ROOT") {
-        eprintln!("Using AMD OCL_SDK_Light");
+    // Read at build time below, so a change re-runs this script instead of needing a clean build
+    for var in [
+        "OPENCL_LIB_DIR",
+        "OPENCL_LIB_NAME",
+        "OPENCL_STATIC",
+        "OPENCL_SDK",
+        "OCL_ROOT",
+        "CUDA_PATH",
+        "ONEAPI_ROOT",
+        "PKG_CONFIG_PATH",
+        "OPENCL_ICD_LOADER_SOURCE",
+        "OPENCL_HEADERS_SOURCE",
+    ] {
+        println!("cargo:rerun-if-env-changed={}", var);
+    }
+    println!("cargo:rerun-if-changed=build.rs");
 
-        dbg!(&path);
+    let dynamic = env::var_os("CARGO_FEATURE_DYNAMIC").is_some();
+    let vendored = env::var_os("CARGO_FEATURE_ICD_LOADER_VENDORED").is_some();
 
-        cfg_if! {
-            if #[cfg(target_arch = "x86_64")] {
-                let arch = "x86_64";
-            } else if #[cfg(target_arch = "x86")] {
-                let arch = "x86";
-            } else {
-                panic!("OCL_SDK_Light only supports x86 and x86_64");
+    // With both, src/main.rs fails the build with a compile_error! naming them, which a panic here
+    // would bury in the build script's output. The dynamic setup keeps it the only error
+    if dynamic {
+        setup_dynamic(&target_os);
+        return;
+    }
+
+    if vendored {
+        build_icd_loader(&target_os);
+        return;
+    }
+
+    // A directory holding the OpenCL library, used as is without any SDK layout
+    if let Ok(dir) = env::var("OPENCL_LIB_DIR") {
+        link_dir(Path::new(&dir), &target_os, "OPENCL_LIB_DIR");
+        return;
+    }
+
+    // macOS ships OpenCL as a system framework, there is no SDK to look for
+    if target_os == "macos" {
+        println!("cargo:warning=Using OpenCL from OpenCL.framework via the macOS system framework");
+        println!("cargo:rustc-link-lib=framework=OpenCL");
+        return;
+    }
+
+    // Everything we looked at, listed in the panic message if nothing works out
+    let mut tried = vec!["OPENCL_LIB_DIR environment variable (not set)".to_owned()];
+
+    if let Ok(path) = env::var("OPENCL_SDK") {
+        link_dir(
+            &Path::new(&path).join("lib"),
+            &target_os,
+            "OPENCL_SDK (KhronosGroup OpenCL-SDK)",
+        );
+        return;
+    }
+    tried.push("OPENCL_SDK environment variable (not set)".to_owned());
+
+    if let Ok(path) = env::var("OCL_ROOT") {
+        // The target's architecture, not the one the build script happens to run on
+        let arch = match target_arch.as_str() {
+            arch @ ("x86_64" | "x86") => arch,
+            arch => panic!(
+                "OCL_SDK_Light only supports x86 and x86_64 (target is {})",
+                arch
+            ),
+        };
+
+        link_dir(
+            &Path::new(&path).join("lib").join(arch),
+            &target_os,
+            "OCL_ROOT (AMD OCL_SDK_Light)",
+        );
+        return;
+    }
+    tried.push("OCL_ROOT environment variable (not set)".to_owned());
+
+    if let Ok(path) = env::var("CUDA_PATH") {
+        let lib_dir = match (target_os.as_str(), target_arch.as_str()) {
+            ("windows", "x86") => "lib/Win32",
+            ("windows", _) => "lib/x64",
+            _ => "lib64",
+        };
+
+        link_dir(
+            &Path::new(&path).join(lib_dir),
+            &target_os,
+            "CUDA_PATH (NVIDIA CUDA toolkit)",
+        );
+        return;
+    }
+    tried.push("CUDA_PATH environment variable (not set)".to_owned());
+
+    if let Ok(path) = env::var("ONEAPI_ROOT") {
+        // Releases before 2024 keep the libraries in a per-OS subdirectory
+        let legacy = if target_os == "windows" {
+            "compiler/latest/windows/lib"
+        } else {
+            "compiler/latest/linux/lib"
+        };
+        let lib_dir = ["compiler/latest/lib", legacy]
+            .into_iter()
+            .map(|dir| PathBuf::from(&path).join(dir))
+            .find(|dir| dir.is_dir())
+            .unwrap_or_else(|| PathBuf::from(&path).join("compiler/latest/lib"));
+
+        link_dir(&lib_dir, &target_os, "ONEAPI_ROOT (Intel oneAPI)");
+        return;
+    }
+    tried.push("ONEAPI_ROOT environment variable (not set)".to_owned());
+
+    if target_os == "linux" {
+        // pkg-config only knows the default name, an override is looked up in the system directories
+        if let Some(lib) = LibOverride::from_env(&target_os) {
+            let dirs = system_lib_dirs(&target_arch);
+            match dirs.iter().find(|dir| dir.join(&lib.file).is_file()) {
+                Some(dir) => link_dir(dir, &target_os, "the system library directories"),
+                None => panic!(
+                    "{} not found in the system library directories ({}), \
+                     set OPENCL_LIB_DIR to the directory containing it",
+                    lib.file,
+                    dirs.iter()
+                        .map(|dir| dir.display().to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
             }
+            return;
         }
 
-        println!(r"cargo:rustc-link-search={}/lib/{}", path, arch);
-    } else {
-        panic!("No OpenCL ICD found");
+        match probe_pkg_config() {
+            Ok(()) => return,
+            Err(err) => tried.push(format!("pkg-config --libs OpenCL ({})", err)),
+        }
+
+        // libOpenCL.so is normally in the default linker search path on Linux
+        println!(
+            "cargo:warning=Using OpenCL from the default linker search path via the system library"
+        );
+        println!("cargo:rustc-link-lib=OpenCL");
+        return;
     }
+    tried.push(format!(
+        "pkg-config and the system library (only on linux, target is {})",
+        target_os
+    ));
+
+    panic!(
+        "No OpenCL ICD found, tried in this order:\n{}",
+        tried
+            .iter()
+            .map(|t| format!("  - {}", t))
+            .collect::<Vec<_>>()
+            .join("\n")
+    );
 
     // unimplemented!()
 }
+
+/// A library name (`OPENCL_LIB_NAME`) or static linking (`OPENCL_STATIC=1`) requested by the user
+struct LibOverride {
+    /// The file expected in the search directory
+    file: String,
+    is_static: bool,
+}
+
+impl LibOverride {
+    fn from_env(target_os: &str) -> Option<Self> {
+        let name = env::var("OPENCL_LIB_NAME").ok();
+        let is_static = matches!(env::var("OPENCL_STATIC").as_deref(), Ok("1" | "true"));
+        if name.is_none() && !is_static {
+            return None;
+        }
+
+        let name = name.unwrap_or_else(|| "OpenCL".to_owned());
+        // A name with an extension is taken as the exact file, e.g. `libOpenCL.so.1`
+        let file = if name.contains('.') {
+            name
+        } else {
+            library_file(&name, target_os, is_static)
+        };
+
+        Some(Self { file, is_static })
+    }
+}
+
+/// The file the linker looks for when asked for library `name`
+fn library_file(name: &str, target_os: &str, is_static: bool) -> String {
+    match (target_os, is_static) {
+        ("windows", _) => format!("{}.lib", name),
+        (_, true) => format!("lib{}.a", name),
+        ("macos", false) => format!("lib{}.dylib", name),
+        (_, false) => format!("lib{}.so", name),
+    }
+}
+
+/// Adds `dir` to the library search path, applying `OPENCL_LIB_NAME` and `OPENCL_STATIC`
+///
+/// opencl-sys always links `OpenCL`, so a library under another name is copied into `OUT_DIR`
+/// under the name the linker expects
+fn link_dir(dir: &Path, target_os: &str, source: &str) {
+    println!(
+        "cargo:warning=Using OpenCL from {} via {}",
+        dir.display(),
+        source
+    );
+
+    let lib = match LibOverride::from_env(target_os) {
+        Some(lib) => lib,
+        None => {
+            println!("cargo:rustc-link-search=native={}", dir.display());
+            return;
+        }
+    };
+
+    let path = dir.join(&lib.file);
+    if !path.is_file() {
+        let mut entries: Vec<String> = fs::read_dir(dir)
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| entry.ok())
+                    .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                    .collect()
+            })
+            .unwrap_or_default();
+        entries.sort();
+
+        panic!(
+            "{} not found in {}, the directory contains:\n{}",
+            lib.file,
+            dir.display(),
+            if entries.is_empty() {
+                "  (nothing, or it doesn't exist)".to_owned()
+            } else {
+                entries
+                    .iter()
+                    .map(|e| format!("  - {}", e))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            }
+        );
+    }
+
+    let expected = library_file("OpenCL", target_os, lib.is_static);
+    if lib.file != expected {
+        let alias_dir = PathBuf::from(env::var("OUT_DIR").unwrap()).join("opencl-lib");
+        fs::create_dir_all(&alias_dir).unwrap();
+        fs::copy(&path, alias_dir.join(&expected)).unwrap();
+
+        println!("cargo:warning=Linking {} as {}", path.display(), expected);
+        println!("cargo:rustc-link-search=native={}", alias_dir.display());
+    }
+    println!("cargo:rustc-link-search=native={}", dir.display());
+
+    if lib.is_static {
+        println!("cargo:rustc-link-lib=static=OpenCL");
+        link_static_loader_deps(target_os);
+    } else {
+        println!("cargo:rustc-link-lib=OpenCL");
+    }
+}
+
+/// Where Linux distributions install shared libraries for `target_arch`
+fn system_lib_dirs(target_arch: &str) -> Vec<PathBuf> {
+    let multiarch = match target_arch {
+        "x86" => "i386-linux-gnu".to_owned(),
+        "arm" => "arm-linux-gnueabihf".to_owned(),
+        arch => format!("{}-linux-gnu", arch),
+    };
+
+    [
+        format!("/usr/lib/{}", multiarch),
+        format!("/lib/{}", multiarch),
+        "/usr/lib64".to_owned(),
+        "/usr/lib".to_owned(),
+        "/usr/local/lib".to_owned(),
+    ]
+    .into_iter()
+    .map(PathBuf::from)
+    .collect()
+}
+
+/// Asks pkg-config for the OpenCL link flags and forwards them to cargo
+fn probe_pkg_config() -> Result<(), String> {
+    let output = Command::new("pkg-config")
+        .args(["--libs", "OpenCL"])
+        .output()
+        .map_err(|err| format!("failed to run: {}", err))?;
+
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_owned());
+    }
+
+    let libs = String::from_utf8_lossy(&output.stdout);
+    println!(
+        "cargo:warning=Using OpenCL from {} via pkg-config",
+        if libs.trim().is_empty() {
+            "the default linker search path"
+        } else {
+            libs.trim()
+        }
+    );
+
+    for flag in libs.split_whitespace() {
+        if let Some(dir) = flag.strip_prefix("-L") {
+            println!("cargo:rustc-link-search=native={}", dir);
+        } else if let Some(lib) = flag.strip_prefix("-l") {
+            println!("cargo:rustc-link-lib={}", lib);
+        }
+    }
+
+    Ok(())
+}
+
+/// Replaces the link against OpenCL with what `src/dynamic.rs` needs to load it at startup,
+/// none of the SDK probing applies
+fn setup_dynamic(target_os: &str) {
+    println!("cargo:warning=Using OpenCL loaded at runtime via the dynamic feature");
+
+    match target_os {
+        "linux" => {
+            let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+
+            // opencl-sys always asks for `-lOpenCL`, satisfy it with an empty archive so no
+            // DT_NEEDED entry is recorded, the trampolines below define the symbols instead
+            fs::write(out_dir.join("libOpenCL.a"), "!<arch>\n").unwrap();
+            println!("cargo:rustc-link-search=native={}", out_dir.display());
+
+            let arch = env::var("CARGO_CFG_TARGET_ARCH").unwrap_or_default();
+            fs::write(
+                out_dir.join("dynamic_trampolines.rs"),
+                dynamic_trampolines(&arch),
+            )
+            .unwrap();
+        }
+        "macos" => {
+            // A weak framework may be missing at runtime, its symbols are then null
+            println!("cargo:rustc-link-arg=-Wl,-weak_framework,OpenCL");
+        }
+        _ => panic!(
+            "The dynamic feature is only supported on linux and macos (target is {})",
+            target_os
+        ),
+    }
+}
+
+/// Generates one trampoline per OpenCL entry point, each jumps through its slot of a table
+/// that `src/dynamic.rs` fills with `dlsym` once the library is loaded
+fn dynamic_trampolines(arch: &str) -> String {
+    let list = "src/dynamic_symbols.txt";
+    println!("cargo:rerun-if-changed={}", list);
+
+    let list = fs::read_to_string(list).unwrap();
+    let symbols: Vec<&str> = list
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect();
+
+    let mut asm = Vec::new();
+    for (i, symbol) in symbols.iter().enumerate() {
+        asm.push(format!(".globl {0}\n.type {0},@function\n{0}:", symbol));
+        match arch {
+            "x86_64" => asm.push(format!("jmp qword ptr [rip + {{table}} + {}]", 8 * i)),
+            "aarch64" => asm.push(format!(
+                "adrp x16, {{table}}\nadd x16, x16, :lo12:{{table}}\nldr x16, [x16, #{}]\nbr x16",
+                8 * i
+            )),
+            _ => panic!(
+                "The dynamic feature is only supported on x86_64 and aarch64 (target is {})",
+                arch
+            ),
+        }
+    }
+
+    format!(
+        "const SYMBOLS: [&CStr; {len}] = [{names}];\n\n\
+         static mut TABLE: [unsafe extern \"C\" fn(); {len}] = [not_loaded; {len}];\n\n\
+         std::arch::global_asm!(\n{asm},\ntable = sym TABLE,\n);\n",
+        len = symbols.len(),
+        names = symbols
+            .iter()
+            .map(|s| format!("c{:?}", s))
+            .collect::<Vec<_>>()
+            .join(", "),
+        asm = asm
+            .iter()
+            .map(|line| format!("    {:?}", line))
+            .collect::<Vec<_>>()
+            .join(",\n"),
+    )
+}
+
+/// Builds the Khronos OpenCL-ICD-Loader from source as a static library and links it, the only
+/// runtime requirement left is a vendor ICD
+///
+/// The cmake crate isn't a dependency, cmake is run directly with the same steps it would take
+fn build_icd_loader(target_os: &str) {
+    if target_os == "macos" {
+        panic!("The icd-loader-vendored feature isn't supported on macOS, which ships its own OpenCL framework");
+    }
+
+    let loader_src = source_dir("OPENCL_ICD_LOADER_SOURCE", "vendor/OpenCL-ICD-Loader");
+    let headers_src = source_dir("OPENCL_HEADERS_SOURCE", "vendor/OpenCL-Headers");
+    println!("cargo:rerun-if-changed={}", loader_src.display());
+
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let build_dir = out_dir.join("icd-loader-build");
+    let install_dir = out_dir.join("icd-loader");
+
+    eprintln!(
+        "Building the OpenCL ICD loader from {}",
+        loader_src.display()
+    );
+
+    let mut configure = Command::new("cmake");
+    configure
+        .arg("-S")
+        .arg(&loader_src)
+        .arg("-B")
+        .arg(&build_dir)
+        .arg(format!("-DCMAKE_INSTALL_PREFIX={}", install_dir.display()))
+        .arg(format!(
+            "-DOPENCL_ICD_LOADER_HEADERS_DIR={}",
+            headers_src.display()
+        ))
+        .args([
+            "-DCMAKE_BUILD_TYPE=Release",
+            "-DCMAKE_INSTALL_LIBDIR=lib",
+            "-DCMAKE_POSITION_INDEPENDENT_CODE=ON",
+            "-DBUILD_SHARED_LIBS=OFF",
+            "-DBUILD_TESTING=OFF",
+        ]);
+    if target_os == "windows" {
+        // Match the static CRT rustc links against by default
+        configure.arg("-DCMAKE_MSVC_RUNTIME_LIBRARY=MultiThreaded");
+    }
+    run_cmake(&mut configure);
+
+    run_cmake(
+        Command::new("cmake")
+            .arg("--build")
+            .arg(&build_dir)
+            .args(["--config", "Release", "--target", "install"]),
+    );
+
+    println!(
+        "cargo:warning=Using OpenCL from {} via the vendored ICD loader",
+        install_dir.join("lib").display()
+    );
+    println!(
+        "cargo:rustc-link-search=native={}",
+        install_dir.join("lib").display()
+    );
+    println!("cargo:rustc-link-lib=static=OpenCL");
+    link_static_loader_deps(target_os);
+}
+
+/// Dependencies of a static ICD loader, a shared build would have pulled these in itself
+fn link_static_loader_deps(target_os: &str) {
+    match target_os {
+        "windows" => {
+            println!("cargo:rustc-link-lib=cfgmgr32");
+            println!("cargo:rustc-link-lib=runtimeobject");
+        }
+        _ => {
+            println!("cargo:rustc-link-lib=dl");
+            println!("cargo:rustc-link-lib=pthread");
+        }
+    }
+}
+
+/// A source checkout named by `var`, or `default` relative to the crate
+fn source_dir(var: &str, default: &str) -> PathBuf {
+    let dir = match env::var(var) {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join(default),
+    };
+
+    if !dir.join("CMakeLists.txt").is_file() {
+        panic!(
+            "{} doesn't contain a CMake project, check out the sources first:\n  \
+             git clone https://github.com/KhronosGroup/OpenCL-ICD-Loader vendor/OpenCL-ICD-Loader\n  \
+             git clone https://github.com/KhronosGroup/OpenCL-Headers vendor/OpenCL-Headers\n\
+             or point {} at an existing checkout",
+            dir.display(),
+            var
+        );
+    }
+
+    dir
+}
+
+fn run_cmake(command: &mut Command) {
+    let status = command
+        .status()
+        .unwrap_or_else(|err| panic!("Failed to run cmake, is it installed? ({})", err));
+    if !status.success() {
+        panic!("{:?} failed with {}", command, status);
+    }
+}
@@ -0,0 +1,12 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+// The built-in saxpy, the OpenCL C of saxpy.spvasm and saxpy.spv for --spirv (see the README)
+kernel void saxpy_float (global float* z,
+    global float const* x,
+    global float const* y,
+    float a)
+{
+    const size_t i = get_global_id(0);
+    z[i] = a*x[i] + y[i];
+}
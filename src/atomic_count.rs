@@ -0,0 +1,234 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+//! The `atomic-count` command: every work-item whose element is above a threshold increments one
+//! global counter
+//!
+//! The counter is zeroed with `clEnqueueFillBuffer` and compared with a count on the host after the
+//! kernel. On devices with OpenCL C 2.0 the program is built with `-cl-std=CL2.0` and increments an
+//! `atomic_uint` with `atomic_fetch_add_explicit`, relaxed and device-scoped: no other memory is
+//! published through the counter and the host only reads it once the kernel completed, so no
+//! stronger order is needed. Older devices use the OpenCL 1.1 `atomic_inc`.
+//!
+//! `--racy` runs a second kernel that increments with a plain load and store, so work-items running
+//! at the same time overwrite each other's increments. Its count is reported next to the right one
+//! instead of failing the command: it shows what the atomic is for, and a driver whose racy count
+//! comes out right on a large input is worth a second look at how it runs work-items.
+
+use anyhow::{anyhow, bail};
+use opencl3::{
+    command_queue::CommandQueue,
+    context::Context,
+    device::Device,
+    kernel::Kernel,
+    memory::{Buffer, CL_MEM_READ_ONLY, CL_MEM_READ_WRITE},
+    program::CL_KERNEL_ARG_INFO,
+    types::{cl_float, cl_uint, CL_BLOCKING},
+};
+
+use crate::{
+    cli::{Args, AtomicCountArgs},
+    error::ClContext,
+    kernels::{self, BuiltinKernel},
+    launch::launch,
+    retry, saxpy,
+};
+
+pub const PROGRAM_SOURCE: &str = r#"
+#ifdef C11_ATOMICS
+#define COUNTER atomic_uint
+#else
+#define COUNTER uint
+#endif
+
+kernel void count_above (global float const* x,
+    float threshold,
+    global COUNTER* count,
+    uint n)
+{
+    const size_t i = get_global_id(0);
+    if (i >= n) return;
+    if (x[i] > threshold) {
+#ifdef C11_ATOMICS
+        atomic_fetch_add_explicit(count, 1u, memory_order_relaxed, memory_scope_device);
+#else
+        atomic_inc(count);
+#endif
+    }
+}
+
+kernel void count_above_racy (global float const* x,
+    float threshold,
+    global volatile uint* count,
+    uint n)
+{
+    const size_t i = get_global_id(0);
+    if (i >= n) return;
+    if (x[i] > threshold) {
+        const uint seen = *count;
+        *count = seen + 1;
+    }
+}"#;
+
+pub const KERNEL_NAME: &str = BuiltinKernel::CountAbove.name();
+pub const RACY_KERNEL_NAME: &str = BuiltinKernel::CountAboveRacy.name();
+
+/// The values the elements cycle through, `0.0` to `999.0`
+const PERIOD: usize = 1000;
+
+/// The elements counted, `i % 1000` so the default threshold of 500 counts about half of them
+pub fn inputs(size: usize) -> Vec<cl_float> {
+    (0..size).map(|i| (i % PERIOD) as cl_float).collect()
+}
+
+/// Whether `device` compiles OpenCL C 2.0, and so the program uses its atomics
+pub fn c11_atomics(device: &Device) -> anyhow::Result<bool> {
+    let version = device
+        .opencl_c_version()
+        .context("Failed to query CL_DEVICE_OPENCL_C_VERSION")?;
+    // "OpenCL C <major>.<minor> <vendor-specific information>", 3.0 devices report the highest
+    // version before 3.0 they support here
+    let major = version
+        .strip_prefix("OpenCL C ")
+        .and_then(|rest| rest.split('.').next())
+        .and_then(|major| major.parse::<u32>().ok());
+
+    Ok(major.is_some_and(|major| major >= 2))
+}
+
+/// The build options of the program, with [`c11_atomics`] given as `c11`, before the user's so
+/// they can override them
+pub fn build_options(args: &Args, c11: bool) -> String {
+    let atomics = if c11 {
+        " -cl-std=CL2.0 -D C11_ATOMICS"
+    } else {
+        ""
+    };
+
+    kernels::join_options(
+        &format!("{}{}", CL_KERNEL_ARG_INFO.trim(), atomics),
+        &kernels::user_build_options(args),
+    )
+}
+
+/// Counts the elements above the threshold on the device, and with `--racy` without atomics too,
+/// and checks the atomic count against the host
+pub fn run(
+    context: &Context,
+    queue: &CommandQueue,
+    device: &Device,
+    args: &Args,
+    count_args: &AtomicCountArgs,
+) -> anyhow::Result<()> {
+    let size = count_args.size;
+    let threshold = count_args.threshold;
+    let n = cl_uint::try_from(size)
+        .map_err(|_| anyhow!("atomic-count counts at most {} elements", cl_uint::MAX))?;
+
+    let c11 = c11_atomics(device)?;
+    let options = build_options(args, c11);
+    let program = kernels::build_program(
+        context,
+        PROGRAM_SOURCE,
+        &options,
+        "the atomic count program",
+        kernels::BuildFlags::from_args(args),
+    )?;
+    let mut program_kernels = kernels::program_kernels(&program)?;
+    let kernel = kernels::take_kernel(
+        &mut program_kernels,
+        KERNEL_NAME,
+        "the atomic count program",
+    )?;
+    let racy_kernel = kernels::take_kernel(
+        &mut program_kernels,
+        RACY_KERNEL_NAME,
+        "the atomic count program",
+    )?;
+
+    let x = inputs(size);
+    let mut x_buffer = saxpy::create_buffer(context, CL_MEM_READ_ONLY, size)?;
+    // Blocking, the host data has to outlive it and this keeps it simple
+    unsafe { queue.enqueue_write_buffer(&mut x_buffer, CL_BLOCKING, 0, &x, &[]) }
+        .context("Failed to write to buffer")?;
+    let mut count_buffer =
+        unsafe { Buffer::<cl_uint>::create(context, CL_MEM_READ_WRITE, 1, std::ptr::null_mut()) }
+            .context("Failed to create counter buffer")?;
+
+    let expected = x.iter().filter(|&&value| value > threshold).count();
+    println!(
+        "{} elements, {} above {}, counted with {}",
+        size,
+        expected,
+        threshold,
+        if c11 {
+            "atomic_fetch_add_explicit (OpenCL C 2.0)"
+        } else {
+            "atomic_inc (OpenCL 1.1)"
+        }
+    );
+
+    let (count, kernel_ns) = run_count(queue, &kernel, &x_buffer, &mut count_buffer, threshold, n)?;
+    println!("{:>8}: {:>10} ({} ns)", "atomic", count, kernel_ns);
+    if count as usize != expected {
+        bail!(
+            "The atomic count is {} but {} elements are above {}, the device's atomics are broken",
+            count,
+            expected,
+            threshold
+        );
+    }
+
+    if count_args.racy {
+        let (racy, kernel_ns) = run_count(
+            queue,
+            &racy_kernel,
+            &x_buffer,
+            &mut count_buffer,
+            threshold,
+            n,
+        )?;
+        println!("{:>8}: {:>10} ({} ns)", "racy", racy, kernel_ns);
+        if racy as usize == expected {
+            println!("The racy count came out right, no increments overlapped on this run");
+        } else {
+            println!(
+                "The racy count lost {} of {} increments to work-items overwriting each other",
+                expected.saturating_sub(racy as usize),
+                expected
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Zeroes the counter, runs `kernel` over the `n` elements and reads the counter back, returning it
+/// and the kernel time
+fn run_count(
+    queue: &CommandQueue,
+    kernel: &Kernel,
+    x: &Buffer<cl_float>,
+    counter: &mut Buffer<cl_uint>,
+    threshold: cl_float,
+    n: cl_uint,
+) -> anyhow::Result<(cl_uint, u64)> {
+    let zero: cl_uint = 0;
+    retry::call("clEnqueueFillBuffer", || unsafe {
+        queue.enqueue_fill_buffer(counter, &[zero], 0, std::mem::size_of::<cl_uint>(), &[])
+    })
+    .context("Failed to fill buffer")?;
+
+    // In queue order after the fill
+    let kernel_event = unsafe {
+        launch!(kernel, global = n as usize; x: x, threshold: &threshold, count: &*counter, n: &n)
+            .enqueue_nd_range(queue)
+    }
+    .map_err(|err| err.context("Failed to execute counting kernel"))?;
+
+    let mut result: [cl_uint; 1] = [0];
+    unsafe { queue.enqueue_read_buffer(counter, CL_BLOCKING, 0, &mut result, &[]) }
+        .context("Failed to read buffer")?;
+
+    Ok((result[0], saxpy::profiled_ns(&kernel_event)?))
+}
@@ -0,0 +1,128 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+//! Several saxpy runs in a row, reported one at a time or pipelined
+//!
+//! [`run_batch`] hands every [`SaxpyRun`] to a callback as soon as it has been read back, so long
+//! sessions can print or record timings incrementally instead of collecting all runs first.
+//!
+//! [`run_pipelined`] amortizes the per-launch overhead that dominates small inputs instead: it
+//! enqueues the uploads, kernels and readbacks of all inputs back-to-back, flushes the queue once
+//! and only then waits, so the device never idles while the host sets up the next launch.
+
+use opencl3::{command_queue::CommandQueue, context::Context, kernel::Kernel, types::cl_float};
+
+use crate::{
+    cancel,
+    cli::{Layout, Op, Transfer},
+    pool::BufferPool,
+    saxpy::{PendingRun, SaxpyBuffers, SaxpyRun},
+};
+
+/// One input of a batch: the scalar `a`, `x` and `y`
+pub type BatchInput = (cl_float, Vec<cl_float>, Vec<cl_float>);
+
+/// What the runs of a batch share: the kernel, how it is launched and the pool the buffers of each
+/// run are taken from and returned to
+pub struct Engine<'a> {
+    pub context: &'a Context,
+    pub queue: &'a CommandQueue,
+    pub kernel: &'a Kernel,
+    pub layout: Layout,
+    pub op: Op,
+    pub transfer: Transfer,
+    pub pool: BufferPool,
+}
+
+impl<'a> Engine<'a> {
+    pub fn new(
+        context: &'a Context,
+        queue: &'a CommandQueue,
+        kernel: &'a Kernel,
+        layout: Layout,
+        op: Op,
+        transfer: Transfer,
+    ) -> Self {
+        Self {
+            context,
+            queue,
+            kernel,
+            layout,
+            op,
+            transfer,
+            pool: BufferPool::default(),
+        }
+    }
+}
+
+/// Runs the kernel of `engine` over each of `inputs` in order and calls `on_result` with the index
+/// of the input and its run right after it completes
+///
+/// `a` is ignored when `engine.op` takes no scalar. The first failing run ends the batch, the runs
+/// before it have been reported already. So does Ctrl-C, failing with [`cancel::Cancelled`]
+pub fn run_batch<'i>(
+    engine: &mut Engine,
+    inputs: impl IntoIterator<Item = &'i BatchInput>,
+    mut on_result: impl FnMut(usize, &SaxpyRun),
+) -> anyhow::Result<()> {
+    for (i, (a, x, y)) in inputs.into_iter().enumerate() {
+        cancel::check(engine.queue)?;
+        let mut buffers =
+            SaxpyBuffers::from_pool(&mut engine.pool, engine.context, engine.layout, x.len())?;
+        let run = buffers.run(
+            engine.queue,
+            engine.kernel,
+            engine.transfer,
+            x,
+            y,
+            engine.op.scalar(*a),
+        )?;
+        // `run` waited for the readback, nothing is in flight on the buffers anymore
+        buffers.return_to(&mut engine.pool);
+
+        on_result(i, &run);
+    }
+
+    Ok(())
+}
+
+/// Runs the kernel of `engine` over all of `inputs` at once, see the module docs, and returns the
+/// run or the error of each input in order
+///
+/// The inputs may differ in length, each gets its own buffers from the pool. An input that fails
+/// to allocate or enqueue doesn't stop the others. The transfers always wait on events, whatever
+/// `engine.transfer` says, since nothing may block between the enqueues
+pub fn run_pipelined(engine: &mut Engine, inputs: &[BatchInput]) -> Vec<anyhow::Result<SaxpyRun>> {
+    // Declared before `pending`, so the buffers outlive the reads even when this returns early
+    let mut buffers = Vec::with_capacity(inputs.len());
+    let mut pending: Vec<anyhow::Result<PendingRun>> = Vec::with_capacity(inputs.len());
+    for (a, x, y) in inputs {
+        let run = SaxpyBuffers::from_pool(&mut engine.pool, engine.context, engine.layout, x.len())
+            .and_then(|mut item_buffers| {
+                let run =
+                    item_buffers.enqueue(engine.queue, engine.kernel, x, y, engine.op.scalar(*a));
+                buffers.push(item_buffers);
+                run
+            });
+        pending.push(run);
+    }
+
+    // Waiting on the reads flushes the queue too, a failed flush only costs the overlap
+    if let Err(err) = engine.queue.flush() {
+        tracing::warn!(
+            "Failed to flush the queue after enqueueing the batch: {}",
+            err
+        );
+    }
+    let runs = pending
+        .into_iter()
+        .map(|run| run.and_then(PendingRun::wait))
+        .collect();
+
+    // Every pending run has been waited for, nothing is in flight on the buffers anymore
+    for item_buffers in buffers {
+        item_buffers.return_to(&mut engine.pool);
+    }
+
+    runs
+}
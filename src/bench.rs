@@ -0,0 +1,249 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+use std::time::Instant;
+
+use anyhow::bail;
+use opencl3::{command_queue::CommandQueue, context::Context, kernel::Kernel, types::cl_float};
+
+use crate::{
+    batch::{self, BatchInput, Engine},
+    cancel,
+    cli::{Args, BenchArgs, Layout},
+    fit, kernels,
+    saxpy::{self, SaxpyBuffers, BYTES_PER_ELEMENT},
+};
+
+/// Min, median, mean and max of a set of timings (ns)
+struct Stats {
+    min: u64,
+    median: u64,
+    mean: u64,
+    max: u64,
+}
+
+impl Stats {
+    fn of(mut samples: Vec<u64>) -> Self {
+        samples.sort_unstable();
+        Self {
+            min: samples[0],
+            median: samples[samples.len() / 2],
+            mean: samples.iter().sum::<u64>() / samples.len() as u64,
+            max: samples[samples.len() - 1],
+        }
+    }
+}
+
+/// The `bench` command: times `bench.iterations` launches after `bench.warmup` untimed ones
+pub fn run(
+    context: &Context,
+    queue: &CommandQueue,
+    args: &Args,
+    bench: &BenchArgs,
+) -> anyhow::Result<()> {
+    if args.layout == Layout::Both {
+        bail!("bench runs a single kernel, pick --layout soa or --layout aos");
+    }
+
+    let kernel = kernels::build_layout_kernel(context, args, args.layout)?;
+    if let Some(batch) = bench.batch {
+        return batched(context, queue, args, bench, &kernel, batch);
+    }
+    let a = args.op.scalar(args.scalar());
+
+    let attempt = |size: usize| -> anyhow::Result<_> {
+        let mut buffers = SaxpyBuffers::create(context, args.layout, size)?;
+        if args.pinned_readback {
+            buffers.enable_pinned_readback(context)?;
+        }
+        let ones: Vec<cl_float> = vec![1.0; size];
+        let sums: Vec<cl_float> = (0..size).map(|i| 1.0 + 1.0 * i as cl_float).collect();
+
+        // Drivers that allocate lazily only fail when the buffers are first used, so --fit-to-memory
+        // launches once more, untimed, before settling on a size
+        if args.fit_to_memory {
+            buffers.run(queue, &kernel, args.transfer, &ones, &sums, a)?;
+        }
+        Ok((buffers, ones, sums))
+    };
+    let (size, (mut buffers, ones, sums)) =
+        fit::with_backoff(bench.size, args.fit_to_memory, attempt)?;
+
+    for _ in 0..bench.warmup {
+        if cancel::requested() {
+            return cancel::check(queue);
+        }
+        buffers.run(queue, &kernel, args.transfer, &ones, &sums, a)?;
+    }
+
+    let mut kernel_ns = Vec::with_capacity(bench.iterations);
+    let mut wall_ns = Vec::with_capacity(bench.iterations);
+    for _ in 0..bench.iterations {
+        if cancel::requested() {
+            break;
+        }
+        let run = buffers.run(queue, &kernel, args.transfer, &ones, &sums, a)?;
+        kernel_ns.push(run.kernel_ns);
+        wall_ns.push(run.wall_ns);
+    }
+
+    println!(
+        "{} elements, {} layout, {} transfers, {} iterations after {} warm-up",
+        size,
+        args.layout,
+        args.transfer,
+        kernel_ns.len(),
+        bench.warmup
+    );
+    if kernel_ns.is_empty() {
+        return cancel::check(queue);
+    }
+    println!(
+        "{:>12} {:>12} {:>12} {:>12} {:>12}",
+        "(us)", "min", "median", "mean", "max"
+    );
+    let kernel = Stats::of(kernel_ns);
+    let median_kernel_ns = kernel.median;
+    for (name, stats) in [("kernel", kernel), ("end-to-end", Stats::of(wall_ns))] {
+        println!(
+            "{:>12} {:>12.3} {:>12.3} {:>12.3} {:>12.3}",
+            name,
+            stats.min as f64 / 1e3,
+            stats.median as f64 / 1e3,
+            stats.mean as f64 / 1e3,
+            stats.max as f64 / 1e3
+        );
+    }
+
+    if let Some(peak) = args.peak_bandwidth {
+        println!(
+            "Median kernel bandwidth {:.2} GB/s, {:.1}% of the {} GB/s peak",
+            bandwidth(size, median_kernel_ns),
+            peak_share(size, median_kernel_ns, peak),
+            peak
+        );
+    }
+
+    cancel::check(queue)?;
+    saxpy::check_kernel_budget(args.max_kernel_ms, median_kernel_ns, "bench (the median)")
+}
+
+/// `bench --batch N`: times batches of `batch` launches pipelined by [`batch::run_pipelined`]
+/// against the same launches run one after the other, each waiting for its result
+fn batched(
+    context: &Context,
+    queue: &CommandQueue,
+    args: &Args,
+    bench: &BenchArgs,
+    kernel: &Kernel,
+    batch: usize,
+) -> anyhow::Result<()> {
+    let size = bench.size;
+    let ones: Vec<cl_float> = vec![1.0; size];
+    let sums: Vec<cl_float> = (0..size).map(|i| 1.0 + 1.0 * i as cl_float).collect();
+    let inputs: Vec<BatchInput> = vec![(args.scalar(), ones, sums); batch];
+
+    // Both share one pool, so after the first round neither allocates
+    let mut engine = Engine::new(context, queue, kernel, args.layout, args.op, args.transfer);
+    let time_both = |engine: &mut Engine| -> anyhow::Result<(u64, u64)> {
+        let start = Instant::now();
+        batch::run_batch(engine, &inputs, |_, _| {})?;
+        let sequential_ns = start.elapsed().as_nanos() as u64;
+
+        let start = Instant::now();
+        let runs = batch::run_pipelined(engine, &inputs);
+        let pipelined_ns = start.elapsed().as_nanos() as u64;
+
+        let failed: Vec<_> = runs
+            .iter()
+            .enumerate()
+            .filter_map(|(i, run)| run.as_ref().err().map(|err| (i, err)))
+            .collect();
+        for (i, err) in &failed {
+            tracing::error!("Launch {} of the batch failed: {:#}", i, err);
+        }
+        if !failed.is_empty() {
+            bail!("{} of the {} batched launches failed", failed.len(), batch);
+        }
+
+        Ok((sequential_ns, pipelined_ns))
+    };
+
+    for _ in 0..bench.warmup {
+        if cancel::requested() {
+            return cancel::check(queue);
+        }
+        time_both(&mut engine)?;
+    }
+
+    let mut sequential_ns = Vec::with_capacity(bench.iterations);
+    let mut pipelined_ns = Vec::with_capacity(bench.iterations);
+    for _ in 0..bench.iterations {
+        if cancel::requested() {
+            break;
+        }
+        let (sequential, pipelined) = time_both(&mut engine)?;
+        sequential_ns.push(sequential);
+        pipelined_ns.push(pipelined);
+    }
+
+    println!(
+        "Batches of {} launches of {} elements, {} layout, {} iterations after {} warm-up",
+        batch,
+        size,
+        args.layout,
+        sequential_ns.len(),
+        bench.warmup
+    );
+    if sequential_ns.is_empty() {
+        return cancel::check(queue);
+    }
+    println!(
+        "{:>24} {:>12} {:>12} {:>12} {:>12}",
+        "(us per batch)", "min", "median", "mean", "max"
+    );
+    let sequential = Stats::of(sequential_ns);
+    let pipelined = Stats::of(pipelined_ns);
+    for (name, stats) in [
+        (format!("independent ({})", args.transfer), &sequential),
+        ("pipelined (events)".to_owned(), &pipelined),
+    ] {
+        println!(
+            "{:>24} {:>12.3} {:>12.3} {:>12.3} {:>12.3}",
+            name,
+            stats.min as f64 / 1e3,
+            stats.median as f64 / 1e3,
+            stats.mean as f64 / 1e3,
+            stats.max as f64 / 1e3
+        );
+    }
+
+    let saved_ns = sequential.median as f64 - pipelined.median as f64;
+    println!(
+        "Pipelining saved {:.3} us per launch ({:.1}% of the median batch)",
+        saved_ns / batch as f64 / 1e3,
+        100.0 * saved_ns / sequential.median.max(1) as f64
+    );
+
+    cancel::check(queue)
+}
+
+/// Achieved bandwidth in GB/s (bytes per nanosecond) of `size` elements in `kernel_ns`
+pub fn bandwidth(size: usize, kernel_ns: u64) -> f64 {
+    (BYTES_PER_ELEMENT * size) as f64 / kernel_ns.max(1) as f64
+}
+
+/// The achieved bandwidth of `size` elements in `kernel_ns` as a percentage of the `peak` of
+/// `--peak-bandwidth`, warning above 100% where the inputs were served from a cache or the peak
+/// is wrong
+pub fn peak_share(size: usize, kernel_ns: u64, peak: f64) -> f64 {
+    let share = 100.0 * bandwidth(size, kernel_ns) / peak;
+    if share > 100.0 {
+        tracing::warn!(
+            "The kernel moved {:.2} GB/s, more than --peak-bandwidth {}: the inputs came from a cache or the peak is too low",
+            bandwidth(size, kernel_ns),
+            peak
+        );
+    }
+    share
+}
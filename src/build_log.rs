@@ -0,0 +1,186 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+//! Performance warnings in the build log of a successful build
+//!
+//! Some compilers report things that cost speed without failing the build: NVIDIA's ptxas lists
+//! spill stores and loads, Intel's compiler says when a kernel was not vectorized, and drivers note
+//! when they had to recompile a kernel. After every build the log is scanned against [`PATTERNS`]
+//! and every matching line is logged as a warning, `--strict-build` fails the build instead. New
+//! drivers only need another entry in the list.
+
+use std::fmt::Write as _;
+
+use anyhow::bail;
+
+/// A kind of performance warning and how to recognize a line reporting it
+pub struct Pattern {
+    /// What the line reports, shown with it
+    pub name: &'static str,
+    /// The compilers known to print it
+    pub drivers: &'static str,
+    matches: fn(&str) -> bool,
+}
+
+/// Every pattern the log is scanned against, each line is tested in lower case
+pub const PATTERNS: &[Pattern] = &[
+    Pattern {
+        name: "register spilling",
+        drivers: "NVIDIA, Intel, AMD",
+        matches: spills,
+    },
+    Pattern {
+        name: "scratch memory",
+        drivers: "AMD",
+        matches: uses_scratch,
+    },
+    Pattern {
+        name: "failed vectorization",
+        drivers: "Intel",
+        matches: |line| line.contains("not vectorized") || line.contains("vectorization failed"),
+    },
+    Pattern {
+        name: "recompilation",
+        drivers: "NVIDIA, Intel",
+        matches: |line| line.contains("recompil"),
+    },
+];
+
+/// A line of a build log matching a pattern
+pub struct Finding<'a> {
+    pub pattern: &'static Pattern,
+    pub line: &'a str,
+}
+
+/// The lines of `log` matching one of [`PATTERNS`], with the first pattern each matches
+pub fn scan(log: &str) -> Vec<Finding<'_>> {
+    log.lines()
+        .filter_map(|line| {
+            let lower = line.to_lowercase();
+            PATTERNS
+                .iter()
+                .find(|pattern| (pattern.matches)(&lower))
+                .map(|pattern| Finding {
+                    pattern,
+                    line: line.trim(),
+                })
+        })
+        .collect()
+}
+
+/// Logs every performance warning in the build `log` of `what` as a warning, or fails with all of
+/// them when `strict`
+pub fn check(log: &str, what: &str, strict: bool) -> anyhow::Result<()> {
+    let findings = scan(log);
+    if findings.is_empty() {
+        return Ok(());
+    }
+
+    if strict {
+        let mut lines = String::new();
+        for finding in &findings {
+            writeln!(lines, "  {}: {}", finding.pattern.name, finding.line).unwrap();
+        }
+        bail!(
+            "The build log of {} reports performance warnings, which --strict-build treats as errors\n{}",
+            what,
+            lines.trim_end()
+        );
+    }
+    for finding in &findings {
+        tracing::warn!(
+            "Build log of {} reports {} ({}): {}",
+            what,
+            finding.pattern.name,
+            finding.pattern.drivers,
+            finding.line
+        );
+    }
+
+    Ok(())
+}
+
+/// Spilled registers, except ptxas' statistics line when it counts 0 bytes of spills
+fn spills(line: &str) -> bool {
+    if !line.contains("spill") {
+        return false;
+    }
+    let counts: Vec<u64> = line
+        .match_indices("bytes spill")
+        .filter_map(|(i, _)| line[..i].split_whitespace().last()?.parse().ok())
+        .collect();
+
+    counts.is_empty() || counts.iter().any(|&bytes| bytes > 0)
+}
+
+/// A non-zero `ScratchSize` in AMD's kernel statistics, or a line saying scratch memory is used
+fn uses_scratch(line: &str) -> bool {
+    if let Some((_, size)) = line.split_once("scratchsize") {
+        let size = size.trim_start_matches([':', '=', ' ', '\t']);
+        let digits: String = size.chars().take_while(char::is_ascii_digit).collect();
+        return digits.parse::<u64>().is_ok_and(|size| size > 0);
+    }
+
+    line.contains("uses scratch")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(log: &str) -> Vec<&'static str> {
+        scan(log)
+            .iter()
+            .map(|finding| finding.pattern.name)
+            .collect()
+    }
+
+    #[test]
+    fn ptxas_spills() {
+        let clean = "ptxas info    : Function properties for saxpy\n    0 bytes stack frame, 0 bytes spill stores, 0 bytes spill loads";
+        let spilled = "ptxas info    : Function properties for saxpy\n    16 bytes stack frame, 8 bytes spill stores, 8 bytes spill loads";
+
+        assert!(names(clean).is_empty());
+        assert_eq!(names(spilled), ["register spilling"]);
+        assert!(!spills(
+            "    0 bytes stack frame, 0 bytes spill stores, 0 bytes spill loads"
+        ));
+        assert!(spills(
+            "    8 bytes stack frame, 0 bytes spill stores, 8 bytes spill loads"
+        ));
+        assert!(spills("warning: register spilling in kernel 'saxpy'"));
+    }
+
+    #[test]
+    fn amd_scratch() {
+        assert!(names("  ScratchSize: 0\n  VGPRs: 12").is_empty());
+        assert_eq!(names("  ScratchSize: 64\n  VGPRs: 256"), ["scratch memory"]);
+        assert!(!uses_scratch("scratchsize = 0"));
+        assert!(uses_scratch("scratchsize = 64"));
+        assert!(uses_scratch("kernel saxpy uses scratch memory"));
+    }
+
+    #[test]
+    fn intel_vectorization() {
+        let log = "Compilation started\nKernel <saxpy> was not vectorized\nDone.";
+
+        assert_eq!(names(log), ["failed vectorization"]);
+        assert!(names(
+            "Compilation started\nKernel <saxpy> was successfully vectorized (8)\nDone."
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn strict_fails_with_the_lines() {
+        let log = "Kernel <saxpy> was not vectorized";
+
+        check(log, "the saxpy program", false).unwrap();
+        check("Done.", "the saxpy program", true).unwrap();
+        let err = check(log, "the saxpy program", true)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("The build log of the saxpy program reports performance warnings"));
+        assert!(err.ends_with("  failed vectorization: Kernel <saxpy> was not vectorized"));
+    }
+}
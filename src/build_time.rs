@@ -0,0 +1,99 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+//! How long building each program took, apart from running its kernels
+//!
+//! Every build path records the time from creating the program to the built program with
+//! [`record`]: compiling source, SPIR-V or a binary given with `--load-binary`, linking with
+//! `--link-helpers`, or loading the binary of an earlier build from the program cache. The log gets
+//! a line per build and `run` lists them in the summary's timing section, so the startup cost of a
+//! compile shows next to the cost of a cache hit.
+
+use std::{
+    sync::{Mutex, PoisonError},
+    time::Instant,
+};
+
+use crate::summary;
+
+/// Where a program came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    /// Compiled with the program cache off or bypassed
+    Compiled,
+    /// Compiled because the program cache had no binary for it, and stored there
+    CacheMiss,
+    /// Created from the binary of an earlier build in the program cache
+    CacheHit,
+    /// Created from the program binary of `--load-binary`
+    Binary,
+    /// Compiled in parts and linked
+    Linked,
+}
+
+impl Origin {
+    /// How the log names it
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Compiled => "compiled",
+            Self::CacheMiss => "cache miss",
+            Self::CacheHit => "cache hit",
+            Self::Binary => "binary",
+            Self::Linked => "linked",
+        }
+    }
+
+    /// The label of the summary row
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Compiled => "build (compiled)",
+            Self::CacheMiss => "build (cache miss)",
+            Self::CacheHit => "build (cache hit)",
+            Self::Binary => "build (binary)",
+            Self::Linked => "build (linked)",
+        }
+    }
+}
+
+/// One built program
+pub struct Build {
+    pub what: String,
+    pub origin: Origin,
+    pub ns: u64,
+}
+
+/// The builds recorded since the last [`take`]
+static BUILDS: Mutex<Vec<Build>> = Mutex::new(Vec::new());
+
+/// Records and logs that building `what` from `origin` took the time since `start`
+pub fn record(what: &str, origin: Origin, start: Instant) {
+    let ns = start.elapsed().as_nanos() as u64;
+    tracing::info!(
+        "Built {} in {} ({})",
+        what,
+        summary::duration(ns),
+        origin.name()
+    );
+
+    BUILDS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .push(Build {
+            what: what.to_owned(),
+            origin,
+            ns,
+        });
+}
+
+/// The builds recorded so far, which are forgotten
+pub fn take() -> Vec<Build> {
+    std::mem::take(&mut *BUILDS.lock().unwrap_or_else(PoisonError::into_inner))
+}
+
+/// The timing rows of `builds` for the summary
+pub fn rows(builds: &[Build]) -> Vec<(&'static str, String)> {
+    builds
+        .iter()
+        .map(|build| (build.origin.label(), summary::duration(build.ns)))
+        .collect()
+}
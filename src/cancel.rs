@@ -0,0 +1,92 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+//! Ctrl-C: finishing the work on the queue and reporting what ran before exiting
+//!
+//! Killing the process while kernels are in flight leaves cleaning up to the driver, which on some
+//! drivers keeps the GPU busy for seconds afterwards. [`install`] replaces the default `SIGINT`
+//! handler with one that only sets a flag. The long-running loops (bench and tune iterations, sweep
+//! sizes, `--repeat` runs, stdin and readback chunks) stop at the next iteration once
+//! [`requested`], print the partial results so far and end with [`check`], which drains the queue
+//! and fails with [`Cancelled`]. `main` then releases the queue and context and exits with
+//! [`EXIT_CODE`]. A second Ctrl-C exits immediately.
+//!
+//! `signal` and `_exit` come from the C runtime on every supported platform, `SIGINT` is 2 on all of
+//! them.
+
+use std::{
+    ffi::c_int,
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use opencl3::command_queue::CommandQueue;
+
+use crate::error::ClContext;
+
+/// The exit code after Ctrl-C, the shell convention of 128 + `SIGINT`
+pub const EXIT_CODE: i32 = 130;
+
+const SIGINT: c_int = 2;
+
+/// What `signal` returns when it failed
+const SIG_ERR: usize = usize::MAX;
+
+extern "C" {
+    fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+    fn _exit(status: c_int) -> !;
+}
+
+/// Whether Ctrl-C was pressed
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+/// The error long-running commands end with after Ctrl-C, once the queue is drained
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Interrupted")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Installs the Ctrl-C handler, see the module docs
+pub fn install() {
+    // SAFETY: the handler only touches an atomic and calls the async-signal-safe `signal` and `_exit`
+    if unsafe { signal(SIGINT, on_interrupt) } == SIG_ERR {
+        tracing::warn!("Failed to install the Ctrl-C handler, Ctrl-C kills the process right away");
+    }
+}
+
+extern "C" fn on_interrupt(_signum: c_int) {
+    if CANCELLED.swap(true, Ordering::SeqCst) {
+        // SAFETY: `_exit` is async-signal-safe and doesn't return
+        unsafe { _exit(EXIT_CODE) }
+    }
+    // The Windows C runtime resets the handler before calling it, without this the second Ctrl-C
+    // would kill the process without the distinct exit code
+    // SAFETY: see `install`
+    unsafe { signal(SIGINT, on_interrupt) };
+}
+
+/// Whether Ctrl-C was pressed, checked by the loops between iterations
+pub fn requested() -> bool {
+    CANCELLED.load(Ordering::SeqCst)
+}
+
+/// Drains `queue` and fails with [`Cancelled`] if Ctrl-C was pressed, for after the partial results
+/// have been printed
+pub fn check(queue: &CommandQueue) -> anyhow::Result<()> {
+    if !requested() {
+        return Ok(());
+    }
+
+    tracing::warn!("Interrupted, finishing the commands on the queue (Ctrl-C again to force exit)");
+    queue
+        .finish()
+        .context("Failed to finish the queue after Ctrl-C")?;
+
+    Err(Cancelled.into())
+}
@@ -0,0 +1,168 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+//! The `chain` command: two kernels connected through an intermediate buffer the host never sees
+//!
+//! `t = a*x + y` is computed by the saxpy kernel into `t`, and `z = t + y` by the vector add. `t`
+//! is created `CL_MEM_HOST_NO_ACCESS`, which lets the driver keep it in memory the host can't map.
+//!
+//! Both kernels come from the one built-in program, or with `--separate-programs` from a program
+//! each. The setup, from the first build to both kernels created, is timed either way, so running
+//! the command both ways shows what one more program costs in the context.
+
+use std::time::Instant;
+
+use anyhow::bail;
+use opencl3::{
+    command_queue::CommandQueue,
+    context::Context,
+    kernel::Kernel,
+    memory::{CL_MEM_HOST_NO_ACCESS, CL_MEM_READ_ONLY, CL_MEM_READ_WRITE, CL_MEM_WRITE_ONLY},
+    types::{cl_event, cl_float, cl_mem_flags, CL_BLOCKING, CL_NON_BLOCKING},
+};
+
+use crate::{
+    build_time,
+    cli::{Args, ChainArgs, Layout, Op},
+    error::ClContext,
+    kernels,
+    launch::launch,
+    run::ARRAY_SIZE,
+    saxpy, summary,
+};
+
+/// Flags of the intermediate `t`, only ever touched by the kernels
+pub const INTERMEDIATE_FLAGS: cl_mem_flags = CL_MEM_READ_WRITE | CL_MEM_HOST_NO_ACCESS;
+
+/// Runs the two kernels over [`ARRAY_SIZE`] elements and checks `z = a*x + 2*y`
+pub fn run(
+    context: &Context,
+    queue: &CommandQueue,
+    args: &Args,
+    chain_args: &ChainArgs,
+) -> anyhow::Result<()> {
+    if args.layout != Layout::Soa {
+        bail!("chain only supports --layout soa");
+    }
+
+    let start = Instant::now();
+    let (saxpy_kernel, vadd_kernel) = if chain_args.separate_programs {
+        (
+            separate_kernel(context, args, Op::Saxpy, kernels::KERNEL_NAME)?,
+            separate_kernel(context, args, Op::Add, kernels::VADD_KERNEL_NAME)?,
+        )
+    } else {
+        combined_kernels(context, args)?
+    };
+    let setup_ns = start.elapsed().as_nanos() as u64;
+
+    let mut x = saxpy::create_buffer(context, CL_MEM_READ_ONLY, ARRAY_SIZE)?;
+    let mut y = saxpy::create_buffer(context, CL_MEM_READ_ONLY, ARRAY_SIZE)?;
+    let t = saxpy::create_buffer(context, INTERMEDIATE_FLAGS, ARRAY_SIZE)?;
+    let z = saxpy::create_buffer(context, CL_MEM_WRITE_ONLY, ARRAY_SIZE)?;
+
+    let ones: Vec<cl_float> = vec![1.0; ARRAY_SIZE];
+    let sums: Vec<cl_float> = (0..ARRAY_SIZE).map(|i| 1.0 + 1.0 * i as cl_float).collect();
+    let a: cl_float = args.scalar();
+
+    // Blocking writes, the host data has to outlive them and this keeps it simple
+    unsafe { queue.enqueue_write_buffer(&mut x, CL_BLOCKING, 0, &ones, &[]) }
+        .context("Failed to write to buffer")?;
+    unsafe { queue.enqueue_write_buffer(&mut y, CL_BLOCKING, 0, &sums, &[]) }
+        .context("Failed to write to buffer")?;
+
+    let saxpy_event = unsafe {
+        launch!(&saxpy_kernel, global = ARRAY_SIZE; z: &t, x: &x, y: &y, a: &a)
+            .enqueue_nd_range(queue)
+    }
+    .map_err(|err| err.context("Failed to execute saxpy kernel"))?;
+
+    let vadd_event = unsafe {
+        launch!(&vadd_kernel, global = ARRAY_SIZE; z: &z, x: &t, y: &y)
+            .set_wait_event(&saxpy_event)
+            .enqueue_nd_range(queue)
+    }
+    .map_err(|err| err.context("Failed to execute vadd kernel"))?;
+
+    let mut result = vec![0.0; ARRAY_SIZE];
+    let events: Vec<cl_event> = vec![vadd_event.get()];
+    let read_event =
+        unsafe { queue.enqueue_read_buffer(&z, CL_NON_BLOCKING, 0, &mut result, &events) }
+            .context("Failed to read buffer")?;
+    read_event.wait().context("Failed to wait to read buffer")?;
+
+    if let Some(i) = (0..ARRAY_SIZE).find(|&i| result[i] != a * ones[i] + sums[i] + sums[i]) {
+        bail!(
+            "Wrong result at index {}: {} instead of {}",
+            i,
+            result[i],
+            a * ones[i] + sums[i] + sums[i]
+        );
+    }
+
+    println!("results front: {}", result[0]);
+    println!("results back: {}", result[ARRAY_SIZE - 1]);
+    println!(
+        "saxpy kernel time (ns): {}, vadd kernel time (ns): {}",
+        saxpy::profiled_ns(&saxpy_event)?,
+        saxpy::profiled_ns(&vadd_event)?
+    );
+    for build in build_time::take() {
+        println!(
+            "{} build time: {} ({})",
+            build.what,
+            summary::duration(build.ns),
+            build.origin.name()
+        );
+    }
+    println!(
+        "setup of {} (builds and kernel creation): {}",
+        if chain_args.separate_programs {
+            "two separate programs"
+        } else {
+            "one combined program"
+        },
+        summary::duration(setup_ns)
+    );
+
+    Ok(())
+}
+
+/// The saxpy and vadd kernels, both from the one built-in program
+fn combined_kernels(context: &Context, args: &Args) -> anyhow::Result<(Kernel, Kernel)> {
+    let program = kernels::build_program(
+        context,
+        kernels::program_source(Layout::Soa),
+        &kernels::arg_info_options(args),
+        "the built-in program",
+        kernels::BuildFlags::from_args(args),
+    )?;
+    let mut program_kernels = kernels::program_kernels(&program)?;
+    let saxpy_kernel = kernels::take_kernel(
+        &mut program_kernels,
+        kernels::KERNEL_NAME,
+        "the built-in program",
+    )?;
+    let vadd_kernel = kernels::take_kernel(
+        &mut program_kernels,
+        kernels::VADD_KERNEL_NAME,
+        "the built-in program",
+    )?;
+
+    Ok((saxpy_kernel, vadd_kernel))
+}
+
+/// The kernel `name` of `op`, from a program of its own
+fn separate_kernel(context: &Context, args: &Args, op: Op, name: &str) -> anyhow::Result<Kernel> {
+    let what = format!("the {} program", name);
+    let program = kernels::build_program(
+        context,
+        &kernels::op_program_source(op, Layout::Soa),
+        &kernels::arg_info_options(args),
+        &what,
+        kernels::BuildFlags::from_args(args),
+    )?;
+    let mut program_kernels = kernels::program_kernels(&program)?;
+
+    kernels::take_kernel(&mut program_kernels, name, &what)
+}
@@ -0,0 +1,2155 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+use std::{fmt, path::PathBuf, str::FromStr};
+
+use anyhow::{anyhow, bail, Context};
+use opencl3::types::cl_float;
+
+use crate::{
+    completions::Shell,
+    config,
+    kernels::{self, BuiltinKernel},
+    launch::NdRange,
+    server, template,
+};
+
+/// The scalar `a` of saxpy when `--scalar` isn't given
+pub const DEFAULT_SCALAR: cl_float = 300.0;
+
+/// The relative error verification allows when `--max-error-threshold` isn't given
+pub const DEFAULT_MAX_ERROR: f64 = 1e-5;
+
+/// The relative error verification allows with `--fast-math` when `--max-error-threshold` isn't given
+pub const FAST_MATH_MAX_ERROR: f64 = 1e-3;
+
+pub const USAGE: &str = "\
+Usage: hello-opencl3 [OPTIONS] [COMMAND] [COMMAND OPTIONS]
+
+Commands:
+    run                        Run saxpy once and print the results (the default)
+    list-devices               List every platform and its devices (alias: list)
+    device-info                Print the driver and OpenCL versions of the selected device (alias: info)
+    bench                      Time repeated launches of the kernel
+    sweep                      Run the kernel over a geometric range of sizes
+    tune                       Time every local work size and report the fastest
+    chain                      Run saxpy into a device-only intermediate and add y with a second kernel
+    atomic-count               Count the elements above a threshold with one global atomic counter
+    pipe-demo                  Pass a*x from one kernel to another through an OpenCL 2.0 pipe and add y
+    repl                       Load, build and run kernels interactively on one context
+    compile                    Build --kernel-file for the selected device and write the binary to --out
+    serve                      Keep the context and programs warm and run the requests sent to --socket
+    client                     Send a request for the kernel of --op over the run inputs to serve
+    replay <PATH>              Run the commands of a trace written by --record and compare the results
+    config print-default       Print a commented template for --config
+    cache clear                Remove every cached program binary
+    completions <SHELL>        Print the completion script for bash, zsh, fish or powershell
+
+Configuration:
+    --config <PATH>            Read options from a TOML file, options on the command line override it
+
+Device selection:
+    --platform <N>             Only consider devices of platform N (as numbered by list-devices)
+    --device <N>               Use the N-th matching device [default: 0, or ask on a terminal when
+                               several GPUs match]
+    --device-type <gpu|cpu|accelerator|all>
+                               Kind of device to look for [default: gpu]
+    --auto-best                Use the matching device with the most compute units, the one with more
+                               global memory on a tie, instead of the first (or asking)
+    --target <PLATFORM:DEVICE> Use a device of any type whose name contains DEVICE on a platform whose
+                               name contains PLATFORM, ignoring case, such as NVIDIA:3090
+
+Logging:
+    --log-level <error|warn|info|debug|trace>
+                               Most verbose log level that is printed [default: trace]
+    -q, --quiet                Shorthand for --log-level warn
+    --fail-on-context-error    Fail the command when the driver reported an asynchronous error through
+                               the context's callback, which is logged either way
+
+Kernel options (run, bench, sweep, tune):
+    --op <saxpy|add|sub|mul|div|max|min>
+                               Kernel to run: z = a*x + y, or the scalar-free z = x + y, x - y, x * y,
+                               x / y, fmax(x, y) or fmin(x, y) [default: saxpy]
+    --layout <aos|soa|both>    Memory layout of the x/y inputs [default: soa]
+    --transfer <blocking|events|finish>
+                               How uploads and readbacks are synchronized [default: events]
+    --blocking-transfers       Shorthand for --transfer blocking
+    --kernel-iters <N>         Repeat the saxpy N times per element (built with -D ITERS=N)
+                               and report GFLOP/s
+    --pinned-readback          Read results back through a page-locked staging buffer
+                               (run also compares its bandwidth against a pageable readback)
+    --define <NAME=VALUE>      Define a preprocessor macro in every kernel build, as -D NAME=VALUE
+                               (repeatable)
+    --build-options <OPTIONS>  Extra options passed to the OpenCL compiler, such as -cl-std=CL2.0
+    --fast-math                Build with -cl-fast-relaxed-math, verification then allows a larger error
+    --mad-enable               Build with -cl-mad-enable
+    --enable-printf            Build with -D DEBUG_PRINT, the built-in kernels then printf every result,
+                               and frame the device output with marker lines ahead of the summary
+    --print-build-log          Print the compiler's build log to stderr even when the build succeeds
+    --strict                   Fail builds whose log reports warnings, such as implicit conversions
+    --strict-build             Fail builds whose log reports register spills, failed vectorization or
+                               recompiles instead of warning about them
+    --no-cache                 Compile the kernel instead of loading the program binary cached by an
+                               earlier build, and don't cache it
+    --kernel-file <PATH>       Build the kernel from the OpenCL C file PATH instead of the built-in one,
+                               it has to take the same arguments (z, x, y, a; z, xy, a with --layout aos).
+                               Repeat it to build several files as one program, in the order given
+    --spirv <PATH>             Load the kernel from the SPIR-V module PATH instead of OpenCL C, needs a
+                               device that accepts SPIR-V (CL_DEVICE_IL_VERSION) and the cl_3_0 feature
+    --kernel-name <NAME>       Kernel of --kernel-file, --spirv or the built-in programs to run [default:
+                               the first one in the files], the built-in kernels are listed at the end
+    --load-binary <PATH>       Load the kernel from a program binary written by compile instead of
+                               building it, the build details next to it are checked against the device
+    --link-helpers             Compile saxpy and a library of helper functions it calls separately and
+                               link them into one program (clCompileProgram/clLinkProgram)
+    --kernel-include-dir <PATH>
+                               Add PATH to the include search path of --kernel-file (repeatable), the
+                               directory of every kernel file is searched first
+    --scalar <A>               The scalar a of saxpy, z = a*x + y [default: 300]
+    --retries <N>              Retry enqueues and allocations failing with CL_OUT_OF_RESOURCES,
+                               CL_MEM_OBJECT_ALLOCATION_FAILURE or CL_DEVICE_NOT_AVAILABLE up to N times
+    --retry-delay-ms <MS>      Wait MS before the first retry, doubling each time [default: 100]
+
+Run options:
+    --compare-transfers        Time every transfer strategy end-to-end and print all of them (also for
+                               sweep, per size with the fastest)
+    --use-host-ptr             Back the device buffers with aligned host memory
+                               (CL_MEM_USE_HOST_PTR), avoids copies on CPU devices
+    --repeat <N>               Run N times, reusing device buffers through a pool
+    --fit-to-memory            Halve the size and retry when the device can't allocate it, up to 8 times
+                               (also for bench), and report the size that ran
+    --stdin                    Read raw little-endian f32 x/y from stdin until EOF, run in chunks and
+                               write the raw f32 results to stdout
+    --stdin-layout <interleaved|concatenated>
+                               x0 y0 x1 y1 ... or all of x followed by all of y [default: interleaved]
+    --chunk-size <SIZE>        Elements per launch with --stdin [default: 1M]
+    --save-profile-csv <PATH>  Append the device, size and kernel/upload/download times of every run
+                               to the CSV file PATH
+    --show-results <N>         Print the first and last N results in the summary [default: 1]
+    --verify-stride <K>        Check only every K-th result (and the last) against the host computation
+                               [default: 1, every result]
+    --no-verify                Skip checking the results against the host computation, which takes
+                               measurable host time on large runs but leaves wrong results unnoticed
+    --max-error-threshold <E>  Fail verification only above a relative error of E, such as 1e-3
+                               [default: 1e-5, 1e-3 with --fast-math]
+    --peak-bandwidth <GB/S>    The device's theoretical memory bandwidth from its spec sheet, such as
+                               936 (OpenCL can't query it), to report the kernel's share of it
+    --max-kernel-ms <MS>       Fail when the profiled kernel time exceeds MS milliseconds, a regression
+                               gate for CI (also for bench, where the median has to stay within it)
+    --record <PATH>            Write the allocations, uploads, launches and readbacks of the run to
+                               the trace PATH for replay
+    --record-data              Store the uploaded and read back values in the trace, instead of
+                               regenerating the uploads and comparing hashes of the readbacks
+    --num-groups <G>           Launch G work-groups of --group-size items, G*L has to cover the input
+    --group-size <L>           and may only exceed it with the bounds-guarded saxpy
+    --local-size <N>           Work-group size of the launch [default: the largest multiple of the
+                               kernel's preferred work-group size multiple it allows]
+    --global <NxM[xK]>         Launch a kernel of --kernel-file over a 1D, 2D or 3D range such as
+                               1024x1024, covering the input [default: the input length]
+    --local <NxM[xK]>          Work-group size in every dimension of --global [default: the driver's]
+    --global-offset <NxM[xK]>  Offset of the global ids in every dimension of --global [default: 0]
+    --offset <N>               Run the saxpy over the elements from N on only, through the global work
+                               offset, and check that the ones before N were left untouched
+    --detect-unwritten         Fill z with a NaN sentinel before the launch and fail when a result
+                               still holds it, as one a kernel skipped would
+    --device-enqueue           Enqueue the saxpy and a sum of z from the device (OpenCL 2.0 on-device
+                               queues), fails with a message on devices without them
+    --template <NAME<TYPE>>    Run a kernel template instantiated with an element type, such as
+                               saxpy<f64>, over the inputs converted to that type
+    --images                   Run a saxpy reading and writing 1D image buffers (image1d_buffer_t)
+                               instead of plain buffers, fails on devices without image support
+    --threads <N>              Run the saxpy from N host threads at once, sharing the context and
+                               program with a command queue each, and check every thread's result
+                               (--repeat times per thread)
+    --async                    Run the --threads saxpys as futures awaiting their events from one host
+                               thread instead, needs the async feature
+    --dump-dag                 Print the dependencies between the enqueued commands as a graphviz digraph
+    --output <PATH>            Write every result to PATH, one value per line
+    --binary                   Write --output as binary with a small header naming the element type
+    --readback-chunk <SIZE>    Read the results back and write them to --output SIZE elements at a time
+                               instead of holding all of them in host memory
+    --size <SIZE>              Number of generated x and y values [default: 1024]
+    --x-file <PATH>            Read x from PATH instead of generating it, the size follows from the file
+    --y-file <PATH>            Read y from PATH, must hold as many values as --x-file
+    --csv-input <PATH>         Read x and y from the CSV file PATH, by the column names in its header
+    --out-file <PATH>          Write z to PATH
+                               (files are .bin/.raw/.f32 little-endian f32, .csv or .npy by extension)
+
+Planning:
+    --dry-run                  Select the device, validate everything and print the buffers, launches
+                               and transfers a command would do, without allocating or enqueuing
+    --json                     Print the list-devices output or the --dry-run plan as JSON
+
+Bench options:
+    --size <SIZE>              Number of elements [default: 1M]
+    --iterations <N>           Timed launches [default: 20]
+    --warmup <N>               Untimed launches before the timed ones [default: 3]
+    --batch <N>                Time batches of N launches enqueued back-to-back with one flush
+                               against N independent launches
+
+Sweep options:
+    --from <SIZE>              Smallest number of elements [default: 1K]
+    --to <SIZE>                Largest number of elements [default: 64M]
+    --factor <N>               Growth factor between sizes [default: 2]
+    --iterations <N>           Launches per size [default: 3]
+    --csv <PATH>               Also write the table as CSV to PATH
+    --realloc-each             Allocate buffers per size instead of once at the largest size
+
+Compile options:
+    --out <PATH>               File the program binary is written to, the device, driver version and
+                               build options go to PATH.json
+
+Serve and client options:
+    --socket <PATH>            Unix socket serve listens on and client connects to, an address on
+                               localhost on Windows [default: /tmp/hello-opencl3.sock, 127.0.0.1:7878]
+
+Tune options:
+    --size <SIZE>              Number of elements [default: 1M]
+    --iterations <N>           Launches per local size, the fastest one counts [default: 5]
+    --guarded                  Run a saxpy that takes the length n and returns for i >= n, so every
+                               local size can be tried with the global size rounded up to a multiple
+
+Chain options:
+    --separate-programs        Build the saxpy and the vadd kernel as a program each instead of one
+                               program holding both, and print the setup time to compare
+
+Atomic count options:
+    --size <SIZE>              Number of elements, cycling through 0 to 999 [default: 1M]
+    --threshold <T>            Count the elements above T [default: 500]
+    --racy                     Also count with a plain load and store instead of the atomic, which
+                               loses the increments of work-items racing each other
+
+Pipe demo options:
+    --size <SIZE>              Number of packets sent through the pipe [default: 1M]
+
+    --list, --list-json and --version-info are kept as aliases of list-devices [--json] and
+    device-info. SIZE accepts a K, M or G suffix (powers of 1024)
+";
+
+/// Which kind of device to select
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DeviceType {
+    #[default]
+    Gpu,
+    Cpu,
+    Accelerator,
+    All,
+}
+
+impl FromStr for DeviceType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gpu" => Ok(Self::Gpu),
+            "cpu" => Ok(Self::Cpu),
+            "accelerator" => Ok(Self::Accelerator),
+            "all" => Ok(Self::All),
+            _ => bail!(
+                "Invalid device type '{}', expected one of: gpu, cpu, accelerator, all",
+                s
+            ),
+        }
+    }
+}
+
+/// How the `x` and `y` inputs are laid out in device memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Layout {
+    /// Separate `x` and `y` buffers
+    #[default]
+    Soa,
+    /// One interleaved `float2` buffer
+    Aos,
+    /// Run both layouts and compare them
+    Both,
+}
+
+impl FromStr for Layout {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "soa" => Ok(Self::Soa),
+            "aos" => Ok(Self::Aos),
+            "both" => Ok(Self::Both),
+            _ => bail!("Invalid layout '{}', expected one of: aos, soa, both", s),
+        }
+    }
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Soa => "soa",
+            Self::Aos => "aos",
+            Self::Both => "both",
+        })
+    }
+}
+
+/// Which element-wise operation the kernel computes, see [`crate::kernels::op_kernel`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Op {
+    /// `z = a*x + y`
+    #[default]
+    Saxpy,
+    /// `z = x + y`, a kernel without the scalar argument like all the ops below
+    Add,
+    /// `z = x - y`
+    Sub,
+    /// `z = x * y`
+    Mul,
+    /// `z = x / y`
+    Div,
+    /// `z = fmax(x, y)`
+    Max,
+    /// `z = fmin(x, y)`
+    Min,
+}
+
+impl Op {
+    pub const ALL: [Self; 7] = [
+        Self::Saxpy,
+        Self::Add,
+        Self::Sub,
+        Self::Mul,
+        Self::Div,
+        Self::Max,
+        Self::Min,
+    ];
+
+    /// The scalar bound as the kernel's last argument, `None` for kernels that don't take one
+    pub fn scalar(self, a: cl_float) -> Option<cl_float> {
+        kernels::op_kernel(self).scalar.then_some(a)
+    }
+}
+
+impl FromStr for Op {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match Self::ALL.into_iter().find(|op| op.to_string() == s) {
+            Some(op) => Ok(op),
+            None => bail!(
+                "Invalid op '{}', expected one of: {}",
+                s,
+                Self::ALL.map(|op| kernels::op_kernel(op).name).join(", ")
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(kernels::op_kernel(*self).name)
+    }
+}
+
+/// A size in each of up to three dimensions, written `1024`, `1024x1024` or `64x64x64`, any of
+/// them with a suffix like `1K`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dims(pub Vec<usize>);
+
+impl FromStr for Dims {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let sizes = s
+            .split(['x', 'X'])
+            .map(parse_size)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if sizes.len() > 3 {
+            bail!(
+                "'{}' has {} dimensions, an NDRange has at most 3",
+                s,
+                sizes.len()
+            );
+        }
+
+        Ok(Self(sizes))
+    }
+}
+
+impl fmt::Display for Dims {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sizes: Vec<String> = self.0.iter().map(usize::to_string).collect();
+        f.write_str(&sizes.join("x"))
+    }
+}
+
+/// `--target PLATFORM:DEVICE`, parts of the platform and device names to select by, matched
+/// ignoring case. An empty part matches every name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    pub platform: String,
+    pub device: String,
+}
+
+impl FromStr for Target {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((platform, device)) = s.split_once(':') else {
+            bail!(
+                "Invalid target '{}', expected PLATFORM:DEVICE such as NVIDIA:3090",
+                s
+            );
+        };
+
+        Ok(Self {
+            platform: platform.trim().to_owned(),
+            device: device.trim().to_owned(),
+        })
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.platform, self.device)
+    }
+}
+
+/// A preprocessor macro the kernels are built with, passed as `-D NAME=VALUE` (or `-D NAME`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Define {
+    pub name: String,
+    pub value: Option<String>,
+}
+
+impl Define {
+    pub fn new(name: &str, value: impl fmt::Display) -> Self {
+        Self {
+            name: name.to_owned(),
+            value: Some(value.to_string()),
+        }
+    }
+}
+
+impl FromStr for Define {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = match s.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (s, None),
+        };
+        let identifier = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !identifier {
+            bail!("Invalid define '{}', the name has to be a C identifier", s);
+        }
+        // The build options are split at whitespace, a value with spaces would fall apart
+        if value.is_some_and(|value| value.is_empty() || value.contains(char::is_whitespace)) {
+            bail!(
+                "Invalid define '{}', the value must be non-empty and without whitespace",
+                s
+            );
+        }
+
+        Ok(Self {
+            name: name.to_owned(),
+            value: value.map(str::to_owned),
+        })
+    }
+}
+
+impl fmt::Display for Define {
+    /// The build option, `-D NAME=VALUE`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.value {
+            Some(value) => write!(f, "-D {}={}", self.name, value),
+            None => write!(f, "-D {}", self.name),
+        }
+    }
+}
+
+/// How host <-> device transfers are synchronized with the kernel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Transfer {
+    /// Blocking writes and a blocking read
+    Blocking,
+    /// Non-blocking transfers chained to the kernel through events
+    #[default]
+    Events,
+    /// Non-blocking transfers without wait lists and a single `clFinish` at the end
+    Finish,
+}
+
+impl Transfer {
+    pub const ALL: [Self; 3] = [Self::Blocking, Self::Events, Self::Finish];
+}
+
+impl FromStr for Transfer {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "blocking" => Ok(Self::Blocking),
+            "events" => Ok(Self::Events),
+            "finish" => Ok(Self::Finish),
+            _ => bail!(
+                "Invalid transfer strategy '{}', expected one of: blocking, events, finish",
+                s
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Transfer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Blocking => "blocking",
+            Self::Events => "events",
+            Self::Finish => "finish",
+        })
+    }
+}
+
+/// How `x` and `y` are arranged in the `--stdin` stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StdinLayout {
+    /// `x0 y0 x1 y1 ...`, processed as it arrives
+    #[default]
+    Interleaved,
+    /// All of `x` followed by all of `y`, processed once the stream ends
+    Concatenated,
+}
+
+impl FromStr for StdinLayout {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "interleaved" => Ok(Self::Interleaved),
+            "concatenated" => Ok(Self::Concatenated),
+            _ => bail!(
+                "Invalid stdin layout '{}', expected one of: interleaved, concatenated",
+                s
+            ),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SweepArgs {
+    pub from: usize,
+    pub to: usize,
+    pub factor: usize,
+    pub iterations: usize,
+    pub csv: Option<PathBuf>,
+    pub realloc_each: bool,
+}
+
+impl Default for SweepArgs {
+    fn default() -> Self {
+        Self {
+            from: 1 << 10,
+            to: 64 << 20,
+            factor: 2,
+            iterations: 3,
+            csv: None,
+            realloc_each: false,
+        }
+    }
+}
+
+impl SweepArgs {
+    /// The geometric sequence of sizes `from, from*factor, ...` up to and including `to`
+    pub fn sizes(&self) -> Vec<usize> {
+        let mut sizes = Vec::new();
+        let mut size = self.from;
+        while size <= self.to {
+            sizes.push(size);
+            size = match size.checked_mul(self.factor) {
+                Some(next) => next,
+                None => break,
+            };
+        }
+
+        sizes
+    }
+}
+
+#[derive(Debug)]
+pub struct BenchArgs {
+    pub size: usize,
+    pub iterations: usize,
+    pub warmup: usize,
+    /// Time batches of this many launches, pipelined and one after the other
+    pub batch: Option<usize>,
+}
+
+impl Default for BenchArgs {
+    fn default() -> Self {
+        Self {
+            size: 1 << 20,
+            iterations: 20,
+            warmup: 3,
+            batch: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct TuneArgs {
+    pub size: usize,
+    pub iterations: usize,
+    /// Run the bounds-guarded kernel, which allows local sizes that don't divide `size`
+    pub guarded: bool,
+}
+
+impl Default for TuneArgs {
+    fn default() -> Self {
+        Self {
+            size: 1 << 20,
+            iterations: 5,
+            guarded: false,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ChainArgs {
+    /// Build the two kernels as two programs instead of one
+    pub separate_programs: bool,
+}
+
+#[derive(Debug)]
+pub struct AtomicCountArgs {
+    pub size: usize,
+    pub threshold: f32,
+    /// Also run the kernel incrementing without atomics
+    pub racy: bool,
+}
+
+impl Default for AtomicCountArgs {
+    fn default() -> Self {
+        Self {
+            size: 1 << 20,
+            threshold: 500.0,
+            racy: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PipeDemoArgs {
+    pub size: usize,
+}
+
+impl Default for PipeDemoArgs {
+    fn default() -> Self {
+        Self { size: 1 << 20 }
+    }
+}
+
+#[derive(Debug, Default)]
+pub enum Command {
+    /// The saxpy demo, what running without a command does
+    #[default]
+    Run,
+    ListDevices,
+    DeviceInfo,
+    Bench(BenchArgs),
+    Sweep(SweepArgs),
+    Tune(TuneArgs),
+    Chain(ChainArgs),
+    AtomicCount(AtomicCountArgs),
+    PipeDemo(PipeDemoArgs),
+    Repl,
+    /// Builds `--kernel-file` into a program binary
+    Compile,
+    Serve,
+    Client,
+    /// Replays the trace at the path, see [`crate::trace`]
+    Replay(PathBuf),
+    /// `config print-default`
+    PrintConfig,
+    /// `cache clear`
+    ClearCache,
+    Completions(Shell),
+    /// Lists the device indices for the completion scripts, not shown in the usage
+    CompleteDevices,
+}
+
+impl Command {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Run => "run",
+            Self::ListDevices => "list-devices",
+            Self::DeviceInfo => "device-info",
+            Self::Bench(_) => "bench",
+            Self::Sweep(_) => "sweep",
+            Self::Tune(_) => "tune",
+            Self::Chain(_) => "chain",
+            Self::AtomicCount(_) => "atomic-count",
+            Self::PipeDemo(_) => "pipe-demo",
+            Self::Repl => "repl",
+            Self::Compile => "compile",
+            Self::Serve => "serve",
+            Self::Client => "client",
+            Self::Replay(_) => "replay",
+            Self::PrintConfig => "config",
+            Self::ClearCache => "cache",
+            Self::Completions(_) => "completions",
+            Self::CompleteDevices => "__complete-devices",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Args {
+    pub platform: Option<usize>,
+    /// `None` takes the first device, or asks when several GPUs match on a terminal
+    pub device: Option<usize>,
+    pub device_type: DeviceType,
+    /// Take the most capable device instead of the first, see [`crate::device_info::select_device`]
+    pub auto_best: bool,
+    /// Select by platform and device name instead of `--platform` and `--device`
+    pub target: Option<Target>,
+    /// `None` keeps the default of `TRACE`
+    pub log_level: Option<tracing::Level>,
+    pub fail_on_context_error: bool,
+    pub op: Op,
+    /// `None` keeps [`DEFAULT_SCALAR`], read through [`Args::scalar`]
+    pub scalar: Option<cl_float>,
+    pub layout: Layout,
+    pub transfer: Transfer,
+    pub compare_transfers: bool,
+    pub kernel_iters: Option<u32>,
+    /// `--define`s, in the order given
+    pub defines: Vec<Define>,
+    pub build_options: Option<String>,
+    pub fast_math: bool,
+    pub mad_enable: bool,
+    /// Defines [`crate::printf::DEFINE`] and frames the device output, see [`crate::printf`]
+    pub enable_printf: bool,
+    pub print_build_log: bool,
+    pub strict: bool,
+    pub strict_build: bool,
+    pub no_cache: bool,
+    /// Sources built as one program, empty for the built-in kernels
+    pub kernel_files: Vec<PathBuf>,
+    pub kernel_include_dirs: Vec<PathBuf>,
+    /// Build the saxpy from separately compiled and linked units
+    pub link_helpers: bool,
+    /// A SPIR-V module to build the kernel from, exclusive with `kernel_files`
+    pub spirv: Option<PathBuf>,
+    /// A program binary written by `compile` to create the kernel from
+    pub load_binary: Option<PathBuf>,
+    /// Where `compile` writes the program binary
+    pub out: Option<PathBuf>,
+    /// `None` keeps [`server::DEFAULT_SOCKET`], read through [`Args::socket`]
+    pub socket: Option<String>,
+    /// `None` picks the first kernel of the file
+    pub kernel_name: Option<String>,
+    pub pinned_readback: bool,
+    pub use_host_ptr: bool,
+    pub repeat: Option<usize>,
+    pub output: Option<PathBuf>,
+    pub binary: bool,
+    /// Elements per readback when the results are streamed to `--output`
+    pub readback_chunk: Option<usize>,
+    /// The number of generated `x` and `y` values of `run`, [`run::ARRAY_SIZE`] if not given
+    pub size: Option<usize>,
+    pub x_file: Option<PathBuf>,
+    pub y_file: Option<PathBuf>,
+    /// A CSV file with `x` and `y` columns, exclusive with `x_file` and `y_file`
+    pub csv_input: Option<PathBuf>,
+    pub out_file: Option<PathBuf>,
+    pub stdin: bool,
+    pub stdin_layout: Option<StdinLayout>,
+    /// `None` keeps the default of 1M elements
+    pub chunk_size: Option<usize>,
+    /// `None` shows one result at either end, unless the results go to a file
+    pub show_results: Option<usize>,
+    /// `None` checks every result
+    pub verify_stride: Option<usize>,
+    pub no_verify: bool,
+    /// `None` keeps the default of [`Args::max_error`]
+    pub max_error_threshold: Option<f64>,
+    /// The kernel time budget, `None` doesn't check the kernel time
+    pub max_kernel_ms: Option<f64>,
+    /// The theoretical memory bandwidth in GB/s the kernel bandwidth is compared against
+    pub peak_bandwidth: Option<f64>,
+    /// The trace the run is recorded to, see [`crate::trace`]
+    pub record: Option<PathBuf>,
+    pub record_data: bool,
+    /// `None` picks the local size from the kernel's preferred work-group size multiple
+    pub local_size: Option<usize>,
+    /// The NDRange replacing the 1D range over the input, read through [`Args::range`]
+    pub global: Option<Dims>,
+    pub local: Option<Dims>,
+    pub global_offset: Option<Dims>,
+    /// The global work offset of the saxpy, the elements before it aren't computed
+    pub offset: Option<usize>,
+    /// Fill `z` with a sentinel before the launch and fail on results still holding it
+    pub detect_unwritten: bool,
+    /// `--num-groups` and `--group-size`, given together, set the global size to their product
+    pub num_groups: Option<usize>,
+    pub group_size: Option<usize>,
+    pub dump_dag: bool,
+    /// Retry allocation failures with half the size, see [`crate::fit`]
+    pub fit_to_memory: bool,
+    /// Host threads running the saxpy at once
+    pub threads: Option<usize>,
+    /// Run the `threads` saxpys as futures on one thread, see [`crate::event_future`]
+    pub async_tasks: bool,
+    pub device_enqueue: bool,
+    /// Run the saxpy over `image1d_buffer_t`s
+    pub images: bool,
+    /// The kernel template instantiation to run instead of the built-in kernels
+    pub template: Option<template::Instance>,
+    pub save_profile_csv: Option<PathBuf>,
+    /// `None` doesn't retry transient errors, see [`crate::retry`]
+    pub retries: Option<u32>,
+    /// `None` keeps the default of [`crate::retry::DEFAULT_DELAY_MS`]
+    pub retry_delay_ms: Option<u64>,
+    pub dry_run: bool,
+    pub json: bool,
+    pub command: Command,
+}
+
+impl Args {
+    pub fn parse() -> anyhow::Result<Self> {
+        Self::parse_from(config::expand(std::env::args().skip(1).collect())?)
+    }
+
+    /// The scalar `a` of `--scalar`, or [`DEFAULT_SCALAR`]
+    pub fn scalar(&self) -> cl_float {
+        self.scalar.unwrap_or(DEFAULT_SCALAR)
+    }
+
+    /// The socket of `serve` and `client`: `--socket`, or [`server::DEFAULT_SOCKET`]
+    pub fn socket(&self) -> &str {
+        self.socket.as_deref().unwrap_or(server::DEFAULT_SOCKET)
+    }
+
+    /// The relative error verification allows: `--max-error-threshold`, or [`DEFAULT_MAX_ERROR`]
+    /// ([`FAST_MATH_MAX_ERROR`] with `--fast-math`)
+    pub fn max_error(&self) -> f64 {
+        self.max_error_threshold.unwrap_or(if self.fast_math {
+            FAST_MATH_MAX_ERROR
+        } else {
+            DEFAULT_MAX_ERROR
+        })
+    }
+
+    /// The NDRange of `--global`, `--local` and `--global-offset`, `None` launches over the input
+    /// length
+    pub fn range(&self) -> Option<NdRange> {
+        let global = self.global.as_ref()?;
+        Some(NdRange {
+            global: global.0.clone(),
+            local: self
+                .local
+                .as_ref()
+                .map_or_else(Vec::new, |local| local.0.clone()),
+            offset: self
+                .global_offset
+                .as_ref()
+                .map_or_else(Vec::new, |offset| offset.0.clone()),
+        })
+    }
+
+    pub fn parse_from(args: impl IntoIterator<Item = String>) -> anyhow::Result<Self> {
+        let mut parsed = Self::default();
+        let mut command_given = false;
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            // Accept both `--flag value` and `--flag=value`
+            let (flag, inline) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => {
+                    (flag.to_owned(), Some(value.to_owned()))
+                }
+                _ => (arg, None),
+            };
+
+            let command = match flag.as_str() {
+                "run" => Some(Command::Run),
+                "list-devices" | "list" | "--list" => Some(Command::ListDevices),
+                "--list-json" => {
+                    parsed.json = true;
+                    Some(Command::ListDevices)
+                }
+                "device-info" | "info" | "--version-info" => Some(Command::DeviceInfo),
+                "bench" => Some(Command::Bench(BenchArgs::default())),
+                "sweep" => Some(Command::Sweep(SweepArgs::default())),
+                "tune" => Some(Command::Tune(TuneArgs::default())),
+                "chain" => Some(Command::Chain(ChainArgs::default())),
+                "atomic-count" => Some(Command::AtomicCount(AtomicCountArgs::default())),
+                "pipe-demo" => Some(Command::PipeDemo(PipeDemoArgs::default())),
+                "repl" => Some(Command::Repl),
+                "compile" => Some(Command::Compile),
+                "serve" => Some(Command::Serve),
+                "client" => Some(Command::Client),
+                "replay" => match args.next() {
+                    Some(path) => Some(Command::Replay(path.into())),
+                    None => bail!("Missing trace for 'replay'"),
+                },
+                "config" => match args.next().as_deref() {
+                    Some("print-default") => Some(Command::PrintConfig),
+                    _ => bail!("Expected 'config print-default'"),
+                },
+                "cache" => match args.next().as_deref() {
+                    Some("clear") => Some(Command::ClearCache),
+                    _ => bail!("Expected 'cache clear'"),
+                },
+                "completions" => match args.next() {
+                    Some(shell) => Some(Command::Completions(shell.parse()?)),
+                    None => bail!("Missing shell for 'completions'"),
+                },
+                "__complete-devices" => Some(Command::CompleteDevices),
+                _ => None,
+            };
+            if let Some(command) = command {
+                if command_given {
+                    bail!(
+                        "'{}' given after the {} command, only one command can run",
+                        flag,
+                        parsed.command.name()
+                    );
+                }
+                parsed.command = command;
+                command_given = true;
+                continue;
+            }
+
+            match flag.as_str() {
+                "-h" | "--help" => {
+                    print!("{}{}{}", USAGE, BuiltinKernel::help(), template::help());
+                    std::process::exit(0);
+                }
+                "--platform" => {
+                    parsed.platform = Some(parse_number(&flag, &value(&flag, inline, &mut args)?)?)
+                }
+                "--device" => {
+                    parsed.device = Some(parse_number(&flag, &value(&flag, inline, &mut args)?)?)
+                }
+                "--device-type" => parsed.device_type = value(&flag, inline, &mut args)?.parse()?,
+                "--auto-best" => parsed.auto_best = true,
+                "--target" => parsed.target = Some(value(&flag, inline, &mut args)?.parse()?),
+                "--log-level" => {
+                    let level = value(&flag, inline, &mut args)?;
+                    parsed.log_level = Some(level.parse().map_err(|_| {
+                        anyhow!(
+                            "Invalid log level '{}', expected one of: error, warn, info, debug, trace",
+                            level
+                        )
+                    })?)
+                }
+                "-q" | "--quiet" => parsed.log_level = Some(tracing::Level::WARN),
+                "--fail-on-context-error" => parsed.fail_on_context_error = true,
+                "--op" => parsed.op = value(&flag, inline, &mut args)?.parse()?,
+                "--scalar" => {
+                    parsed.scalar = Some(parse_number(&flag, &value(&flag, inline, &mut args)?)?)
+                }
+                "--layout" => parsed.layout = value(&flag, inline, &mut args)?.parse()?,
+                "--transfer" => parsed.transfer = value(&flag, inline, &mut args)?.parse()?,
+                "--blocking-transfers" => parsed.transfer = Transfer::Blocking,
+                "--compare-transfers" => parsed.compare_transfers = true,
+                "--pinned-readback" => parsed.pinned_readback = true,
+                "--use-host-ptr" => parsed.use_host_ptr = true,
+                "--output" => parsed.output = Some(value(&flag, inline, &mut args)?.into()),
+                "--binary" => parsed.binary = true,
+                "--x-file" => parsed.x_file = Some(value(&flag, inline, &mut args)?.into()),
+                "--y-file" => parsed.y_file = Some(value(&flag, inline, &mut args)?.into()),
+                "--csv-input" => parsed.csv_input = Some(value(&flag, inline, &mut args)?.into()),
+                "--out-file" => parsed.out_file = Some(value(&flag, inline, &mut args)?.into()),
+                "--dump-dag" => parsed.dump_dag = true,
+                "--async" => parsed.async_tasks = true,
+                "--device-enqueue" => parsed.device_enqueue = true,
+                "--images" => parsed.images = true,
+                "--template" => parsed.template = Some(value(&flag, inline, &mut args)?.parse()?),
+                "--retries" => {
+                    parsed.retries = Some(parse_number(&flag, &value(&flag, inline, &mut args)?)?)
+                }
+                "--retry-delay-ms" => {
+                    parsed.retry_delay_ms =
+                        Some(parse_number(&flag, &value(&flag, inline, &mut args)?)?)
+                }
+                "--save-profile-csv" => {
+                    parsed.save_profile_csv = Some(value(&flag, inline, &mut args)?.into())
+                }
+                "--show-results" => {
+                    parsed.show_results =
+                        Some(parse_number(&flag, &value(&flag, inline, &mut args)?)?)
+                }
+                "--verify-stride" => {
+                    parsed.verify_stride =
+                        Some(parse_number(&flag, &value(&flag, inline, &mut args)?)?)
+                }
+                "--max-kernel-ms" => {
+                    parsed.max_kernel_ms =
+                        Some(parse_number(&flag, &value(&flag, inline, &mut args)?)?)
+                }
+                "--peak-bandwidth" => {
+                    parsed.peak_bandwidth =
+                        Some(parse_number(&flag, &value(&flag, inline, &mut args)?)?)
+                }
+                "--no-verify" => parsed.no_verify = true,
+                "--max-error-threshold" => {
+                    parsed.max_error_threshold =
+                        Some(parse_number(&flag, &value(&flag, inline, &mut args)?)?)
+                }
+                "--record" => parsed.record = Some(value(&flag, inline, &mut args)?.into()),
+                "--record-data" => parsed.record_data = true,
+                "--num-groups" => {
+                    parsed.num_groups =
+                        Some(parse_number(&flag, &value(&flag, inline, &mut args)?)?)
+                }
+                "--group-size" => {
+                    parsed.group_size =
+                        Some(parse_number(&flag, &value(&flag, inline, &mut args)?)?)
+                }
+                "--local-size" => {
+                    parsed.local_size =
+                        Some(parse_number(&flag, &value(&flag, inline, &mut args)?)?)
+                }
+                "--global" | "--local" | "--global-offset" => {
+                    let dims = value(&flag, inline, &mut args)?
+                        .parse()
+                        .with_context(|| format!("Invalid value for '{}'", flag))?;
+                    match flag.as_str() {
+                        "--global" => parsed.global = Some(dims),
+                        "--local" => parsed.local = Some(dims),
+                        _ => parsed.global_offset = Some(dims),
+                    }
+                }
+                "--detect-unwritten" => parsed.detect_unwritten = true,
+                "--offset" => parsed.offset = Some(parse_size(&value(&flag, inline, &mut args)?)?),
+                "--threads" => {
+                    parsed.threads = Some(parse_number(&flag, &value(&flag, inline, &mut args)?)?)
+                }
+                "--fit-to-memory" => parsed.fit_to_memory = true,
+                "--stdin" => parsed.stdin = true,
+                "--stdin-layout" => {
+                    parsed.stdin_layout = Some(value(&flag, inline, &mut args)?.parse()?)
+                }
+                "--readback-chunk" => {
+                    parsed.readback_chunk = Some(parse_size(&value(&flag, inline, &mut args)?)?)
+                }
+                "--chunk-size" => {
+                    parsed.chunk_size = Some(parse_size(&value(&flag, inline, &mut args)?)?)
+                }
+                "--repeat" => {
+                    parsed.repeat = Some(parse_number(&flag, &value(&flag, inline, &mut args)?)?)
+                }
+                "--kernel-iters" => {
+                    parsed.kernel_iters =
+                        Some(parse_number(&flag, &value(&flag, inline, &mut args)?)?)
+                }
+                "--define" => parsed
+                    .defines
+                    .push(value(&flag, inline, &mut args)?.parse()?),
+                "--build-options" => parsed.build_options = Some(value(&flag, inline, &mut args)?),
+                "--fast-math" => parsed.fast_math = true,
+                "--mad-enable" => parsed.mad_enable = true,
+                "--enable-printf" => parsed.enable_printf = true,
+                "--print-build-log" => parsed.print_build_log = true,
+                "--strict" => parsed.strict = true,
+                "--strict-build" => parsed.strict_build = true,
+                "--no-cache" => parsed.no_cache = true,
+                "--kernel-file" => parsed
+                    .kernel_files
+                    .push(value(&flag, inline, &mut args)?.into()),
+                "--kernel-include-dir" => parsed
+                    .kernel_include_dirs
+                    .push(value(&flag, inline, &mut args)?.into()),
+                "--link-helpers" => parsed.link_helpers = true,
+                "--spirv" => parsed.spirv = Some(value(&flag, inline, &mut args)?.into()),
+                "--load-binary" => {
+                    parsed.load_binary = Some(value(&flag, inline, &mut args)?.into())
+                }
+                "--out" => parsed.out = Some(value(&flag, inline, &mut args)?.into()),
+                "--socket" => parsed.socket = Some(value(&flag, inline, &mut args)?),
+                "--kernel-name" => parsed.kernel_name = Some(value(&flag, inline, &mut args)?),
+                "--json" => parsed.json = true,
+                "--dry-run" => parsed.dry_run = true,
+                "--size" => {
+                    let size = parse_size(&value(&flag, inline, &mut args)?)?;
+                    match &mut parsed.command {
+                        Command::Run => parsed.size = Some(size),
+                        Command::Bench(bench) => bench.size = size,
+                        Command::Tune(tune) => tune.size = size,
+                        Command::AtomicCount(count) => count.size = size,
+                        Command::PipeDemo(pipe) => pipe.size = size,
+                        _ => bail!(
+                            "'--size' is only valid for the run, bench, tune, atomic-count and pipe-demo commands"
+                        ),
+                    }
+                }
+                "--iterations" => {
+                    let iterations = parse_number(&flag, &value(&flag, inline, &mut args)?)?;
+                    match &mut parsed.command {
+                        Command::Bench(bench) => bench.iterations = iterations,
+                        Command::Sweep(sweep) => sweep.iterations = iterations,
+                        Command::Tune(tune) => tune.iterations = iterations,
+                        _ => bail!(
+                            "'--iterations' is only valid for the bench, sweep and tune commands"
+                        ),
+                    }
+                }
+                "--warmup" => match &mut parsed.command {
+                    Command::Bench(bench) => {
+                        bench.warmup = parse_number(&flag, &value(&flag, inline, &mut args)?)?
+                    }
+                    _ => bail!("'--warmup' is only valid for the bench command"),
+                },
+                "--batch" => match &mut parsed.command {
+                    Command::Bench(bench) => {
+                        bench.batch = Some(parse_number(&flag, &value(&flag, inline, &mut args)?)?)
+                    }
+                    _ => bail!("'--batch' is only valid for the bench command"),
+                },
+                "--from" => {
+                    sweep_args(&mut parsed.command, &flag)?.from =
+                        parse_size(&value(&flag, inline, &mut args)?)?
+                }
+                "--to" => {
+                    sweep_args(&mut parsed.command, &flag)?.to =
+                        parse_size(&value(&flag, inline, &mut args)?)?
+                }
+                "--factor" => {
+                    sweep_args(&mut parsed.command, &flag)?.factor =
+                        parse_number(&flag, &value(&flag, inline, &mut args)?)?
+                }
+                "--csv" => {
+                    sweep_args(&mut parsed.command, &flag)?.csv =
+                        Some(value(&flag, inline, &mut args)?.into())
+                }
+                "--realloc-each" => sweep_args(&mut parsed.command, &flag)?.realloc_each = true,
+                "--guarded" => match &mut parsed.command {
+                    Command::Tune(tune) => tune.guarded = true,
+                    _ => bail!("'--guarded' is only valid for the tune command"),
+                },
+                "--threshold" => match &mut parsed.command {
+                    Command::AtomicCount(count) => {
+                        count.threshold = parse_number(&flag, &value(&flag, inline, &mut args)?)?
+                    }
+                    _ => bail!("'--threshold' is only valid for the atomic-count command"),
+                },
+                "--separate-programs" => match &mut parsed.command {
+                    Command::Chain(chain) => chain.separate_programs = true,
+                    _ => bail!("'--separate-programs' is only valid for the chain command"),
+                },
+                "--racy" => match &mut parsed.command {
+                    Command::AtomicCount(count) => count.racy = true,
+                    _ => bail!("'--racy' is only valid for the atomic-count command"),
+                },
+                _ => bail!("Unknown argument '{}'\n\n{}", flag, USAGE),
+            }
+        }
+
+        parsed.validate_device()?;
+        parsed.validate_kernel_name()?;
+        parsed.validate_dry_run()?;
+        parsed.validate_atomic_count()?;
+        parsed.validate_pipe_demo()?;
+        parsed.validate_run_outputs()?;
+        parsed.validate_run_inputs()?;
+        parsed.validate_verification()?;
+        parsed.validate_run_range()?;
+        parsed.validate_run_modes()?;
+        parsed.validate_kernel_source()?;
+        parsed.validate_limits()?;
+        parsed.validate_record()?;
+        parsed.validate_serve()?;
+        parsed.validate_compile()?;
+        parsed.validate_sweep()?;
+        parsed.validate_bench()?;
+        parsed.validate_tune()?;
+
+        Ok(parsed)
+    }
+
+    /// Checks the device selection flags against each other
+    fn validate_device(&self) -> anyhow::Result<()> {
+        if self.auto_best && self.device.is_some() {
+            bail!("--auto-best picks the device itself and can't be combined with --device");
+        }
+
+        if self.target.is_some() && (self.platform.is_some() || self.device.is_some()) {
+            bail!("--target selects the platform and device by name and can't be combined with --platform or --device");
+        }
+
+        Ok(())
+    }
+
+    /// Checks `--kernel-name` naming a built-in kernel against `--layout` and `--op`, and takes
+    /// the op of an element-wise one
+    fn validate_kernel_name(&mut self) -> anyhow::Result<()> {
+        if let (Some(name), [], None, None) = (
+            &self.kernel_name,
+            self.kernel_files.as_slice(),
+            &self.spirv,
+            &self.load_binary,
+        ) {
+            let kernel = match name.parse::<BuiltinKernel>() {
+                Ok(kernel) => kernel,
+                Err(err) => bail!(
+                    "--kernel-name picks a kernel of --kernel-file, --spirv, --load-binary or the built-in element-wise programs, and {}",
+                    err
+                ),
+            };
+            match kernel.layout_op() {
+                // One of the kernels of the built-in programs, which picks the op ahead of the
+                // checks below
+                Some((layout, op)) if !self.link_helpers => {
+                    if self.layout != layout {
+                        bail!("{} runs over --layout {}", kernel, layout);
+                    }
+                    if self.op != Op::default() && self.op != op {
+                        bail!("{} computes --op {}, not --op {}", kernel, op, self.op);
+                    }
+                    if self.kernel_iters.is_some() {
+                        bail!(
+                            "--kernel-iters runs its own saxpy and can't be combined with --kernel-name {}",
+                            kernel
+                        );
+                    }
+                    self.op = op;
+                }
+                _ => bail!(
+                    "--kernel-name picks a kernel of --kernel-file, --spirv, --load-binary or the built-in element-wise programs, the built-in {} is selected with {}",
+                    kernel,
+                    kernel.selected_by()
+                ),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks `--dry-run` and `--json` against the commands they apply to
+    fn validate_dry_run(&self) -> anyhow::Result<()> {
+        if self.dry_run
+            && !matches!(
+                self.command,
+                Command::Run
+                    | Command::Bench(_)
+                    | Command::Sweep(_)
+                    | Command::Tune(_)
+                    | Command::Chain(_)
+                    | Command::AtomicCount(_)
+            )
+        {
+            bail!(
+                "--dry-run applies to the run, bench, sweep, tune, chain and atomic-count commands"
+            );
+        }
+
+        if self.json && !(self.dry_run || matches!(self.command, Command::ListDevices)) {
+            bail!("--json is only valid for list-devices and --dry-run");
+        }
+
+        Ok(())
+    }
+
+    /// Checks the options of `atomic-count`
+    fn validate_atomic_count(&self) -> anyhow::Result<()> {
+        if let Command::AtomicCount(count) = &self.command {
+            if count.size == 0 || u32::try_from(count.size).is_err() {
+                bail!(
+                    "atomic-count --size must be from 1 to {} elements",
+                    u32::MAX
+                );
+            }
+            if !count.threshold.is_finite() {
+                bail!("--threshold must be a finite number such as 500");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks the options of `pipe-demo`
+    fn validate_pipe_demo(&self) -> anyhow::Result<()> {
+        if let Command::PipeDemo(pipe) = &self.command {
+            if pipe.size == 0 || u32::try_from(pipe.size).is_err() {
+                bail!("pipe-demo --size must be from 1 to {} packets", u32::MAX);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks the options of `run` picking what a run reports and how its results come back
+    fn validate_run_outputs(&self) -> anyhow::Result<()> {
+        let is_run = matches!(self.command, Command::Run);
+
+        if self.compare_transfers
+            && (self.layout == Layout::Both
+                || !(is_run || matches!(self.command, Command::Sweep(_))))
+        {
+            bail!("--compare-transfers runs a single layout and only applies to the run and sweep commands");
+        }
+
+        if self.repeat.is_some()
+            && (self.layout == Layout::Both || self.compare_transfers || !is_run)
+        {
+            bail!("--repeat runs a single layout and transfer strategy with the run command");
+        }
+
+        if self.output.is_some() && (self.compare_transfers || self.repeat.is_some() || !is_run) {
+            bail!("--output writes the results of a single run and can't be combined with --compare-transfers, --repeat or another command");
+        }
+
+        if self.use_host_ptr
+            && (self.layout == Layout::Both
+                || self.compare_transfers
+                || self.repeat.is_some()
+                || self.stdin
+                || !is_run)
+        {
+            bail!("--use-host-ptr only applies to a single run of one layout");
+        }
+
+        if self.show_results.is_some()
+            && (self.layout == Layout::Both
+                || self.compare_transfers
+                || self.repeat.is_some()
+                || self.stdin
+                || !is_run)
+        {
+            bail!("--show-results applies to the summary of a single run of one layout");
+        }
+
+        if self.save_profile_csv.is_some()
+            && (self.layout == Layout::Both || self.compare_transfers || self.stdin || !is_run)
+        {
+            bail!("--save-profile-csv records single runs of one layout with the run command, optionally with --repeat");
+        }
+
+        if self.dump_dag && (self.repeat.is_some() || !is_run) {
+            bail!("--dump-dag only applies to the run command and can't be combined with --repeat");
+        }
+
+        if self.binary && self.output.is_none() {
+            bail!("--binary requires --output");
+        }
+
+        if self.readback_chunk.is_some() {
+            if self.output.is_none() {
+                bail!("--readback-chunk streams the results to --output, which is missing");
+            }
+            if self.layout == Layout::Both
+                || self.pinned_readback
+                || self.use_host_ptr
+                || self.show_results.is_some()
+                || self.dump_dag
+                || self.save_profile_csv.is_some()
+            {
+                bail!("--readback-chunk only writes the results of one layout and can't be combined with --pinned-readback, --use-host-ptr, --show-results, --dump-dag or --save-profile-csv");
+            }
+            if self.readback_chunk == Some(0) {
+                bail!("--readback-chunk must be at least 1");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks the options of `run` and `client` replacing the generated inputs
+    fn validate_run_inputs(&self) -> anyhow::Result<()> {
+        let is_run = matches!(self.command, Command::Run);
+        let is_client = matches!(self.command, Command::Client);
+
+        if self.x_file.is_some() != self.y_file.is_some() {
+            bail!("--x-file and --y-file have to be given together");
+        }
+
+        if self.csv_input.is_some() && self.x_file.is_some() {
+            bail!("--csv-input replaces --x-file and --y-file, give one or the other");
+        }
+
+        if self.size.is_some() {
+            if !is_run {
+                bail!("--size has to follow the command it applies to, run, bench, tune, atomic-count or pipe-demo");
+            }
+            if self.x_file.is_some() || self.csv_input.is_some() || self.stdin {
+                bail!("--size sets the number of generated inputs, which --x-file, --csv-input and --stdin replace");
+            }
+        }
+
+        if self.size == Some(0) {
+            bail!("--size must be at least 1");
+        }
+
+        if (self.x_file.is_some() || self.csv_input.is_some() || self.out_file.is_some())
+            && !(is_run || is_client)
+        {
+            bail!("--x-file, --y-file, --csv-input and --out-file only apply to the run and client commands");
+        }
+
+        if self.out_file.is_some()
+            && (self.output.is_some() || self.compare_transfers || self.repeat.is_some())
+        {
+            bail!("--out-file writes the results of a single run and can't be combined with --output, --compare-transfers or --repeat");
+        }
+
+        if (self.stdin_layout.is_some() || self.chunk_size.is_some()) && !self.stdin {
+            bail!("--stdin-layout and --chunk-size only apply to --stdin");
+        }
+
+        if self.stdin
+            && (!is_run
+                || self.layout == Layout::Both
+                || self.compare_transfers
+                || self.repeat.is_some()
+                || self.output.is_some()
+                || self.x_file.is_some()
+                || self.csv_input.is_some()
+                || self.out_file.is_some()
+                || self.dump_dag)
+        {
+            bail!("--stdin streams a single layout with the run command and replaces the other input and output options");
+        }
+
+        if self.chunk_size == Some(0) {
+            bail!("--chunk-size must be at least 1");
+        }
+
+        Ok(())
+    }
+
+    /// Checks the options configuring the verification of a run and the checks of its kernel
+    fn validate_verification(&self) -> anyhow::Result<()> {
+        let is_run = matches!(self.command, Command::Run);
+        let is_client = matches!(self.command, Command::Client);
+
+        if self.verify_stride.is_some()
+            && (self.layout == Layout::Both
+                || self.compare_transfers
+                || (self.repeat.is_some() && self.threads.is_none())
+                || self.stdin
+                || !is_run)
+        {
+            bail!("--verify-stride applies to the verification of a single run of one layout with the run command");
+        }
+
+        if self.verify_stride == Some(0) {
+            bail!("--verify-stride must be at least 1");
+        }
+
+        if self.no_verify {
+            if !is_run || self.device_enqueue || self.images || self.template.is_some() {
+                bail!("--no-verify skips the verification of the run command, which --device-enqueue, --images and --template always do");
+            }
+            if self.verify_stride.is_some() || self.max_error_threshold.is_some() {
+                bail!("--no-verify skips the verification that --verify-stride and --max-error-threshold configure");
+            }
+        }
+
+        if let Some(threshold) = self.max_error_threshold {
+            if !(is_run || is_client || matches!(self.command, Command::Replay(_))) {
+                bail!("--max-error-threshold applies to the verification of the run, client and replay commands");
+            }
+            if !(threshold.is_finite() && threshold >= 0.0) {
+                bail!("--max-error-threshold must be a finite, non-negative error such as 1e-3");
+            }
+        }
+
+        if self.detect_unwritten
+            && (self.layout == Layout::Both
+                || self.compare_transfers
+                || self.repeat.is_some()
+                || self.threads.is_some()
+                || self.stdin
+                || self.readback_chunk.is_some()
+                || self.device_enqueue
+                || self.images
+                || self.template.is_some()
+                || !is_run)
+        {
+            bail!("--detect-unwritten checks a single run of one layout with the run command and can't be combined with --compare-transfers, --repeat, --threads, --stdin, --readback-chunk, --device-enqueue, --images or --template");
+        }
+
+        if self.enable_printf {
+            if self.layout == Layout::Both
+                || self.compare_transfers
+                || self.repeat.is_some()
+                || self.threads.is_some()
+                || self.stdin
+                || self.fit_to_memory
+                || self.readback_chunk.is_some()
+                || self.pinned_readback
+                || !is_run
+            {
+                bail!("--enable-printf frames the output of a single launch of one layout with the run command and can't be combined with --compare-transfers, --repeat, --threads, --stdin, --fit-to-memory, --readback-chunk or --pinned-readback");
+            }
+            if self.device_enqueue
+                || self.images
+                || self.template.is_some()
+                || self.spirv.is_some()
+                || self.load_binary.is_some()
+            {
+                bail!("--enable-printf defines DEBUG_PRINT for the saxpy kernels and --kernel-file, it can't reach --device-enqueue, --images, --template or the prebuilt --spirv and --load-binary");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks the options of `run` setting the NDRange of its launch
+    fn validate_run_range(&self) -> anyhow::Result<()> {
+        let is_run = matches!(self.command, Command::Run);
+
+        if self.local_size.is_some()
+            && (self.layout == Layout::Both
+                || self.compare_transfers
+                || self.repeat.is_some()
+                || self.stdin
+                || self.device_enqueue
+                || self.readback_chunk.is_some()
+                || !is_run)
+        {
+            bail!("--local-size applies to a single run of one layout with the run command");
+        }
+
+        if self.local_size == Some(0) {
+            bail!("--local-size must be at least 1");
+        }
+
+        if let Some(global) = &self.global {
+            if self.layout == Layout::Both
+                || self.compare_transfers
+                || self.repeat.is_some()
+                || self.threads.is_some()
+                || self.stdin
+                || self.fit_to_memory
+                || self.readback_chunk.is_some()
+                || self.local_size.is_some()
+                || self.num_groups.is_some()
+                || !is_run
+            {
+                bail!("--global sets the range of a single run of one layout with the run command and can't be combined with --compare-transfers, --repeat, --threads, --stdin, --fit-to-memory, --readback-chunk, --local-size or --num-groups");
+            }
+            if self.kernel_files.is_empty() && self.spirv.is_none() && self.load_binary.is_none() {
+                bail!("--global launches a kernel of --kernel-file, --spirv or --load-binary, the built-in kernels index a 1D range over the input");
+            }
+            if global.0.contains(&0) || self.local.as_ref().is_some_and(|l| l.0.contains(&0)) {
+                bail!("--global and --local sizes must be at least 1");
+            }
+            for (flag, dims) in [
+                ("--local", &self.local),
+                ("--global-offset", &self.global_offset),
+            ] {
+                if let Some(dims) = dims.as_ref().filter(|dims| dims.0.len() != global.0.len()) {
+                    bail!(
+                        "{} {} and --global {} differ in their number of dimensions",
+                        flag,
+                        dims,
+                        global
+                    );
+                }
+            }
+        } else if self.local.is_some() || self.global_offset.is_some() {
+            bail!("--local and --global-offset apply to the range of --global, which is missing");
+        }
+
+        if self.offset.is_some() {
+            if self.layout == Layout::Both
+                || self.compare_transfers
+                || self.repeat.is_some()
+                || self.threads.is_some()
+                || self.stdin
+                || self.fit_to_memory
+                || self.readback_chunk.is_some()
+                || !is_run
+            {
+                bail!("--offset applies to a single run of one layout with the run command and can't be combined with --compare-transfers, --repeat, --threads, --stdin, --fit-to-memory or --readback-chunk");
+            }
+            if self.device_enqueue
+                || self.images
+                || self.template.is_some()
+                || self.global.is_some()
+            {
+                bail!("--offset offsets the saxpy kernels, --device-enqueue, --images and --template launch their own and --global has --global-offset");
+            }
+        }
+
+        if self.num_groups.is_some() || self.group_size.is_some() {
+            if self.num_groups.is_none() || self.group_size.is_none() {
+                bail!("--num-groups and --group-size are given together");
+            }
+            if self.local_size.is_some() {
+                bail!("--group-size sets the local size, it can't be combined with --local-size");
+            }
+            if self.layout == Layout::Both
+                || self.compare_transfers
+                || self.repeat.is_some()
+                || self.stdin
+                || self.device_enqueue
+                || self.images
+                || self.template.is_some()
+                || self.threads.is_some()
+                || self.readback_chunk.is_some()
+                || !is_run
+            {
+                bail!("--num-groups and --group-size apply to a single run of one layout with the run command");
+            }
+            if self.num_groups == Some(0) || self.group_size == Some(0) {
+                bail!("--num-groups and --group-size must be at least 1");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks the alternative ways `run` has of running, with their own kernels or threads
+    fn validate_run_modes(&self) -> anyhow::Result<()> {
+        let is_run = matches!(self.command, Command::Run);
+
+        if self.device_enqueue
+            && (!is_run
+                || self.layout != Layout::Soa
+                || self.op != Op::Saxpy
+                || self.compare_transfers
+                || self.repeat.is_some()
+                || self.stdin
+                || self.output.is_some()
+                || self.out_file.is_some()
+                || self.kernel_iters.is_some()
+                || !self.kernel_files.is_empty()
+                || self.spirv.is_some()
+                || self.load_binary.is_some()
+                || self.link_helpers
+                || self.dump_dag
+                || self.dry_run)
+        {
+            bail!("--device-enqueue runs its own saxpy and sum kernel once with the run command and can't be combined with other layouts, ops, kernels or output options");
+        }
+
+        if self.images
+            && (!is_run
+                || self.layout != Layout::Soa
+                || self.op != Op::Saxpy
+                || self.device_enqueue
+                || self.compare_transfers
+                || self.repeat.is_some()
+                || self.stdin
+                || self.output.is_some()
+                || self.out_file.is_some()
+                || self.local_size.is_some()
+                || self.use_host_ptr
+                || self.pinned_readback
+                || self.show_results.is_some()
+                || self.save_profile_csv.is_some()
+                || self.kernel_iters.is_some()
+                || !self.kernel_files.is_empty()
+                || self.spirv.is_some()
+                || self.load_binary.is_some()
+                || self.link_helpers
+                || self.dump_dag
+                || self.dry_run)
+        {
+            bail!("--images runs its own image saxpy once with the run command and can't be combined with other layouts, ops, kernels or buffer and output options");
+        }
+
+        if self.template.is_some()
+            && (!is_run
+                || self.layout != Layout::Soa
+                || self.op != Op::Saxpy
+                || self.device_enqueue
+                || self.images
+                || self.compare_transfers
+                || self.repeat.is_some()
+                || self.stdin
+                || self.output.is_some()
+                || self.out_file.is_some()
+                || self.local_size.is_some()
+                || self.use_host_ptr
+                || self.pinned_readback
+                || self.show_results.is_some()
+                || self.save_profile_csv.is_some()
+                || self.kernel_iters.is_some()
+                || !self.kernel_files.is_empty()
+                || self.spirv.is_some()
+                || self.load_binary.is_some()
+                || self.link_helpers
+                || self.dump_dag
+                || self.dry_run)
+        {
+            bail!("--template picks the kernel and its element type, it runs once with the run command and can't be combined with --layout, --op, other kernels or buffer and output options");
+        }
+
+        if self.threads.is_some()
+            && (!is_run
+                || self.layout == Layout::Both
+                || self.device_enqueue
+                || self.images
+                || self.template.is_some()
+                || self.compare_transfers
+                || self.stdin
+                || self.output.is_some()
+                || self.out_file.is_some()
+                || self.local_size.is_some()
+                || self.use_host_ptr
+                || self.pinned_readback
+                || self.show_results.is_some()
+                || self.save_profile_csv.is_some()
+                || !self.kernel_files.is_empty()
+                || self.spirv.is_some()
+                || self.load_binary.is_some()
+                || self.link_helpers
+                || self.dump_dag)
+        {
+            bail!("--threads runs a built-in kernel of one layout in every thread with the run command and can't be combined with other run modes, kernels or buffer and output options");
+        }
+
+        if self.fit_to_memory
+            && (!(is_run || matches!(self.command, Command::Bench(_)))
+                || self.layout == Layout::Both
+                || self.compare_transfers
+                || self.repeat.is_some()
+                || self.stdin
+                || self.readback_chunk.is_some()
+                || self.device_enqueue
+                || self.images
+                || self.template.is_some()
+                || self.threads.is_some()
+                || self.num_groups.is_some())
+        {
+            bail!("--fit-to-memory applies to a single run of one layout or to bench, and can't be combined with other run modes or --num-groups");
+        }
+
+        if self.retry_delay_ms.is_some() && self.retries.is_none() {
+            bail!("--retry-delay-ms only applies with --retries");
+        }
+
+        if self.async_tasks && self.threads.is_none() {
+            bail!("--async runs the saxpys of --threads as futures, it requires --threads");
+        }
+
+        if self.threads == Some(0) {
+            bail!("--threads must be at least 1");
+        }
+
+        Ok(())
+    }
+
+    /// Checks the options picking where the kernel comes from and what it computes
+    fn validate_kernel_source(&self) -> anyhow::Result<()> {
+        if self.kernel_iters == Some(0) {
+            bail!("--kernel-iters must be at least 1");
+        }
+
+        if self.scalar.is_some() && self.op.scalar(0.0).is_none() {
+            bail!(
+                "--op {} takes no scalar, --scalar only applies to saxpy",
+                self.op
+            );
+        }
+
+        if self.kernel_iters.is_some() && self.op != Op::Saxpy {
+            bail!(
+                "--kernel-iters repeats the saxpy and can't be combined with --op {}",
+                self.op
+            );
+        }
+
+        if !self.kernel_include_dirs.is_empty() && self.kernel_files.is_empty() {
+            bail!("--kernel-include-dir requires --kernel-file");
+        }
+
+        if self.link_helpers
+            && (self.layout != Layout::Soa
+                || self.op != Op::Saxpy
+                || self.kernel_iters.is_some()
+                || !self.kernel_files.is_empty()
+                || self.spirv.is_some()
+                || !matches!(
+                    self.command,
+                    Command::Run | Command::Bench(_) | Command::Sweep(_) | Command::Tune(_)
+                ))
+        {
+            bail!("--link-helpers builds its own saxpy for --layout soa with the run, bench, sweep and tune commands and can't be combined with another --op, --kernel-iters, --kernel-file or --spirv");
+        }
+
+        if self.spirv.is_some() {
+            if !self.kernel_files.is_empty() {
+                bail!("--spirv and --kernel-file both provide the kernel, pick one");
+            }
+            if !matches!(
+                self.command,
+                Command::Run | Command::Bench(_) | Command::Sweep(_) | Command::Tune(_)
+            ) {
+                bail!("--spirv applies to the run, bench, sweep and tune commands");
+            }
+            if self.layout == Layout::Both {
+                bail!("--spirv holds the kernel for one layout, pick --layout soa or --layout aos");
+            }
+            if self.kernel_iters.is_some() {
+                bail!(
+                    "--kernel-iters selects a built-in kernel and can't be combined with --spirv"
+                );
+            }
+        }
+
+        if self.load_binary.is_some() {
+            if !self.kernel_files.is_empty() || self.spirv.is_some() || self.link_helpers {
+                bail!("--load-binary provides the kernel and can't be combined with --kernel-file, --spirv or --link-helpers");
+            }
+            if !matches!(
+                self.command,
+                Command::Run | Command::Bench(_) | Command::Sweep(_) | Command::Tune(_)
+            ) {
+                bail!("--load-binary applies to the run, bench, sweep and tune commands");
+            }
+            if self.layout == Layout::Both {
+                bail!("--load-binary holds the kernel for one layout, pick --layout soa or --layout aos");
+            }
+            if self.kernel_iters.is_some() {
+                bail!("--kernel-iters selects a built-in kernel and can't be combined with --load-binary");
+            }
+        }
+
+        if !self.kernel_files.is_empty() && !matches!(self.command, Command::Compile) {
+            if !matches!(
+                self.command,
+                Command::Run | Command::Bench(_) | Command::Sweep(_) | Command::Tune(_)
+            ) {
+                bail!("--kernel-file applies to the run, bench, sweep and tune commands");
+            }
+            if self.layout == Layout::Both {
+                bail!("--kernel-file holds the kernel for one layout, pick --layout soa or --layout aos");
+            }
+            if self.kernel_iters.is_some() {
+                bail!("--kernel-iters selects a built-in kernel and can't be combined with --kernel-file");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks the thresholds a run or bench is held to
+    fn validate_limits(&self) -> anyhow::Result<()> {
+        let is_run = matches!(self.command, Command::Run);
+
+        if let Some(max_kernel_ms) = self.max_kernel_ms {
+            if !(max_kernel_ms.is_finite() && max_kernel_ms > 0.0) {
+                bail!("--max-kernel-ms must be a positive number of milliseconds such as 2.5");
+            }
+            let timed_bench = matches!(self.command, Command::Bench(BenchArgs { batch: None, .. }));
+            if !(is_run || timed_bench)
+                || self.stdin
+                || self.readback_chunk.is_some()
+                || self.device_enqueue
+                || self.images
+                || self.template.is_some()
+            {
+                bail!("--max-kernel-ms checks the profiled kernel time of the run and bench commands and can't be combined with --stdin, --readback-chunk, --device-enqueue, --images, --template or bench --batch");
+            }
+        }
+
+        if let Some(peak_bandwidth) = self.peak_bandwidth {
+            if !(peak_bandwidth.is_finite() && peak_bandwidth > 0.0) {
+                bail!("--peak-bandwidth must be a positive number of GB/s such as 936");
+            }
+            let timed_bench = matches!(self.command, Command::Bench(BenchArgs { batch: None, .. }));
+            let single_run = is_run
+                && self.layout != Layout::Both
+                && !self.compare_transfers
+                && self.repeat.is_none()
+                && self.threads.is_none();
+            if !(single_run || timed_bench)
+                || self.stdin
+                || self.readback_chunk.is_some()
+                || self.device_enqueue
+                || self.images
+                || self.template.is_some()
+            {
+                bail!("--peak-bandwidth rates the kernel of a single run of one layout or of bench and can't be combined with --compare-transfers, --repeat, --threads, --stdin, --readback-chunk, --device-enqueue, --images, --template or bench --batch");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks `--record` against the run it traces
+    fn validate_record(&self) -> anyhow::Result<()> {
+        let is_run = matches!(self.command, Command::Run);
+
+        if self.record.is_some()
+            && (!is_run
+                || self.dry_run
+                || self.spirv.is_some()
+                || self.load_binary.is_some()
+                || self.link_helpers
+                || self.device_enqueue
+                || self.images
+                || self.template.is_some())
+        {
+            bail!("--record traces the run command over kernels built from source and can't be combined with --dry-run, --spirv, --load-binary, --link-helpers, --device-enqueue, --images or --template");
+        }
+
+        if self.record_data && self.record.is_none() {
+            bail!("--record-data stores the values in the trace of --record, which is missing");
+        }
+
+        Ok(())
+    }
+
+    /// Checks the options of `serve` and `client`
+    fn validate_serve(&self) -> anyhow::Result<()> {
+        if self.socket.is_some() && !matches!(self.command, Command::Serve | Command::Client) {
+            bail!("'--socket' is only valid for the serve and client commands");
+        }
+
+        if matches!(self.command, Command::Serve | Command::Client)
+            && (self.kernel_name.is_some() || self.kernel_iters.is_some())
+        {
+            bail!(
+                "{} runs the kernels of the built-in element-wise programs, picked by --op and --layout for client, and can't be combined with --kernel-name or --kernel-iters",
+                self.command.name()
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Checks the options of `compile`
+    fn validate_compile(&self) -> anyhow::Result<()> {
+        if matches!(self.command, Command::Compile) {
+            if self.kernel_files.is_empty() || self.out.is_none() {
+                bail!(
+                    "compile builds --kernel-file into the program binary --out, both are required"
+                );
+            }
+            if self.dry_run {
+                bail!("compile doesn't run anything, --dry-run doesn't apply");
+            }
+        } else if self.out.is_some() {
+            bail!("'--out' is only valid for the compile command");
+        }
+
+        Ok(())
+    }
+
+    /// Checks the options of `sweep`
+    fn validate_sweep(&self) -> anyhow::Result<()> {
+        if let Command::Sweep(sweep) = &self.command {
+            if sweep.from == 0 || sweep.from > sweep.to {
+                bail!("--from must be non-zero and not larger than --to");
+            }
+            if sweep.factor < 2 {
+                bail!("--factor must be at least 2");
+            }
+            if sweep.iterations == 0 {
+                bail!("--iterations must be at least 1");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks the options of `bench`
+    fn validate_bench(&self) -> anyhow::Result<()> {
+        let Command::Bench(BenchArgs {
+            size,
+            iterations,
+            batch,
+            ..
+        }) = &self.command
+        else {
+            return Ok(());
+        };
+        validate_iterations(*size, *iterations)?;
+
+        if let Some(batch) = batch {
+            if *batch == 0 {
+                bail!("--batch must be at least 1");
+            }
+            if self.fit_to_memory || self.pinned_readback {
+                bail!("--batch reads into the results directly and can't be combined with --fit-to-memory or --pinned-readback");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks the options of `tune`
+    fn validate_tune(&self) -> anyhow::Result<()> {
+        let Command::Tune(TuneArgs {
+            size,
+            iterations,
+            guarded,
+            ..
+        }) = &self.command
+        else {
+            return Ok(());
+        };
+        validate_iterations(*size, *iterations)?;
+
+        if *guarded
+            && (self.layout != Layout::Soa
+                || self.op != Op::Saxpy
+                || self.kernel_iters.is_some()
+                || !self.kernel_files.is_empty()
+                || self.spirv.is_some()
+                || self.load_binary.is_some()
+                || self.link_helpers)
+        {
+            bail!("--guarded runs its own saxpy for --layout soa and can't be combined with another --op, --kernel-iters, --kernel-file, --spirv, --load-binary or --link-helpers");
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks the `--size` and `--iterations` of `bench` and `tune`
+fn validate_iterations(size: usize, iterations: usize) -> anyhow::Result<()> {
+    if size == 0 {
+        bail!("--size must be at least 1");
+    }
+    if iterations == 0 {
+        bail!("--iterations must be at least 1");
+    }
+
+    Ok(())
+}
+
+/// Whether `token` names a command (or one of the aliases of a command)
+pub fn is_command(token: &str) -> bool {
+    matches!(
+        token,
+        "run"
+            | "list-devices"
+            | "list"
+            | "--list"
+            | "--list-json"
+            | "device-info"
+            | "info"
+            | "--version-info"
+            | "bench"
+            | "sweep"
+            | "tune"
+            | "chain"
+            | "atomic-count"
+            | "pipe-demo"
+            | "repl"
+            | "compile"
+            | "serve"
+            | "client"
+            | "replay"
+            | "config"
+            | "cache"
+            | "completions"
+            | "__complete-devices"
+    )
+}
+
+/// Parses an element count such as `4096`, `1K` or `64M`
+pub fn parse_size(s: &str) -> anyhow::Result<usize> {
+    let (digits, shift) = match s.trim().to_ascii_uppercase() {
+        s if s.ends_with('K') => (s[..s.len() - 1].to_owned(), 10),
+        s if s.ends_with('M') => (s[..s.len() - 1].to_owned(), 20),
+        s if s.ends_with('G') => (s[..s.len() - 1].to_owned(), 30),
+        s => (s, 0),
+    };
+
+    let value: usize = digits
+        .parse()
+        .with_context(|| format!("Invalid size '{}'", s))?;
+    value
+        .checked_mul(1 << shift)
+        .ok_or_else(|| anyhow!("Size '{}' is too large", s))
+}
+
+fn parse_number<T: FromStr>(flag: &str, value: &str) -> anyhow::Result<T> {
+    value
+        .parse()
+        .map_err(|_| anyhow!("Invalid value '{}' for '{}'", value, flag))
+}
+
+fn sweep_args<'a>(command: &'a mut Command, flag: &str) -> anyhow::Result<&'a mut SweepArgs> {
+    match command {
+        Command::Sweep(sweep) => Ok(sweep),
+        _ => bail!("'{}' is only valid for the sweep command", flag),
+    }
+}
+
+fn value(
+    flag: &str,
+    inline: Option<String>,
+    args: &mut impl Iterator<Item = String>,
+) -> anyhow::Result<String> {
+    inline
+        .or_else(|| args.next())
+        .ok_or_else(|| anyhow!("Missing value for '{}'", flag))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dims(s: &str) -> anyhow::Result<Vec<usize>> {
+        s.parse::<Dims>().map(|dims| dims.0)
+    }
+
+    fn parse(args: &str) -> anyhow::Result<Args> {
+        Args::parse_from(args.split_whitespace().map(str::to_owned))
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(dims("1024").unwrap(), [1024]);
+        assert_eq!(dims("1024x1024").unwrap(), [1024, 1024]);
+        assert_eq!(dims("64X64x1K").unwrap(), [64, 64, 1024]);
+        assert_eq!(Dims(vec![1024, 1024]).to_string(), "1024x1024");
+        // Zero is a valid offset, --global and --local reject it
+        assert_eq!(dims("0x4").unwrap(), [0, 4]);
+    }
+
+    #[test]
+    fn bad_ranges() {
+        assert_eq!(
+            dims("1x2x3x4").unwrap_err().to_string(),
+            "'1x2x3x4' has 4 dimensions, an NDRange has at most 3"
+        );
+        assert_eq!(dims("12x").unwrap_err().to_string(), "Invalid size ''");
+        assert_eq!(dims("x12").unwrap_err().to_string(), "Invalid size ''");
+        assert_eq!(dims("12y4").unwrap_err().to_string(), "Invalid size '12y4'");
+        assert_eq!(dims("").unwrap_err().to_string(), "Invalid size ''");
+        assert!(dims("99999999999999999999").is_err());
+    }
+
+    #[test]
+    fn range_options() {
+        let args =
+            parse("run --kernel-file k.cl --global 64x32 --local 8x8 --global-offset 0x4").unwrap();
+        let range = args.range().unwrap();
+        assert_eq!(range.global, [64, 32]);
+        assert_eq!(range.local, [8, 8]);
+        assert_eq!(range.offset, [0, 4]);
+
+        let err = |args| format!("{:#}", parse(args).unwrap_err());
+        assert!(err("run --kernel-file k.cl --global 0x4").contains("must be at least 1"));
+        assert!(err("run --kernel-file k.cl --global 4x4 --local 4")
+            .contains("differ in their number of dimensions"));
+        assert!(err("run --kernel-file k.cl --global 1x2x3x4").contains("at most 3"));
+        assert!(err("run --local 4").contains("which is missing"));
+    }
+
+    #[test]
+    fn run_size() {
+        let args = parse("run --size 4K").unwrap();
+        assert_eq!(args.size, Some(4096));
+        assert_eq!(crate::run::inputs(&args).unwrap().0.len(), 4096);
+        // run is the default command
+        assert_eq!(parse("--size=100").unwrap().size, Some(100));
+        assert_eq!(
+            crate::run::inputs(&parse("run").unwrap()).unwrap().0.len(),
+            crate::run::ARRAY_SIZE
+        );
+
+        let err = |args| format!("{:#}", parse(args).unwrap_err());
+        assert!(err("run --size 0").contains("at least 1"));
+        assert!(err("run --size 1K --csv-input xy.csv").contains("which --x-file, --csv-input"));
+        assert!(err("--size 1K bench").contains("has to follow the command"));
+        assert!(err("list-devices --size 1K").contains("only valid for the run, bench"));
+    }
+}
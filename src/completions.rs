@@ -0,0 +1,373 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+//! `completions <shell>`: shell completion scripts generated from the usage text
+//!
+//! Commands and options are read from [`cli::USAGE`], so the scripts follow it without a second
+//! list to maintain. Values come from the `<a|b|c>` placeholders, paths complete as files,
+//! `--kernel-name` offers the built-in kernels and `--device` asks the binary itself
+//! (`__complete-devices`), which quietly completes nothing when no OpenCL runtime is available.
+
+use std::{fmt::Write as _, str::FromStr};
+
+use anyhow::bail;
+
+use crate::{cli, kernels::BuiltinKernel};
+
+const BIN: &str = "hello-opencl3";
+
+/// Shells completions can be generated for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+    Powershell,
+}
+
+impl FromStr for Shell {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bash" => Ok(Self::Bash),
+            "zsh" => Ok(Self::Zsh),
+            "fish" => Ok(Self::Fish),
+            "powershell" => Ok(Self::Powershell),
+            _ => bail!(
+                "Invalid shell '{}', expected one of: bash, zsh, fish, powershell",
+                s
+            ),
+        }
+    }
+}
+
+/// What the value of an option completes to
+#[derive(Debug, PartialEq, Eq)]
+enum Hint {
+    /// The option is a switch without a value
+    None,
+    /// One of a fixed set of values
+    Values(Vec<String>),
+    File,
+    Device,
+    /// A number or size, nothing to complete
+    Any,
+}
+
+struct Opt {
+    names: Vec<String>,
+    hint: Hint,
+    description: String,
+}
+
+struct Cmd {
+    name: String,
+    description: String,
+}
+
+/// Prints the completion script for `shell`
+pub fn print(shell: Shell) {
+    let (commands, options) = parse_usage(cli::USAGE);
+    let script = match shell {
+        Shell::Bash => bash(&commands, &options),
+        Shell::Zsh => zsh(&commands, &options),
+        Shell::Fish => fish(&commands, &options),
+        Shell::Powershell => powershell(&commands, &options),
+    };
+    print!("{}", script);
+}
+
+/// Collects the commands and options from the usage text
+///
+/// Option lines start with `-` after the indentation and hold the names, an optional `<VALUE>`
+/// placeholder and the description, which is on the next line when the names are too long
+fn parse_usage(usage: &str) -> (Vec<Cmd>, Vec<Opt>) {
+    let mut commands = Vec::new();
+    let mut options: Vec<Opt> = Vec::new();
+    let mut in_commands = false;
+
+    let lines: Vec<&str> = usage.lines().collect();
+    for (i, line) in lines.iter().enumerate() {
+        if !line.starts_with(' ') {
+            in_commands = line.starts_with("Commands:");
+            continue;
+        }
+        let trimmed = line.trim();
+
+        let (spec, description) = match trimmed.split_once("  ") {
+            Some((spec, description)) => (spec, description.trim()),
+            None => (trimmed, lines.get(i + 1).map_or("", |next| next.trim())),
+        };
+
+        if in_commands {
+            if let Some(name) = spec.split_whitespace().next() {
+                commands.push(Cmd {
+                    name: name.to_owned(),
+                    description: description.to_owned(),
+                });
+            }
+            continue;
+        }
+        if !trimmed.starts_with('-') {
+            continue;
+        }
+
+        let (names, placeholder) = match spec.split_once(" <") {
+            Some((names, placeholder)) => (names, Some(placeholder.trim_end_matches('>'))),
+            None => (spec, None),
+        };
+        let names: Vec<String> = names.split(", ").map(str::to_owned).collect();
+        // Skips prose such as the note on aliases and options listed under several commands
+        if names
+            .iter()
+            .any(|name| !name.starts_with('-') || name.contains(' '))
+            || options.iter().any(|opt| opt.names == names)
+        {
+            continue;
+        }
+
+        let hint = match placeholder {
+            None => Hint::None,
+            Some(_) if names.iter().any(|name| name == "--device") => Hint::Device,
+            Some("PATH") => Hint::File,
+            Some(_) if names.iter().any(|name| name == "--kernel-name") => Hint::Values(
+                BuiltinKernel::all()
+                    .map(|kernel| kernel.to_string())
+                    .collect(),
+            ),
+            Some(values) if values.contains('|') => {
+                Hint::Values(values.split('|').map(str::to_owned).collect())
+            }
+            Some(_) => Hint::Any,
+        };
+
+        options.push(Opt {
+            names,
+            hint,
+            description: description.to_owned(),
+        });
+    }
+
+    (commands, options)
+}
+
+fn bash(commands: &[Cmd], options: &[Opt]) -> String {
+    let mut cases = String::new();
+    for opt in options {
+        let action = match &opt.hint {
+            Hint::None => continue,
+            Hint::Values(values) => {
+                format!("COMPREPLY=($(compgen -W \"{}\" -- \"$cur\"))", values.join(" "))
+            }
+            Hint::File => "COMPREPLY=($(compgen -f -- \"$cur\"))".to_owned(),
+            Hint::Device => format!(
+                "COMPREPLY=($(compgen -W \"$({} __complete-devices 2>/dev/null | cut -f1)\" -- \"$cur\"))",
+                BIN
+            ),
+            Hint::Any => "COMPREPLY=()".to_owned(),
+        };
+        writeln!(
+            cases,
+            "        {})\n            {}\n            return\n            ;;",
+            opt.names.join("|"),
+            action
+        )
+        .unwrap();
+    }
+
+    let words: Vec<&str> = commands
+        .iter()
+        .map(|cmd| cmd.name.as_str())
+        .chain(
+            options
+                .iter()
+                .flat_map(|opt| opt.names.iter().map(String::as_str)),
+        )
+        .collect();
+
+    format!(
+        r#"_hello_opencl3() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}"
+    local prev="${{COMP_WORDS[COMP_CWORD-1]}}"
+
+    case "$prev" in
+{cases}    esac
+
+    COMPREPLY=($(compgen -W "{words}" -- "$cur"))
+}}
+
+complete -F _hello_opencl3 {bin}
+"#,
+        cases = cases,
+        words = words.join(" "),
+        bin = BIN
+    )
+}
+
+fn zsh(commands: &[Cmd], options: &[Opt]) -> String {
+    let mut specs = String::new();
+    for opt in options {
+        let action = match &opt.hint {
+            Hint::None => String::new(),
+            Hint::Values(values) => format!(":value:({})", values.join(" ")),
+            Hint::File => ":file:_files".to_owned(),
+            Hint::Device => ":device:_hello_opencl3_devices".to_owned(),
+            Hint::Any => ":value: ".to_owned(),
+        };
+        for name in &opt.names {
+            writeln!(
+                specs,
+                "        '{}[{}]{}' \\",
+                name,
+                zsh_escape(&opt.description),
+                action
+            )
+            .unwrap();
+        }
+    }
+
+    let mut command_list = String::new();
+    for cmd in commands {
+        writeln!(
+            command_list,
+            "        '{}:{}'",
+            cmd.name,
+            zsh_escape(&cmd.description)
+        )
+        .unwrap();
+    }
+
+    format!(
+        r#"#compdef {bin}
+
+_hello_opencl3_devices() {{
+    local -a devices
+    devices=(${{(f)"$({bin} __complete-devices 2>/dev/null | sed 's/\t/:/')"}})
+    _describe 'device' devices
+}}
+
+_hello_opencl3_commands() {{
+    local -a commands
+    commands=(
+{command_list}    )
+    _describe 'command' commands
+}}
+
+_hello_opencl3() {{
+    _arguments \
+{specs}        '*::command:_hello_opencl3_commands'
+}}
+
+_hello_opencl3 "$@"
+"#,
+        bin = BIN,
+        command_list = command_list,
+        specs = specs
+    )
+}
+
+fn fish(commands: &[Cmd], options: &[Opt]) -> String {
+    let mut script = String::new();
+    for cmd in commands {
+        writeln!(
+            script,
+            "complete -c {} -f -n __fish_use_subcommand -a {} -d '{}'",
+            BIN,
+            cmd.name,
+            fish_escape(&cmd.description)
+        )
+        .unwrap();
+    }
+
+    for opt in options {
+        let mut line = format!("complete -c {}", BIN);
+        for name in &opt.names {
+            match name.strip_prefix("--") {
+                Some(long) => write!(line, " -l {}", long).unwrap(),
+                None => write!(line, " -s {}", name.trim_start_matches('-')).unwrap(),
+            }
+        }
+        match &opt.hint {
+            Hint::None => {}
+            Hint::Values(values) => write!(line, " -x -a '{}'", values.join(" ")).unwrap(),
+            Hint::File => line.push_str(" -r -F"),
+            Hint::Device => {
+                write!(line, " -x -a '({} __complete-devices 2>/dev/null)'", BIN).unwrap()
+            }
+            Hint::Any => line.push_str(" -x"),
+        }
+        writeln!(line, " -d '{}'", fish_escape(&opt.description)).unwrap();
+        script.push_str(&line);
+    }
+
+    script
+}
+
+fn powershell(commands: &[Cmd], options: &[Opt]) -> String {
+    let mut values = String::new();
+    for opt in options {
+        let completions = match &opt.hint {
+            Hint::Values(values) => values
+                .iter()
+                .map(|value| format!("'{}'", value))
+                .collect::<Vec<_>>()
+                .join(", "),
+            Hint::Device => format!(
+                "& {} __complete-devices 2>$null | ForEach-Object {{ ($_ -split \"`t\")[0] }}",
+                BIN
+            ),
+            Hint::None | Hint::File | Hint::Any => continue,
+        };
+        for name in &opt.names {
+            writeln!(values, "        '{}' = {{ {} }}", name, completions).unwrap();
+        }
+    }
+
+    let words: Vec<String> = commands
+        .iter()
+        .map(|cmd| format!("'{}'", cmd.name))
+        .chain(
+            options
+                .iter()
+                .flat_map(|opt| opt.names.iter().map(|name| format!("'{}'", name))),
+        )
+        .collect();
+
+    format!(
+        r#"Register-ArgumentCompleter -Native -CommandName {bin} -ScriptBlock {{
+    param($wordToComplete, $commandAst, $cursorPosition)
+
+    $values = @{{
+{values}    }}
+
+    $elements = $commandAst.CommandElements | ForEach-Object {{ $_.ToString() }}
+    $previous = if ($wordToComplete) {{ $elements[-2] }} else {{ $elements[-1] }}
+
+    $candidates = if ($values.ContainsKey($previous)) {{
+        & $values[$previous]
+    }} else {{
+        @({words})
+    }}
+
+    $candidates | Where-Object {{ $_ -like "$wordToComplete*" }} | ForEach-Object {{
+        [System.Management.Automation.CompletionResult]::new($_, $_, 'ParameterValue', $_)
+    }}
+}}
+"#,
+        bin = BIN,
+        values = values,
+        words = words.join(", ")
+    )
+}
+
+fn zsh_escape(s: &str) -> String {
+    s.replace('\'', "'\\''")
+        .replace('[', "\\[")
+        .replace(']', "\\]")
+        .replace(':', "\\:")
+}
+
+fn fish_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('\'', "\\'")
+}
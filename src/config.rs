@@ -0,0 +1,763 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+//! `--config run.toml`: experiment definitions in a TOML file
+//!
+//! Every key corresponds to a command-line option and the file is turned into those options, placed
+//! before the ones given on the command line. Keys the command line sets are dropped from the file,
+//! as are the keys that only make sense with a value it overrides (see [`DEPENDENT_KEYS`]), so the
+//! command line wins over the file and the file over the defaults. Only the subset of TOML
+//! the options need is understood: `[section]` headers, `key = value` with strings, integers,
+//! floats and booleans, and `#` comments. Unknown sections and keys are errors.
+
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context};
+
+use crate::cli;
+
+/// A template listing every key with its default, printed by `config print-default`
+pub const TEMPLATE: &str = r#"# hello-opencl3 run configuration
+#
+# Keys mirror the command-line options of the same name, options given on the command line
+# override the file. Relative paths are resolved against the directory of this file.
+
+# Command to run: run, bench, sweep, tune, chain, compile, serve or client
+command = "run"
+# log-level = "trace"
+# fail-on-context-error = false
+# dry-run = false
+
+[device]
+# platform = 0
+# device = 0
+# device-type = "gpu"
+# auto-best = false
+# target = "NVIDIA:3090"
+
+[kernel]
+# op = "saxpy"
+# scalar = 300.0
+# layout = "soa"
+# transfer = "events"
+# kernel-iters = 1
+# pinned-readback = false
+# define = "TILE=16"
+# build-options = "-cl-std=CL2.0"
+# fast-math = false
+# mad-enable = false
+# enable-printf = false
+# print-build-log = false
+# strict = false
+# strict-build = false
+# no-cache = false
+# kernel-file = "saxpy.cl"
+# spirv = "saxpy.spv"
+# load-binary = "saxpy.bin"
+# kernel-name = "saxpy_float"
+# kernel-include-dir = "include"
+# link-helpers = false
+# retries = 3
+# retry-delay-ms = 100
+
+[run]
+# size = "64K"
+# x-file = "x.npy"
+# y-file = "y.npy"
+# csv-input = "xy.csv"
+# out-file = "z.npy"
+# output = "results.txt"
+# binary = false
+# readback-chunk = "1M"
+# show-results = 1
+# verify-stride = 1000
+# no-verify = false
+# max-error-threshold = 1e-3
+# max-kernel-ms = 2.5
+# peak-bandwidth = 936
+# record = "trace.bin"
+# record-data = false
+# local-size = 256
+# num-groups = 4
+# group-size = 256
+# global = "1024x1024"
+# local = "16x16"
+# global-offset = "0x0"
+# offset = 256
+# detect-unwritten = false
+# use-host-ptr = false
+# repeat = 10
+# fit-to-memory = false
+# compare-transfers = false
+# dump-dag = false
+# threads = 4
+# async = false
+# device-enqueue = false
+# template = "saxpy<f64>"
+# images = false
+# save-profile-csv = "profile.csv"
+# stdin = false
+# stdin-layout = "interleaved"
+# chunk-size = "1M"
+
+[bench]
+# size = "1M"
+# iterations = 20
+# warmup = 3
+# batch = 100
+# max-kernel-ms = 2.5
+# peak-bandwidth = 936
+# fit-to-memory = false
+
+[sweep]
+# from = "1K"
+# to = "64M"
+# factor = 2
+# iterations = 3
+# csv = "sweep.csv"
+# realloc-each = false
+# compare-transfers = false
+
+[compile]
+# out = "saxpy.bin"
+
+[serve]
+# Also read by client
+# socket = "/tmp/hello-opencl3.sock"
+
+[tune]
+# size = "1M"
+# iterations = 5
+
+[chain]
+# separate-programs = false
+
+[atomic-count]
+# size = "1M"
+# threshold = 500.0
+# racy = false
+
+[pipe-demo]
+# size = "1M"
+"#;
+
+/// Whether a key is a switch or takes a value, and whether the value is a path
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Switch,
+    Value,
+    Path,
+}
+
+/// The keys each section accepts, the top level is the section ""
+const SECTIONS: &[(&str, &[(&str, Kind)])] = &[
+    (
+        "",
+        &[
+            ("command", Kind::Value),
+            ("log-level", Kind::Value),
+            ("fail-on-context-error", Kind::Switch),
+            ("dry-run", Kind::Switch),
+        ],
+    ),
+    (
+        "device",
+        &[
+            ("platform", Kind::Value),
+            ("device", Kind::Value),
+            ("device-type", Kind::Value),
+            ("auto-best", Kind::Switch),
+            ("target", Kind::Value),
+        ],
+    ),
+    (
+        "kernel",
+        &[
+            ("op", Kind::Value),
+            ("scalar", Kind::Value),
+            ("layout", Kind::Value),
+            ("transfer", Kind::Value),
+            ("kernel-iters", Kind::Value),
+            ("pinned-readback", Kind::Switch),
+            ("define", Kind::Value),
+            ("build-options", Kind::Value),
+            ("fast-math", Kind::Switch),
+            ("mad-enable", Kind::Switch),
+            ("enable-printf", Kind::Switch),
+            ("print-build-log", Kind::Switch),
+            ("strict", Kind::Switch),
+            ("strict-build", Kind::Switch),
+            ("no-cache", Kind::Switch),
+            ("kernel-file", Kind::Path),
+            ("spirv", Kind::Path),
+            ("load-binary", Kind::Path),
+            ("kernel-name", Kind::Value),
+            ("kernel-include-dir", Kind::Path),
+            ("link-helpers", Kind::Switch),
+            ("retries", Kind::Value),
+            ("retry-delay-ms", Kind::Value),
+        ],
+    ),
+    (
+        "run",
+        &[
+            ("size", Kind::Value),
+            ("x-file", Kind::Path),
+            ("y-file", Kind::Path),
+            ("csv-input", Kind::Path),
+            ("out-file", Kind::Path),
+            ("output", Kind::Path),
+            ("binary", Kind::Switch),
+            ("readback-chunk", Kind::Value),
+            ("show-results", Kind::Value),
+            ("verify-stride", Kind::Value),
+            ("no-verify", Kind::Switch),
+            ("max-error-threshold", Kind::Value),
+            ("max-kernel-ms", Kind::Value),
+            ("peak-bandwidth", Kind::Value),
+            ("record", Kind::Path),
+            ("record-data", Kind::Switch),
+            ("local-size", Kind::Value),
+            ("num-groups", Kind::Value),
+            ("group-size", Kind::Value),
+            ("global", Kind::Value),
+            ("local", Kind::Value),
+            ("global-offset", Kind::Value),
+            ("offset", Kind::Value),
+            ("detect-unwritten", Kind::Switch),
+            ("use-host-ptr", Kind::Switch),
+            ("repeat", Kind::Value),
+            ("fit-to-memory", Kind::Switch),
+            ("compare-transfers", Kind::Switch),
+            ("dump-dag", Kind::Switch),
+            ("threads", Kind::Value),
+            ("async", Kind::Switch),
+            ("device-enqueue", Kind::Switch),
+            ("template", Kind::Value),
+            ("images", Kind::Switch),
+            ("save-profile-csv", Kind::Path),
+            ("stdin", Kind::Switch),
+            ("stdin-layout", Kind::Value),
+            ("chunk-size", Kind::Value),
+        ],
+    ),
+    (
+        "bench",
+        &[
+            ("size", Kind::Value),
+            ("iterations", Kind::Value),
+            ("warmup", Kind::Value),
+            ("batch", Kind::Value),
+            ("max-kernel-ms", Kind::Value),
+            ("peak-bandwidth", Kind::Value),
+            ("fit-to-memory", Kind::Switch),
+        ],
+    ),
+    (
+        "sweep",
+        &[
+            ("from", Kind::Value),
+            ("to", Kind::Value),
+            ("factor", Kind::Value),
+            ("iterations", Kind::Value),
+            ("csv", Kind::Path),
+            ("realloc-each", Kind::Switch),
+            ("compare-transfers", Kind::Switch),
+        ],
+    ),
+    ("compile", &[("out", Kind::Path)]),
+    ("serve", &[("socket", Kind::Value)]),
+    (
+        "tune",
+        &[
+            ("size", Kind::Value),
+            ("iterations", Kind::Value),
+            ("guarded", Kind::Switch),
+        ],
+    ),
+    ("chain", &[("separate-programs", Kind::Switch)]),
+    (
+        "atomic-count",
+        &[
+            ("size", Kind::Value),
+            ("threshold", Kind::Value),
+            ("racy", Kind::Switch),
+        ],
+    ),
+    ("pipe-demo", &[("size", Kind::Value)]),
+];
+
+/// Sections whose options only apply to the command of the same name, `[serve]` to `client` as well
+const COMMAND_SECTIONS: &[&str] = &[
+    "run",
+    "bench",
+    "sweep",
+    "tune",
+    "compile",
+    "serve",
+    "chain",
+    "atomic-count",
+    "pipe-demo",
+];
+
+/// Options that, given on the command line, also drop the keys of the file that depend on the value
+/// they override: a file's `scalar` only applies to the `op` the file picks, its `size` to the
+/// generated inputs other inputs replace, and its `platform` and `device` are replaced by a `target`
+/// given on the command line, and the other way around
+const DEPENDENT_KEYS: &[(&str, &[&str])] = &[
+    ("op", &["scalar"]),
+    ("x-file", &["size"]),
+    ("csv-input", &["size"]),
+    ("stdin", &["size"]),
+    ("target", &["platform", "device"]),
+    ("platform", &["target"]),
+    ("device", &["target"]),
+];
+
+/// A value as written in the file
+enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+}
+
+/// The options one key stands for, with the key
+type KeyOptions = (String, Vec<String>);
+
+/// The options a configuration file stands for, grouped by section and then by key
+struct Config {
+    command: Option<String>,
+    options: Vec<(String, Vec<KeyOptions>)>,
+}
+
+/// Replaces `--config PATH` in `args` with the options from PATH
+///
+/// The command comes from the command line if one is given there and from the file otherwise, and
+/// only the section of that command is used, so one file can configure several commands.
+pub fn expand(args: Vec<String>) -> anyhow::Result<Vec<String>> {
+    let mut rest = Vec::with_capacity(args.len());
+    let mut path = None;
+    let mut iter = args.into_iter();
+    while let Some(arg) = iter.next() {
+        if arg == "--config" {
+            path = Some(
+                iter.next()
+                    .ok_or_else(|| anyhow!("Missing value for '--config'"))?,
+            );
+        } else if let Some(value) = arg.strip_prefix("--config=") {
+            path = Some(value.to_owned());
+        } else {
+            rest.push(arg);
+        }
+    }
+
+    let Some(path) = path else {
+        return Ok(rest);
+    };
+    let path = PathBuf::from(path);
+    let config = load(&path)?;
+
+    let cli_command = rest
+        .iter()
+        .position(|arg| cli::is_command(arg))
+        .map(|i| rest.remove(i));
+    let command = cli_command
+        .or(config.command)
+        .unwrap_or_else(|| "run".to_owned());
+
+    let overridden = overridden_keys(&rest);
+    let mut expanded = vec![command.clone()];
+    for (section, options) in config.options {
+        let applies = section == command || (section == "serve" && command == "client");
+        if COMMAND_SECTIONS.contains(&section.as_str()) && !applies {
+            continue;
+        }
+        for (key, options) in options {
+            if overridden.contains(key.as_str()) {
+                tracing::debug!("--{} from the command line replaces the config file's", key);
+                continue;
+            }
+            expanded.extend(options);
+        }
+    }
+    expanded.extend(rest);
+
+    Ok(expanded)
+}
+
+/// The keys of the file the command-line options `args` override, theirs and the ones depending on
+/// them
+fn overridden_keys(args: &[String]) -> HashSet<&str> {
+    let mut keys = HashSet::new();
+    for arg in args {
+        let Some(flag) = arg.strip_prefix("--") else {
+            continue;
+        };
+        let key = flag.split_once('=').map_or(flag, |(key, _)| key);
+        keys.insert(key);
+        if let Some((_, dependent)) = DEPENDENT_KEYS.iter().find(|(option, _)| *option == key) {
+            keys.extend(dependent.iter().copied());
+        }
+    }
+
+    keys
+}
+
+fn load(path: &Path) -> anyhow::Result<Config> {
+    let text =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    parse(&text, path)
+}
+
+/// Parses the `text` of the file at `path`, which relative paths are resolved against and errors
+/// name
+fn parse(text: &str, path: &Path) -> anyhow::Result<Config> {
+    let base = path.parent().unwrap_or(Path::new(""));
+
+    let mut config = Config {
+        command: None,
+        options: vec![(String::new(), Vec::new())],
+    };
+    let mut section = String::new();
+    let mut seen = HashSet::new();
+
+    for (i, line) in text.lines().enumerate() {
+        let at = || format!("{}:{}", path.display(), i + 1);
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(name) = line.strip_prefix('[') {
+            let name = name
+                .strip_suffix(']')
+                .ok_or_else(|| anyhow!("{}: unterminated section header", at()))?
+                .trim();
+            if !SECTIONS.iter().any(|(known, _)| *known == name) {
+                bail!(
+                    "{}: unknown section [{}], expected one of: {}",
+                    at(),
+                    name,
+                    section_names()
+                );
+            }
+            section = name.to_owned();
+            config.options.push((section.clone(), Vec::new()));
+            continue;
+        }
+
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| anyhow!("{}: expected 'key = value'", at()))?;
+        let key = key.trim().replace('_', "-");
+        let value = parse_value(value.trim()).with_context(|| format!("{}: '{}'", at(), key))?;
+
+        let keys = SECTIONS
+            .iter()
+            .find(|(name, _)| *name == section)
+            .map(|(_, keys)| *keys)
+            .unwrap_or_default();
+        let Some(&(_, kind)) = keys.iter().find(|(known, _)| *known == key) else {
+            bail!(
+                "{}: unknown key '{}' in {}, expected one of: {}",
+                at(),
+                key,
+                section_label(&section),
+                keys.iter()
+                    .map(|(key, _)| *key)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        };
+        if !seen.insert((section.clone(), key.clone())) {
+            bail!(
+                "{}: '{}' is set twice in {}",
+                at(),
+                key,
+                section_label(&section)
+            );
+        }
+
+        if section.is_empty() && key == "command" {
+            match value {
+                Value::String(command) if cli::is_command(&command) => {
+                    config.command = Some(command)
+                }
+                _ => bail!(
+                    "{}: 'command' has to name a command, such as \"bench\"",
+                    at()
+                ),
+            }
+            continue;
+        }
+
+        let mut options = Vec::new();
+        match (kind, value) {
+            (Kind::Switch, Value::Boolean(true)) => options.push(format!("--{}", key)),
+            (Kind::Switch, Value::Boolean(false)) => {}
+            (Kind::Switch, _) => bail!("{}: '{}' takes true or false", at(), key),
+            (_, Value::Boolean(_)) => bail!("{}: '{}' takes a value, not a boolean", at(), key),
+            (Kind::Path, Value::String(value)) => {
+                options.push(format!("--{}", key));
+                options.push(base.join(value).to_string_lossy().into_owned());
+            }
+            (_, Value::String(value)) => options.extend([format!("--{}", key), value]),
+            (_, Value::Integer(value)) => options.extend([format!("--{}", key), value.to_string()]),
+            (_, Value::Float(value)) => options.extend([format!("--{}", key), value.to_string()]),
+        }
+        config.options.last_mut().unwrap().1.push((key, options));
+    }
+
+    Ok(config)
+}
+
+/// Cuts a trailing `# comment`, leaving `#` inside quoted strings alone
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '#') => return &line[..i],
+            _ => {}
+        }
+        escaped = false;
+    }
+
+    line
+}
+
+fn parse_value(text: &str) -> anyhow::Result<Value> {
+    if let Some(inner) = text.strip_prefix('"') {
+        let inner = inner
+            .strip_suffix('"')
+            .ok_or_else(|| anyhow!("unterminated string"))?;
+        let mut value = String::with_capacity(inner.len());
+        let mut chars = inner.chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                value.push(c);
+                continue;
+            }
+            value.push(match chars.next() {
+                Some('"') => '"',
+                Some('\\') => '\\',
+                Some('n') => '\n',
+                Some('t') => '\t',
+                other => bail!("unsupported escape '\\{}'", other.unwrap_or(' ')),
+            });
+        }
+        return Ok(Value::String(value));
+    }
+
+    if let Some(inner) = text.strip_prefix('\'') {
+        return inner
+            .strip_suffix('\'')
+            .map(|inner| Value::String(inner.to_owned()))
+            .ok_or_else(|| anyhow!("unterminated string"));
+    }
+
+    match text {
+        "true" => return Ok(Value::Boolean(true)),
+        "false" => return Ok(Value::Boolean(false)),
+        _ => {}
+    }
+
+    let number = text.replace('_', "");
+    if let Ok(value) = number.parse() {
+        return Ok(Value::Integer(value));
+    }
+    // TOML floats need a digit on both sides of the point, `1.` and `.5` aren't valid
+    let digits_around_point = number.split_once('.').is_none_or(|(int, frac)| {
+        int.ends_with(|c: char| c.is_ascii_digit())
+            && frac.starts_with(|c: char| c.is_ascii_digit())
+    });
+    match number.parse::<f64>() {
+        Ok(value) if digits_around_point && value.is_finite() => Ok(Value::Float(value)),
+        _ => bail!("expected a string, a number or a boolean, found {}", text),
+    }
+}
+
+fn section_label(section: &str) -> String {
+    if section.is_empty() {
+        "the top level".to_owned()
+    } else {
+        format!("[{}]", section)
+    }
+}
+
+fn section_names() -> String {
+    SECTIONS
+        .iter()
+        .filter(|(name, _)| !name.is_empty())
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_str(text: &str) -> anyhow::Result<Config> {
+        parse(text, Path::new("dir/test.toml"))
+    }
+
+    /// The options of `section`, flattened in file order
+    fn section_options(config: &Config, section: &str) -> Vec<String> {
+        config
+            .options
+            .iter()
+            .filter(|(name, _)| name == section)
+            .flat_map(|(_, options)| options.iter().flat_map(|(_, options)| options.clone()))
+            .collect()
+    }
+
+    fn error(text: &str) -> String {
+        match parse_str(text) {
+            Ok(_) => panic!("parsed: {}", text),
+            Err(err) => format!("{:#}", err),
+        }
+    }
+
+    #[test]
+    fn tables_and_top_level() {
+        let config = parse_str(
+            "command = \"bench\"\nlog-level = \"info\"\n\n[device]\nplatform = 1\n\n[bench]\nsize = \"4M\"\niterations = 20\n",
+        )
+        .unwrap();
+
+        assert_eq!(config.command.as_deref(), Some("bench"));
+        assert_eq!(section_options(&config, ""), ["--log-level", "info"]);
+        assert_eq!(section_options(&config, "device"), ["--platform", "1"]);
+        assert_eq!(
+            section_options(&config, "bench"),
+            ["--size", "4M", "--iterations", "20"]
+        );
+    }
+
+    #[test]
+    fn numbers() {
+        let config = parse_str(
+            "[kernel]\nscalar = 2.5\nretries = 1_000\n[run]\nmax-error-threshold = 1e-3\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            section_options(&config, "kernel"),
+            ["--scalar", "2.5", "--retries", "1000"]
+        );
+        assert_eq!(
+            section_options(&config, "run"),
+            ["--max-error-threshold", "0.001"]
+        );
+        assert!(error("[kernel]\nscalar = 1.\n").contains("expected a string, a number"));
+        assert!(error("[kernel]\nscalar = .5\n").contains("expected a string, a number"));
+        assert!(error("[kernel]\nscalar = inf\n").contains("expected a string, a number"));
+    }
+
+    #[test]
+    fn strings_and_switches() {
+        let config = parse_str(
+            "[kernel]\nbuild-options = \"-D A=\\\"#1\\\"\"\ndefine = 'B=#2'\nfast-math = true\nmad-enable = false\nkernel-file = \"k.cl\"\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            section_options(&config, "kernel"),
+            [
+                "--build-options",
+                "-D A=\"#1\"",
+                "--define",
+                "B=#2",
+                "--fast-math",
+                "--kernel-file",
+                &Path::new("dir").join("k.cl").to_string_lossy(),
+            ]
+        );
+        assert!(error("[kernel]\nop = \"add\n").contains("unterminated string"));
+        assert!(error("[kernel]\nop = \"a\\qb\"\n").contains("unsupported escape"));
+    }
+
+    #[test]
+    fn comments() {
+        let config = parse_str(
+            "# a comment\n  # indented\n[kernel] # after a header\nop = \"add\" # after a value\n\n",
+        )
+        .unwrap();
+
+        assert_eq!(section_options(&config, "kernel"), ["--op", "add"]);
+    }
+
+    #[test]
+    fn bad_lines() {
+        assert!(error("[kernel]\nop\n").contains("dir/test.toml:2: expected 'key = value'"));
+        assert!(error("[kernel\n").contains("unterminated section header"));
+        assert!(error("[nope]\n").contains("unknown section [nope]"));
+        assert!(error("[kernel]\nnope = 1\n").contains("unknown key 'nope' in [kernel]"));
+        assert!(error("[kernel]\nop = \"add\"\nop = \"sub\"\n").contains("'op' is set twice"));
+        assert!(error("[kernel]\nfast-math = 1\n").contains("takes true or false"));
+        assert!(error("[kernel]\nop = true\n").contains("takes a value, not a boolean"));
+        assert!(error("command = \"nope\"\n").contains("'command' has to name a command"));
+    }
+
+    #[test]
+    fn template_parses_uncommented() {
+        let text: String = TEMPLATE
+            .lines()
+            .map(|line| match line.strip_prefix("# ") {
+                Some(option) if option.contains(" = ") => option,
+                _ => line,
+            })
+            .map(|line| format!("{}\n", line))
+            .collect();
+
+        parse_str(&text).unwrap();
+    }
+
+    #[test]
+    fn command_line_overrides_file_keys() {
+        let path = std::env::temp_dir().join(format!("hello-opencl3-{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            "[device]\ndevice = 1\n[kernel]\nscalar = 2.5\nfast-math = true\n",
+        )
+        .unwrap();
+        let expand_with = |args: &[&str]| {
+            let mut all = vec!["--config".to_owned(), path.to_string_lossy().into_owned()];
+            all.extend(args.iter().map(|arg| arg.to_string()));
+            expand(all).unwrap()
+        };
+
+        assert_eq!(
+            expand_with(&["--op", "add"]),
+            ["run", "--device", "1", "--fast-math", "--op", "add"]
+        );
+        assert_eq!(
+            expand_with(&["--scalar=3"]),
+            ["run", "--device", "1", "--fast-math", "--scalar=3"]
+        );
+        assert_eq!(
+            expand_with(&["bench", "--target", "NVIDIA:3090"]),
+            [
+                "bench",
+                "--scalar",
+                "2.5",
+                "--fast-math",
+                "--target",
+                "NVIDIA:3090"
+            ]
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,260 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+//! `run --device-enqueue`: saxpy and a reduction enqueued from the device (OpenCL 2.0)
+//!
+//! The host launches a single work-item, which enqueues the saxpy over every element and then a
+//! two-level sum of `z` on the device's default queue: a first pass summing [`CHUNKS`] chunks of
+//! `z` that waits on the saxpy, and a final pass over those partial sums that waits on the first.
+//! No host round trip happens between the kernels, the parent only completes once its children
+//! have. The on-device queue is created with `CL_DEVICE_QUEUE_ON_DEVICE_PREFERRED_SIZE`.
+//!
+//! Support is `CL_DEVICE_QUEUE_SUPPORTED` in `CL_DEVICE_DEVICE_ENQUEUE_CAPABILITIES` on OpenCL 3.0
+//! devices, where it is optional, and a non-empty `CL_DEVICE_QUEUE_ON_DEVICE_PROPERTIES` on 2.x
+//! devices. Devices without it fail before the build, naming what they lack.
+
+use std::mem;
+
+use anyhow::bail;
+use opencl3::{
+    command_queue::{
+        CommandQueue, CL_QUEUE_ON_DEVICE, CL_QUEUE_ON_DEVICE_DEFAULT,
+        CL_QUEUE_OUT_OF_ORDER_EXEC_MODE_ENABLE,
+    },
+    context::Context,
+    device::Device,
+    memory::{CL_MEM_READ_ONLY, CL_MEM_READ_WRITE, CL_MEM_WRITE_ONLY},
+    types::{cl_float, cl_uint, CL_BLOCKING},
+};
+
+use crate::{
+    cli::{Args, Op},
+    error::ClContext,
+    kernels::{self, BuiltinKernel},
+    launch::launch,
+    retry, run, saxpy,
+};
+
+/// The parent kernel, blocks are compiled as OpenCL C 2.0
+///
+/// `chunks` comes before `n` so the launch check takes it as the bounds of `partial`
+pub const PROGRAM_SOURCE: &str = r#"
+kernel void saxpy_then_sum (global float* z,
+    global float const* x,
+    global float const* y,
+    float a,
+    global float* partial,
+    global float* sum,
+    uint chunks,
+    uint n)
+{
+    queue_t queue = get_default_queue();
+    clk_event_t saxpy_done;
+    clk_event_t partial_done;
+
+    enqueue_kernel(queue, CLK_ENQUEUE_FLAGS_NO_WAIT, ndrange_1D(n), 0, NULL, &saxpy_done, ^{
+        const size_t i = get_global_id(0);
+        z[i] = a*x[i] + y[i];
+    });
+
+    // Every work-item of the first pass sums one chunk of z
+    const uint chunk = (n + chunks - 1) / chunks;
+    enqueue_kernel(queue, CLK_ENQUEUE_FLAGS_NO_WAIT, ndrange_1D(chunks), 1, &saxpy_done, &partial_done, ^{
+        const uint j = get_global_id(0);
+        const uint end = min(n, (j + 1) * chunk);
+        float total = 0.0f;
+        for (uint i = j * chunk; i < end; ++i) {
+            total += z[i];
+        }
+        partial[j] = total;
+    });
+
+    // The final pass, left unwritten when an enqueue failed
+    enqueue_kernel(queue, CLK_ENQUEUE_FLAGS_NO_WAIT, ndrange_1D(1), 1, &partial_done, NULL, ^{
+        float total = 0.0f;
+        for (uint j = 0; j < chunks; ++j) {
+            total += partial[j];
+        }
+        *sum = total;
+    });
+
+    release_event(saxpy_done);
+    release_event(partial_done);
+}"#;
+
+/// The number of chunks the first pass of the sum splits `z` into, fewer for shorter inputs
+pub const CHUNKS: usize = 256;
+
+pub const KERNEL_NAME: &str = BuiltinKernel::SaxpyThenSum.name();
+
+/// Runs the parent kernel over the inputs of `args` and checks `z` and its sum on the host
+pub fn run(
+    context: &Context,
+    queue: &CommandQueue,
+    device: &Device,
+    args: &Args,
+) -> anyhow::Result<()> {
+    check_support(device)?;
+
+    let options = format!("-cl-std=CL2.0 {}", kernels::arg_info_options(args));
+    let kernel = kernels::build_kernel(
+        context,
+        PROGRAM_SOURCE,
+        KERNEL_NAME,
+        options.trim_end(),
+        kernels::BuildFlags::from_args(args),
+    )?;
+
+    // The device's default queue, which `get_default_queue()` returns; it has to exist while the
+    // parent runs
+    let queue_size = device_queue_size(device)?;
+    let _device_queue = unsafe {
+        CommandQueue::create_with_properties(
+            context,
+            device.id(),
+            CL_QUEUE_OUT_OF_ORDER_EXEC_MODE_ENABLE
+                | CL_QUEUE_ON_DEVICE
+                | CL_QUEUE_ON_DEVICE_DEFAULT,
+            queue_size,
+        )
+    }
+    .context("Failed to create the on-device queue")?;
+
+    let (x, y) = run::inputs(args)?;
+    let size = x.len();
+    let a: cl_float = args.scalar();
+    let n = cl_uint::try_from(size)?;
+    let chunks = CHUNKS.min(size);
+    let chunks_arg = chunks as cl_uint;
+
+    let mut x_buffer = saxpy::create_buffer(context, CL_MEM_READ_ONLY, size)?;
+    let mut y_buffer = saxpy::create_buffer(context, CL_MEM_READ_ONLY, size)?;
+    // Read back on the device by the first pass of the sum
+    let z_buffer = saxpy::create_buffer(context, CL_MEM_READ_WRITE, size)?;
+    let partial_buffer = saxpy::create_buffer(context, CL_MEM_READ_WRITE, chunks)?;
+    let mut sum_buffer = saxpy::create_buffer(context, CL_MEM_WRITE_ONLY, 1)?;
+
+    unsafe { queue.enqueue_write_buffer(&mut x_buffer, CL_BLOCKING, 0, &x, &[]) }
+        .context("Failed to write to buffer")?;
+    unsafe { queue.enqueue_write_buffer(&mut y_buffer, CL_BLOCKING, 0, &y, &[]) }
+        .context("Failed to write to buffer")?;
+    // A final pass that never ran leaves it as is
+    retry::call("clEnqueueFillBuffer", || unsafe {
+        queue.enqueue_fill_buffer(
+            &mut sum_buffer,
+            &[saxpy::UNTOUCHED],
+            0,
+            mem::size_of::<cl_float>(),
+            &[],
+        )
+    })
+    .context("Failed to fill buffer")?;
+
+    let parent_event = unsafe {
+        launch!(
+            &kernel,
+            global = 1;
+            z: &z_buffer,
+            x: &x_buffer,
+            y: &y_buffer,
+            a: &a,
+            partial: &partial_buffer,
+            sum: &sum_buffer,
+            chunks: &chunks_arg,
+            n: &n,
+        )
+        .enqueue_nd_range(queue)
+    }
+    .map_err(|err| err.context("Failed to execute kernel"))?;
+
+    let mut z = vec![0.0; size];
+    let mut sum: [cl_float; 1] = [0.0];
+    unsafe { queue.enqueue_read_buffer(&z_buffer, CL_BLOCKING, 0, &mut z, &[]) }
+        .context("Failed to read buffer")?;
+    unsafe { queue.enqueue_read_buffer(&sum_buffer, CL_BLOCKING, 0, &mut sum, &[]) }
+        .context("Failed to read buffer")?;
+
+    let checked = match saxpy::verify(
+        Op::Saxpy,
+        None,
+        args.max_error(),
+        args.verify_stride.unwrap_or(1),
+        Some(a),
+        &x,
+        &y,
+        &z,
+    ) {
+        Ok(checked) => checked,
+        Err(i) => bail!("Verification failed at index {}: got {}", i, z[i]),
+    };
+    if saxpy::find_unwritten(&sum).is_some() {
+        bail!("The final pass of the sum never ran, the parent kernel failed to enqueue its children (on-device queue of {} bytes)", queue_size);
+    }
+    // Summed in the same chunks and order as the child kernels, only contraction and fast math can
+    // differ
+    let chunk = size.div_ceil(chunks);
+    let expected: cl_float = z
+        .chunks(chunk)
+        .map(|chunk| chunk.iter().sum::<cl_float>())
+        .sum();
+    if (sum[0] - expected).abs() > 1e-3 * expected.abs().max(1.0) {
+        bail!("Device-side sum is {}, expected {}", sum[0], expected);
+    }
+
+    println!("results front: {}", z[0]);
+    println!("results back: {}", z[size - 1]);
+    println!("verified {} of {} results", checked, size);
+    println!("sum of z: {} (over {} partial sums)", sum[0], chunks);
+    // The parent only completes once its children have, so this covers both
+    println!(
+        "parent kernel time including children (ns): {}",
+        saxpy::profiled_ns(&parent_event)?
+    );
+
+    Ok(())
+}
+
+/// Checks that `device` supports device-side enqueue, or fails naming the property it lacks
+fn check_support(device: &Device) -> anyhow::Result<()> {
+    let name = device.name().context("Failed to query device name")?;
+    // Only OpenCL 3.0 devices answer it, 2.x devices support device-side enqueue if they have
+    // on-device queue properties
+    #[cfg(feature = "cl_3_0")]
+    if let Ok(capabilities) = device.device_enqueue_capabilities() {
+        if capabilities & opencl3::device::CL_DEVICE_QUEUE_SUPPORTED == 0 {
+            bail!(
+                "{} doesn't support device-side enqueue (CL_DEVICE_QUEUE_SUPPORTED is not in CL_DEVICE_DEVICE_ENQUEUE_CAPABILITIES), run without --device-enqueue",
+                name
+            );
+        }
+    }
+
+    let properties = device
+        .queue_on_device_properties()
+        .ok()
+        .and_then(|properties| properties.first().copied())
+        .unwrap_or(0);
+    if properties == 0 {
+        bail!(
+            "{} doesn't support device-side enqueue (CL_DEVICE_QUEUE_ON_DEVICE_PROPERTIES is empty), run without --device-enqueue",
+            name
+        );
+    }
+
+    Ok(())
+}
+
+/// The size in bytes of the on-device queue, `CL_DEVICE_QUEUE_ON_DEVICE_PREFERRED_SIZE` within
+/// `CL_DEVICE_QUEUE_ON_DEVICE_MAX_SIZE`
+fn device_queue_size(device: &Device) -> anyhow::Result<cl_uint> {
+    let preferred = device
+        .queue_on_device_preferred_size()
+        .context("Failed to query CL_DEVICE_QUEUE_ON_DEVICE_PREFERRED_SIZE")?;
+    let max = device
+        .queue_on_device_max_size()
+        .context("Failed to query CL_DEVICE_QUEUE_ON_DEVICE_MAX_SIZE")?;
+    let size = preferred.min(max);
+    tracing::debug!("On-device queue of {} bytes (at most {})", size, max);
+
+    Ok(cl_uint::try_from(size).unwrap_or(cl_uint::MAX))
+}
@@ -0,0 +1,565 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+use anyhow::{anyhow, bail, Context as _};
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, IsTerminal, Write as _},
+};
+
+use opencl3::{
+    device::{
+        Device, CL_DEVICE_TYPE_ACCELERATOR, CL_DEVICE_TYPE_ALL, CL_DEVICE_TYPE_CPU,
+        CL_DEVICE_TYPE_CUSTOM, CL_DEVICE_TYPE_GPU,
+    },
+    error_codes::{CL_DEVICE_NOT_FOUND, CL_PLATFORM_NOT_FOUND_KHR},
+    platform::{get_platforms, Platform},
+    types::{cl_device_id, cl_device_type, cl_uint, cl_ulong},
+};
+
+use crate::{
+    cli::{Args, DeviceType, Target},
+    error::ClContext,
+    output::json_string,
+    template,
+};
+
+/// A platform and the key attributes of its devices, as listed by `--list`
+pub struct PlatformInfo {
+    pub name: String,
+    pub version: String,
+    pub devices: Vec<DeviceInfo>,
+}
+
+pub struct DeviceInfo {
+    pub name: String,
+    pub device_type: cl_device_type,
+    pub compute_units: cl_uint,
+    /// Global memory size in bytes
+    pub global_mem: cl_ulong,
+    pub version: String,
+    pub extensions: Vec<String>,
+}
+
+impl DeviceType {
+    fn cl_type(self) -> cl_device_type {
+        match self {
+            DeviceType::Gpu => CL_DEVICE_TYPE_GPU,
+            DeviceType::Cpu => CL_DEVICE_TYPE_CPU,
+            DeviceType::Accelerator => CL_DEVICE_TYPE_ACCELERATOR,
+            DeviceType::All => CL_DEVICE_TYPE_ALL,
+        }
+    }
+}
+
+/// Picks the device selected by `--platform`, `--device` and `--device-type`, or with `--auto-best`
+/// the most capable one, across every platform through [`best_device`] unless one is given
+///
+/// Without `--device` the first device reporting compute units is taken, broken drivers report 0
+/// for `CL_DEVICE_MAX_COMPUTE_UNITS` and such devices are skipped with a warning. A device picked by
+/// index keeps its number and fails instead
+pub fn select_device(args: &Args) -> anyhow::Result<Device> {
+    if args.auto_best && args.target.is_none() && args.platform.is_none() {
+        return best_device(args.device_type);
+    }
+    let devices = match &args.target {
+        Some(target) => target_devices(target)?,
+        None => matching_devices(args)?,
+    };
+    if devices.is_empty() {
+        anyhow::bail!("No device found");
+    }
+
+    let index = match args.device {
+        Some(index) => index,
+        None if args.auto_best => best_index(&devices)?,
+        None if should_prompt(args, &devices) => prompt_device(&devices)?,
+        None => first_with_compute_units(&devices)?,
+    };
+    let device_id = *devices.get(index).ok_or_else(|| {
+        anyhow!(
+            "Device {} doesn't exist, {} matching device(s) found",
+            index,
+            devices.len()
+        )
+    })?;
+    if has_no_compute_units(device_id) {
+        anyhow::bail!(
+            "Device {} ({}) reports 0 compute units (CL_DEVICE_MAX_COMPUTE_UNITS), its driver is broken; pick another with --device",
+            index,
+            Device::new(device_id).name().unwrap_or_default()
+        );
+    }
+    tracing::debug!("Found device: {:p}", device_id);
+
+    Ok(Device::new(device_id))
+}
+
+/// The index of the first of `devices` that doesn't report 0 compute units, warning about the ones
+/// before it
+fn first_with_compute_units(devices: &[cl_device_id]) -> anyhow::Result<usize> {
+    for (i, &id) in devices.iter().enumerate() {
+        if !has_no_compute_units(id) {
+            return Ok(i);
+        }
+        tracing::warn!(
+            "Skipping device {} ({}), it reports 0 compute units (CL_DEVICE_MAX_COMPUTE_UNITS)",
+            i,
+            Device::new(id).name().unwrap_or_default()
+        );
+    }
+
+    anyhow::bail!(
+        "Every matching device reports 0 compute units (CL_DEVICE_MAX_COMPUTE_UNITS), the OpenCL installation is broken"
+    )
+}
+
+/// The most capable device of type `kind` on any platform, as [`best_index`] picks it
+pub fn best_device(kind: DeviceType) -> anyhow::Result<Device> {
+    let devices = find_devices(kind.cl_type())?;
+    if devices.is_empty() {
+        bail!("No device found");
+    }
+    let index = best_index(&devices)?;
+    tracing::debug!("Found device: {:p}", devices[index]);
+
+    Ok(Device::new(devices[index]))
+}
+
+/// The index of the most capable of `devices` for `--auto-best`: the most compute units, then the
+/// most global memory, the first of equals. On a laptop with an integrated and a discrete GPU that is
+/// the discrete one, which tends to be listed second
+fn best_index(devices: &[cl_device_id]) -> anyhow::Result<usize> {
+    let mut best: Option<(usize, (u32, u64))> = None;
+    for (i, &id) in devices.iter().enumerate() {
+        let device = Device::new(id);
+        let name = device.name().unwrap_or_default();
+        let (Ok(units), Ok(memory)) = (device.max_compute_units(), device.global_mem_size()) else {
+            tracing::warn!("Skipping device {} ({}), it can't be queried", i, name);
+            continue;
+        };
+        tracing::debug!(
+            "Device {} ({}): {} compute units, {} MiB global memory",
+            i,
+            name,
+            units,
+            memory >> 20
+        );
+        if units > 0 && best.is_none_or(|(_, capability)| (units, memory) > capability) {
+            best = Some((i, (units, memory)));
+        }
+    }
+
+    let Some((index, (units, memory))) = best else {
+        anyhow::bail!(
+            "No matching device reports any compute units (CL_DEVICE_MAX_COMPUTE_UNITS), the OpenCL installation is broken"
+        );
+    };
+    tracing::info!(
+        "Picked device {} ({}) with {} compute units and {} MiB global memory",
+        index,
+        Device::new(devices[index]).name().unwrap_or_default(),
+        units,
+        memory >> 20
+    );
+
+    Ok(index)
+}
+
+/// Whether `id` reports 0 for `CL_DEVICE_MAX_COMPUTE_UNITS`, a failing query is left for the calls
+/// that need the device to report
+fn has_no_compute_units(id: cl_device_id) -> bool {
+    Device::new(id)
+        .max_compute_units()
+        .is_ok_and(|units| units == 0)
+}
+
+/// Whether to ask for the device instead of taking the first: several GPUs match and both stdout
+/// and stdin are a terminal, which `--stdin` rules out as it reads the inputs from there
+fn should_prompt(args: &Args, devices: &[cl_device_id]) -> bool {
+    args.device_type == DeviceType::Gpu
+        && devices.len() > 1
+        && !args.stdin
+        && io::stdout().is_terminal()
+        && io::stdin().is_terminal()
+}
+
+/// Lists `devices` and reads the index of one from stdin, an empty line or EOF takes the first
+fn prompt_device(devices: &[cl_device_id]) -> anyhow::Result<usize> {
+    println!("Several GPUs found (pass --device N to skip this):");
+    for (i, &id) in devices.iter().enumerate() {
+        println!("  [{}] {}", i, Device::new(id).name().unwrap_or_default());
+    }
+
+    let mut stdin = io::stdin().lock();
+    loop {
+        print!("Device [0]: ");
+        io::stdout().flush().context("Failed to write to stdout")?;
+
+        let mut line = String::new();
+        if stdin
+            .read_line(&mut line)
+            .context("Failed to read from stdin")?
+            == 0
+        {
+            println!();
+            return Ok(0);
+        }
+        match line.trim() {
+            "" => return Ok(0),
+            choice => match choice.parse::<usize>() {
+                Ok(index) if index < devices.len() => return Ok(index),
+                _ => println!("Enter a number from 0 to {}", devices.len() - 1),
+            },
+        }
+    }
+}
+
+/// Prints the index `--device` accepts for every matching device and its name, tab separated,
+/// for the shell completions
+pub fn print_device_indices(args: &Args) -> anyhow::Result<()> {
+    for (i, id) in matching_devices(args)?.into_iter().enumerate() {
+        let name = Device::new(id).name().unwrap_or_default();
+        println!("{}\t{}", i, name);
+    }
+
+    Ok(())
+}
+
+/// The devices `--device` indexes into, given `--platform` and `--device-type`
+fn matching_devices(args: &Args) -> anyhow::Result<Vec<cl_device_id>> {
+    let device_type = args.device_type.cl_type();
+
+    let devices = match args.platform {
+        Some(index) => {
+            let platforms = platforms()?;
+            let platform = platforms.get(index).ok_or_else(|| {
+                anyhow!(
+                    "Platform {} doesn't exist, {} platform(s) found",
+                    index,
+                    platforms.len()
+                )
+            })?;
+
+            match platform.get_devices(device_type) {
+                Ok(ids) => ids,
+                Err(err) if err.0 == CL_DEVICE_NOT_FOUND => Vec::new(),
+                Err(err) => return Err(err).context("Failed to query devices"),
+            }
+        }
+        None => find_devices(device_type)?,
+    };
+
+    Ok(devices)
+}
+
+/// The `list-devices` command
+pub fn list_devices(json: bool) -> anyhow::Result<()> {
+    let platforms = query_platforms()?;
+    if json {
+        print_device_list_json(&platforms);
+    } else {
+        print_device_list(&platforms);
+    }
+
+    Ok(())
+}
+
+/// Every installed platform, failing with a hint to install a driver when there are none
+///
+/// Getting this far means the OpenCL library was linked at build time (or loaded by `dynamic`), so
+/// an empty list isn't a build problem: the ICD loader works but no vendor driver registered an ICD
+/// with it. Khronos' loader reports that as `CL_PLATFORM_NOT_FOUND_KHR` rather than an empty list.
+fn platforms() -> anyhow::Result<Vec<Platform>> {
+    let platforms = match get_platforms() {
+        Ok(platforms) => platforms,
+        Err(err) if err.0 == CL_PLATFORM_NOT_FOUND_KHR => Vec::new(),
+        Err(err) => return Err(err).context("Failed to query platforms"),
+    };
+    if platforms.is_empty() {
+        bail!(
+            "The OpenCL ICD loader is installed but found no platforms, install the OpenCL driver of \
+             your GPU or CPU (on Linux it registers a .icd file in /etc/OpenCL/vendors). This isn't \
+             a build problem, the OpenCL library was {}",
+            if cfg!(feature = "dynamic") {
+                "loaded at startup"
+            } else {
+                "found when linking"
+            }
+        );
+    }
+
+    Ok(platforms)
+}
+
+/// The devices of any type matching `--target`, in platform order
+///
+/// Selects the platforms by name first and then their devices, failing with the names of the
+/// candidates when either finds nothing
+fn target_devices(target: &Target) -> anyhow::Result<Vec<cl_device_id>> {
+    let contains = |name: &str, part: &str| name.to_lowercase().contains(&part.to_lowercase());
+
+    let platforms = platforms()?;
+    let named: Vec<(&Platform, String)> = platforms
+        .iter()
+        .map(|platform| (platform, platform.name().unwrap_or_default()))
+        .collect();
+    let matching: Vec<&(&Platform, String)> = named
+        .iter()
+        .filter(|(_, name)| contains(name, &target.platform))
+        .collect();
+    if matching.is_empty() {
+        bail!(
+            "No platform name contains '{}' (--target {}), the platforms are:\n  {}",
+            target.platform,
+            target,
+            named
+                .iter()
+                .map(|(_, name)| name.as_str())
+                .collect::<Vec<_>>()
+                .join("\n  ")
+        );
+    }
+
+    let mut candidates = Vec::new();
+    let mut devices = Vec::new();
+    for (platform, platform_name) in matching {
+        let ids = match platform.get_devices(CL_DEVICE_TYPE_ALL) {
+            Ok(ids) => ids,
+            Err(err) if err.0 == CL_DEVICE_NOT_FOUND => continue,
+            Err(err) => return Err(err).context("Failed to query devices"),
+        };
+        for id in ids {
+            let name = Device::new(id).name().unwrap_or_default();
+            if contains(&name, &target.device) {
+                devices.push(id);
+            }
+            candidates.push(format!("{} on {}", name, platform_name));
+        }
+    }
+    if devices.is_empty() {
+        bail!(
+            "No device name contains '{}' (--target {}), the devices of the matching platforms are:\n  {}",
+            target.device,
+            target,
+            candidates.join("\n  ")
+        );
+    }
+    tracing::debug!("--target {} matches {} device(s)", target, devices.len());
+
+    Ok(devices)
+}
+
+/// Devices of `device_type` on every platform, in platform order
+///
+/// `get_all_devices` can stop at the first platform on some ICD loaders, which hides e.g. a GPU
+/// behind a CPU-only platform on multi-ICD systems. A platform that fails to enumerate is skipped
+/// with a warning, devices reported by more than one platform are only listed once.
+fn find_devices(device_type: cl_device_type) -> anyhow::Result<Vec<cl_device_id>> {
+    let platforms = platforms()?;
+
+    let mut devices = Vec::new();
+    for platform in &platforms {
+        let ids = match platform.get_devices(device_type) {
+            Ok(ids) => ids,
+            Err(err) if err.0 == CL_DEVICE_NOT_FOUND => continue,
+            Err(err) => {
+                tracing::warn!(
+                    "Skipping platform {}: failed to query devices: {}",
+                    platform.name().unwrap_or_default(),
+                    err
+                );
+                continue;
+            }
+        };
+
+        for id in ids {
+            if !devices.contains(&id) {
+                devices.push(id);
+            }
+        }
+    }
+
+    tracing::debug!(
+        "Found {} device(s) across {} platform(s)",
+        devices.len(),
+        platforms.len()
+    );
+
+    Ok(devices)
+}
+
+/// Prints the driver, device and OpenCL C versions of `device`, handy for bug reports
+pub fn print_version_info(device: &Device) -> anyhow::Result<()> {
+    let name = device.name().context("Failed to query device name")?;
+    let driver_version = device
+        .driver_version()
+        .context("Failed to query CL_DRIVER_VERSION")?;
+    let device_version = device
+        .version()
+        .context("Failed to query CL_DEVICE_VERSION")?;
+    let opencl_c_version = device
+        .opencl_c_version()
+        .context("Failed to query CL_DEVICE_OPENCL_C_VERSION")?;
+
+    let platform = Platform::new(
+        device
+            .platform()
+            .context("Failed to query CL_DEVICE_PLATFORM")?,
+    );
+    let platform_name = platform.name().context("Failed to query platform name")?;
+    let platform_version = platform
+        .version()
+        .context("Failed to query CL_PLATFORM_VERSION")?;
+
+    println!("Device:            {}", name);
+    println!(
+        "Platform:          {} ({})",
+        platform_name, platform_version
+    );
+    println!("Driver version:    {}", driver_version);
+    println!("Device version:    {}", device_version);
+    println!("OpenCL C version:  {}", opencl_c_version);
+    println!();
+    template::print_instances(device)?;
+
+    Ok(())
+}
+
+/// Queries every platform and all of its devices
+fn query_platforms() -> anyhow::Result<Vec<PlatformInfo>> {
+    let platforms = platforms()?;
+
+    platforms
+        .iter()
+        .map(|platform| {
+            let ids = match platform.get_devices(CL_DEVICE_TYPE_ALL) {
+                Ok(ids) => ids,
+                Err(err) if err.0 == CL_DEVICE_NOT_FOUND => Vec::new(),
+                Err(err) => return Err(err).context("Failed to query devices"),
+            };
+
+            Ok(PlatformInfo {
+                name: platform.name().context("Failed to query platform name")?,
+                version: platform
+                    .version()
+                    .context("Failed to query CL_PLATFORM_VERSION")?,
+                devices: ids
+                    .into_iter()
+                    .map(|id| query_device(&Device::new(id)))
+                    .collect::<anyhow::Result<_>>()?,
+            })
+        })
+        .collect()
+}
+
+fn query_device(device: &Device) -> anyhow::Result<DeviceInfo> {
+    Ok(DeviceInfo {
+        name: device.name().context("Failed to query device name")?,
+        device_type: device
+            .dev_type()
+            .context("Failed to query CL_DEVICE_TYPE")?,
+        compute_units: device
+            .max_compute_units()
+            .context("Failed to query CL_DEVICE_MAX_COMPUTE_UNITS")?,
+        global_mem: device
+            .global_mem_size()
+            .context("Failed to query CL_DEVICE_GLOBAL_MEM_SIZE")?,
+        version: device
+            .version()
+            .context("Failed to query CL_DEVICE_VERSION")?,
+        extensions: device
+            .extensions()
+            .context("Failed to query CL_DEVICE_EXTENSIONS")?
+            .split_whitespace()
+            .map(str::to_owned)
+            .collect(),
+    })
+}
+
+/// Prints one line per device, grouped by platform
+fn print_device_list(platforms: &[PlatformInfo]) {
+    for (p, platform) in platforms.iter().enumerate() {
+        println!("Platform {}: {} ({})", p, platform.name, platform.version);
+
+        for (d, device) in platform.devices.iter().enumerate() {
+            println!(
+                "  Device {}: {} [{}], {} compute units, {} MiB, {}",
+                d,
+                device.name,
+                device_type_name(device.device_type),
+                device.compute_units,
+                device.global_mem >> 20,
+                device.version
+            );
+        }
+    }
+}
+
+/// Prints `platforms` as a JSON array for tooling
+///
+/// The JSON is written by hand, the schema is small and fixed:
+/// `[{"name", "version", "devices": [{"name", "type", "compute_units", "global_mem_bytes",
+/// "version", "extensions": [..]}]}]`
+fn print_device_list_json(platforms: &[PlatformInfo]) {
+    let mut json = String::from("[");
+
+    for (p, platform) in platforms.iter().enumerate() {
+        if p > 0 {
+            json.push(',');
+        }
+        write!(
+            json,
+            "\n  {{\"name\": {}, \"version\": {}, \"devices\": [",
+            json_string(&platform.name),
+            json_string(&platform.version)
+        )
+        .unwrap();
+
+        for (d, device) in platform.devices.iter().enumerate() {
+            if d > 0 {
+                json.push(',');
+            }
+            let extensions: Vec<String> =
+                device.extensions.iter().map(|e| json_string(e)).collect();
+            write!(
+                json,
+                "\n    {{\"name\": {}, \"type\": {}, \"compute_units\": {}, \"global_mem_bytes\": {}, \"version\": {}, \"extensions\": [{}]}}",
+                json_string(&device.name),
+                json_string(device_type_name(device.device_type)),
+                device.compute_units,
+                device.global_mem,
+                json_string(&device.version),
+                extensions.join(", ")
+            )
+            .unwrap();
+        }
+
+        if !platform.devices.is_empty() {
+            json.push_str("\n  ");
+        }
+        json.push_str("]}");
+    }
+
+    if !platforms.is_empty() {
+        json.push('\n');
+    }
+    json.push(']');
+
+    println!("{}", json);
+}
+
+/// The most specific name for a `CL_DEVICE_TYPE` bitfield
+fn device_type_name(device_type: cl_device_type) -> &'static str {
+    if device_type & CL_DEVICE_TYPE_GPU != 0 {
+        "gpu"
+    } else if device_type & CL_DEVICE_TYPE_CPU != 0 {
+        "cpu"
+    } else if device_type & CL_DEVICE_TYPE_ACCELERATOR != 0 {
+        "accelerator"
+    } else if device_type & CL_DEVICE_TYPE_CUSTOM != 0 {
+        "custom"
+    } else {
+        "unknown"
+    }
+}
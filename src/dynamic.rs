@@ -0,0 +1,112 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+//! Runtime loading of the OpenCL library for builds with the `dynamic` feature
+//!
+//! Nothing links against libOpenCL, so a machine without an ICD loader gets a clear error instead
+//! of a loader failure at startup. On Linux build.rs generates a trampoline for every `cl*` symbol
+//! (listed in `src/dynamic_symbols.txt`) that jumps through a table filled here with `dlsym`, on
+//! macOS the framework is weak-linked and only has to be present.
+
+use std::ffi::{CStr, CString};
+
+use anyhow::bail;
+
+#[cfg(target_os = "linux")]
+const CANDIDATES: &[&str] = &["libOpenCL.so.1", "libOpenCL.so"];
+
+#[cfg(target_os = "macos")]
+const CANDIDATES: &[&str] = &["/System/Library/Frameworks/OpenCL.framework/OpenCL"];
+
+#[cfg(target_os = "linux")]
+include!(concat!(env!("OUT_DIR"), "/dynamic_trampolines.rs"));
+
+/// Target of the trampolines whose entry point the loaded library doesn't export
+#[cfg(target_os = "linux")]
+extern "C" fn not_loaded() {
+    eprintln!("Called an OpenCL entry point the loaded runtime doesn't provide");
+    std::process::abort();
+}
+
+/// Loads the OpenCL library, must run before any other OpenCL call
+pub fn load_opencl() -> anyhow::Result<()> {
+    let mut errors = Vec::new();
+
+    for name in CANDIDATES {
+        let c_name = CString::new(*name).unwrap();
+        let handle = unsafe { libc::dlopen(c_name.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+
+        if handle.is_null() {
+            errors.push(format!("{}: {}", name, last_dl_error()));
+            continue;
+        }
+
+        #[cfg(target_os = "linux")]
+        resolve_symbols(handle);
+
+        tracing::debug!("Loaded OpenCL runtime from {}", resolved_path(handle, name));
+        // The handle is intentionally leaked, the library has to stay loaded for the whole run
+        return Ok(());
+    }
+
+    bail!("OpenCL runtime not found:\n  {}", errors.join("\n  "))
+}
+
+/// Points the trampolines at the entry points of the library behind `handle`
+#[cfg(target_os = "linux")]
+fn resolve_symbols(handle: *mut libc::c_void) {
+    let table = std::ptr::addr_of_mut!(TABLE).cast::<unsafe extern "C" fn()>();
+    let mut missing = 0;
+
+    for (i, symbol) in SYMBOLS.iter().enumerate() {
+        let address = unsafe { libc::dlsym(handle, symbol.as_ptr()) };
+        if address.is_null() {
+            missing += 1;
+            continue;
+        }
+
+        // Only runs before the first OpenCL call, nothing reads the table concurrently
+        unsafe {
+            table.add(i).write(std::mem::transmute::<
+                *mut libc::c_void,
+                unsafe extern "C" fn(),
+            >(address))
+        };
+    }
+
+    tracing::trace!(
+        "Resolved {} of {} OpenCL entry points",
+        SYMBOLS.len() - missing,
+        SYMBOLS.len()
+    );
+}
+
+/// Finds the file `handle` was actually loaded from, through the address of `clGetPlatformIDs`
+fn resolved_path(handle: *mut libc::c_void, fallback: &str) -> String {
+    unsafe {
+        let symbol = libc::dlsym(handle, c"clGetPlatformIDs".as_ptr());
+        if symbol.is_null() {
+            return fallback.to_owned();
+        }
+
+        let mut info: libc::Dl_info = std::mem::zeroed();
+        if libc::dladdr(symbol, &mut info) == 0 || info.dli_fname.is_null() {
+            return fallback.to_owned();
+        }
+
+        CStr::from_ptr(info.dli_fname)
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+fn last_dl_error() -> String {
+    let error = unsafe { libc::dlerror() };
+    if error.is_null() {
+        "unknown error".to_owned()
+    } else {
+        unsafe { CStr::from_ptr(error) }
+            .to_string_lossy()
+            .into_owned()
+    }
+}
@@ -0,0 +1,40 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+//! Attaching context to OpenCL errors
+//!
+//! opencl3's `ClError` doesn't implement `std::error::Error`, so `anyhow::Context` can't be used on
+//! its results directly. [`ClContext`] wraps the error into [`ClErrorCause`] first, which keeps
+//! the OpenCL error as the cause printed under the high-level message.
+
+use std::fmt;
+
+use opencl3::error_codes::ClError;
+
+/// An OpenCL error code as the cause of an `anyhow::Error`
+#[derive(Debug)]
+pub struct ClErrorCause(pub ClError);
+
+impl fmt::Display for ClErrorCause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.0, self.0 .0)
+    }
+}
+
+impl std::error::Error for ClErrorCause {}
+
+/// `anyhow::Context::context` for results carrying a `ClError`
+pub trait ClContext<T> {
+    fn context<C>(self, context: C) -> anyhow::Result<T>
+    where
+        C: fmt::Display + Send + Sync + 'static;
+}
+
+impl<T> ClContext<T> for Result<T, ClError> {
+    fn context<C>(self, context: C) -> anyhow::Result<T>
+    where
+        C: fmt::Display + Send + Sync + 'static,
+    {
+        self.map_err(|err| anyhow::Error::new(ClErrorCause(err)).context(context))
+    }
+}
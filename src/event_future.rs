@@ -0,0 +1,162 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+//! `--async` (the `async` feature): awaiting OpenCL events instead of blocking in `clWaitForEvents`
+//!
+//! [`EventFuture`] registers a `CL_COMPLETE` callback (`clSetEventCallback`) on its first poll,
+//! which stores the event's final status and wakes the task that polled last. The driver calls the
+//! callback right away when the event has completed already, possibly on the polling thread while
+//! the callback is being registered, so the waker is stored first and the status checked after.
+//! A negative status, the driver's error for a command that failed, resolves the future to `Err`.
+//!
+//! Nothing here depends on a runtime, the futures work with any executor. [`block_on_all`] is the
+//! small one `run --threads N --async` drives its saxpys with, polling them all from the calling
+//! thread and parking it until a callback wakes one.
+
+use std::{
+    ffi::c_void,
+    future::Future,
+    panic,
+    pin::Pin,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    task::{Context, Poll, Wake, Waker},
+    thread::{self, Thread},
+};
+
+use opencl3::{
+    error_codes::ClError,
+    event::{Event, CL_COMPLETE},
+    types::{cl_event, cl_int},
+};
+
+/// What the callback and the future share
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// The status the callback was called with, `None` until then
+    status: Option<cl_int>,
+    /// The waker of the last poll
+    waker: Option<Waker>,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Resolves once `event` has completed, see the module docs
+pub struct EventFuture<'e> {
+    event: &'e Event,
+    /// Set once the callback is registered
+    shared: Option<Arc<Shared>>,
+}
+
+impl<'e> EventFuture<'e> {
+    pub fn new(event: &'e Event) -> Self {
+        Self {
+            event,
+            shared: None,
+        }
+    }
+}
+
+impl Future for EventFuture<'_> {
+    type Output = Result<(), ClError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let shared = match &self.shared {
+            Some(shared) => Arc::clone(shared),
+            None => {
+                let shared = Arc::new(Shared::default());
+                shared.lock().waker = Some(cx.waker().clone());
+
+                // The callback takes over this reference, it runs exactly once per registration
+                let user_data = Arc::into_raw(Arc::clone(&shared)) as *mut c_void;
+                if let Err(err) = self.event.set_callback(CL_COMPLETE, on_complete, user_data) {
+                    // SAFETY: the callback wasn't registered, so the reference is still ours
+                    drop(unsafe { Arc::from_raw(user_data as *const Shared) });
+                    return Poll::Ready(Err(err));
+                }
+                self.shared = Some(Arc::clone(&shared));
+                shared
+            }
+        };
+
+        let mut state = shared.lock();
+        match state.status {
+            Some(status) if status < 0 => Poll::Ready(Err(ClError(status))),
+            Some(_) => Poll::Ready(Ok(())),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// The `CL_COMPLETE` callback, stores `status` and wakes the future
+extern "C" fn on_complete(_event: cl_event, status: cl_int, user_data: *mut c_void) {
+    // SAFETY: `user_data` is the reference `EventFuture::poll` gave up for this registration
+    let shared = unsafe { Arc::from_raw(user_data as *const Shared) };
+    let waker = {
+        let mut state = shared.lock();
+        state.status = Some(status);
+        state.waker.take()
+    };
+
+    // Unwinding into the driver is undefined behaviour, a panicking waker only loses the wake-up
+    if let Some(waker) = waker {
+        let _ = panic::catch_unwind(|| waker.wake());
+    }
+}
+
+/// Waits for `event` when dropped, for host memory a command may still write to while the future
+/// awaiting it is dropped early
+pub struct WaitOnDrop<'e>(pub &'e Event);
+
+impl Drop for WaitOnDrop<'_> {
+    fn drop(&mut self) {
+        if let Err(err) = self.0.wait() {
+            tracing::error!("Failed to wait for an event: {}", err);
+        }
+    }
+}
+
+/// Wakes a parked thread
+struct Unparker(Thread);
+
+impl Wake for Unparker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Polls all of `futures` from the calling thread until every one is ready, parking it in between,
+/// and returns their outputs in order
+pub fn block_on_all<F: Future>(futures: Vec<F>) -> Vec<F::Output> {
+    let waker = Waker::from(Arc::new(Unparker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+
+    let mut futures: Vec<Pin<Box<F>>> = futures.into_iter().map(Box::pin).collect();
+    let mut outputs: Vec<Option<F::Output>> = futures.iter().map(|_| None).collect();
+    loop {
+        for (future, output) in futures.iter_mut().zip(&mut outputs) {
+            if output.is_none() {
+                if let Poll::Ready(value) = future.as_mut().poll(&mut cx) {
+                    *output = Some(value);
+                }
+            }
+        }
+        if outputs.iter().all(Option::is_some) {
+            return outputs.into_iter().map(Option::unwrap).collect();
+        }
+
+        // A wake-up before this returns right away, spurious ones only cost another round of polls
+        thread::park();
+    }
+}
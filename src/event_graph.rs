@@ -0,0 +1,61 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+//! The dependency structure of the commands one saxpy launch enqueues, printed by `--dump-dag`
+
+use std::fmt;
+
+/// Why one command runs after another
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dependency {
+    /// The event of the first command is in the wait list of the second
+    WaitList,
+    /// Nothing links them explicitly, the in-order queue runs them one after the other
+    QueueOrder,
+    /// The host blocks on the first command before it enqueues the second
+    HostWait,
+}
+
+impl fmt::Display for Dependency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::WaitList => "wait list",
+            Self::QueueOrder => "in-order queue",
+            Self::HostWait => "host wait",
+        })
+    }
+}
+
+/// Commands as nodes, each edge pointing from a command to one that waits for it
+#[derive(Debug, Default)]
+pub struct EventGraph {
+    nodes: Vec<String>,
+    edges: Vec<(usize, usize, Dependency)>,
+}
+
+impl EventGraph {
+    /// Adds a command and returns its node index
+    pub fn node(&mut self, label: impl Into<String>) -> usize {
+        self.nodes.push(label.into());
+        self.nodes.len() - 1
+    }
+
+    /// Records that `to` runs after `from`
+    pub fn edge(&mut self, from: usize, to: usize, dependency: Dependency) {
+        self.edges.push((from, to, dependency));
+    }
+}
+
+/// Prints the graph in graphviz dot syntax under the name `saxpy`
+impl fmt::Display for EventGraph {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "digraph saxpy {{")?;
+        for (i, label) in self.nodes.iter().enumerate() {
+            writeln!(f, "    n{} [label=\"{}\"];", i, label)?;
+        }
+        for (from, to, dependency) in &self.edges {
+            writeln!(f, "    n{} -> n{} [label=\"{}\"];", from, to, dependency)?;
+        }
+        write!(f, "}}")
+    }
+}
@@ -0,0 +1,75 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+//! `--fit-to-memory`: retrying a size the device can't allocate with half of it
+//!
+//! A size that doesn't fit fails in `clCreateBuffer` or, where drivers allocate lazily, in the
+//! first command using the buffers, with `CL_MEM_OBJECT_ALLOCATION_FAILURE` or
+//! `CL_OUT_OF_RESOURCES`. [`with_backoff`] runs an attempt, and when it fails with one of those two
+//! retries it with half the size, up to [`MAX_RETRIES`] times. Any other error ends it right away.
+//! The attempt owns everything it allocates, so the buffers of a failed attempt are released when
+//! it returns, before the next one asks for memory.
+
+use opencl3::error_codes::{CL_MEM_OBJECT_ALLOCATION_FAILURE, CL_OUT_OF_RESOURCES};
+
+use crate::error::ClErrorCause;
+
+/// How often a size is halved before giving up
+pub const MAX_RETRIES: u32 = 8;
+
+/// Whether `err` was caused by the device running out of memory, see the module docs
+pub fn is_allocation_failure(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause
+            .downcast_ref::<ClErrorCause>()
+            .is_some_and(|ClErrorCause(err)| {
+                matches!(
+                    err.0,
+                    CL_MEM_OBJECT_ALLOCATION_FAILURE | CL_OUT_OF_RESOURCES
+                )
+            })
+    })
+}
+
+/// Runs `attempt` with `size` elements, and with `enabled` again with half as many after every
+/// allocation failure. Returns the size that worked with the result of its attempt
+pub fn with_backoff<T>(
+    size: usize,
+    enabled: bool,
+    mut attempt: impl FnMut(usize) -> anyhow::Result<T>,
+) -> anyhow::Result<(usize, T)> {
+    let mut current = size;
+    let mut retries = 0;
+    loop {
+        let err = match attempt(current) {
+            Ok(result) => {
+                if current < size {
+                    tracing::warn!(
+                        "Fitted to the device memory: running {} of the requested {} elements",
+                        current,
+                        size
+                    );
+                }
+                return Ok((current, result));
+            }
+            Err(err) if enabled && is_allocation_failure(&err) => err,
+            Err(err) => return Err(err),
+        };
+
+        if retries == MAX_RETRIES || current == 1 {
+            return Err(err.context(format!(
+                "{} elements still don't fit after halving the size {} times",
+                current, retries
+            )));
+        }
+        retries += 1;
+        let next = current / 2;
+        tracing::warn!(
+            "{} elements don't fit in the device memory ({:#}), retrying with {}",
+            current,
+            err,
+            next
+        );
+        current = next;
+    }
+}
@@ -0,0 +1,76 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+//! Aligned host allocations for `CL_MEM_USE_HOST_PTR` buffers
+//!
+//! A host pointer that doesn't meet `CL_DEVICE_MEM_BASE_ADDR_ALIGN` makes the driver fall back to
+//! copying into its own allocation, which is exactly what `CL_MEM_USE_HOST_PTR` was meant to avoid.
+//! This matters most on CPU devices, where an aligned buffer is used in place. Placement on a
+//! particular NUMA node is out of scope, the memory is first touched by the thread that fills it.
+
+use std::{
+    alloc::{self, Layout},
+    mem,
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+    slice,
+};
+
+use opencl3::{device::Device, types::cl_float};
+
+use crate::error::ClContext;
+
+/// A zero-initialized `[cl_float]` allocation with a caller-chosen alignment
+///
+/// This isn't a `Vec<cl_float>`: a `Vec` frees its buffer with the alignment of `cl_float`, which
+/// doesn't match the layout the memory was allocated with.
+pub struct AlignedVec {
+    ptr: NonNull<cl_float>,
+    len: usize,
+    layout: Layout,
+}
+
+/// Allocates `len` zeroed floats aligned to `align` bytes, which must be a power of two
+pub fn aligned_host_vec(len: usize, align: usize) -> AlignedVec {
+    let layout = Layout::from_size_align(
+        // Zero-sized allocations aren't allowed, keep at least one element
+        len.max(1) * mem::size_of::<cl_float>(),
+        align.max(mem::align_of::<cl_float>()),
+    )
+    .expect("alignment must be a power of two");
+
+    let ptr = unsafe { alloc::alloc_zeroed(layout) };
+    let ptr = NonNull::new(ptr.cast()).unwrap_or_else(|| alloc::handle_alloc_error(layout));
+
+    AlignedVec { ptr, len, layout }
+}
+
+/// The alignment in bytes `device` requires of host pointers, `CL_DEVICE_MEM_BASE_ADDR_ALIGN` is
+/// reported in bits
+pub fn host_ptr_alignment(device: &Device) -> anyhow::Result<usize> {
+    let bits = device
+        .mem_base_addr_align()
+        .context("Failed to query CL_DEVICE_MEM_BASE_ADDR_ALIGN")?;
+
+    Ok((bits as usize / 8).max(mem::align_of::<cl_float>()))
+}
+
+impl Deref for AlignedVec {
+    type Target = [cl_float];
+
+    fn deref(&self) -> &[cl_float] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for AlignedVec {
+    fn deref_mut(&mut self) -> &mut [cl_float] {
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for AlignedVec {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr.as_ptr().cast(), self.layout) }
+    }
+}
@@ -0,0 +1,184 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+//! `run --images`: saxpy reading and writing 1D image buffers instead of plain buffers
+//!
+//! Each of `x`, `y` and `z` is a buffer as usual, wrapped in an `image1d_buffer_t` with one float
+//! channel (`CL_R`, `CL_FLOAT`) that the kernel goes through with `read_imagef` and `write_imagef`.
+//! The image shares the storage of its buffer, so the inputs are written and `z` is read back
+//! through the buffers. This shows the image path only, no GL interop is involved.
+
+use std::ptr;
+
+use anyhow::bail;
+use opencl3::{
+    command_queue::CommandQueue,
+    context::Context,
+    device::Device,
+    memory::{
+        Buffer, ClMem, Image, CL_FLOAT, CL_MEM_OBJECT_IMAGE1D_BUFFER, CL_MEM_READ_ONLY,
+        CL_MEM_WRITE_ONLY, CL_R,
+    },
+    types::{cl_float, cl_image_desc, cl_image_format, cl_mem_flags, CL_BLOCKING},
+};
+
+use crate::{
+    cli::{Args, Op},
+    error::ClContext,
+    kernels::{self, BuiltinKernel},
+    launch::launch,
+    run, saxpy,
+};
+
+pub const PROGRAM_SOURCE: &str = r#"
+kernel void saxpy_float_image (write_only image1d_buffer_t z,
+    read_only image1d_buffer_t x,
+    read_only image1d_buffer_t y,
+    float a)
+{
+    const int i = get_global_id(0);
+    const float v = a*read_imagef(x, i).x + read_imagef(y, i).x;
+    write_imagef(z, i, (float4)(v, 0.0f, 0.0f, 1.0f));
+}"#;
+
+pub const KERNEL_NAME: &str = BuiltinKernel::SaxpyImage.name();
+
+/// The one-channel float format of every image
+const FORMAT: cl_image_format = cl_image_format {
+    image_channel_order: CL_R,
+    image_channel_data_type: CL_FLOAT,
+};
+
+/// Runs the image kernel over the inputs of `args` and checks `z` on the host
+pub fn run(
+    context: &Context,
+    queue: &CommandQueue,
+    device: &Device,
+    args: &Args,
+) -> anyhow::Result<()> {
+    let name = device.name().context("Failed to query device name")?;
+    if !device
+        .image_support()
+        .context("Failed to query CL_DEVICE_IMAGE_SUPPORT")?
+    {
+        bail!(
+            "{} doesn't support images (CL_DEVICE_IMAGE_SUPPORT is false), run without --images",
+            name
+        );
+    }
+
+    let (x, y) = run::inputs(args)?;
+    let size = x.len();
+    let max_width = device
+        .image_max_buffer_size()
+        .context("Failed to query CL_DEVICE_IMAGE_MAX_BUFFER_SIZE")?;
+    if size > max_width {
+        bail!(
+            "{} elements exceed the {} pixels an image buffer can have on {} (CL_DEVICE_IMAGE_MAX_BUFFER_SIZE)",
+            size,
+            max_width,
+            name
+        );
+    }
+    for flags in [CL_MEM_READ_ONLY, CL_MEM_WRITE_ONLY] {
+        let formats = context
+            .get_supported_image_formats(flags, CL_MEM_OBJECT_IMAGE1D_BUFFER)
+            .context("Failed to query the supported image formats")?;
+        if !formats.iter().any(|format| {
+            format.image_channel_order == FORMAT.image_channel_order
+                && format.image_channel_data_type == FORMAT.image_channel_data_type
+        }) {
+            bail!(
+                "{} has no CL_R/CL_FLOAT 1D image buffers, which --images needs",
+                name
+            );
+        }
+    }
+
+    let kernel = kernels::build_kernel(
+        context,
+        PROGRAM_SOURCE,
+        KERNEL_NAME,
+        &kernels::arg_info_options(args),
+        kernels::BuildFlags::from_args(args),
+    )?;
+
+    let mut x_buffer = saxpy::create_buffer(context, CL_MEM_READ_ONLY, size)?;
+    let mut y_buffer = saxpy::create_buffer(context, CL_MEM_READ_ONLY, size)?;
+    let z_buffer = saxpy::create_buffer(context, CL_MEM_WRITE_ONLY, size)?;
+    let x_image = image_over(context, &x_buffer, CL_MEM_READ_ONLY, size)?;
+    let y_image = image_over(context, &y_buffer, CL_MEM_READ_ONLY, size)?;
+    let z_image = image_over(context, &z_buffer, CL_MEM_WRITE_ONLY, size)?;
+
+    unsafe { queue.enqueue_write_buffer(&mut x_buffer, CL_BLOCKING, 0, &x, &[]) }
+        .context("Failed to write to buffer")?;
+    unsafe { queue.enqueue_write_buffer(&mut y_buffer, CL_BLOCKING, 0, &y, &[]) }
+        .context("Failed to write to buffer")?;
+
+    let a: cl_float = args.scalar();
+    let kernel_event = unsafe {
+        launch!(
+            &kernel,
+            global = size;
+            z: (&z_image, "image1d_buffer_t"),
+            x: (&x_image, "image1d_buffer_t"),
+            y: (&y_image, "image1d_buffer_t"),
+            a: &a,
+        )
+        .enqueue_nd_range(queue)
+    }
+    .map_err(|err| err.context("Failed to execute kernel"))?;
+
+    // In queue order behind the kernel, which wrote z through the image
+    let mut z = vec![0.0; size];
+    unsafe { queue.enqueue_read_buffer(&z_buffer, CL_BLOCKING, 0, &mut z, &[]) }
+        .context("Failed to read buffer")?;
+
+    let checked = match saxpy::verify(
+        Op::Saxpy,
+        None,
+        args.max_error(),
+        args.verify_stride.unwrap_or(1),
+        Some(a),
+        &x,
+        &y,
+        &z,
+    ) {
+        Ok(checked) => checked,
+        Err(i) => bail!("Verification failed at index {}: got {}", i, z[i]),
+    };
+
+    println!("results front: {}", z[0]);
+    println!("results back: {}", z[size - 1]);
+    println!("verified {} of {} results", checked, size);
+    println!(
+        "image kernel time (ns): {}",
+        saxpy::profiled_ns(&kernel_event)?
+    );
+
+    Ok(())
+}
+
+/// A 1D image of `width` single-float pixels sharing the storage of `buffer`
+fn image_over(
+    context: &Context,
+    buffer: &Buffer<cl_float>,
+    flags: cl_mem_flags,
+    width: usize,
+) -> anyhow::Result<Image> {
+    let desc = cl_image_desc {
+        image_type: CL_MEM_OBJECT_IMAGE1D_BUFFER,
+        image_width: width,
+        image_height: 0,
+        image_depth: 0,
+        image_array_size: 0,
+        image_row_pitch: 0,
+        image_slice_pitch: 0,
+        num_mip_levels: 0,
+        num_samples: 0,
+        buffer: buffer.get(),
+    };
+
+    unsafe { Image::create(context, flags, &FORMAT, &desc, ptr::null_mut()) }
+        .context("Failed to create image")
+}
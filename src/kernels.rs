@@ -0,0 +1,1254 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Write as _},
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::OnceLock,
+    time::Instant,
+};
+
+use anyhow::{bail, Context as _};
+use opencl3::{
+    context::Context,
+    device::Device,
+    error_codes::ClError,
+    kernel::{
+        create_program_kernels, Kernel, CL_KERNEL_ARG_ADDRESS_GLOBAL, CL_KERNEL_ARG_ADDRESS_PRIVATE,
+    },
+    program::{
+        get_program_build_info, Program, CL_BUILD_ERROR, CL_BUILD_IN_PROGRESS, CL_BUILD_NONE,
+        CL_BUILD_SUCCESS, CL_KERNEL_ARG_INFO, CL_PROGRAM_BUILD_LOG, CL_PROGRAM_BUILD_STATUS,
+    },
+    types::{cl_device_id, cl_float, cl_int, cl_kernel_arg_address_qualifier, cl_program},
+};
+
+use crate::{
+    build_log,
+    build_time::{self, Origin},
+    cli::{Args, Command, Define, Layout, Op},
+    error::ClContext,
+    link, offline, printf, program_cache, trace,
+};
+
+/// The kernel of one element-wise op, [`program_source`] generates both layouts' kernels from it
+pub struct OpKernel {
+    /// The name `--op` takes
+    pub name: &'static str,
+    /// The struct-of-arrays kernel, over `x` and `y` in separate buffers
+    pub soa_name: &'static str,
+    /// The array-of-structs kernel, over `x` and `y` interleaved into one `float2` buffer
+    pub aos_name: &'static str,
+    /// The OpenCL C expression computing `z` from `v.x` and `v.y`, and `a` when it takes the scalar
+    pub expression: &'static str,
+    /// Whether the kernel takes the scalar `a` after the buffers
+    pub scalar: bool,
+    /// The same computation on the host for verification, from `a`, `x` and `y`
+    pub host: HostOp,
+}
+
+impl OpKernel {
+    /// The name of the kernel over inputs in `layout`
+    pub const fn kernel_name(&self, layout: Layout) -> &'static str {
+        match layout {
+            Layout::Aos => self.aos_name,
+            _ => self.soa_name,
+        }
+    }
+}
+
+/// How the host computes an op, from `a`, `x` and `y`
+type HostOp = fn(cl_float, cl_float, cl_float) -> cl_float;
+
+/// The kernel of every op, adding an op is one more arm here
+pub const fn op_kernel(op: Op) -> OpKernel {
+    let (name, soa_name, aos_name, expression, scalar, host): (_, _, _, _, _, HostOp) = match op {
+        Op::Saxpy => (
+            "saxpy",
+            "saxpy_float",
+            "saxpy_float2",
+            "a*v.x + v.y",
+            true,
+            |a, x, y| a * x + y,
+        ),
+        Op::Add => (
+            "add",
+            "vadd_float",
+            "vadd_float2",
+            "v.x + v.y",
+            false,
+            |_, x, y| x + y,
+        ),
+        Op::Sub => (
+            "sub",
+            "vsub_float",
+            "vsub_float2",
+            "v.x - v.y",
+            false,
+            |_, x, y| x - y,
+        ),
+        Op::Mul => (
+            "mul",
+            "vmul_float",
+            "vmul_float2",
+            "v.x * v.y",
+            false,
+            |_, x, y| x * y,
+        ),
+        Op::Div => (
+            "div",
+            "vdiv_float",
+            "vdiv_float2",
+            "v.x / v.y",
+            false,
+            |_, x, y| x / y,
+        ),
+        Op::Max => (
+            "max",
+            "vmax_float",
+            "vmax_float2",
+            "fmax(v.x, v.y)",
+            false,
+            |_, x, y| x.max(y),
+        ),
+        Op::Min => (
+            "min",
+            "vmin_float",
+            "vmin_float2",
+            "fmin(v.x, v.y)",
+            false,
+            |_, x, y| x.min(y),
+        ),
+    };
+
+    OpKernel {
+        name,
+        soa_name,
+        aos_name,
+        expression,
+        scalar,
+        host,
+    }
+}
+
+/// The program holding the kernel of every op over inputs in `layout`, `--op` or `--kernel-name`
+/// picks the kernel. Generated from [`op_kernel`] on first use
+pub fn program_source(layout: Layout) -> &'static str {
+    static SOA: OnceLock<String> = OnceLock::new();
+    static AOS: OnceLock<String> = OnceLock::new();
+
+    let cell = match layout {
+        Layout::Aos => &AOS,
+        _ => &SOA,
+    };
+    cell.get_or_init(|| {
+        let mut source = String::new();
+        for op in Op::ALL {
+            write_op_kernel(&mut source, op, layout);
+        }
+
+        source
+    })
+}
+
+/// A program holding only the kernel of `op` over inputs in `layout`, as in [`program_source`],
+/// for `chain --separate-programs`
+pub fn op_program_source(op: Op, layout: Layout) -> String {
+    let mut source = String::new();
+    write_op_kernel(&mut source, op, layout);
+
+    source
+}
+
+/// Appends the kernel of `op` over inputs in `layout` to `source`
+fn write_op_kernel(source: &mut String, op: Op, layout: Layout) {
+    let (inputs, load) = match layout {
+        Layout::Aos => ("global float2 const* xy", "xy[i]"),
+        _ => (
+            "global float const* x,\n    global float const* y",
+            "(float2)(x[i], y[i])",
+        ),
+    };
+    let kernel = op_kernel(op);
+    write!(
+        source,
+        "\nkernel void {} (global float* z,\n    {}{})\n{{\n    const size_t i = get_global_id(0);\n    const float2 v = {};\n    const float zi = {};\n    z[i] = zi;\n#ifdef DEBUG_PRINT\n    printf(\"{} z[%u] = %f\\n\", (uint)i, zi);\n#endif\n}}\n",
+        kernel.kernel_name(layout),
+        inputs,
+        if kernel.scalar { ",\n    float a" } else { "" },
+        load,
+        kernel.expression,
+        kernel.kernel_name(layout)
+    )
+    .unwrap();
+}
+
+pub const KERNEL_NAME: &str = op_kernel(Op::Saxpy).soa_name;
+pub const VADD_KERNEL_NAME: &str = op_kernel(Op::Add).soa_name;
+
+/// Saxpy repeated `ITERS` times per element to raise the arithmetic intensity,
+/// `ITERS` is supplied as a `-D ITERS=n` build option
+pub const ITERS_PROGRAM_SOURCE: &str = r#"
+kernel void saxpy_float_iters (global float* z,
+    global float const* x,
+    global float const* y,
+    float a)
+{
+    const size_t i = get_global_id(0);
+    const float xi = x[i];
+    float zi = y[i];
+    for (int k = 0; k < ITERS; ++k) {
+        zi = a*xi + zi;
+    }
+    z[i] = zi;
+#ifdef DEBUG_PRINT
+    printf("saxpy_float_iters z[%u] = %f\n", (uint)i, zi);
+#endif
+}"#;
+
+pub const ITERS_KERNEL_NAME: &str = BuiltinKernel::SaxpyIters.name();
+
+/// Struct-of-arrays saxpy for a global size rounded up past the end of the inputs, the work-items
+/// from `n` on return right away
+pub const GUARDED_PROGRAM_SOURCE: &str = r#"
+kernel void saxpy_float_guarded (global float* z,
+    global float const* x,
+    global float const* y,
+    float a,
+    uint n)
+{
+    const size_t i = get_global_id(0);
+    if (i >= n) return;
+    const float zi = a*x[i] + y[i];
+    z[i] = zi;
+#ifdef DEBUG_PRINT
+    printf("saxpy_float_guarded z[%u] = %f\n", (uint)i, zi);
+#endif
+}"#;
+
+pub const GUARDED_KERNEL_NAME: &str = BuiltinKernel::SaxpyGuarded.name();
+
+/// Every kernel this tool builds from its own source, the registry `--help` and the error messages
+/// about kernel names are generated from
+///
+/// The `*KERNEL_NAME` constants of the modules holding the sources are taken from here, so a kernel
+/// can't be renamed without the list following. The element-wise kernels of [`program_source`] are
+/// one entry per op and layout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuiltinKernel {
+    Elementwise(Op, Layout),
+    SaxpyIters,
+    SaxpyGuarded,
+    SaxpyLinked,
+    SaxpyThenSum,
+    SaxpyImage,
+    CountAbove,
+    CountAboveRacy,
+    PipeProduce,
+    PipeConsume,
+}
+
+impl BuiltinKernel {
+    /// Every built-in kernel, the element-wise ones first
+    pub fn all() -> impl Iterator<Item = Self> {
+        let elementwise = Op::ALL
+            .into_iter()
+            .flat_map(|op| [Layout::Soa, Layout::Aos].map(|layout| Self::Elementwise(op, layout)));
+
+        elementwise.chain([
+            Self::SaxpyIters,
+            Self::SaxpyGuarded,
+            Self::SaxpyLinked,
+            Self::SaxpyThenSum,
+            Self::SaxpyImage,
+            Self::CountAbove,
+            Self::CountAboveRacy,
+            Self::PipeProduce,
+            Self::PipeConsume,
+        ])
+    }
+
+    /// The name of the kernel function
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Elementwise(op, layout) => op_kernel(op).kernel_name(layout),
+            Self::SaxpyIters => "saxpy_float_iters",
+            Self::SaxpyGuarded => "saxpy_float_guarded",
+            Self::SaxpyLinked => "saxpy_float_linked",
+            Self::SaxpyThenSum => "saxpy_then_sum",
+            Self::SaxpyImage => "saxpy_float_image",
+            Self::CountAbove => "count_above",
+            Self::CountAboveRacy => "count_above_racy",
+            Self::PipeProduce => "pipe_produce",
+            Self::PipeConsume => "pipe_consume",
+        }
+    }
+
+    /// The options that make a command run the kernel
+    pub fn selected_by(self) -> String {
+        match self {
+            Self::Elementwise(Op::Saxpy, Layout::Soa) => {
+                "--op saxpy (the default) or --kernel-name with --layout soa".to_owned()
+            }
+            Self::Elementwise(op, layout) => {
+                format!("--op {} or --kernel-name with --layout {}", op, layout)
+            }
+            Self::SaxpyIters => "--kernel-iters N".to_owned(),
+            Self::SaxpyGuarded => "single runs of the soa saxpy, tune --guarded".to_owned(),
+            Self::SaxpyLinked => "--link-helpers".to_owned(),
+            Self::SaxpyThenSum => "run --device-enqueue".to_owned(),
+            Self::SaxpyImage => "run --images".to_owned(),
+            Self::CountAbove => "atomic-count".to_owned(),
+            Self::CountAboveRacy => "atomic-count --racy".to_owned(),
+            Self::PipeProduce | Self::PipeConsume => "pipe-demo".to_owned(),
+        }
+    }
+
+    /// The layout and op of the kernels of [`program_source`], which `--kernel-name` can pick
+    /// without a kernel file
+    pub fn layout_op(self) -> Option<(Layout, Op)> {
+        match self {
+            Self::Elementwise(op, layout) => Some((layout, op)),
+            _ => None,
+        }
+    }
+
+    /// The section of `--help` listing every kernel and how it is selected
+    pub fn help() -> String {
+        let mut help = String::from("\nBuilt-in kernels:\n");
+        for kernel in Self::all() {
+            writeln!(help, "    {:<27}{}", kernel.name(), kernel.selected_by()).unwrap();
+        }
+
+        help
+    }
+
+    fn names() -> String {
+        Self::all().map(Self::name).collect::<Vec<_>>().join(", ")
+    }
+}
+
+impl fmt::Display for BuiltinKernel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for BuiltinKernel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match Self::all().find(|kernel| kernel.name() == s) {
+            Some(kernel) => Ok(kernel),
+            None => bail!(
+                "'{}' isn't a built-in kernel, expected one of: {}",
+                s,
+                Self::names()
+            ),
+        }
+    }
+}
+
+/// How programs are built, from the `--print-build-log`, `--no-cache` and `--strict` options
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BuildFlags {
+    /// Print the build log to stderr after a successful build too
+    pub print_log: bool,
+    /// Take the program from and store it in the [`program_cache`]
+    pub cache: bool,
+    /// Fail builds whose log reports warnings
+    pub strict: bool,
+    /// Fail builds whose log reports performance warnings, see [`build_log`]
+    pub strict_build: bool,
+}
+
+impl BuildFlags {
+    pub fn from_args(args: &Args) -> Self {
+        Self {
+            print_log: args.print_build_log,
+            cache: !args.no_cache,
+            strict: args.strict,
+            strict_build: args.strict_build,
+        }
+    }
+}
+
+/// Builds `source` and creates the kernel called `name` from it
+pub fn build_kernel(
+    context: &Context,
+    source: &str,
+    name: &str,
+    options: &str,
+    flags: BuildFlags,
+) -> anyhow::Result<Kernel> {
+    let program = build_program(context, source, options, name, flags)?;
+    let kernel = Kernel::create(&program, name).context("Failed to create kernel")?;
+
+    tracing::debug!(
+        "Created program + kernel ({}) with options \"{}\" and source:\n{}",
+        name,
+        options,
+        source
+    );
+
+    Ok(kernel)
+}
+
+/// Creates a program from `source` and builds it with `options` for the devices of `context`
+///
+/// A failed build reports `what` was built and the build status and log of every device, which is
+/// also where the compiler explains options it rejected with `CL_INVALID_BUILD_OPTIONS`. With
+/// `flags.print_log` the logs go to stderr after a successful build too, warnings such as register
+/// spills end up there
+///
+/// With `flags.cache` the program is created from binaries cached by an earlier build when there are
+/// any, and stored in the cache after compiling it otherwise. `flags.strict` and
+/// `flags.strict_build` always compile, the warnings are only in the log of a compilation
+pub fn build_program(
+    context: &Context,
+    source: &str,
+    options: &str,
+    what: &str,
+    flags: BuildFlags,
+) -> anyhow::Result<Program> {
+    let start = Instant::now();
+    let key = if flags.cache {
+        program_cache::key(source, options, context.devices())
+    } else {
+        None
+    };
+    if let Some(key) = key.filter(|_| !flags.strict && !flags.strict_build) {
+        if let Some(program) = program_cache::load(context, key, options) {
+            tracing::info!("Program cache hit for {} ({:016x})", what, key);
+            build_time::record(what, Origin::CacheHit, start);
+            if flags.print_log {
+                eprint!(
+                    "Build log of {} (cached binary):\n{}",
+                    what,
+                    build_logs(program.get(), context.devices())
+                );
+            }
+            trace::program(program.get(), source, options);
+            return Ok(program);
+        }
+        tracing::info!("Program cache miss for {} ({:016x})", what, key);
+    }
+
+    let program = Program::create_from_source(context, source)
+        .context("Program::create_from_source failed")?;
+    let program = build_created(context, program, options, what, flags)?;
+    let origin = if key.is_some() && !flags.strict && !flags.strict_build {
+        Origin::CacheMiss
+    } else {
+        Origin::Compiled
+    };
+    build_time::record(what, origin, start);
+
+    if let Some(key) = key {
+        program_cache::store(&program, key);
+    }
+    trace::program(program.get(), source, options);
+
+    Ok(program)
+}
+
+/// Builds the freshly created `program` with `options`, reporting failures as [`build_program`]
+/// does. The caller records the build time with [`build_time::record`]
+///
+/// With `flags.strict` a successful build whose log mentions a warning on any device fails as
+/// well. Only the word is looked for, as some compilers log progress messages on every build. The
+/// performance warnings of [`build_log`] are logged, or fail the build with `flags.strict_build`
+pub fn build_created(
+    context: &Context,
+    mut program: Program,
+    options: &str,
+    what: &str,
+    flags: BuildFlags,
+) -> anyhow::Result<Program> {
+    match program.build(context.devices(), options) {
+        Err(err) => bail!(
+            "Failed to build {} with options \"{}\": {}\n{}",
+            what,
+            options,
+            err,
+            build_logs(program.get(), context.devices()).trim_end()
+        ),
+        Ok(()) if flags.strict && has_warnings(program.get(), context.devices()) => bail!(
+            "Building {} with options \"{}\" produced warnings, which --strict treats as errors\n{}",
+            what,
+            options,
+            build_logs(program.get(), context.devices()).trim_end()
+        ),
+        Ok(()) if flags.print_log => eprint!(
+            "Build log of {}:\n{}",
+            what,
+            build_logs(program.get(), context.devices())
+        ),
+        Ok(()) => {}
+    }
+    build_log::check(
+        &build_logs(program.get(), context.devices()),
+        what,
+        flags.strict_build,
+    )?;
+
+    Ok(program)
+}
+
+/// Whether the build log of `program` on any of `devices` mentions a warning
+pub fn has_warnings(program: cl_program, devices: &[cl_device_id]) -> bool {
+    devices.iter().any(|&id| {
+        get_program_build_info(program, id, CL_PROGRAM_BUILD_LOG)
+            .is_ok_and(|log| String::from(log).to_lowercase().contains("warning"))
+    })
+}
+
+/// The build status and log of `program` on each of `devices`, every log under a line naming the
+/// device
+///
+/// Takes the raw program, so programs that opencl3 can't wrap (see [`crate::link`]) report their
+/// logs the same way
+pub fn build_logs(program: cl_program, devices: &[cl_device_id]) -> String {
+    let mut logs = String::new();
+    for &id in devices {
+        let name = Device::new(id)
+            .name()
+            .unwrap_or_else(|_| format!("device {:p}", id));
+        let status = get_program_build_info(program, id, CL_PROGRAM_BUILD_STATUS).map(cl_int::from);
+        let status = match status {
+            Ok(CL_BUILD_SUCCESS) => "success",
+            Ok(CL_BUILD_ERROR) => "error",
+            Ok(CL_BUILD_NONE) => "not built",
+            Ok(CL_BUILD_IN_PROGRESS) => "in progress",
+            Ok(_) => "unknown",
+            Err(_) => "not available",
+        };
+        writeln!(logs, "--- {} (build status: {}) ---", name, status).unwrap();
+
+        match get_program_build_info(program, id, CL_PROGRAM_BUILD_LOG).map(String::from) {
+            Ok(log) if log.trim().is_empty() => logs.push_str("(empty build log)\n"),
+            Ok(log) => writeln!(logs, "{}", log.trim_end_matches(['\n', '\0'])).unwrap(),
+            Err(err) => writeln!(
+                logs,
+                "(failed to query CL_PROGRAM_BUILD_LOG: {})",
+                ClError(err)
+            )
+            .unwrap(),
+        }
+    }
+
+    logs
+}
+
+/// The options `--define`, `--build-options`, `--fast-math` and `--mad-enable` add to every kernel
+/// build
+///
+/// They are part of the options the program cache key is computed from, so a changed define
+/// builds the program again
+pub fn user_build_options(args: &Args) -> String {
+    let mut options = Vec::new();
+    if args.fast_math {
+        options.push("-cl-fast-relaxed-math".to_owned());
+    }
+    if args.mad_enable {
+        options.push("-cl-mad-enable".to_owned());
+    }
+    if args.enable_printf {
+        options.push(format!("-D {}", printf::DEFINE));
+    }
+    options.extend(args.defines.iter().map(Define::to_string));
+    // Last, so they can override what the switches imply
+    if let Some(extra) = &args.build_options {
+        options.push(extra.trim().to_owned());
+    }
+
+    options.join(" ")
+}
+
+/// [`user_build_options`] after `-cl-kernel-arg-info`, for the kernels launched through
+/// [`crate::launch::CheckedKernel`] that aren't built by [`kernel_options`]
+pub fn arg_info_options(args: &Args) -> String {
+    join_options(CL_KERNEL_ARG_INFO.trim(), &user_build_options(args))
+}
+
+/// The full build options of the kernel `args` ask for over inputs in `layout`
+pub fn kernel_options(args: &Args, layout: Layout) -> anyhow::Result<String> {
+    let options = match args.kernel_files.as_slice() {
+        // The module is compiled already, only the user's options apply
+        _ if args.spirv.is_some() || args.load_binary.is_some() => String::new(),
+        _ if args.link_helpers => CL_KERNEL_ARG_INFO.trim().to_owned(),
+        [] => join_options(
+            CL_KERNEL_ARG_INFO.trim(),
+            &layout_kernel(layout, args.op, args.kernel_iters)?.options,
+        ),
+        paths => file_build_options(paths, &args.kernel_include_dirs)?,
+    };
+
+    Ok(join_options(&options, &user_build_options(args)))
+}
+
+/// The macros `options` define, `NAME=VALUE` or `NAME`, whether given as `-D NAME` or `-DNAME`
+pub fn defines(options: &str) -> Vec<String> {
+    let mut defines = Vec::new();
+    let mut tokens = options.split_whitespace();
+    while let Some(token) = tokens.next() {
+        match token.strip_prefix("-D") {
+            Some("") => defines.extend(tokens.next().map(str::to_owned)),
+            Some(define) => defines.push(define.to_owned()),
+            None => {}
+        }
+    }
+
+    defines
+}
+
+pub fn join_options(lhs: &str, rhs: &str) -> String {
+    match (lhs.is_empty(), rhs.is_empty()) {
+        (_, true) => lhs.to_owned(),
+        (true, false) => rhs.to_owned(),
+        (false, false) => format!("{} {}", lhs, rhs),
+    }
+}
+
+/// A kernel of this file together with the options it is built with
+pub struct KernelSpec {
+    pub source: &'static str,
+    pub name: &'static str,
+    pub options: String,
+}
+
+/// Looks up the kernel computing `op` over inputs in `layout`
+///
+/// With `kernel_iters` the arithmetic-intensity saxpy variant is picked instead, which only exists
+/// for the struct-of-arrays layout
+pub fn layout_kernel(
+    layout: Layout,
+    op: Op,
+    kernel_iters: Option<u32>,
+) -> anyhow::Result<KernelSpec> {
+    let (source, name, options) = match (layout, kernel_iters) {
+        (Layout::Both, _) => anyhow::bail!("A kernel can only be built for a single layout"),
+        (layout, None) => (
+            program_source(layout),
+            op_kernel(op).kernel_name(layout),
+            String::new(),
+        ),
+        (Layout::Soa, Some(iters)) if op == Op::Saxpy => (
+            ITERS_PROGRAM_SOURCE,
+            ITERS_KERNEL_NAME,
+            Define::new("ITERS", iters).to_string(),
+        ),
+        (Layout::Aos, Some(_)) if op == Op::Saxpy => {
+            anyhow::bail!("--kernel-iters is only supported with --layout soa")
+        }
+        (_, Some(_)) => anyhow::bail!("--kernel-iters is only supported with --op saxpy"),
+    };
+
+    Ok(KernelSpec {
+        source,
+        name,
+        options,
+    })
+}
+
+/// Where the kernel `args` ask for comes from as shown in messages: the `--kernel-file`s, the
+/// `--spirv` module, the `--load-binary` program or the linked program, `None` for the built-in
+/// kernels
+pub fn source_label(args: &Args) -> Option<String> {
+    if args.link_helpers {
+        return Some(link::SOURCE_LABEL.to_owned());
+    }
+    if let Some(path) = &args.load_binary {
+        return Some(path.display().to_string());
+    }
+    match (&args.spirv, args.kernel_files.as_slice()) {
+        (Some(path), _) => Some(path.display().to_string()),
+        (None, []) => None,
+        (None, paths) => Some(files_label(paths)),
+    }
+}
+
+/// Builds the kernel `args` ask for over inputs in `layout`: the one from `--kernel-file`,
+/// `--spirv` or `--load-binary` if given, the saxpy linked with the helpers with `--link-helpers`,
+/// otherwise the built-in one picked by [`layout_kernel`], taken from the kernels of its program
+pub fn build_layout_kernel(
+    context: &Context,
+    args: &Args,
+    layout: Layout,
+) -> anyhow::Result<Kernel> {
+    if args.link_helpers {
+        let kernel = link::build_linked_kernel(
+            context,
+            &kernel_options(args, layout)?,
+            BuildFlags::from_args(args),
+        )?;
+        check_signature(
+            &kernel,
+            link::LINKED_KERNEL_NAME,
+            &expected_args(layout, args.op, false),
+        )
+        .with_context(|| format!("Can't run '{}'", link::LINKED_KERNEL_NAME))?;
+        return Ok(kernel);
+    }
+    if let Some(path) = &args.load_binary {
+        return offline::build_binary_kernel(
+            context,
+            path,
+            &kernel_options(args, layout)?,
+            args.kernel_name.as_deref(),
+            layout,
+            args.op,
+            BuildFlags::from_args(args),
+        );
+    }
+    if let Some(path) = &args.spirv {
+        return build_spirv_kernel(
+            context,
+            path,
+            &kernel_options(args, layout)?,
+            args.kernel_name.as_deref(),
+            layout,
+            args.op,
+            BuildFlags::from_args(args),
+        );
+    }
+    if !args.kernel_files.is_empty() {
+        return build_file_kernel(
+            context,
+            &args.kernel_files,
+            &kernel_options(args, layout)?,
+            args.kernel_name.as_deref(),
+            layout,
+            args.op,
+            BuildFlags::from_args(args),
+        );
+    }
+
+    let spec = layout_kernel(layout, args.op, args.kernel_iters)?;
+    let options = kernel_options(args, layout)?;
+    let program = build_program(
+        context,
+        spec.source,
+        &options,
+        spec.name,
+        BuildFlags::from_args(args),
+    )?;
+    let kernel = take_kernel(
+        &mut program_kernels(&program)?,
+        spec.name,
+        "the built-in program",
+    )?;
+    check_signature(&kernel, spec.name, &expected_args(layout, args.op, false))
+        .with_context(|| format!("Can't run '{}'", spec.name))?;
+
+    tracing::debug!(
+        "Created program + kernel ({}) with options \"{}\"",
+        spec.name,
+        options
+    );
+
+    Ok(kernel)
+}
+
+/// Every kernel of the built `program` by name, created in one go with
+/// `clCreateKernelsInProgram`
+pub fn program_kernels(program: &Program) -> anyhow::Result<HashMap<String, Kernel>> {
+    create_program_kernels(program)
+        .context("Failed to create the kernels of the program")?
+        .into_iter()
+        .map(|kernel| {
+            let name = kernel
+                .function_name()
+                .context("Failed to query CL_KERNEL_FUNCTION_NAME")?;
+            Ok((name, kernel))
+        })
+        .collect()
+}
+
+/// Removes the kernel called `name` from `kernels`, failing with the names there are otherwise
+///
+/// `label` names the program the kernels came from in the message
+pub fn take_kernel(
+    kernels: &mut HashMap<String, Kernel>,
+    name: &str,
+    label: &str,
+) -> anyhow::Result<Kernel> {
+    match kernels.remove(name) {
+        Some(kernel) => Ok(kernel),
+        None => {
+            let mut names: Vec<&str> = kernels.keys().map(String::as_str).collect();
+            names.sort_unstable();
+            bail!(
+                "{} has no kernel called '{}', it defines: {}",
+                label,
+                name,
+                names.join(", ")
+            )
+        }
+    }
+}
+
+/// Whether `args` launch [`GUARDED_PROGRAM_SOURCE`] instead of the kernel of `layout`: with
+/// `tune --guarded`, and for a single run of the built-in saxpy over `--layout soa`, whose global
+/// size is rounded up to the local size picked by [`crate::saxpy::choose_local_size`]
+pub fn uses_guarded_kernel(args: &Args, layout: Layout) -> bool {
+    match &args.command {
+        Command::Tune(tune) => tune.guarded,
+        Command::Run => {
+            layout == Layout::Soa
+                && args.layout == Layout::Soa
+                && args.op == Op::Saxpy
+                && args.kernel_iters.is_none()
+                && args.kernel_files.is_empty()
+                && args.spirv.is_none()
+                && args.load_binary.is_none()
+                && args.kernel_name.is_none()
+                && !args.link_helpers
+                && !args.compare_transfers
+                && args.repeat.is_none()
+                && args.readback_chunk.is_none()
+                && !args.stdin
+                && !args.device_enqueue
+                && args.threads.is_none()
+        }
+        _ => false,
+    }
+}
+
+/// Builds [`GUARDED_PROGRAM_SOURCE`], which takes the input length after the scalar so it can run
+/// over a global size rounded up to a multiple of the local size, see [`crate::saxpy::round_up`]
+pub fn build_guarded_kernel(context: &Context, args: &Args) -> anyhow::Result<Kernel> {
+    let kernel = build_kernel(
+        context,
+        GUARDED_PROGRAM_SOURCE,
+        GUARDED_KERNEL_NAME,
+        &arg_info_options(args),
+        BuildFlags::from_args(args),
+    )?;
+    check_signature(
+        &kernel,
+        GUARDED_KERNEL_NAME,
+        &expected_args(Layout::Soa, Op::Saxpy, true),
+    )
+    .with_context(|| format!("Can't run '{}'", GUARDED_KERNEL_NAME))?;
+
+    Ok(kernel)
+}
+
+/// Reads and builds the OpenCL C files at `paths` as one program and creates the kernel called
+/// `name` from it, or the first kernel of the program without a name
+///
+/// `options` have to include the [`file_build_options`], see [`kernel_options`]
+///
+/// The kernel has to take the arguments the built-in kernel of `layout` and `op` takes, as the
+/// buffers and the scalar are bound the same way
+pub fn build_file_kernel(
+    context: &Context,
+    paths: &[PathBuf],
+    options: &str,
+    name: Option<&str>,
+    layout: Layout,
+    op: Op,
+    flags: BuildFlags,
+) -> anyhow::Result<Kernel> {
+    let files = files_label(paths);
+    let source = file_source(paths)?;
+    let program = build_program(context, &source, options, &files, flags)?;
+
+    program_kernel(&program, name, &files, options, layout, op)
+}
+
+/// Creates a program from the SPIR-V module at `path`, builds it with `options` and creates the
+/// kernel called `name` from it, or the first kernel of the module without a name
+///
+/// Every device of the context has to report SPIR-V in `CL_DEVICE_IL_VERSION`. The kernel is
+/// looked up, checked and bound exactly like one from [`build_file_kernel`], but modules carry no
+/// argument info, so only the number of arguments is checked
+pub fn build_spirv_kernel(
+    context: &Context,
+    path: &Path,
+    options: &str,
+    name: Option<&str>,
+    layout: Layout,
+    op: Op,
+    flags: BuildFlags,
+) -> anyhow::Result<Kernel> {
+    let label = path.display().to_string();
+    let il = fs::read(path).with_context(|| format!("Failed to read SPIR-V module {}", label))?;
+    if !il.starts_with(&SPIRV_MAGIC) {
+        bail!(
+            "{} is not a SPIR-V module, it doesn't start with the SPIR-V magic number",
+            label
+        );
+    }
+
+    for &id in context.devices() {
+        let device = Device::new(id);
+        let il_version = device.il_version().unwrap_or_default();
+        if !il_version.contains("SPIR-V") {
+            bail!(
+                "{} can't load SPIR-V modules (CL_DEVICE_IL_VERSION is {:?}), it needs OpenCL 2.1 or cl_khr_il_program",
+                device.name().context("Failed to query device name")?,
+                il_version.trim_end_matches('\0')
+            );
+        }
+    }
+
+    let start = Instant::now();
+    let program = create_il_program(context, &il)?;
+    let program = build_created(context, program, options, &label, flags)?;
+    build_time::record(&label, Origin::Compiled, start);
+
+    program_kernel(&program, name, &label, options, layout, op)
+}
+
+/// The SPIR-V magic number in little-endian byte order, as `clang` and `llvm-spirv` write it
+const SPIRV_MAGIC: [u8; 4] = [0x03, 0x02, 0x23, 0x07];
+
+#[cfg(feature = "cl_3_0")]
+fn create_il_program(context: &Context, il: &[u8]) -> anyhow::Result<Program> {
+    Program::create_from_il(context, il).context("Program::create_from_il failed")
+}
+
+/// `clCreateProgramWithIL` is OpenCL 2.1 API, which only the `cl_3_0` feature enables
+#[cfg(not(feature = "cl_3_0"))]
+fn create_il_program(_context: &Context, _il: &[u8]) -> anyhow::Result<Program> {
+    bail!("--spirv needs the OpenCL 2.1 API, build with the cl_3_0 feature")
+}
+
+/// Creates the kernel called `name` from the built `program`, or its first kernel without a name,
+/// and checks that it takes the arguments of `layout` and `op`
+///
+/// `label` names where the program came from in messages
+pub fn program_kernel(
+    program: &Program,
+    name: Option<&str>,
+    label: &str,
+    options: &str,
+    layout: Layout,
+    op: Op,
+) -> anyhow::Result<Kernel> {
+    log_kernel_names(program, label);
+    let names = kernel_names(program);
+    let name = match name {
+        Some(name) if names.iter().any(|defined| defined == name) => name,
+        Some(name) => match name.parse::<BuiltinKernel>() {
+            Ok(builtin) => bail!(
+                "{} has no kernel called '{}', it defines: {} ('{}' is a built-in kernel, run it without --kernel-file or --spirv through {})",
+                label,
+                name,
+                names.join(", "),
+                name,
+                builtin.selected_by()
+            ),
+            Err(_) => bail!(
+                "{} has no kernel called '{}', it defines: {}",
+                label,
+                name,
+                names.join(", ")
+            ),
+        },
+        None => match names.first() {
+            Some(first) => first,
+            None => bail!("{} doesn't define any kernel", label),
+        },
+    };
+
+    let kernel = Kernel::create(program, name).context("Failed to create kernel")?;
+    check_signature(&kernel, name, &expected_args(layout, op, false))
+        .with_context(|| format!("Can't run '{}' from {}", name, label))?;
+
+    tracing::debug!(
+        "Created program + kernel ({}) from {} with options \"{}\"",
+        name,
+        label,
+        options
+    );
+
+    Ok(kernel)
+}
+
+/// The names of the kernels the built `program` defines, in the order the driver lists them
+pub fn kernel_names(program: &Program) -> Vec<String> {
+    program
+        .kernel_names()
+        .split(';')
+        .filter(|name| !name.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Logs the kernels the built `program` defines, so loading a kernel file shows what was found in it.
+/// `label` names where the program came from
+pub fn log_kernel_names(program: &Program, label: &str) {
+    let names = kernel_names(program);
+    if names.is_empty() {
+        tracing::info!("{} defines no kernels", label);
+    } else {
+        tracing::info!("{} defines kernels: {}", label, names.join(", "));
+    }
+}
+
+/// The kernel files one after the other, each preceded by a `#line` directive so the compiler
+/// reports errors against the file and line they are in
+pub fn file_source(paths: &[PathBuf]) -> anyhow::Result<String> {
+    let mut source = String::new();
+    for path in paths {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read kernel file {}", path.display()))?;
+        // Backslashes would be escapes in the string, forward slashes work on Windows as well
+        let name = path.display().to_string().replace('\\', "/");
+        writeln!(source, "#line 1 \"{}\"", name).unwrap();
+        source.push_str(&text);
+        if !text.ends_with('\n') {
+            source.push('\n');
+        }
+    }
+
+    Ok(source)
+}
+
+/// Build options for the kernel files: the argument info lets the signature be checked before
+/// anything is bound, and the directory of every file is searched for includes before the
+/// `--kernel-include-dir`s, as the program is built from a string that has no directory of its own
+pub fn file_build_options(paths: &[PathBuf], include_dirs: &[PathBuf]) -> anyhow::Result<String> {
+    let mut dirs: Vec<PathBuf> = Vec::new();
+    let file_dirs = paths
+        .iter()
+        .map(|path| path.parent().unwrap_or(Path::new("")));
+    for dir in file_dirs.chain(include_dirs.iter().map(PathBuf::as_path)) {
+        // Absolute, the compiler doesn't necessarily resolve against our working directory
+        let dir = fs::canonicalize(Path::new(".").join(dir))
+            .with_context(|| format!("Include directory {} not found", dir.display()))?;
+        if !dirs.contains(&dir) {
+            dirs.push(dir);
+        }
+    }
+
+    let mut options = CL_KERNEL_ARG_INFO.trim().to_owned();
+    for dir in dirs {
+        let dir = dir.to_string_lossy();
+        if dir.contains(char::is_whitespace) {
+            write!(options, " -I \"{}\"", dir).unwrap();
+        } else {
+            write!(options, " -I {}", dir).unwrap();
+        }
+    }
+
+    Ok(options)
+}
+
+/// The kernel files as shown in messages, comma separated
+pub fn files_label(paths: &[PathBuf]) -> String {
+    paths
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// An argument the kernel is bound with: its name, address space and type as the program reports
+/// them with `-cl-kernel-arg-info`
+struct ExpectedArg {
+    name: &'static str,
+    address: cl_kernel_arg_address_qualifier,
+    type_name: &'static str,
+}
+
+impl fmt::Display for ExpectedArg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.address == CL_KERNEL_ARG_ADDRESS_GLOBAL {
+            write!(f, "global {} {}", self.type_name, self.name)
+        } else {
+            write!(f, "{} {}", self.type_name, self.name)
+        }
+    }
+}
+
+/// The arguments [`crate::saxpy::SaxpyBuffers::run`] binds for `layout` and `op`, in order, with
+/// the length `n` last for `bounds_guard`
+fn expected_args(layout: Layout, op: Op, bounds_guard: bool) -> Vec<ExpectedArg> {
+    let buffer = |name, type_name| ExpectedArg {
+        name,
+        address: CL_KERNEL_ARG_ADDRESS_GLOBAL,
+        type_name,
+    };
+    let mut args = match layout {
+        Layout::Aos => vec![buffer("z", "float*"), buffer("xy", "float2*")],
+        _ => vec![
+            buffer("z", "float*"),
+            buffer("x", "float*"),
+            buffer("y", "float*"),
+        ],
+    };
+    if op.scalar(0.0).is_some() {
+        args.push(ExpectedArg {
+            name: "a",
+            address: CL_KERNEL_ARG_ADDRESS_PRIVATE,
+            type_name: "float",
+        });
+    }
+    if bounds_guard {
+        args.push(ExpectedArg {
+            name: "n",
+            address: CL_KERNEL_ARG_ADDRESS_PRIVATE,
+            type_name: "uint",
+        });
+    }
+
+    args
+}
+
+/// Checks that `kernel` takes the `expected` arguments, see [`expected_args`]
+///
+/// Without argument info (the program wasn't built with `-cl-kernel-arg-info` or the driver doesn't
+/// keep it) only the number of arguments can be checked, with it the address space and type of
+/// every argument are compared as well, so a mismatch is reported here rather than surfacing as
+/// `CL_INVALID_ARG_*` at enqueue
+fn check_signature(kernel: &Kernel, name: &str, expected: &[ExpectedArg]) -> anyhow::Result<()> {
+    let signature = || {
+        let args: Vec<String> = expected.iter().map(ExpectedArg::to_string).collect();
+        format!("kernel void {}({})", name, args.join(", "))
+    };
+
+    let num_args = kernel
+        .num_args()
+        .context("Failed to query the kernel arguments")?;
+    if num_args as usize != expected.len() {
+        bail!("it takes {} arguments, expected {}", num_args, signature());
+    }
+
+    for (i, arg) in (0..num_args).zip(expected) {
+        let (Ok(address), Ok(type_name)) = (
+            kernel.get_arg_address_qualifier(i),
+            kernel.get_arg_type_name(i),
+        ) else {
+            tracing::debug!(
+                "No argument info for {}, only checked the argument count",
+                name
+            );
+            return Ok(());
+        };
+
+        // Drivers differ in spacing (`float *`) and may leave a trailing NUL
+        let type_name: String = type_name
+            .chars()
+            .filter(|c| !c.is_whitespace() && *c != '\0')
+            .collect();
+        if address != arg.address || type_name != arg.type_name {
+            let actual = if address == CL_KERNEL_ARG_ADDRESS_GLOBAL {
+                format!("global {}", type_name)
+            } else if address == CL_KERNEL_ARG_ADDRESS_PRIVATE {
+                type_name
+            } else {
+                format!("{} in a local or constant address space", type_name)
+            };
+            bail!(
+                "argument {} is {}, where {} is bound, expected {}",
+                i,
+                actual,
+                arg,
+                signature()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Floating point operations per element: one multiply and one add per saxpy iteration, a single
+/// operation for the other ops
+pub fn flops_per_element(op: Op, kernel_iters: Option<u32>) -> u64 {
+    match op {
+        Op::Saxpy => 2 * u64::from(kernel_iters.unwrap_or(1)),
+        _ => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_device;
+
+    #[test]
+    fn build_error_carries_the_log() {
+        let Some(test) = test_device::get() else {
+            return;
+        };
+        let source = "kernel void broken (global float* z)\n{\n    z[0] = undeclared_value;\n}\n";
+
+        let err = build_program(
+            &test.context,
+            source,
+            "",
+            "the broken program",
+            BuildFlags::default(),
+        )
+        .unwrap_err();
+        let message = format!("{:#}", err);
+        assert!(
+            message.starts_with("Failed to build the broken program with options \"\""),
+            "{}",
+            message
+        );
+        assert!(message.contains("(build status: error) ---"), "{}", message);
+        // Every compiler names the identifier it doesn't know
+        assert!(message.contains("undeclared_value"), "{}", message);
+    }
+
+    fn spirv_fixture() -> std::path::PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/spirv/saxpy.spv")
+    }
+
+    #[test]
+    fn spirv_fixture_is_a_module() {
+        let il = fs::read(spirv_fixture()).unwrap();
+
+        assert!(il.starts_with(&SPIRV_MAGIC));
+        assert_eq!(il.len() % 4, 0);
+        // SPIR-V 1.0, which every device that loads SPIR-V takes
+        assert_eq!(il[4..8], 0x0001_0000u32.to_le_bytes());
+    }
+
+    #[cfg(feature = "cl_3_0")]
+    #[test]
+    fn spirv_fixture_runs() {
+        use crate::{cli::Transfer, saxpy};
+
+        let Some(test) = test_device::get() else {
+            return;
+        };
+        let il_version = test.device.il_version().unwrap_or_default();
+        if !il_version.contains("SPIR-V") {
+            eprintln!(
+                "{} doesn't load SPIR-V, skipped",
+                test.device.name().unwrap()
+            );
+            return;
+        }
+
+        let kernel = build_spirv_kernel(
+            &test.context,
+            &spirv_fixture(),
+            "",
+            Some(KERNEL_NAME),
+            Layout::Soa,
+            Op::Saxpy,
+            BuildFlags::default(),
+        )
+        .unwrap();
+        let (x, y) = test_device::inputs(1024);
+        let mut buffers = saxpy::SaxpyBuffers::create(&test.context, Layout::Soa, x.len()).unwrap();
+        let run = buffers
+            .run(&test.queue, &kernel, Transfer::default(), &x, &y, Some(2.0))
+            .unwrap();
+
+        assert_eq!(
+            saxpy::verify(Op::Saxpy, None, 1e-6, 1, Some(2.0), &x, &y, &run.result),
+            Ok(x.len())
+        );
+    }
+}
@@ -0,0 +1,934 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+//! Kernel launches whose bindings are checked against the kernel before they are enqueued
+//!
+//! [`CheckedKernel`] wraps `ExecuteKernel` and remembers what every argument was bound to, a buffer
+//! or a scalar of some element type. Right before `clEnqueueNDRangeKernel` the bindings are compared
+//! with the argument info of the kernel (`clGetKernelArgInfo`, kept when the program is built with
+//! `-cl-kernel-arg-info`), so arguments bound in the wrong order fail with the argument they concern
+//! instead of `CL_INVALID_ARG_*` or a garbage result. Without argument info only the count is
+//! checked and a warning says so.
+//!
+//! Arguments bound by name, with [`CheckedKernel::arg`] or the [`launch!`] macro, are also checked
+//! against the parameter names: binding `x` where the kernel declares `z` fails naming both
+//! positions, which catches swapped buffers of the same type, and a launch missing an argument
+//! names the one left out. Names the kernel doesn't declare at all are taken to be a kernel with
+//! parameter names of its own, such as one from `--kernel-file`, and only the types are checked.
+//!
+//! The NDRange, of one to three dimensions, is checked the same way: the number of dimensions
+//! against `CL_DEVICE_MAX_WORK_ITEM_DIMENSIONS`, and those of the local size and global offset, when
+//! given, against the global size's. The local size of every dimension is checked against
+//! `CL_DEVICE_MAX_WORK_ITEM_SIZES`, their product against the kernel's `CL_KERNEL_WORK_GROUP_SIZE`
+//! and every global size against being a multiple of its local size. A violation names the limit,
+//! the requested value and a local size that would work, instead of `CL_INVALID_WORK_GROUP_SIZE`.
+//!
+//! Every buffer's length (`CL_MEM_SIZE`, in elements of the pointee type when argument info gives a
+//! vector type like `float2`) is compared with the number of work-items too, shifted by the global
+//! offset: kernels index their buffers with the global id, so a global size past the end of a
+//! buffer fails before the launch instead of writing out of bounds. Kernels taking an integer
+//! argument are taken to be guarded by it (`if (i >= n) return;`) and are launched anyway, with a
+//! warning when that count exceeds a buffer. Single-element buffers hold reductions and aren't
+//! indexed by the count.
+
+use std::{fmt, mem, sync::Once};
+
+use anyhow::bail;
+use opencl3::{
+    command_queue::CommandQueue,
+    device::Device,
+    event::Event,
+    kernel::{
+        ExecuteKernel, Kernel, CL_KERNEL_ARG_ADDRESS_CONSTANT, CL_KERNEL_ARG_ADDRESS_GLOBAL,
+        CL_KERNEL_ARG_ADDRESS_LOCAL, CL_KERNEL_ARG_ADDRESS_PRIVATE,
+    },
+    memory::{Buffer, ClMem, Image},
+    types::{cl_double, cl_event, cl_float, cl_int, cl_uint},
+};
+
+use crate::{error::ClContext, retry, trace};
+
+/// A type kernel arguments are bound with, named as in OpenCL C and on the host
+pub trait ArgType {
+    const CL_NAME: &'static str;
+    const HOST_NAME: &'static str;
+
+    /// The value as an element count, for the integer types a bounds guard compares with
+    fn count(&self) -> Option<usize> {
+        None
+    }
+
+    /// The value as little-endian bytes, for [`trace`]
+    fn le_bytes(&self) -> Vec<u8>;
+}
+
+impl ArgType for cl_float {
+    const CL_NAME: &'static str = "float";
+    const HOST_NAME: &'static str = "f32";
+
+    fn le_bytes(&self) -> Vec<u8> {
+        self.to_le_bytes().to_vec()
+    }
+}
+
+impl ArgType for cl_double {
+    const CL_NAME: &'static str = "double";
+    const HOST_NAME: &'static str = "f64";
+
+    fn le_bytes(&self) -> Vec<u8> {
+        self.to_le_bytes().to_vec()
+    }
+}
+
+impl ArgType for cl_int {
+    const CL_NAME: &'static str = "int";
+    const HOST_NAME: &'static str = "i32";
+
+    fn le_bytes(&self) -> Vec<u8> {
+        self.to_le_bytes().to_vec()
+    }
+
+    fn count(&self) -> Option<usize> {
+        usize::try_from(*self).ok()
+    }
+}
+
+impl ArgType for cl_uint {
+    const CL_NAME: &'static str = "uint";
+    const HOST_NAME: &'static str = "u32";
+
+    fn le_bytes(&self) -> Vec<u8> {
+        self.to_le_bytes().to_vec()
+    }
+
+    fn count(&self) -> Option<usize> {
+        usize::try_from(*self).ok()
+    }
+}
+
+/// What an argument was bound to
+#[derive(Clone, Copy)]
+enum Binding {
+    Buffer {
+        cl_name: &'static str,
+        host_name: &'static str,
+        /// Elements of the host type, `None` when `CL_MEM_SIZE` couldn't be queried
+        len: Option<usize>,
+    },
+    Scalar {
+        cl_name: &'static str,
+        host_name: &'static str,
+        /// [`ArgType::count`] of the value
+        count: Option<usize>,
+    },
+    Image {
+        cl_name: &'static str,
+    },
+    /// A pipe of `cl_name` packets
+    #[cfg(all(feature = "cl_2_0", not(target_os = "macos")))]
+    Pipe {
+        cl_name: &'static str,
+    },
+}
+
+impl fmt::Display for Binding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Binding::Buffer { host_name, .. } => write!(f, "a buffer of {}", host_name),
+            Binding::Scalar { host_name, .. } => write!(f, "a scalar {}", host_name),
+            Binding::Image { cl_name } => write!(f, "an {}", cl_name),
+            #[cfg(all(feature = "cl_2_0", not(target_os = "macos")))]
+            Binding::Pipe { cl_name } => write!(f, "a pipe of {}", cl_name),
+        }
+    }
+}
+
+/// A value [`CheckedKernel::arg`] binds: a buffer, a scalar, or an image or pipe with the type the
+/// kernel declares it as
+pub trait KernelArg {
+    /// Binds `self` to the next argument of `kernel`
+    ///
+    /// # Safety
+    ///
+    /// As for `ExecuteKernel::set_arg`, buffers and images have to outlive the launch
+    unsafe fn bind(self, kernel: &mut CheckedKernel<'_>);
+}
+
+impl<T: ArgType> KernelArg for &Buffer<T> {
+    unsafe fn bind(self, kernel: &mut CheckedKernel<'_>) {
+        kernel.set_buffer(self);
+    }
+}
+
+impl<T: ArgType> KernelArg for &T {
+    unsafe fn bind(self, kernel: &mut CheckedKernel<'_>) {
+        kernel.set_scalar(self);
+    }
+}
+
+impl KernelArg for (&Image, &'static str) {
+    unsafe fn bind(self, kernel: &mut CheckedKernel<'_>) {
+        kernel.set_image(self.0, self.1);
+    }
+}
+
+#[cfg(all(feature = "cl_2_0", not(target_os = "macos")))]
+impl KernelArg for (&opencl3::memory::Pipe, &'static str) {
+    unsafe fn bind(self, kernel: &mut CheckedKernel<'_>) {
+        kernel.set_pipe(self.0, self.1);
+    }
+}
+
+/// A [`CheckedKernel`] over the 1D range `global`, optionally in work-groups of `local`, with every
+/// argument bound by the name the kernel declares it with, such as
+/// `launch!(&kernel, global = n; z: &z_buffer, x: &x_buffer, y: &y_buffer, a: &a)`
+///
+/// Has to be used in an `unsafe` block, see [`CheckedKernel::arg`]
+macro_rules! launch {
+    ($kernel:expr, global = $global:expr $(, local = $local:expr)?; $($name:ident: $value:expr),* $(,)?) => {{
+        let mut launch = $crate::launch::CheckedKernel::new($kernel);
+        $(launch.arg(stringify!($name), $value);)*
+        launch.set_global_work_size($global);
+        $(launch.set_local_work_size($local);)?
+        launch
+    }};
+}
+pub(crate) use launch;
+
+/// The global size, local size and global offset of a launch over up to three dimensions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NdRange {
+    pub global: Vec<usize>,
+    /// Empty when the driver picks the local size
+    pub local: Vec<usize>,
+    /// Empty for global ids starting at 0
+    pub offset: Vec<usize>,
+}
+
+impl NdRange {
+    /// The number of work-items
+    pub fn items(&self) -> usize {
+        self.global.iter().product()
+    }
+}
+
+/// The global size, such as `1024x1024`
+impl fmt::Display for NdRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sizes: Vec<String> = self.global.iter().map(usize::to_string).collect();
+        f.write_str(&sizes.join("x"))
+    }
+}
+
+/// Warns about missing argument info once per process rather than once per launch
+static NO_ARG_INFO: Once = Once::new();
+
+/// `ExecuteKernel` checking its bindings against the kernel's argument info at enqueue
+pub struct CheckedKernel<'a> {
+    kernel: &'a Kernel,
+    execute: ExecuteKernel<'a>,
+    bindings: Vec<Binding>,
+    /// The parameter name every binding was made for, `None` when bound by position only
+    names: Vec<Option<&'static str>>,
+    /// The global size of every dimension
+    global: Vec<usize>,
+    /// The local size of every dimension, empty when the driver picks it
+    local: Vec<usize>,
+    /// The global offset of every dimension, empty for none
+    offset: Vec<usize>,
+    /// The arguments as [`trace`] records them, only kept while recording
+    trace_args: Vec<trace::Arg>,
+}
+
+impl<'a> CheckedKernel<'a> {
+    pub fn new(kernel: &'a Kernel) -> Self {
+        Self {
+            kernel,
+            execute: ExecuteKernel::new(kernel),
+            bindings: Vec::new(),
+            names: Vec::new(),
+            global: Vec::new(),
+            local: Vec::new(),
+            offset: Vec::new(),
+            trace_args: Vec::new(),
+        }
+    }
+
+    /// Binds `buffer` to the next argument
+    ///
+    /// # Safety
+    ///
+    /// As for `ExecuteKernel::set_arg`, the buffer has to outlive the launch
+    pub unsafe fn set_buffer<T: ArgType>(&mut self, buffer: &Buffer<T>) -> &mut Self {
+        self.execute.set_arg(buffer);
+        self.bindings.push(Binding::Buffer {
+            cl_name: T::CL_NAME,
+            host_name: T::HOST_NAME,
+            len: buffer.size().ok().map(|bytes| bytes / mem::size_of::<T>()),
+        });
+        self.names.push(None);
+        if trace::active() {
+            self.trace_args.push(trace::Arg::Buffer(buffer.get()));
+        }
+        self
+    }
+
+    /// Binds `value` to the next argument
+    ///
+    /// # Safety
+    ///
+    /// As for `ExecuteKernel::set_arg`
+    pub unsafe fn set_scalar<T: ArgType>(&mut self, value: &T) -> &mut Self {
+        self.execute.set_arg(value);
+        self.bindings.push(Binding::Scalar {
+            cl_name: T::CL_NAME,
+            host_name: T::HOST_NAME,
+            count: value.count(),
+        });
+        self.names.push(None);
+        if trace::active() {
+            self.trace_args.push(trace::Arg::Scalar {
+                cl_name: T::CL_NAME,
+                bytes: value.le_bytes(),
+            });
+        }
+        self
+    }
+
+    /// Binds `image` to the next argument, which has to be declared as `cl_name` (such as
+    /// `image1d_buffer_t`), the image itself doesn't tell its type
+    ///
+    /// # Safety
+    ///
+    /// As for `ExecuteKernel::set_arg`, the image has to outlive the launch
+    pub unsafe fn set_image(&mut self, image: &Image, cl_name: &'static str) -> &mut Self {
+        self.execute.set_arg(image);
+        self.bindings.push(Binding::Image { cl_name });
+        self.names.push(None);
+        if trace::active() {
+            self.trace_args.push(trace::Arg::Unsupported("an image"));
+        }
+        self
+    }
+
+    /// Binds `pipe` to the next argument, which has to be a pipe of `cl_name` packets (such as
+    /// `uint2`), the pipe only knows their size
+    ///
+    /// # Safety
+    ///
+    /// As for `ExecuteKernel::set_arg`, the pipe has to outlive the launch
+    #[cfg(all(feature = "cl_2_0", not(target_os = "macos")))]
+    pub unsafe fn set_pipe(
+        &mut self,
+        pipe: &opencl3::memory::Pipe,
+        cl_name: &'static str,
+    ) -> &mut Self {
+        self.execute.set_arg(&pipe.get());
+        self.bindings.push(Binding::Pipe { cl_name });
+        self.names.push(None);
+        if trace::active() {
+            self.trace_args.push(trace::Arg::Unsupported("a pipe"));
+        }
+        self
+    }
+
+    /// Binds `value` to the next argument, which the kernel has to declare as `name` if it declares
+    /// a parameter of that name at all, see the module docs
+    ///
+    /// # Safety
+    ///
+    /// As for [`KernelArg::bind`]
+    pub unsafe fn arg(&mut self, name: &'static str, value: impl KernelArg) -> &mut Self {
+        value.bind(self);
+        if let Some(last) = self.names.last_mut() {
+            *last = Some(name);
+        }
+        self
+    }
+
+    pub fn set_global_work_size(&mut self, size: usize) -> &mut Self {
+        self.execute.set_global_work_size(size);
+        self.global.push(size);
+        self
+    }
+
+    pub fn set_local_work_size(&mut self, size: usize) -> &mut Self {
+        self.execute.set_local_work_size(size);
+        self.local.push(size);
+        self
+    }
+
+    pub fn set_global_work_offset(&mut self, offset: usize) -> &mut Self {
+        self.execute.set_global_work_offset(offset);
+        self.offset.push(offset);
+        self
+    }
+
+    /// Launches over `range` instead of sizes set one dimension at a time
+    pub fn set_range(&mut self, range: &NdRange) -> &mut Self {
+        for &size in &range.global {
+            self.set_global_work_size(size);
+        }
+        for &size in &range.local {
+            self.set_local_work_size(size);
+        }
+        for &offset in &range.offset {
+            self.set_global_work_offset(offset);
+        }
+        self
+    }
+
+    pub fn set_wait_event(&mut self, event: &Event) -> &mut Self {
+        self.execute.set_wait_event(event);
+        self
+    }
+
+    pub fn set_event_wait_list(&mut self, events: &[cl_event]) -> &mut Self {
+        self.execute.set_event_wait_list(events);
+        self
+    }
+
+    /// Checks the bindings with [`Self::check`], the NDRange with [`Self::check_range`] and the
+    /// buffer lengths with [`Self::check_lengths`], and enqueues the kernel on `queue`. The launch is
+    /// recorded when a [`trace`] is
+    ///
+    /// # Safety
+    ///
+    /// As for `ExecuteKernel::enqueue_nd_range`
+    pub unsafe fn enqueue_nd_range(&mut self, queue: &CommandQueue) -> anyhow::Result<Event> {
+        self.check()?;
+        self.check_range(queue)?;
+        self.check_lengths()?;
+        let event = retry::call("clEnqueueNDRangeKernel", || {
+            self.execute.enqueue_nd_range(queue)
+        })
+        .context("clEnqueueNDRangeKernel failed")?;
+        trace::launch(
+            self.kernel,
+            &self.trace_args,
+            &self.global,
+            &self.local,
+            &self.offset,
+        );
+
+        Ok(event)
+    }
+
+    /// Compares every binding with the address space and type the kernel declares for it
+    ///
+    /// Buffers have to be global or constant pointers to their element type (vector types like
+    /// `float2*` included), scalars private values of their type and images of the declared type
+    pub fn check(&self) -> anyhow::Result<()> {
+        let name = self.kernel.function_name().unwrap_or_default();
+        let name = name.trim_end_matches('\0');
+        let num_args = self
+            .kernel
+            .num_args()
+            .context("Failed to query the kernel arguments")?;
+        // Only available with argument info
+        let declared: Option<Vec<String>> = (0..num_args)
+            .map(|i| {
+                let arg_name = self.kernel.get_arg_name(i).ok()?;
+                Some(arg_name.trim_end_matches('\0').to_owned())
+            })
+            .collect();
+        if num_args as usize != self.bindings.len() {
+            let missing = match &declared {
+                Some(declared) if num_args as usize > self.bindings.len() => {
+                    format!(
+                        ", missing {}",
+                        missing_args(declared, &self.names).join(", ")
+                    )
+                }
+                _ => String::new(),
+            };
+            bail!(
+                "{} takes {} arguments but {} were bound{}",
+                name,
+                num_args,
+                self.bindings.len(),
+                missing
+            );
+        }
+
+        if let Some(declared) = &declared {
+            for (i, (bound, arg_name)) in self.names.iter().zip(declared).enumerate() {
+                let Some(bound) = bound.filter(|bound| bound != arg_name) else {
+                    continue;
+                };
+                if let Some(j) = declared.iter().position(|arg_name| arg_name == bound) {
+                    bail!(
+                        "{}: '{}' was bound to arg {} '{}', the kernel declares it as arg {}",
+                        name,
+                        bound,
+                        i,
+                        arg_name,
+                        j
+                    );
+                }
+            }
+        }
+
+        for (i, binding) in (0..num_args).zip(&self.bindings) {
+            let (Ok(address), Ok(type_name)) = (
+                self.kernel.get_arg_address_qualifier(i),
+                self.kernel.get_arg_type_name(i),
+            ) else {
+                NO_ARG_INFO.call_once(|| {
+                    tracing::warn!(
+                        "No argument info for {} (built without -cl-kernel-arg-info or not kept by the driver), only the argument count is checked before launches",
+                        name
+                    )
+                });
+                return Ok(());
+            };
+            let arg_name = self.kernel.get_arg_name(i).unwrap_or_default();
+            let arg_name = arg_name.trim_end_matches('\0');
+
+            let type_name = normalize_type(&type_name);
+            let compatible = match binding {
+                Binding::Buffer { cl_name, .. } => {
+                    (address == CL_KERNEL_ARG_ADDRESS_GLOBAL
+                        || address == CL_KERNEL_ARG_ADDRESS_CONSTANT)
+                        && type_name
+                            .strip_suffix('*')
+                            .is_some_and(|pointee| scalar_type(pointee) == *cl_name)
+                }
+                Binding::Scalar { cl_name, .. } => {
+                    address == CL_KERNEL_ARG_ADDRESS_PRIVATE && type_name == *cl_name
+                }
+                // Images are global memory objects, the access qualifier isn't part of the type
+                Binding::Image { cl_name } => type_name == *cl_name,
+                // The type of a pipe is that of its packets, the qualifier tells it's a pipe
+                #[cfg(all(feature = "cl_2_0", not(target_os = "macos")))]
+                Binding::Pipe { cl_name } => {
+                    type_name == *cl_name
+                        && self
+                            .kernel
+                            .get_arg_type_qualifier(i)
+                            .is_ok_and(|qualifier| {
+                                qualifier & opencl3::kernel::CL_KERNEL_ARG_TYPE_PIPE as u64 != 0
+                            })
+                }
+            };
+            if !compatible {
+                let expected = if address == CL_KERNEL_ARG_ADDRESS_GLOBAL {
+                    format!("global {}", type_name)
+                } else if address == CL_KERNEL_ARG_ADDRESS_CONSTANT {
+                    format!("constant {}", type_name)
+                } else if address == CL_KERNEL_ARG_ADDRESS_LOCAL {
+                    format!("local {}", type_name)
+                } else {
+                    type_name
+                };
+                bail!(
+                    "{}: arg {} '{}' expects {} but {} was bound",
+                    name,
+                    i,
+                    arg_name,
+                    expected,
+                    binding
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Compares the NDRange with the limits of the device of `queue` and the kernel, see the
+    /// module docs. 1D launches leaving the local size to the driver aren't checked
+    pub fn check_range(&self, queue: &CommandQueue) -> anyhow::Result<()> {
+        if self.local.is_empty() && self.global.len() <= 1 && self.offset.len() <= 1 {
+            return Ok(());
+        }
+
+        let device = Device::new(
+            queue
+                .device()
+                .context("Failed to query the queue's device")?,
+        );
+        let limits = RangeLimits {
+            max_dimensions: device
+                .max_work_item_dimensions()
+                .context("Failed to query CL_DEVICE_MAX_WORK_ITEM_DIMENSIONS")?
+                as usize,
+            max_sizes: device
+                .max_work_item_sizes()
+                .context("Failed to query CL_DEVICE_MAX_WORK_ITEM_SIZES")?,
+            max_items: self
+                .kernel
+                .get_work_group_size(device.id())
+                .context("Failed to query CL_KERNEL_WORK_GROUP_SIZE")?,
+        };
+        let name = self.kernel.function_name().unwrap_or_default();
+
+        limits.check(
+            name.trim_end_matches('\0'),
+            &self.global,
+            &self.local,
+            &self.offset,
+        )
+    }
+
+    /// Compares the number of work-items with the length of every bound buffer, see the module docs
+    pub fn check_lengths(&self) -> anyhow::Result<()> {
+        let items: usize = (0..self.global.len())
+            .map(|d| self.global[d] + self.offset.get(d).copied().unwrap_or(0))
+            .product();
+        let guard = self.bindings.iter().find_map(|binding| match binding {
+            Binding::Scalar { count, .. } => *count,
+            _ => None,
+        });
+        let name = self.kernel.function_name().unwrap_or_default();
+        let name = name.trim_end_matches('\0');
+
+        for (i, binding) in (0..).zip(&self.bindings) {
+            let Binding::Buffer { len: Some(len), .. } = binding else {
+                continue;
+            };
+            let len = match self.kernel.get_arg_type_name(i) {
+                Ok(type_name) => len / vector_width(&normalize_type(&type_name)),
+                Err(_) => *len,
+            };
+            let arg_name = self.kernel.get_arg_name(i).unwrap_or_default();
+            let arg = format!("arg {} '{}'", i, arg_name.trim_end_matches('\0'));
+
+            check_length(name, &arg, items, guard, len)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The `declared` parameters no binding was made for, found by name when every binding has one of
+/// the `bound` names and by position otherwise
+fn missing_args(declared: &[String], bound: &[Option<&str>]) -> Vec<String> {
+    let bound_by_name = bound.iter().all(Option::is_some);
+    declared
+        .iter()
+        .enumerate()
+        .filter(|&(i, arg_name)| {
+            if bound_by_name {
+                !bound.contains(&Some(arg_name.as_str()))
+            } else {
+                i >= bound.len()
+            }
+        })
+        .map(|(i, arg_name)| format!("arg {} '{}'", i, arg_name))
+        .collect()
+}
+
+/// Compares `items` work-items, or the `guard` count of a guarded kernel, with the `len` elements
+/// of the buffer bound to `arg`
+fn check_length(
+    name: &str,
+    arg: &str,
+    items: usize,
+    guard: Option<usize>,
+    len: usize,
+) -> anyhow::Result<()> {
+    match guard {
+        Some(count) if len > 1 && count > len => tracing::warn!(
+            "{}: the count {} exceeds the {} elements of the buffer bound to {}, the kernel's bounds guard doesn't keep it in bounds",
+            name,
+            count,
+            len,
+            arg
+        ),
+        Some(_) => {}
+        None if len > 1 && items > len => bail!(
+            "{}: {} work-items exceed the {} elements of the buffer bound to {}, the kernel would access it out of bounds",
+            name,
+            items,
+            len,
+            arg
+        ),
+        None => {}
+    }
+
+    Ok(())
+}
+
+/// The limits an NDRange with a local size has to stay within
+struct RangeLimits {
+    /// `CL_DEVICE_MAX_WORK_ITEM_DIMENSIONS`
+    max_dimensions: usize,
+    /// `CL_DEVICE_MAX_WORK_ITEM_SIZES`, one per dimension
+    max_sizes: Vec<usize>,
+    /// The kernel's `CL_KERNEL_WORK_GROUP_SIZE`, at most `CL_DEVICE_MAX_WORK_GROUP_SIZE`
+    max_items: usize,
+}
+
+impl RangeLimits {
+    /// Fails with the first limit `global`, `local` and `offset` break, naming a valid local size
+    fn check(
+        &self,
+        name: &str,
+        global: &[usize],
+        local: &[usize],
+        offset: &[usize],
+    ) -> anyhow::Result<()> {
+        let dimensions = global.len();
+        if dimensions > self.max_dimensions {
+            bail!(
+                "{}: a {}D range exceeds the device's CL_DEVICE_MAX_WORK_ITEM_DIMENSIONS of {}",
+                name,
+                dimensions,
+                self.max_dimensions
+            );
+        }
+        if !offset.is_empty() && offset.len() != dimensions {
+            bail!(
+                "{}: the global offset has {} dimensions but the global size {}",
+                name,
+                offset.len(),
+                dimensions
+            );
+        }
+        if local.is_empty() {
+            return Ok(());
+        }
+        if local.len() != dimensions {
+            bail!(
+                "{}: the local size has {} dimensions but the global size {}",
+                name,
+                local.len(),
+                dimensions
+            );
+        }
+        let suggestion = || {
+            let local = self.suggest(global);
+            let sizes: Vec<String> = local.iter().map(usize::to_string).collect();
+            format!("a valid local size is {}", sizes.join("x"))
+        };
+
+        for (d, (&global, &local)) in global.iter().zip(local).enumerate() {
+            let max = self.max_sizes.get(d).copied().unwrap_or(1);
+            if local == 0 || local > max {
+                bail!(
+                    "{}: the local size {} of dimension {} is outside 1 to CL_DEVICE_MAX_WORK_ITEM_SIZES[{}] = {}, {}",
+                    name,
+                    local,
+                    d,
+                    d,
+                    max,
+                    suggestion()
+                );
+            }
+            if !global.is_multiple_of(local) {
+                bail!(
+                    "{}: the global size {} of dimension {} isn't a multiple of its local size {}, {}",
+                    name,
+                    global,
+                    d,
+                    local,
+                    suggestion()
+                );
+            }
+        }
+        let items: usize = local.iter().product();
+        if items > self.max_items {
+            bail!(
+                "{}: a work-group of {} items exceeds the kernel's CL_KERNEL_WORK_GROUP_SIZE of {}, {}",
+                name,
+                items,
+                self.max_items,
+                suggestion()
+            );
+        }
+
+        Ok(())
+    }
+
+    /// The largest local size dividing `global`, dimension by dimension, within the limits
+    fn suggest(&self, global: &[usize]) -> Vec<usize> {
+        let mut items_left = self.max_items.max(1);
+        global
+            .iter()
+            .enumerate()
+            .map(|(d, &global)| {
+                let max = self
+                    .max_sizes
+                    .get(d)
+                    .copied()
+                    .unwrap_or(1)
+                    .min(items_left)
+                    .min(global)
+                    .max(1);
+                let local = (1..=max)
+                    .rev()
+                    .find(|&local| global.is_multiple_of(local))
+                    .unwrap_or(1);
+                items_left /= local;
+                local
+            })
+            .collect()
+    }
+}
+
+/// `type_name` without whitespace or a trailing NUL, `unsigned int` spelled `uint`
+fn normalize_type(type_name: &str) -> String {
+    let type_name: String = type_name
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '\0')
+        .collect();
+    // C11 atomics have the size and representation of their plain type
+    let type_name = type_name.strip_prefix("atomic_").unwrap_or(&type_name);
+    match type_name.strip_prefix("unsignedint") {
+        Some(rest) => format!("uint{}", rest),
+        None => type_name.to_owned(),
+    }
+}
+
+/// The scalar type of a vector type like `float4`
+fn scalar_type(type_name: &str) -> &str {
+    type_name.trim_end_matches(|c: char| c.is_ascii_digit())
+}
+
+/// How many elements of the host type one element of the pointer type `type_name` spans, such as 2
+/// for `float2*`. Three-component vectors take the space of four
+fn vector_width(type_name: &str) -> usize {
+    let pointee = type_name.strip_suffix('*').unwrap_or(type_name);
+    match pointee[scalar_type(pointee).len()..].parse() {
+        Ok(3) => 4,
+        Ok(width) if width > 0 => width,
+        _ => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use opencl3::memory::{CL_MEM_READ_ONLY, CL_MEM_WRITE_ONLY};
+
+    use super::*;
+    use crate::{saxpy, test_device};
+
+    #[test]
+    fn short_buffer_fails() {
+        let err = check_length("saxpy", "arg 2 'z'", 1024, None, 1000).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "saxpy: 1024 work-items exceed the 1000 elements of the buffer bound to arg 2 'z', the kernel would access it out of bounds"
+        );
+        check_length("saxpy", "arg 2 'z'", 1000, None, 1000).unwrap();
+    }
+
+    #[test]
+    fn single_element_buffer_is_exempt() {
+        check_length("sum", "arg 1 'total'", 1024, None, 1).unwrap();
+    }
+
+    #[test]
+    fn missing_args_by_name_and_position() {
+        let declared: Vec<String> = ["x", "a", "y", "z"].map(str::to_owned).to_vec();
+
+        assert_eq!(
+            missing_args(&declared, &[Some("x"), Some("a"), Some("z")]),
+            ["arg 2 'y'"]
+        );
+        assert_eq!(
+            missing_args(&declared, &[None, None]),
+            ["arg 2 'y'", "arg 3 'z'"]
+        );
+        assert!(missing_args(&declared, &[Some("x"), Some("a"), Some("y"), Some("z")]).is_empty());
+    }
+
+    #[test]
+    fn omitted_argument_is_named() {
+        let Some(test) = test_device::get() else {
+            return;
+        };
+        let kernel = test_device::saxpy_kernel_with(&test, "-cl-kernel-arg-info");
+        let x = saxpy::create_buffer(&test.context, CL_MEM_READ_ONLY, 16).unwrap();
+        let z = saxpy::create_buffer(&test.context, CL_MEM_WRITE_ONLY, 16).unwrap();
+        let a: cl_float = 2.0;
+
+        let err = unsafe {
+            launch!(&kernel, global = 16; z: &z, x: &x, a: &a).enqueue_nd_range(&test.queue)
+        }
+        .unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("missing arg 2 'y'"),
+            "{:#}",
+            err
+        );
+    }
+
+    /// A device taking up to 3 dimensions of 1024x1024x64 items, and a kernel of 256
+    fn limits() -> RangeLimits {
+        RangeLimits {
+            max_dimensions: 3,
+            max_sizes: vec![1024, 1024, 64],
+            max_items: 256,
+        }
+    }
+
+    fn range_error(global: &[usize], local: &[usize], offset: &[usize]) -> String {
+        limits()
+            .check("k", global, local, offset)
+            .unwrap_err()
+            .to_string()
+    }
+
+    #[test]
+    fn valid_ranges() {
+        limits().check("k", &[1 << 20], &[], &[]).unwrap();
+        limits()
+            .check("k", &[1024, 1024], &[16, 16], &[0, 4])
+            .unwrap();
+        limits()
+            .check("k", &[64, 64, 64], &[4, 4, 16], &[])
+            .unwrap();
+    }
+
+    #[test]
+    fn invalid_ranges() {
+        assert_eq!(
+            range_error(&[1, 1, 1, 1], &[], &[]),
+            "k: a 4D range exceeds the device's CL_DEVICE_MAX_WORK_ITEM_DIMENSIONS of 3"
+        );
+        assert_eq!(
+            range_error(&[64, 64], &[], &[1]),
+            "k: the global offset has 1 dimensions but the global size 2"
+        );
+        assert_eq!(
+            range_error(&[64, 64], &[8], &[]),
+            "k: the local size has 1 dimensions but the global size 2"
+        );
+        assert_eq!(
+            range_error(&[64], &[0], &[]),
+            "k: the local size 0 of dimension 0 is outside 1 to CL_DEVICE_MAX_WORK_ITEM_SIZES[0] = 1024, a valid local size is 64"
+        );
+        assert_eq!(
+            range_error(&[64, 64, 128], &[1, 1, 128], &[]),
+            "k: the local size 128 of dimension 2 is outside 1 to CL_DEVICE_MAX_WORK_ITEM_SIZES[2] = 64, a valid local size is 64x4x1"
+        );
+        assert_eq!(
+            range_error(&[100], &[8], &[]),
+            "k: the global size 100 of dimension 0 isn't a multiple of its local size 8, a valid local size is 100"
+        );
+        assert_eq!(
+            range_error(&[1024, 1024], &[32, 32], &[]),
+            "k: a work-group of 1024 items exceeds the kernel's CL_KERNEL_WORK_GROUP_SIZE of 256, a valid local size is 256x1"
+        );
+    }
+
+    #[test]
+    fn suggested_local_sizes() {
+        assert_eq!(limits().suggest(&[1000]), [250]);
+        assert_eq!(limits().suggest(&[1021]), [1]);
+        assert_eq!(limits().suggest(&[7, 7]), [7, 7]);
+        assert_eq!(limits().suggest(&[1 << 20, 1 << 20, 64]), [256, 1, 1]);
+        assert_eq!(limits().suggest(&[8, 1024, 64]), [8, 32, 1]);
+        for global in [[1000, 30, 7], [64, 64, 64], [3, 5, 9]] {
+            let local = limits().suggest(&global);
+            limits().check("k", &global, &local, &[]).unwrap();
+        }
+    }
+
+    #[test]
+    fn guarded_kernel_only_warns() {
+        check_length("saxpy", "arg 2 'z'", 1024, Some(2000), 1000).unwrap();
+        check_length("saxpy", "arg 2 'z'", 1024, Some(1000), 1000).unwrap();
+    }
+}
@@ -0,0 +1,216 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+//! `--link-helpers`: the kernel and a library of helper functions compiled separately and linked
+//!
+//! The helpers are compiled into a program object of their own with `clCompileProgram`, the kernel
+//! only declares their prototypes and `clLinkProgram` resolves the calls, like linking object
+//! files. opencl3's `Program::link` passes the program where the context belongs and drops the
+//! program of a failed link along with its log, so the link goes through `clLinkProgram` directly
+//! and its result is owned by [`LinkedProgram`].
+
+use std::{
+    ffi::{c_char, c_void, CString},
+    ptr,
+    time::Instant,
+};
+
+use anyhow::bail;
+use opencl3::{
+    context::Context,
+    error_codes::{ClError, CL_SUCCESS},
+    kernel::{create_kernel, Kernel},
+    program::{release_program, Program},
+    types::{cl_context, cl_device_id, cl_int, cl_program, cl_uint},
+};
+
+use crate::{
+    build_log,
+    build_time::{self, Origin},
+    error::ClContext,
+    kernels::{self, BuildFlags, BuiltinKernel},
+};
+
+/// Device functions shared between kernels, compiled once on their own
+pub const HELPERS_SOURCE: &str = r#"
+float axpy(float a, float x, float y)
+{
+    return a*x + y;
+}"#;
+
+/// Saxpy calling `axpy` from [`HELPERS_SOURCE`], which it only declares
+pub const LINKED_PROGRAM_SOURCE: &str = r#"
+float axpy(float a, float x, float y);
+
+kernel void saxpy_float_linked (global float* z,
+    global float const* x,
+    global float const* y,
+    float a)
+{
+    const size_t i = get_global_id(0);
+    z[i] = axpy(a, x[i], y[i]);
+}"#;
+
+pub const LINKED_KERNEL_NAME: &str = BuiltinKernel::SaxpyLinked.name();
+
+/// Where the kernel comes from in messages and the summary
+pub const SOURCE_LABEL: &str = "saxpy linked with the helper library";
+
+extern "system" {
+    fn clLinkProgram(
+        context: cl_context,
+        num_devices: cl_uint,
+        device_list: *const cl_device_id,
+        options: *const c_char,
+        num_input_programs: cl_uint,
+        input_programs: *const cl_program,
+        pfn_notify: Option<unsafe extern "C" fn(program: cl_program, user_data: *mut c_void)>,
+        user_data: *mut c_void,
+        errcode_ret: *mut cl_int,
+    ) -> cl_program;
+}
+
+/// A program created by `clLinkProgram`, released on drop
+struct LinkedProgram(cl_program);
+
+impl Drop for LinkedProgram {
+    fn drop(&mut self) {
+        if self.0.is_null() {
+            return;
+        }
+        if let Err(err) = unsafe { release_program(self.0) } {
+            tracing::error!("Failed to release linked program: {}", ClError(err));
+        }
+    }
+}
+
+/// Compiles the helpers and the kernel with `options`, links them and creates the kernel
+///
+/// A failure names the phase and carries the build logs of the program it happened in: the helper
+/// library or the kernel for a compile error, the linked program for an undefined function
+pub fn build_linked_kernel(
+    context: &Context,
+    options: &str,
+    flags: BuildFlags,
+) -> anyhow::Result<Kernel> {
+    let start = Instant::now();
+    let helpers = compile(
+        context,
+        HELPERS_SOURCE,
+        options,
+        "the helper library",
+        flags,
+    )?;
+    let saxpy = compile(
+        context,
+        LINKED_PROGRAM_SOURCE,
+        options,
+        LINKED_KERNEL_NAME,
+        flags,
+    )?;
+
+    let devices = context.devices();
+    let inputs = [helpers.get(), saxpy.get()];
+    let link_options = CString::new("").unwrap();
+    let mut status = CL_SUCCESS;
+    let linked = LinkedProgram(unsafe {
+        clLinkProgram(
+            context.get(),
+            devices.len() as cl_uint,
+            devices.as_ptr(),
+            link_options.as_ptr(),
+            inputs.len() as cl_uint,
+            inputs.as_ptr(),
+            None,
+            ptr::null_mut(),
+            &mut status,
+        )
+    });
+    if status != CL_SUCCESS {
+        // Most drivers return the failed program to read the log from, some only the error
+        let logs = if linked.0.is_null() {
+            "(no program to read a link log from)".to_owned()
+        } else {
+            kernels::build_logs(linked.0, devices)
+        };
+        bail!(
+            "Failed to link {} with the helper library: {}\n{}",
+            LINKED_KERNEL_NAME,
+            ClError(status),
+            logs.trim_end()
+        );
+    }
+    if flags.strict && kernels::has_warnings(linked.0, devices) {
+        bail!(
+            "Linking {} produced warnings, which --strict treats as errors\n{}",
+            LINKED_KERNEL_NAME,
+            kernels::build_logs(linked.0, devices).trim_end()
+        );
+    }
+    if flags.print_log {
+        eprint!(
+            "Link log of {}:\n{}",
+            LINKED_KERNEL_NAME,
+            kernels::build_logs(linked.0, devices)
+        );
+    }
+    build_log::check(
+        &kernels::build_logs(linked.0, devices),
+        LINKED_KERNEL_NAME,
+        flags.strict_build,
+    )?;
+
+    build_time::record(LINKED_KERNEL_NAME, Origin::Linked, start);
+
+    let name = CString::new(LINKED_KERNEL_NAME).unwrap();
+    // The kernel keeps its own reference to the program, so `linked` can go
+    let kernel = create_kernel(linked.0, &name)
+        .map_err(ClError)
+        .context("Failed to create kernel")?;
+
+    tracing::debug!(
+        "Linked {} with the helper library, compiled with options \"{}\"",
+        LINKED_KERNEL_NAME,
+        options
+    );
+
+    Ok(Kernel::new(kernel))
+}
+
+/// Compiles `source` into a program object for linking, `what` names it in errors
+///
+/// `flags.strict` fails on warnings like [`kernels::build_kernel`] does, the cache isn't used
+fn compile(
+    context: &Context,
+    source: &str,
+    options: &str,
+    what: &str,
+    flags: BuildFlags,
+) -> anyhow::Result<Program> {
+    let mut program = Program::create_from_source(context, source)
+        .context("Program::create_from_source failed")?;
+
+    match program.compile(context.devices(), options, &[], &[]) {
+        Err(err) => bail!(
+            "Failed to compile {} with options \"{}\": {}\n{}",
+            what,
+            options,
+            err,
+            kernels::build_logs(program.get(), context.devices()).trim_end()
+        ),
+        Ok(()) if flags.strict && kernels::has_warnings(program.get(), context.devices()) => bail!(
+            "Compiling {} with options \"{}\" produced warnings, which --strict treats as errors\n{}",
+            what,
+            options,
+            kernels::build_logs(program.get(), context.devices()).trim_end()
+        ),
+        Ok(()) if flags.print_log => eprint!(
+            "Compile log of {}:\n{}",
+            what,
+            kernels::build_logs(program.get(), context.devices())
+        ),
+        Ok(()) => {}
+    }
+
+    Ok(program)
+}
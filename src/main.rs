@@ -1,175 +1,272 @@
 // Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
 // SPDX-License-Identifier: MIT
 
-const PROGRAM_SOURCE: &str = r#"
-kernel void saxpy_float (global float* z,
-    global float const* x,
-    global float const* y,
-    float a)
-{
-    const size_t i = get_global_id(0);
-    z[i] = a*x[i] + y[i];
-}"#;
+mod atomic_count;
+mod batch;
+mod bench;
+mod build_log;
+mod build_time;
+mod cancel;
+mod chain;
+mod cli;
+mod completions;
+mod config;
+#[cfg(all(feature = "cl_2_0", not(target_os = "macos")))]
+mod device_enqueue;
+mod device_info;
+#[cfg(feature = "dynamic")]
+mod dynamic;
+mod error;
+#[cfg(feature = "async")]
+mod event_future;
+mod event_graph;
+mod fit;
+mod host_alloc;
+mod images;
+mod kernels;
+mod launch;
+mod link;
+mod notify;
+mod offline;
+mod output;
+#[cfg(all(feature = "cl_2_0", not(target_os = "macos")))]
+mod pipe_demo;
+mod plan;
+mod pool;
+mod printf;
+mod program_cache;
+mod readback;
+mod repl;
+mod resources;
+mod retry;
+mod run;
+mod saxpy;
+mod server;
+mod shared;
+mod stream;
+mod summary;
+mod sweep;
+mod template;
+#[cfg(test)]
+mod test_device;
+mod trace;
+mod tune;
+mod vector_file;
+mod wire;
 
-const KERNEL_NAME: &str = "saxpy_float";
+#[cfg(not(feature = "cl_1_2"))]
+compile_error!("enable one of the OpenCL version features: cl_1_2, cl_2_0 or cl_3_0");
 
-use std::ptr;
+#[cfg(all(feature = "dynamic", feature = "icd-loader-vendored"))]
+compile_error!(
+    "the dynamic and icd-loader-vendored features are mutually exclusive: dynamic loads the OpenCL \
+     library at runtime, icd-loader-vendored links a loader built from vendor/ into the binary, \
+     enable only one of them"
+);
 
+use std::{ptr, sync::Arc};
+
+use anyhow::bail;
 use opencl3::{
-    command_queue::{CommandQueue, CL_QUEUE_PROFILING_ENABLE},
+    command_queue::{CommandQueue, CL_QUEUE_ON_DEVICE, CL_QUEUE_PROFILING_ENABLE},
     context::Context,
-    device::{get_all_devices, Device, CL_DEVICE_TYPE_GPU},
-    kernel::{ExecuteKernel, Kernel},
-    memory::{Buffer, CL_MEM_READ_ONLY, CL_MEM_WRITE_ONLY},
-    program::Program,
-    types::{cl_event, cl_float, CL_NON_BLOCKING},
+    device::Device,
+    types::{cl_device_id, cl_uint},
 };
 
+use cli::{Args, Command};
+use error::ClContext;
+
 // From https://github.com/kenba/opencl3/blob/4619128df954ac3aa1f2af7774c543f3be808b6c/examples/basic.rs
 fn main() -> anyhow::Result<()> {
+    let args = Args::parse()?;
+    match args.command {
+        Command::PrintConfig => {
+            print!("{}", config::TEMPLATE);
+            return Ok(());
+        }
+        Command::Completions(shell) => {
+            completions::print(shell);
+            return Ok(());
+        }
+        Command::ClearCache => return program_cache::clear(),
+        _ => {}
+    }
+
     tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::TRACE)
+        .with_max_level(args.log_level.unwrap_or(tracing::Level::TRACE))
+        .with_writer(std::io::stderr)
         .init();
 
-    let device_id = *get_all_devices(CL_DEVICE_TYPE_GPU)
-        .expect("get_all_devices failed")
-        .first()
-        .expect("No device found");
-    tracing::debug!("Found device: {:p}", device_id);
+    // The client only talks to the server, it doesn't need OpenCL
+    if let Command::Client = args.command {
+        return server::client(&args, args.socket());
+    }
+
+    #[cfg(feature = "dynamic")]
+    dynamic::load_opencl()?;
+
+    if let Command::ListDevices = args.command {
+        return device_info::list_devices(args.json);
+    }
+    if let Command::CompleteDevices = args.command {
+        return device_info::print_device_indices(&args);
+    }
 
-    let device = Device::new(device_id);
+    let device = device_info::select_device(&args)?;
     tracing::debug!("Constructed device");
 
-    let context = Context::from_device(&device)
-        .map_err(|err| format!("Context::from_device failed: {}", err.to_string()))
-        .unwrap();
+    if let Command::DeviceInfo = args.command {
+        return device_info::print_version_info(&device);
+    }
+
+    if args.dry_run {
+        return plan::dry_run(&device, &args);
+    }
+
+    let context = Arc::new(build_context(std::slice::from_ref(&device))?);
     tracing::debug!("Constructed context: {:#?}", device);
 
-    let queue =
-        CommandQueue::create_default_with_properties(&context, CL_QUEUE_PROFILING_ENABLE, 0)
-            .map_err(|err| format!("Failed to create queue: {}", err.to_string()))
-            .unwrap();
-    let queue_size = {
-        if let Ok(size) = queue
-            .size()
-            .map_err(|err| format!("Failed to get queue size: {}", err.to_string()))
-        {
-            Some(size)
-        } else {
-            None
-        }
-    };
+    let queue = create_queue(&context).context("Failed to create queue")?;
+    let queue_size = queue_size(&queue)?;
 
     tracing::debug!("Created queue with size ({:?})", queue_size);
 
-    let program = Program::create_and_build_from_source(&context, PROGRAM_SOURCE, "")
-        .map_err(|err| {
-            format!(
-                "Program::create_and_build_from_source failed: {}",
-                err.to_string()
-            )
-        })
-        .unwrap();
-
-    let kernel = Kernel::create(&program, KERNEL_NAME)
-        .map_err(|err| format!("Failed to create kernel: {}", err.to_string()))
-        .unwrap();
-
-    tracing::debug!(
-        "Created program + kernel ({}) with source:\n{}",
-        KERNEL_NAME,
-        PROGRAM_SOURCE
-    );
-
-    const ARRAY_SIZE: usize = 1024;
-    let ones: [cl_float; ARRAY_SIZE] = [1.0; ARRAY_SIZE];
-    let sums: [cl_float; ARRAY_SIZE] = {
-        let mut sums: [cl_float; ARRAY_SIZE] = [0.0; ARRAY_SIZE];
-        for i in 0..ARRAY_SIZE {
-            sums[i] = 1.0 + 1.0 * i as cl_float;
-        }
-
-        sums
-    };
+    retry::configure(args.retries, args.retry_delay_ms);
 
-    let mut x = unsafe {
-        Buffer::<cl_float>::create(&context, CL_MEM_READ_ONLY, ARRAY_SIZE, ptr::null_mut())
-            .map_err(|err| format!("Failed to create buffer: {}", err.to_string()))
-            .unwrap()
-    };
+    // The REPL blocks reading stdin and the server accepting connections, where a flag set by
+    // Ctrl-C would go unnoticed
+    if !matches!(args.command, Command::Repl | Command::Serve) {
+        cancel::install();
+    }
 
-    let mut y = unsafe {
-        Buffer::<cl_float>::create(&context, CL_MEM_READ_ONLY, ARRAY_SIZE, ptr::null_mut())
-            .map_err(|err| format!("Failed to create buffer: {}", err.to_string()))
-            .unwrap()
-    };
+    if let Some(path) = &args.record {
+        trace::start(path, args.record_data)?;
+    }
 
-    let z = unsafe {
-        Buffer::<cl_float>::create(&context, CL_MEM_WRITE_ONLY, ARRAY_SIZE, ptr::null_mut())
-            .map_err(|err| format!("Failed to create buffer: {}", err.to_string()))
-            .unwrap()
+    let result = match &args.command {
+        Command::Run => match args.threads {
+            Some(threads) => shared::run_threads(Arc::clone(&context), &args, threads),
+            None => run::run(&context, &queue, &device, &args),
+        },
+        Command::Bench(bench_args) => bench::run(&context, &queue, &args, bench_args),
+        Command::Sweep(sweep_args) => sweep::run(&context, &queue, &device, &args, sweep_args),
+        Command::Tune(tune_args) => tune::run(&context, &queue, &device, &args, tune_args),
+        Command::Chain(chain_args) => chain::run(&context, &queue, &args, chain_args),
+        Command::AtomicCount(count_args) => {
+            atomic_count::run(&context, &queue, &device, &args, count_args)
+        }
+        #[cfg(all(feature = "cl_2_0", not(target_os = "macos")))]
+        Command::PipeDemo(pipe_args) => pipe_demo::run(&context, &queue, &device, &args, pipe_args),
+        #[cfg(any(not(feature = "cl_2_0"), target_os = "macos"))]
+        Command::PipeDemo(_) => Err(anyhow::anyhow!(
+            "pipe-demo needs the OpenCL 2.0 API, which this build doesn't use (built without the cl_2_0 feature or for macOS)"
+        )),
+        Command::Repl => repl::run(&context, &queue, &device),
+        Command::Compile => {
+            let out = args.out.as_deref().expect("compile requires --out");
+            offline::compile(&context, &device, &args, out)
+        }
+        Command::Serve => server::serve(&context, &queue, &args, args.socket()),
+        Command::Replay(path) => trace::replay(&context, &queue, &device, &args, path),
+        Command::ListDevices
+        | Command::DeviceInfo
+        | Command::PrintConfig
+        | Command::ClearCache
+        | Command::Completions(_)
+        | Command::CompleteDevices
+        | Command::Client => {
+            unreachable!("handled above")
+        }
     };
 
-    let x_write_event = unsafe {
-        queue
-            .enqueue_write_buffer(&mut x, CL_NON_BLOCKING, 0, &ones, &[])
-            .map_err(|err| format!("Failed to write to buffer: {}", err.to_string()))
-            .unwrap()
-    };
+    retry::report();
 
-    let y_write_event = unsafe {
-        queue
-            .enqueue_write_buffer(&mut y, CL_NON_BLOCKING, 0, &sums, &[])
-            .map_err(|err| format!("Failed to write to buffer: {}", err.to_string()))
-            .unwrap()
+    // A failed run is worth replaying too, so the trace is finished either way
+    let result = match &args.record {
+        Some(_) => {
+            let finished = trace::finish();
+            result.and(finished)
+        }
+        None => result,
     };
 
-    let a: cl_float = 300.0;
-
-    let kernel_event = unsafe {
-        ExecuteKernel::new(&kernel)
-            .set_arg(&z)
-            .set_arg(&x)
-            .set_arg(&y)
-            .set_arg(&a)
-            .set_global_work_size(ARRAY_SIZE)
-            .set_wait_event(&x_write_event)
-            .set_wait_event(&y_write_event)
-            .enqueue_nd_range(&queue)
+    // A command without a loop to stop still drains the queue and exits as interrupted
+    let result = result.and_then(|()| cancel::check(&queue));
+    if result
+        .as_ref()
+        .is_err_and(|err| err.is::<cancel::Cancelled>())
+    {
+        drop(queue);
+        drop(context);
+        eprintln!("Interrupted, the results above are partial");
+        std::process::exit(cancel::EXIT_CODE);
     }
-    .map_err(|err| format!("Failed to execute kernel: {}", err.to_string()))
-    .unwrap();
 
-    let mut events = Vec::<cl_event>::default();
-    events.push(kernel_event.get());
-
-    let mut result: [cl_float; ARRAY_SIZE] = [0.0; ARRAY_SIZE];
-    let read_event =
-        unsafe { queue.enqueue_read_buffer(&z, CL_NON_BLOCKING, 0, &mut result, &events) }
-            .map_err(|err| format!("Failed to read buffer: {}", err.to_string()))
-            .unwrap();
+    result.and_then(|()| notify::check(args.fail_on_context_error))
+}
 
-    read_event
-        .wait()
-        .map_err(|err| format!("Failed to wait to read buffer: {}", err.to_string()))
-        .unwrap();
+/// Creates one context spanning `devices`, which have to belong to the same platform, with the
+/// notification callback of [`notify`]
+fn build_context(devices: &[Device]) -> anyhow::Result<Context> {
+    let Some(first) = devices.first() else {
+        bail!("A context needs at least one device");
+    };
 
-    println!("results front: {}", result[0]);
-    println!("results back: {}", result[ARRAY_SIZE - 1]);
+    let platform = first
+        .platform()
+        .context("Failed to query device platform")?;
+    for device in &devices[1..] {
+        if device
+            .platform()
+            .context("Failed to query device platform")?
+            != platform
+        {
+            bail!(
+                "{} and {} belong to different platforms and can't share a context",
+                first.name().context("Failed to query device name")?,
+                device.name().context("Failed to query device name")?
+            );
+        }
+    }
 
-    let start_time = kernel_event
-        .profiling_command_start()
-        .map_err(|err| format!("Failed to start profiling command: {}", err.to_string()))
-        .unwrap();
+    let ids: Vec<cl_device_id> = devices.iter().map(Device::id).collect();
+    Context::from_devices(&ids, &[], notify::callback(), ptr::null_mut())
+        .context("Context::from_devices failed")
+}
 
-    let end_time = kernel_event
-        .profiling_command_end()
-        .map_err(|err| format!("Failed to end profiling command: {}", err.to_string()))
-        .unwrap();
+/// `CL_QUEUE_SIZE` of `queue`, `None` for host queues
+///
+/// Only queues created with `CL_QUEUE_ON_DEVICE` have a size, asking a host queue for it fails with
+/// `CL_INVALID_COMMAND_QUEUE`, so it is only queried for device queues
+fn queue_size(queue: &CommandQueue) -> anyhow::Result<Option<cl_uint>> {
+    let properties = queue
+        .properties()
+        .context("Failed to query CL_QUEUE_PROPERTIES")?;
+    if properties & CL_QUEUE_ON_DEVICE == 0 {
+        tracing::debug!("The queue is a host queue, only device queues have a CL_QUEUE_SIZE");
+        return Ok(None);
+    }
 
-    let duration = end_time - start_time;
-    tracing::info!("Kernel execution time (ns): {}", duration);
+    queue
+        .size()
+        .context("Failed to query CL_QUEUE_SIZE")
+        .map(Some)
+}
 
-    Ok(())
+/// Creates a profiling queue on the default device of `context`
+#[cfg(all(feature = "cl_2_0", not(target_os = "macos")))]
+fn create_queue(context: &Context) -> opencl3::Result<CommandQueue> {
+    CommandQueue::create_default_with_properties(context, CL_QUEUE_PROFILING_ENABLE, 0)
 }
 
+/// Creates a profiling queue on the default device of `context`
+///
+/// Built for OpenCL 1.2 (without the `cl_2_0` feature) the 1.2 entry point is the only one there
+/// is. Apple's OpenCL stops at 1.2 and doesn't export `clCreateCommandQueueWithProperties` either,
+/// so macOS uses it regardless of the feature
+#[cfg(any(not(feature = "cl_2_0"), target_os = "macos"))]
+#[allow(deprecated)]
+fn create_queue(context: &Context) -> opencl3::Result<CommandQueue> {
+    CommandQueue::create_default(context, CL_QUEUE_PROFILING_ENABLE)
+}
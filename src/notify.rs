@@ -0,0 +1,114 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+//! The context's notification callback, forwarding asynchronous driver errors to the log
+//!
+//! Drivers report some errors after the call that caused them returned, such as running out of
+//! resources while a kernel executes, only through the `pfn_notify` callback of `clCreateContext`.
+//! [`callback`] logs each report with `tracing::error!` and counts it, `--fail-on-context-error`
+//! then fails the command at the end with [`check`], since results computed after such an error
+//! usually can't be trusted.
+//!
+//! The driver may call the callback from any of its threads, while or after any API call. It keeps
+//! no state but a static counter, so no user data has to outlive the context and nothing is shared
+//! that isn't `Sync`.
+
+use std::{
+    ffi::{c_char, c_void, CStr},
+    fmt::Write as _,
+    panic, slice,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use anyhow::bail;
+
+/// The most bytes of the private info that are logged, drivers pass opaque and sometimes large blobs
+const MAX_PRIVATE_INFO: usize = 64;
+
+/// How often the callback was called by any context
+static REPORTS: AtomicUsize = AtomicUsize::new(0);
+
+/// The `pfn_notify` to create contexts with, its user data is unused and may be null
+pub fn callback() -> Option<unsafe extern "C" fn(*const c_char, *const c_void, usize, *mut c_void)>
+{
+    Some(report)
+}
+
+/// Logs one report of the driver
+///
+/// # Safety
+///
+/// `errinfo` has to be null or a nul-terminated string, and `private_info` null or `cb` readable
+/// bytes, as the OpenCL specification guarantees for the arguments of `pfn_notify`
+unsafe extern "C" fn report(
+    errinfo: *const c_char,
+    private_info: *const c_void,
+    cb: usize,
+    _user_data: *mut c_void,
+) {
+    REPORTS.fetch_add(1, Ordering::Relaxed);
+
+    let errinfo = if errinfo.is_null() {
+        "(no description)".into()
+    } else {
+        // SAFETY: see the function docs
+        unsafe { CStr::from_ptr(errinfo) }.to_string_lossy()
+    };
+    let private_info: &[u8] = if private_info.is_null() {
+        &[]
+    } else {
+        // SAFETY: see the function docs
+        unsafe { slice::from_raw_parts(private_info.cast(), cb) }
+    };
+
+    // Unwinding into the driver is undefined behaviour, a panicking subscriber only loses the line
+    let _ = panic::catch_unwind(|| {
+        if private_info.is_empty() {
+            tracing::error!("The OpenCL driver reported: {}", errinfo);
+        } else {
+            tracing::error!(
+                "The OpenCL driver reported: {} (private info: {})",
+                errinfo,
+                hex_dump(private_info)
+            );
+        }
+    });
+}
+
+/// The first [`MAX_PRIVATE_INFO`] bytes of `bytes` in hex, with the total length when cut off
+fn hex_dump(bytes: &[u8]) -> String {
+    let mut dump = String::new();
+    for (i, byte) in bytes.iter().take(MAX_PRIVATE_INFO).enumerate() {
+        if i > 0 {
+            dump.push(' ');
+        }
+        write!(dump, "{:02x}", byte).unwrap();
+    }
+    if bytes.len() > MAX_PRIVATE_INFO {
+        write!(dump, " ... ({} bytes)", bytes.len()).unwrap();
+    }
+
+    dump
+}
+
+/// Fails when `fail` and the driver reported an error through the callback, otherwise warns that
+/// it did
+pub fn check(fail: bool) -> anyhow::Result<()> {
+    let reports = REPORTS.load(Ordering::Relaxed);
+    if reports == 0 {
+        return Ok(());
+    }
+
+    if fail {
+        bail!(
+            "The OpenCL driver reported {} asynchronous error(s) through the context, see the log above",
+            reports
+        );
+    }
+    tracing::warn!(
+        "The OpenCL driver reported {} asynchronous error(s) through the context, the results may be wrong",
+        reports
+    );
+
+    Ok(())
+}
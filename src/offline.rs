@@ -0,0 +1,279 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+//! `compile`: building kernel files ahead of time into a device binary, and `--load-binary` to run it
+//!
+//! `compile` builds the `--kernel-file`s for the selected device without allocating or running
+//! anything, so a CI job catches build errors, and writes the program's `CL_PROGRAM_BINARIES` raw to
+//! `--out`. Next to it goes a JSON sidecar (`<out>.json`) recording the device, its driver version
+//! and the build options. `run --load-binary` creates the program from the binary and checks the
+//! sidecar against the device it runs on: a different device or driver only warns, since the driver
+//! decides whether it can use the binary, and a binary it rejects is an error.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use anyhow::{bail, Context as _};
+use opencl3::{context::Context, device::Device, kernel::Kernel, program::Program};
+
+use crate::{
+    build_time::{self, Origin},
+    cli::{Args, Layout, Op},
+    error::ClContext,
+    kernels::{self, BuildFlags},
+    output::json_string,
+    summary,
+};
+
+/// What `compile` records about a binary
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Sidecar {
+    device: String,
+    driver_version: String,
+    options: String,
+    source: String,
+}
+
+/// The `compile` command: builds the kernel files of `args` for the device of `context` and writes
+/// the binary to `out` and the sidecar next to it
+pub fn compile(context: &Context, device: &Device, args: &Args, out: &Path) -> anyhow::Result<()> {
+    let files = kernels::files_label(&args.kernel_files);
+    let options = kernels::kernel_options(args, args.layout)?;
+    let source = kernels::file_source(&args.kernel_files)?;
+
+    // Always through the compiler, a cached binary would hide the build errors this is for
+    let flags = BuildFlags {
+        cache: false,
+        ..BuildFlags::from_args(args)
+    };
+    let start = Instant::now();
+    let program = Program::create_from_source(context, &source)
+        .context("Program::create_from_source failed")?;
+    let program = kernels::build_created(context, program, &options, &files, flags)?;
+    build_time::record(&files, Origin::Compiled, start);
+
+    let kernel_names = kernels::kernel_names(&program);
+    if let Some(name) = &args.kernel_name {
+        if !kernel_names.contains(name) {
+            bail!(
+                "{} has no kernel called '{}', it defines: {}",
+                files,
+                name,
+                kernel_names.join(", ")
+            );
+        }
+    }
+
+    let binaries = program
+        .get_binaries()
+        .context("Failed to query the program binaries")?;
+    let binary = match binaries.first() {
+        Some(binary) if !binary.is_empty() => binary,
+        _ => bail!("The driver returned no program binary for {}", files),
+    };
+
+    let sidecar = Sidecar {
+        device: device.name().context("Failed to query device name")?,
+        driver_version: device
+            .driver_version()
+            .context("Failed to query driver version")?,
+        options,
+        source: files.clone(),
+    };
+    fs::write(out, binary).with_context(|| format!("Failed to write {}", out.display()))?;
+    let sidecar_path = sidecar_path(out);
+    fs::write(&sidecar_path, sidecar.to_json())
+        .with_context(|| format!("Failed to write {}", sidecar_path.display()))?;
+
+    println!(
+        "compiled {} for {} into {} ({}), kernels: {}",
+        files,
+        sidecar.device,
+        out.display(),
+        summary::bytes(binary.len() as u64),
+        kernel_names.join(", ")
+    );
+    println!("wrote the build details to {}", sidecar_path.display());
+
+    Ok(())
+}
+
+/// Creates the program from the binary at `path`, builds it with `options` and creates the kernel
+/// called `name` from it, or its first kernel without a name
+///
+/// The sidecar is compared with the device of `context` first, mismatches and a missing sidecar
+/// are warnings. The kernel is looked up, checked and bound like one from
+/// [`kernels::build_file_kernel`]
+pub fn build_binary_kernel(
+    context: &Context,
+    path: &Path,
+    options: &str,
+    name: Option<&str>,
+    layout: Layout,
+    op: Op,
+    flags: BuildFlags,
+) -> anyhow::Result<Kernel> {
+    let label = path.display().to_string();
+    let binary =
+        fs::read(path).with_context(|| format!("Failed to read program binary {}", label))?;
+
+    let device = Device::new(context.default_device());
+    check_sidecar(path, &device)?;
+
+    let start = Instant::now();
+    // SAFETY: the context was created from this one device
+    let program = unsafe { Program::create_from_binary(context, context.devices(), &[&binary]) }
+        .context(format!(
+            "The driver rejected the program binary {}, compile it again for this device",
+            label
+        ))?;
+    let program = kernels::build_created(context, program, options, &label, flags)?;
+    build_time::record(&label, Origin::Binary, start);
+
+    kernels::program_kernel(&program, name, &label, options, layout, op)
+}
+
+/// `<out>.json`
+fn sidecar_path(binary: &Path) -> PathBuf {
+    let mut path = binary.as_os_str().to_owned();
+    path.push(".json");
+    PathBuf::from(path)
+}
+
+/// Warns when the sidecar of `binary` is missing or names another device or driver than `device`
+fn check_sidecar(binary: &Path, device: &Device) -> anyhow::Result<()> {
+    let path = sidecar_path(binary);
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(err) => {
+            tracing::warn!(
+                "No build details for {} ({}: {}), can't check it was compiled for this device",
+                binary.display(),
+                path.display(),
+                err
+            );
+            return Ok(());
+        }
+    };
+    let sidecar = match Sidecar::parse(&text) {
+        Ok(sidecar) => sidecar,
+        Err(err) => {
+            tracing::warn!("Ignoring {}: {:#}", path.display(), err);
+            return Ok(());
+        }
+    };
+
+    let name = device.name().context("Failed to query device name")?;
+    let driver_version = device
+        .driver_version()
+        .context("Failed to query driver version")?;
+    if sidecar.device != name {
+        tracing::warn!(
+            "{} was compiled for {}, running it on {}",
+            binary.display(),
+            sidecar.device,
+            name
+        );
+    } else if sidecar.driver_version != driver_version {
+        tracing::warn!(
+            "{} was compiled with driver {}, this one is {}",
+            binary.display(),
+            sidecar.driver_version,
+            driver_version
+        );
+    }
+    tracing::info!(
+        "Loading {} compiled from {} with options \"{}\"",
+        binary.display(),
+        sidecar.source,
+        sidecar.options
+    );
+
+    Ok(())
+}
+
+impl Sidecar {
+    fn to_json(&self) -> String {
+        format!(
+            "{{\n  \"device\": {},\n  \"driver_version\": {},\n  \"options\": {},\n  \"source\": {}\n}}\n",
+            json_string(&self.device),
+            json_string(&self.driver_version),
+            json_string(&self.options),
+            json_string(&self.source)
+        )
+    }
+
+    /// Reads the flat object of strings [`Sidecar::to_json`] writes, unknown keys are skipped
+    fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut sidecar = Self::default();
+        let mut rest = text
+            .trim()
+            .strip_prefix('{')
+            .and_then(|rest| rest.strip_suffix('}'))
+            .context("expected a JSON object")?
+            .trim_start();
+
+        while !rest.is_empty() {
+            let (key, after) = json_string_prefix(rest)?;
+            let after = after
+                .trim_start()
+                .strip_prefix(':')
+                .context("expected ':' after a key")?;
+            let (value, after) = json_string_prefix(after.trim_start())?;
+            match key.as_str() {
+                "device" => sidecar.device = value,
+                "driver_version" => sidecar.driver_version = value,
+                "options" => sidecar.options = value,
+                "source" => sidecar.source = value,
+                _ => {}
+            }
+
+            let after = after.trim_start();
+            rest = match after.strip_prefix(',') {
+                Some(next) => next.trim_start(),
+                None if after.is_empty() => after,
+                None => bail!("expected ',' between the entries"),
+            };
+        }
+
+        Ok(sidecar)
+    }
+}
+
+/// The JSON string `s` starts with, unescaped, and what follows it
+fn json_string_prefix(s: &str) -> anyhow::Result<(String, &str)> {
+    let mut chars = s
+        .strip_prefix('"')
+        .context("expected a string")?
+        .char_indices();
+    let mut value = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((value, &s[i + 2..])),
+            '\\' => match chars.next().map(|(_, c)| c) {
+                Some('n') => value.push('\n'),
+                Some('r') => value.push('\r'),
+                Some('t') => value.push('\t'),
+                Some('u') => {
+                    let digits: String = (0..4)
+                        .filter_map(|_| chars.next())
+                        .map(|(_, c)| c)
+                        .collect();
+                    let code = u32::from_str_radix(&digits, 16)
+                        .ok()
+                        .and_then(char::from_u32)
+                        .context("invalid \\u escape")?;
+                    value.push(code);
+                }
+                Some(c) => value.push(c),
+                None => break,
+            },
+            c => value.push(c),
+        }
+    }
+
+    bail!("unterminated string")
+}
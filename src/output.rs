@@ -0,0 +1,154 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+use std::{
+    fmt::Write as _,
+    fs::{File, OpenOptions},
+    io::{BufWriter, ErrorKind, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use opencl3::types::cl_float;
+
+use crate::saxpy::SaxpyRun;
+
+/// Identifies a binary result file
+pub const BINARY_MAGIC: &[u8; 4] = b"HOCL";
+
+/// Version of the binary header layout
+pub const BINARY_VERSION: u8 = 1;
+
+/// Columns of `--save-profile-csv`, the timestamp is in seconds since the Unix epoch
+const PROFILE_CSV_HEADER: &str = "timestamp,device,size,kernel_ns,upload_ns,download_ns\n";
+
+/// Writes `result` to `path`, as text with one value per line or as a self-describing binary file
+///
+/// The binary layout is the magic `HOCL`, a version byte, the element type as a NUL-padded 4 byte
+/// string (`f32`), the element count as a little-endian `u64` and then the elements, little-endian
+pub fn write_results(path: &Path, result: &[cl_float], binary: bool) -> anyhow::Result<()> {
+    let mut writer = ResultWriter::create(path, result.len(), binary)?;
+    writer.write(result)?;
+    writer.finish()
+}
+
+/// A results file written piece by piece, in the format of [`write_results`]
+pub struct ResultWriter<'a> {
+    path: &'a Path,
+    writer: BufWriter<File>,
+    binary: bool,
+}
+
+impl<'a> ResultWriter<'a> {
+    /// Creates the file for `len` results, the binary header needs the count up front
+    pub fn create(path: &'a Path, len: usize, binary: bool) -> anyhow::Result<Self> {
+        let file =
+            File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        let mut writer = BufWriter::new(file);
+
+        if binary {
+            writer.write_all(BINARY_MAGIC)?;
+            writer.write_all(&[BINARY_VERSION])?;
+            writer.write_all(b"f32\0")?;
+            writer.write_all(&(len as u64).to_le_bytes())?;
+        }
+
+        Ok(Self {
+            path,
+            writer,
+            binary,
+        })
+    }
+
+    /// Appends the next `values`
+    pub fn write(&mut self, values: &[cl_float]) -> anyhow::Result<()> {
+        for value in values {
+            if self.binary {
+                self.writer.write_all(&value.to_le_bytes())?;
+            } else {
+                writeln!(self.writer, "{}", value)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> anyhow::Result<()> {
+        self.writer
+            .flush()
+            .with_context(|| format!("Failed to write {}", self.path.display()))
+    }
+}
+
+/// Appends the timings of `run` as a row to the CSV file at `path`, writing the header first if the
+/// file is new
+///
+/// Each row goes out in a single append, so runs appending to the same file concurrently don't
+/// interleave within a line
+pub fn append_profile_row(
+    path: &Path,
+    device: &str,
+    size: usize,
+    run: &SaxpyRun,
+) -> anyhow::Result<()> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    let row = format!(
+        "{:.3},{},{},{},{},{}\n",
+        timestamp,
+        csv_field(device),
+        size,
+        run.kernel_ns,
+        run.write_ns,
+        run.read_ns
+    );
+
+    // Only the process that creates the file writes the header
+    match OpenOptions::new().write(true).create_new(true).open(path) {
+        Ok(mut file) => file
+            .write_all(PROFILE_CSV_HEADER.as_bytes())
+            .with_context(|| format!("Failed to write {}", path.display()))?,
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => {}
+        Err(err) => {
+            return Err(err).with_context(|| format!("Failed to create {}", path.display()))
+        }
+    }
+
+    OpenOptions::new()
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(row.as_bytes()))
+        .with_context(|| format!("Failed to append to {}", path.display()))
+}
+
+/// Quotes a CSV field if it contains a separator, quote or line break
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+/// Quotes and escapes `s` as a JSON string
+pub fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(quoted, "\\u{:04x}", c as u32).unwrap(),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+
+    quoted
+}
@@ -0,0 +1,326 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+//! The `pipe-demo` command: a producer kernel passes `a*x` to a consumer kernel through an OpenCL
+//! 2.0 pipe, and the consumer adds `y` into `z`
+//!
+//! Every producer work-item reserves one packet with `reserve_write_pipe`, writes its index and
+//! product into it and commits the reservation, the consumer launch waits on the producer's event
+//! and every one of its work-items reads one packet. Packets come out in whatever order the driver
+//! keeps them, so each carries the index it is for, as a `uint2` of the index and the bits of the
+//! product. Reservations that fail, reads of an empty pipe and indices past the end are counted in
+//! single-element counters instead of being dropped silently.
+//!
+//! The host checks every result, bit for bit: the product and the sum are separate kernels and so
+//! can't be contracted into a fused multiply-add, and `z` is filled with
+//! [`saxpy::UNTOUCHED`](crate::saxpy::UNTOUCHED) beforehand so a lost or duplicated packet shows up
+//! as results never written.
+
+use anyhow::{anyhow, bail};
+use opencl3::{
+    command_queue::CommandQueue,
+    context::Context,
+    device::Device,
+    memory::{
+        Buffer, Pipe, CL_MEM_HOST_NO_ACCESS, CL_MEM_READ_ONLY, CL_MEM_READ_WRITE, CL_MEM_WRITE_ONLY,
+    },
+    types::{cl_event, cl_float, cl_uint, CL_BLOCKING},
+};
+
+use crate::{
+    cli::{Args, PipeDemoArgs},
+    error::ClContext,
+    kernels::{self, BuiltinKernel},
+    launch::launch,
+    retry, saxpy,
+};
+
+pub const PROGRAM_SOURCE: &str = r#"
+kernel void pipe_produce (global float const* x,
+    float a,
+    write_only pipe uint2 packets,
+    global uint* dropped,
+    uint n)
+{
+    const size_t i = get_global_id(0);
+    if (i >= n) return;
+    reserve_id_t reservation = reserve_write_pipe(packets, 1);
+    if (!is_valid_reserve_id(reservation)) {
+        atomic_inc(dropped);
+        return;
+    }
+    const uint2 packet = (uint2)((uint)i, as_uint(a*x[i]));
+    if (write_pipe(packets, reservation, 0, &packet) != 0) {
+        atomic_inc(dropped);
+    }
+    // Committed either way, an uncommitted reservation would hold the pipe
+    commit_write_pipe(packets, reservation);
+}
+
+kernel void pipe_consume (read_only pipe uint2 packets,
+    global float const* y,
+    global float* z,
+    global uint* missed,
+    global uint* stray,
+    uint n)
+{
+    const size_t i = get_global_id(0);
+    if (i >= n) return;
+    uint2 packet;
+    if (read_pipe(packets, &packet) != 0) {
+        atomic_inc(missed);
+        return;
+    }
+    if (packet.x >= n) {
+        atomic_inc(stray);
+        return;
+    }
+    z[packet.x] = as_float(packet.y) + y[packet.x];
+}"#;
+
+pub const PRODUCER_KERNEL_NAME: &str = BuiltinKernel::PipeProduce.name();
+pub const CONSUMER_KERNEL_NAME: &str = BuiltinKernel::PipeConsume.name();
+
+/// The OpenCL C type of the packets, an index and the bits of a float
+pub const PACKET_TYPE: &str = "uint2";
+pub const PACKET_SIZE: cl_uint = 2 * std::mem::size_of::<cl_uint>() as cl_uint;
+
+/// The inputs, `x` cycling through 0 to 999 and `y` through 0 to 6
+pub fn inputs(size: usize) -> (Vec<cl_float>, Vec<cl_float>) {
+    let x = (0..size).map(|i| (i % 1000) as cl_float).collect();
+    let y = (0..size).map(|i| (i % 7) as cl_float).collect();
+    (x, y)
+}
+
+/// Checks that `device` has pipes that hold `size` packets, or fails naming what it lacks
+pub fn check_support(device: &Device, size: usize) -> anyhow::Result<()> {
+    let name = device.name().context("Failed to query device name")?;
+    let version = device
+        .version()
+        .context("Failed to query CL_DEVICE_VERSION")?;
+    // "OpenCL <major>.<minor> <vendor-specific information>"
+    let major = version
+        .strip_prefix("OpenCL ")
+        .and_then(|rest| rest.split('.').next())
+        .and_then(|major| major.parse::<u32>().ok());
+    if major.is_none_or(|major| major < 2) {
+        bail!(
+            "{} is an {} device, pipes need OpenCL 2.0 or later",
+            name,
+            version.trim()
+        );
+    }
+
+    // Optional from OpenCL 3.0, where devices without them report 0
+    let max_packet_size = device.pipe_max_packet_size().unwrap_or(0);
+    if max_packet_size == 0 {
+        bail!(
+            "{} doesn't support pipes (CL_DEVICE_PIPE_MAX_PACKET_SIZE is 0)",
+            name
+        );
+    }
+    if PACKET_SIZE > max_packet_size {
+        bail!(
+            "{} takes pipe packets of at most {} bytes (CL_DEVICE_PIPE_MAX_PACKET_SIZE), pipe-demo sends {}",
+            name,
+            max_packet_size,
+            PACKET_SIZE
+        );
+    }
+
+    let bytes = size as u64 * PACKET_SIZE as u64;
+    let max_alloc = device
+        .max_mem_alloc_size()
+        .context("Failed to query CL_DEVICE_MAX_MEM_ALLOC_SIZE")?;
+    if bytes > max_alloc {
+        bail!(
+            "A pipe of {} packets takes {} bytes but {} allocates at most {}, run with a smaller --size",
+            size,
+            bytes,
+            name,
+            max_alloc
+        );
+    }
+
+    Ok(())
+}
+
+/// The build options of the program, pipes are OpenCL C 2.0
+pub fn build_options(args: &Args) -> String {
+    format!("-cl-std=CL2.0 {}", kernels::arg_info_options(args))
+        .trim_end()
+        .to_owned()
+}
+
+/// Sends the inputs through a pipe between the two kernels and checks every result of `z`
+pub fn run(
+    context: &Context,
+    queue: &CommandQueue,
+    device: &Device,
+    args: &Args,
+    pipe_args: &PipeDemoArgs,
+) -> anyhow::Result<()> {
+    let size = pipe_args.size;
+    let n = cl_uint::try_from(size)
+        .map_err(|_| anyhow!("pipe-demo sends at most {} packets", cl_uint::MAX))?;
+    check_support(device, size)?;
+
+    let program = kernels::build_program(
+        context,
+        PROGRAM_SOURCE,
+        &build_options(args),
+        "the pipe program",
+        kernels::BuildFlags::from_args(args),
+    )?;
+    let mut program_kernels = kernels::program_kernels(&program)?;
+    let producer = kernels::take_kernel(
+        &mut program_kernels,
+        PRODUCER_KERNEL_NAME,
+        "the pipe program",
+    )?;
+    let consumer = kernels::take_kernel(
+        &mut program_kernels,
+        CONSUMER_KERNEL_NAME,
+        "the pipe program",
+    )?;
+
+    // Room for every packet, so the producer never has to wait for the consumer
+    let pipe = unsafe {
+        Pipe::create(
+            context,
+            CL_MEM_READ_WRITE | CL_MEM_HOST_NO_ACCESS,
+            PACKET_SIZE,
+            n,
+        )
+    }
+    .context("Failed to create pipe")?;
+
+    let (x, y) = inputs(size);
+    let a: cl_float = args.scalar();
+    let mut x_buffer = saxpy::create_buffer(context, CL_MEM_READ_ONLY, size)?;
+    let mut y_buffer = saxpy::create_buffer(context, CL_MEM_READ_ONLY, size)?;
+    let mut z_buffer = saxpy::create_buffer(context, CL_MEM_WRITE_ONLY, size)?;
+    // Blocking, the host data has to outlive it and this keeps it simple
+    unsafe { queue.enqueue_write_buffer(&mut x_buffer, CL_BLOCKING, 0, &x, &[]) }
+        .context("Failed to write to buffer")?;
+    unsafe { queue.enqueue_write_buffer(&mut y_buffer, CL_BLOCKING, 0, &y, &[]) }
+        .context("Failed to write to buffer")?;
+    retry::call("clEnqueueFillBuffer", || unsafe {
+        queue.enqueue_fill_buffer(
+            &mut z_buffer,
+            &[saxpy::UNTOUCHED],
+            0,
+            size * std::mem::size_of::<cl_float>(),
+            &[],
+        )
+    })
+    .context("Failed to fill buffer")?;
+
+    let dropped = counter(context, queue)?;
+    let missed = counter(context, queue)?;
+    let stray = counter(context, queue)?;
+
+    println!(
+        "{} packets of {} bytes through a pipe of {}, z = (a*x) + y with a = {}",
+        size, PACKET_SIZE, size, a
+    );
+
+    // In queue order after the fills
+    let producer_event = unsafe {
+        launch!(
+            &producer,
+            global = size;
+            x: &x_buffer,
+            a: &a,
+            packets: (&pipe, PACKET_TYPE),
+            dropped: &dropped,
+            n: &n,
+        )
+        .enqueue_nd_range(queue)
+    }
+    .map_err(|err| err.context("Failed to execute producer kernel"))?;
+
+    let consumer_event = unsafe {
+        launch!(
+            &consumer,
+            global = size;
+            packets: (&pipe, PACKET_TYPE),
+            y: &y_buffer,
+            z: &z_buffer,
+            missed: &missed,
+            stray: &stray,
+            n: &n,
+        )
+        .set_wait_event(&producer_event)
+        .enqueue_nd_range(queue)
+    }
+    .map_err(|err| err.context("Failed to execute consumer kernel"))?;
+
+    let mut z = vec![0.0; size];
+    let events: Vec<cl_event> = vec![consumer_event.get()];
+    unsafe { queue.enqueue_read_buffer(&z_buffer, CL_BLOCKING, 0, &mut z, &events) }
+        .context("Failed to read buffer")?;
+    let dropped = read_counter(queue, &dropped)?;
+    let missed = read_counter(queue, &missed)?;
+    let stray = read_counter(queue, &stray)?;
+
+    println!(
+        "producer kernel time (ns): {}, consumer kernel time (ns): {}",
+        saxpy::profiled_ns(&producer_event)?,
+        saxpy::profiled_ns(&consumer_event)?
+    );
+
+    if dropped != 0 || missed != 0 || stray != 0 {
+        bail!(
+            "The pipe lost packets: {} writes failed, {} reads found the pipe empty and {} packets had an index past {}",
+            dropped,
+            missed,
+            stray,
+            size
+        );
+    }
+    if let Some((first, count)) = saxpy::find_unwritten(&z) {
+        bail!(
+            "{} of the {} results were never written (the first at index {}), their packets were lost or another index's were read twice",
+            count,
+            size,
+            first
+        );
+    }
+    if let Some(i) = (0..size).find(|&i| z[i].to_bits() != (a * x[i] + y[i]).to_bits()) {
+        bail!(
+            "Wrong result at index {}: {} instead of {}",
+            i,
+            z[i],
+            a * x[i] + y[i]
+        );
+    }
+
+    println!("results front: {}", z[0]);
+    println!("results back: {}", z[size - 1]);
+    println!("verified all {} results, every packet arrived once", size);
+
+    Ok(())
+}
+
+/// A single-element counter, zeroed in queue order
+fn counter(context: &Context, queue: &CommandQueue) -> anyhow::Result<Buffer<cl_uint>> {
+    let mut buffer =
+        unsafe { Buffer::<cl_uint>::create(context, CL_MEM_READ_WRITE, 1, std::ptr::null_mut()) }
+            .context("Failed to create counter buffer")?;
+    let zero: cl_uint = 0;
+    retry::call("clEnqueueFillBuffer", || unsafe {
+        queue.enqueue_fill_buffer(&mut buffer, &[zero], 0, std::mem::size_of::<cl_uint>(), &[])
+    })
+    .context("Failed to fill buffer")?;
+
+    Ok(buffer)
+}
+
+fn read_counter(queue: &CommandQueue, counter: &Buffer<cl_uint>) -> anyhow::Result<cl_uint> {
+    let mut result: [cl_uint; 1] = [0];
+    unsafe { queue.enqueue_read_buffer(counter, CL_BLOCKING, 0, &mut result, &[]) }
+        .context("Failed to read buffer")?;
+
+    Ok(result[0])
+}
@@ -0,0 +1,622 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+//! `--dry-run`: everything a command would allocate, launch and transfer, without touching the device
+//!
+//! Only device queries are made, no context, buffer or queue is created. Limits that need a built
+//! kernel (such as `CL_KERNEL_WORK_GROUP_SIZE`) are checked against the device-wide maximum instead.
+
+use std::{fmt::Write as _, fs, mem};
+
+use anyhow::{bail, Context as _};
+use opencl3::{
+    device::Device,
+    types::{cl_float, cl_uint},
+};
+
+use crate::{
+    atomic_count,
+    cli::{Args, Command, Layout, Transfer},
+    error::ClContext,
+    kernels, link,
+    output::json_string,
+    run, saxpy, stream, sweep, tune,
+};
+
+/// A device buffer the command would create
+struct PlannedBuffer {
+    name: String,
+    elements: usize,
+    flags: String,
+}
+
+impl PlannedBuffer {
+    fn bytes(&self) -> u64 {
+        (self.elements * mem::size_of::<cl_float>()) as u64
+    }
+}
+
+/// `count` launches of one kernel with the same sizes and arguments
+struct PlannedLaunch {
+    kernel: String,
+    options: String,
+    global: usize,
+    local: Option<usize>,
+    args: Vec<String>,
+    count: usize,
+}
+
+struct Plan {
+    device: String,
+    command: &'static str,
+    buffers: Vec<PlannedBuffer>,
+    launches: Vec<PlannedLaunch>,
+    bytes_to_device: u64,
+    bytes_from_device: u64,
+    notes: Vec<String>,
+}
+
+impl Plan {
+    /// Adds `count` launches of the `layout` kernel over `size` elements, with their transfers
+    fn launch(
+        &mut self,
+        args: &Args,
+        layout: Layout,
+        size: usize,
+        local: Option<usize>,
+        count: usize,
+    ) -> anyhow::Result<()> {
+        let guarded = kernels::uses_guarded_kernel(args, layout);
+        let kernel = match (&args.load_binary, &args.spirv, args.kernel_files.as_slice()) {
+            _ if guarded => kernels::GUARDED_KERNEL_NAME.to_owned(),
+            _ if args.link_helpers => {
+                format!("{} from {}", link::LINKED_KERNEL_NAME, link::SOURCE_LABEL)
+            }
+            (Some(path), _, _) => {
+                fs::metadata(path)
+                    .with_context(|| format!("Failed to read program binary {}", path.display()))?;
+                let name = args.kernel_name.as_deref().unwrap_or("first kernel");
+                format!("{} from {}", name, path.display())
+            }
+            (None, None, []) => kernels::layout_kernel(layout, args.op, args.kernel_iters)?
+                .name
+                .to_owned(),
+            (None, Some(path), _) => {
+                fs::metadata(path)
+                    .with_context(|| format!("Failed to read SPIR-V module {}", path.display()))?;
+                let name = args.kernel_name.as_deref().unwrap_or("first kernel");
+                format!("{} from {}", name, path.display())
+            }
+            (None, None, paths) => {
+                // Only reading them here, whether they build is up to the device compiler
+                kernels::file_source(paths)?;
+                let name = args.kernel_name.as_deref().unwrap_or("first kernel");
+                format!("{} from {}", name, kernels::files_label(paths))
+            }
+        };
+        let options = kernels::kernel_options(args, layout)?;
+        let mut kernel_args: Vec<String> = match layout {
+            Layout::Aos => vec!["z".into(), "xy".into()],
+            _ => vec!["z".into(), "x".into(), "y".into()],
+        };
+        if let Some(a) = args.op.scalar(args.scalar()) {
+            kernel_args.push(format!("a = {}", a));
+        }
+        if guarded {
+            kernel_args.push("n = size".into());
+        }
+
+        let bytes = (size * mem::size_of::<cl_float>()) as u64;
+        self.bytes_to_device += 2 * bytes * count as u64;
+        self.bytes_from_device += bytes * count as u64;
+
+        // The work-items from --offset on
+        let items = size.saturating_sub(args.offset.unwrap_or(0));
+        self.launches.push(PlannedLaunch {
+            kernel,
+            options,
+            global: match (local, args.num_groups) {
+                (Some(local), Some(groups)) => {
+                    saxpy::groups_global_size(groups, local, items, guarded)?
+                }
+                (Some(local), None) if guarded => saxpy::round_up(items, local),
+                _ => items,
+            },
+            local,
+            args: kernel_args,
+            count,
+        });
+
+        Ok(())
+    }
+
+    /// Adds the buffers [`crate::saxpy::SaxpyBuffers`] creates for `layout` at `capacity`
+    fn buffers(&mut self, args: &Args, layout: Layout, capacity: usize, suffix: &str) {
+        let host = if args.use_host_ptr {
+            " | CL_MEM_USE_HOST_PTR"
+        } else {
+            ""
+        };
+        let mut add = |name: &str, elements, flags: &str| {
+            self.buffers.push(PlannedBuffer {
+                name: format!("{}{}", name, suffix),
+                elements,
+                flags: format!("{}{}", flags, host),
+            })
+        };
+
+        match layout {
+            Layout::Aos => add("xy", 2 * capacity, "CL_MEM_READ_ONLY"),
+            _ => {
+                add("x", capacity, "CL_MEM_READ_ONLY");
+                add("y", capacity, "CL_MEM_READ_ONLY");
+            }
+        }
+        // The on-device sum reads z back
+        let z_flags = if args.device_enqueue {
+            "CL_MEM_READ_WRITE"
+        } else {
+            "CL_MEM_WRITE_ONLY"
+        };
+        add("z", capacity, z_flags);
+
+        if args.pinned_readback {
+            self.buffers.push(PlannedBuffer {
+                name: format!("staging{}", suffix),
+                elements: capacity,
+                flags: "CL_MEM_READ_WRITE | CL_MEM_ALLOC_HOST_PTR".to_owned(),
+            });
+        }
+    }
+}
+
+/// Validates the command in `args` against `device` and prints its plan
+pub fn dry_run(device: &Device, args: &Args) -> anyhow::Result<()> {
+    let mut plan = Plan {
+        device: device.name().context("Failed to query device name")?,
+        command: "",
+        buffers: Vec::new(),
+        launches: Vec::new(),
+        bytes_to_device: 0,
+        bytes_from_device: 0,
+        notes: Vec::new(),
+    };
+
+    match &args.command {
+        Command::Run => {
+            plan.command = "run";
+            let size = if args.stdin {
+                let chunk_size = args.chunk_size.unwrap_or(stream::DEFAULT_CHUNK_SIZE);
+                plan.notes.push(format!(
+                    "the input is streamed from stdin, one launch per chunk of {} elements",
+                    chunk_size
+                ));
+                chunk_size
+            } else {
+                run::inputs(args)?.0.len()
+            };
+            if let Some(threads) = args.threads {
+                plan.notes.push(format!(
+                    "{} threads each run the launch on their own queue and buffers",
+                    threads
+                ));
+            }
+            if let Some(offset) = args.offset {
+                plan.notes.push(format!(
+                    "z is filled before the kernel, which starts at global id {}, the results before it have to keep the fill",
+                    offset
+                ));
+            }
+            if args.detect_unwritten {
+                plan.notes.push(
+                    "z is filled with a sentinel before the kernel, results still holding it fail the run"
+                        .to_owned(),
+                );
+            }
+            if args.enable_printf {
+                plan.notes.push(
+                    "the kernel is built with -D DEBUG_PRINT and its printf output framed by marker lines"
+                        .to_owned(),
+                );
+            }
+            if args.no_verify {
+                plan.notes
+                    .push("the results aren't checked against the host computation".to_owned());
+            }
+            if let Some(stride) = args.verify_stride.filter(|&stride| stride > 1) {
+                plan.notes.push(format!(
+                    "every {}th result and the last are checked against the host computation",
+                    stride
+                ));
+            }
+            if let Some(chunk_size) = args.readback_chunk {
+                plan.notes.push(format!(
+                    "z is read back in {} chunks of up to {} elements",
+                    size.div_ceil(chunk_size),
+                    chunk_size
+                ));
+            }
+            let layouts = match args.layout {
+                Layout::Both => vec![Layout::Soa, Layout::Aos],
+                layout => vec![layout],
+            };
+
+            for layout in layouts {
+                let suffix = if args.layout == Layout::Both {
+                    format!(" ({})", layout)
+                } else {
+                    String::new()
+                };
+                plan.buffers(args, layout, size, &suffix);
+
+                let count = if args.compare_transfers {
+                    // One warm-up launch, then one per strategy
+                    1 + Transfer::ALL.len()
+                } else if let Some(repeat) = args.repeat {
+                    plan.notes.push(
+                        "--repeat takes the buffers from a pool, they are allocated once"
+                            .to_owned(),
+                    );
+                    repeat
+                } else if args.pinned_readback && args.layout != Layout::Both {
+                    plan.notes.push(
+                        "the first launch reads back pageable memory for comparison".to_owned(),
+                    );
+                    2
+                } else {
+                    1
+                };
+                plan.launch(
+                    args,
+                    layout,
+                    size,
+                    args.local_size.or(args.group_size),
+                    count,
+                )?;
+            }
+            let single_run = !(args.layout == Layout::Both
+                || args.compare_transfers
+                || args.repeat.is_some()
+                || args.readback_chunk.is_some()
+                || args.stdin);
+            if let Some(range) = args.range() {
+                let local = match range.local.as_slice() {
+                    [] => "work-groups the driver picks".to_owned(),
+                    local => format!(
+                        "work-groups of {}",
+                        local
+                            .iter()
+                            .map(usize::to_string)
+                            .collect::<Vec<_>>()
+                            .join("x")
+                    ),
+                };
+                plan.notes.push(format!(
+                    "the kernel runs over a {}D range of {} in {}, which has to cover the {} elements",
+                    range.global.len(),
+                    range,
+                    local,
+                    size
+                ));
+            } else if single_run && args.local_size.is_none() && args.group_size.is_none() {
+                plan.notes.push(
+                    "the local size is picked from the kernel's preferred work-group size multiple once it is built"
+                        .to_owned(),
+                );
+            }
+        }
+        Command::Bench(bench) => {
+            plan.command = "bench";
+            plan.buffers(args, args.layout, bench.size, "");
+            // --batch runs every batch twice, once independently and once pipelined
+            let count = match bench.batch {
+                Some(batch) => {
+                    plan.notes.push(format!(
+                        "--batch takes a set of buffers like the above for each of the {} launches of a batch from a pool",
+                        batch
+                    ));
+                    2 * batch
+                }
+                None => 1,
+            };
+            plan.launch(
+                args,
+                args.layout,
+                bench.size,
+                None,
+                count * (bench.warmup + bench.iterations),
+            )?;
+        }
+        Command::Sweep(sweep_args) => {
+            plan.command = "sweep";
+            if args.layout == Layout::Both {
+                bail!("sweep runs a single kernel, pick --layout soa or --layout aos");
+            }
+
+            let (sizes, skipped) = sweep::fitting_sizes(device, args.layout, &sweep_args.sizes())?;
+            plan.notes.extend(skipped);
+            let largest = match sizes.last() {
+                Some(&largest) => largest,
+                None => bail!("None of the requested sizes fit in device memory"),
+            };
+
+            if sweep_args.realloc_each {
+                for &size in &sizes {
+                    plan.buffers(args, args.layout, size, &format!(" ({} elements)", size));
+                }
+            } else {
+                plan.buffers(args, args.layout, largest, "");
+            }
+            // --compare-transfers warms up once per size, then runs the iterations per strategy
+            let count = if args.compare_transfers {
+                1 + Transfer::ALL.len() * sweep_args.iterations
+            } else {
+                sweep_args.iterations
+            };
+            for size in sizes {
+                plan.launch(args, args.layout, size, None, count)?;
+            }
+        }
+        Command::Tune(tune_args) => {
+            plan.command = "tune";
+            let max_local = device
+                .max_work_group_size()
+                .context("Failed to query CL_DEVICE_MAX_WORK_GROUP_SIZE")?;
+            plan.notes.push(format!(
+                "local sizes go up to the device maximum of {}, the kernel's own limit is only known once it is built",
+                max_local
+            ));
+
+            plan.buffers(args, args.layout, tune_args.size, "");
+            for local in tune::local_size_candidates(tune_args.size, max_local, tune_args.guarded) {
+                plan.launch(
+                    args,
+                    args.layout,
+                    tune_args.size,
+                    local,
+                    tune_args.iterations,
+                )?;
+            }
+        }
+        Command::Chain(chain_args) => {
+            plan.command = "chain";
+            let size = run::ARRAY_SIZE;
+            for (name, flags) in [
+                ("x", "CL_MEM_READ_ONLY"),
+                ("y", "CL_MEM_READ_ONLY"),
+                ("t", "CL_MEM_READ_WRITE | CL_MEM_HOST_NO_ACCESS"),
+                ("z", "CL_MEM_WRITE_ONLY"),
+            ] {
+                plan.buffers.push(PlannedBuffer {
+                    name: name.to_owned(),
+                    elements: size,
+                    flags: flags.to_owned(),
+                });
+            }
+            for (kernel, kernel_args) in [
+                (
+                    kernels::KERNEL_NAME,
+                    vec![
+                        "t".into(),
+                        "x".into(),
+                        "y".into(),
+                        format!("a = {}", args.scalar()),
+                    ],
+                ),
+                (
+                    kernels::VADD_KERNEL_NAME,
+                    vec!["z".into(), "t".into(), "y".into()],
+                ),
+            ] {
+                plan.launches.push(PlannedLaunch {
+                    kernel: kernel.to_owned(),
+                    options: kernels::arg_info_options(args),
+                    global: size,
+                    local: None,
+                    args: kernel_args,
+                    count: 1,
+                });
+            }
+            let bytes = (size * mem::size_of::<cl_float>()) as u64;
+            plan.bytes_to_device = 2 * bytes;
+            plan.bytes_from_device = bytes;
+            if chain_args.separate_programs {
+                plan.notes.push(format!(
+                    "{} and {} are built as two programs instead of one",
+                    kernels::KERNEL_NAME,
+                    kernels::VADD_KERNEL_NAME
+                ));
+            }
+        }
+        Command::AtomicCount(count_args) => {
+            plan.command = "atomic-count";
+            let size = count_args.size;
+            plan.buffers.push(PlannedBuffer {
+                name: "x".to_owned(),
+                elements: size,
+                flags: "CL_MEM_READ_ONLY".to_owned(),
+            });
+            plan.buffers.push(PlannedBuffer {
+                name: "count".to_owned(),
+                elements: 1,
+                flags: "CL_MEM_READ_WRITE".to_owned(),
+            });
+            let c11 = atomic_count::c11_atomics(device)?;
+            let kernels = if count_args.racy {
+                vec![atomic_count::KERNEL_NAME, atomic_count::RACY_KERNEL_NAME]
+            } else {
+                vec![atomic_count::KERNEL_NAME]
+            };
+            for kernel in &kernels {
+                plan.launches.push(PlannedLaunch {
+                    kernel: (*kernel).to_owned(),
+                    options: atomic_count::build_options(args, c11),
+                    global: size,
+                    local: None,
+                    args: vec![
+                        "x".into(),
+                        format!("threshold = {}", count_args.threshold),
+                        "count".into(),
+                        "n = size".into(),
+                    ],
+                    count: 1,
+                });
+            }
+            plan.bytes_to_device = (size * mem::size_of::<cl_float>()) as u64;
+            plan.bytes_from_device = (kernels.len() * mem::size_of::<cl_uint>()) as u64;
+            plan.notes.push(format!(
+                "the counter is zeroed with clEnqueueFillBuffer before each launch and incremented with {}",
+                if c11 {
+                    "atomic_fetch_add_explicit"
+                } else {
+                    "atomic_inc"
+                }
+            ));
+        }
+        Command::ListDevices
+        | Command::DeviceInfo
+        | Command::Repl
+        | Command::PrintConfig
+        | Command::ClearCache
+        | Command::Completions(_)
+        | Command::CompleteDevices
+        | Command::Compile
+        | Command::Serve
+        | Command::Client
+        | Command::PipeDemo(_)
+        | Command::Replay(_) => {
+            bail!(
+                "--dry-run applies to the run, bench, sweep, tune, chain and atomic-count commands"
+            )
+        }
+    }
+
+    validate(device, &plan)?;
+
+    if args.json {
+        print_json(&plan);
+    } else {
+        print_text(&plan);
+    }
+
+    Ok(())
+}
+
+/// Checks the buffers against the device's memory limits
+fn validate(device: &Device, plan: &Plan) -> anyhow::Result<()> {
+    let global_mem_size = device
+        .global_mem_size()
+        .context("Failed to query global memory size")?;
+    let max_alloc_size = device
+        .max_mem_alloc_size()
+        .context("Failed to query max allocation size")?;
+
+    for buffer in &plan.buffers {
+        if buffer.bytes() > max_alloc_size {
+            bail!(
+                "Buffer {} needs {} MiB but the device allows at most {} MiB per allocation",
+                buffer.name,
+                buffer.bytes() >> 20,
+                max_alloc_size >> 20
+            );
+        }
+    }
+
+    let total: u64 = plan.buffers.iter().map(PlannedBuffer::bytes).sum();
+    if total > global_mem_size {
+        bail!(
+            "The buffers need {} MiB but the device has {} MiB",
+            total >> 20,
+            global_mem_size >> 20
+        );
+    }
+
+    Ok(())
+}
+
+fn print_text(plan: &Plan) {
+    println!("Plan for {} on {}", plan.command, plan.device);
+
+    println!("Buffers:");
+    for buffer in &plan.buffers {
+        println!(
+            "  {:<24} {:>12} elements {:>14} bytes  {}",
+            buffer.name,
+            buffer.elements,
+            buffer.bytes(),
+            buffer.flags
+        );
+    }
+
+    println!("Launches:");
+    for launch in &plan.launches {
+        println!(
+            "  {:>4} x {} global {} local {}, options \"{}\", args [{}]",
+            launch.count,
+            launch.kernel,
+            launch.global,
+            tune::local_label(launch.local),
+            launch.options,
+            launch.args.join(", ")
+        );
+    }
+
+    println!(
+        "Transfers: {} bytes to the device, {} bytes from the device",
+        plan.bytes_to_device, plan.bytes_from_device
+    );
+
+    for note in &plan.notes {
+        println!("note: {}", note);
+    }
+}
+
+fn print_json(plan: &Plan) {
+    let buffers: Vec<String> = plan
+        .buffers
+        .iter()
+        .map(|buffer| {
+            format!(
+                "{{\"name\": {}, \"elements\": {}, \"bytes\": {}, \"flags\": {}}}",
+                json_string(&buffer.name),
+                buffer.elements,
+                buffer.bytes(),
+                json_string(&buffer.flags)
+            )
+        })
+        .collect();
+
+    let launches: Vec<String> = plan
+        .launches
+        .iter()
+        .map(|launch| {
+            let args: Vec<String> = launch.args.iter().map(|arg| json_string(arg)).collect();
+            format!(
+                "{{\"kernel\": {}, \"options\": {}, \"global\": {}, \"local\": {}, \"args\": [{}], \"count\": {}}}",
+                json_string(&launch.kernel),
+                json_string(&launch.options),
+                launch.global,
+                launch
+                    .local
+                    .map_or_else(|| "null".to_owned(), |local| local.to_string()),
+                args.join(", "),
+                launch.count
+            )
+        })
+        .collect();
+
+    let notes: Vec<String> = plan.notes.iter().map(|note| json_string(note)).collect();
+
+    let mut json = String::new();
+    writeln!(json, "{{").unwrap();
+    writeln!(json, "  \"command\": {},", json_string(plan.command)).unwrap();
+    writeln!(json, "  \"device\": {},", json_string(&plan.device)).unwrap();
+    writeln!(json, "  \"buffers\": [{}],", buffers.join(", ")).unwrap();
+    writeln!(json, "  \"launches\": [{}],", launches.join(", ")).unwrap();
+    writeln!(json, "  \"bytes_to_device\": {},", plan.bytes_to_device).unwrap();
+    writeln!(json, "  \"bytes_from_device\": {},", plan.bytes_from_device).unwrap();
+    writeln!(json, "  \"notes\": [{}]", notes.join(", ")).unwrap();
+    json.push('}');
+
+    println!("{}", json);
+}
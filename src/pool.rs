@@ -0,0 +1,72 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+use std::{collections::HashMap, ptr};
+
+use opencl3::{
+    context::Context,
+    memory::Buffer,
+    types::{cl_float, cl_mem_flags},
+};
+
+use crate::{error::ClContext, retry};
+
+/// Hands out `Buffer<cl_float>`s and takes them back so repeated runs don't churn the driver allocator
+///
+/// Buffers are keyed by their creation flags and length, `take` only reuses a buffer that matches both.
+///
+/// # Safety invariant
+///
+/// A buffer must only be given back once every command that touches it has completed, i.e. after
+/// the events of the writes, kernels and reads using it have been waited on. The next `take` hands
+/// the same `cl_mem` to a new run, so a buffer returned while still in flight would be overwritten
+/// by the next upload while the previous kernel or readback is still using it.
+#[derive(Default)]
+pub struct BufferPool {
+    free: HashMap<(cl_mem_flags, usize), Vec<Buffer<cl_float>>>,
+    allocations: usize,
+    reuses: usize,
+}
+
+impl BufferPool {
+    /// Returns a free buffer with the given `flags` and `len`, allocating one if there is none
+    pub fn take(
+        &mut self,
+        context: &Context,
+        flags: cl_mem_flags,
+        len: usize,
+    ) -> anyhow::Result<Buffer<cl_float>> {
+        if let Some(buffer) = self.free.get_mut(&(flags, len)).and_then(Vec::pop) {
+            self.reuses += 1;
+            return Ok(buffer);
+        }
+
+        let buffer = retry::call("clCreateBuffer", || unsafe {
+            Buffer::<cl_float>::create(context, flags, len, ptr::null_mut())
+        })
+        .context("Failed to create buffer")?;
+        self.allocations += 1;
+
+        Ok(buffer)
+    }
+
+    /// Gives `buffer` back to the pool for reuse
+    ///
+    /// # Safety
+    ///
+    /// No command using `buffer` may still be pending, see the [`BufferPool`] safety invariant.
+    /// `flags` and `len` must be the values the buffer was taken with.
+    pub unsafe fn put(&mut self, buffer: Buffer<cl_float>, flags: cl_mem_flags, len: usize) {
+        self.free.entry((flags, len)).or_default().push(buffer);
+    }
+
+    /// Number of buffers actually allocated on the device
+    pub fn allocations(&self) -> usize {
+        self.allocations
+    }
+
+    /// Number of `take`s served by a previously returned buffer
+    pub fn reuses(&self) -> usize {
+        self.reuses
+    }
+}
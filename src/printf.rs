@@ -0,0 +1,134 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+//! Device-side `printf` for `--enable-printf`
+//!
+//! The built-in kernels print every result they compute in a block guarded by `DEBUG_PRINT`, which
+//! `--enable-printf` defines (kernel files can use the same define). The driver writes the output
+//! to the process' C `stdout` whenever it likes, usually when the kernel finishes, so [`begin`] and
+//! [`end`] frame it with marker lines on stdout: [`end`] waits for the queue and flushes the C
+//! streams before the closing marker, so the summary printed after it never interleaves with it.
+
+use std::{
+    ffi::{c_int, c_void},
+    io::{self, Write as _},
+};
+
+use anyhow::Context as _;
+use opencl3::{command_queue::CommandQueue, device::Device};
+
+use crate::error::ClContext;
+
+/// The define the `printf` blocks of the kernels are guarded by
+pub const DEFINE: &str = "DEBUG_PRINT";
+
+/// A generous estimate of one line the built-in kernels print, a kernel name, an index and a float
+const LINE_BYTES: usize = 64;
+
+const BEGIN: &str = "----- device printf -----";
+const END: &str = "----- end of device printf -----";
+
+extern "C" {
+    fn fflush(stream: *mut c_void) -> c_int;
+}
+
+/// Warns when `CL_DEVICE_PRINTF_BUFFER_SIZE` can't hold a line from each of `items` work-items,
+/// drivers drop what doesn't fit, and prints the opening marker
+///
+/// The size is fixed by the driver, OpenCL has no API to enlarge it
+pub fn begin(device: &Device, items: usize) -> anyhow::Result<()> {
+    let buffer = device
+        .printf_buffer_size()
+        .context("Failed to query CL_DEVICE_PRINTF_BUFFER_SIZE")?;
+    let needed = items.saturating_mul(LINE_BYTES);
+    if needed > buffer {
+        tracing::warn!(
+            "{} work-items print about {} bytes but the device's printf buffer holds {}, lines past it may be lost, run fewer elements",
+            items,
+            needed,
+            buffer
+        );
+    } else {
+        tracing::debug!("printf buffer of {} bytes for about {}", buffer, needed);
+    }
+
+    println!("{}", BEGIN);
+    io::stdout().flush().context("Failed to flush stdout")
+}
+
+/// Waits for everything on `queue`, flushes the driver's output and prints the closing marker
+pub fn end(queue: &CommandQueue) -> anyhow::Result<()> {
+    queue.finish().context("Failed to finish queue")?;
+    // A null stream flushes every C output stream, the one the driver printed to among them
+    if unsafe { fflush(std::ptr::null_mut()) } != 0 {
+        tracing::warn!("Failed to flush the device printf output");
+    }
+
+    println!("{}", END);
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{fs, os::fd::AsRawFd};
+
+    use opencl3::{
+        memory::{CL_MEM_READ_ONLY, CL_MEM_WRITE_ONLY},
+        types::cl_float,
+    };
+
+    use super::*;
+    use crate::{kernels, launch::launch, saxpy, test_device};
+
+    extern "C" {
+        fn dup(fd: c_int) -> c_int;
+        fn dup2(fd: c_int, new_fd: c_int) -> c_int;
+        fn close(fd: c_int) -> c_int;
+    }
+
+    #[test]
+    fn one_line_per_work_item() {
+        let Some(test) = test_device::get() else {
+            return;
+        };
+        // Not a multiple of any usual work-group size
+        let items = 37;
+        let kernel = test_device::saxpy_kernel_with(&test, &format!("-D {}", DEFINE));
+        let x = saxpy::create_buffer(&test.context, CL_MEM_READ_ONLY, items).unwrap();
+        let y = saxpy::create_buffer(&test.context, CL_MEM_READ_ONLY, items).unwrap();
+        let z = saxpy::create_buffer(&test.context, CL_MEM_WRITE_ONLY, items).unwrap();
+        let a: cl_float = 2.0;
+
+        // The driver prints to file descriptor 1, which the test harness doesn't capture
+        let path =
+            std::env::temp_dir().join(format!("hello-opencl3-printf-{}", std::process::id()));
+        let file = fs::File::create(&path).unwrap();
+        let stdout = unsafe {
+            fflush(std::ptr::null_mut());
+            let stdout = dup(1);
+            dup2(file.as_raw_fd(), 1);
+            stdout
+        };
+        let launched = unsafe {
+            launch!(&kernel, global = items; z: &z, x: &x, y: &y, a: &a)
+                .enqueue_nd_range(&test.queue)
+        };
+        let ended = end(&test.queue);
+        unsafe {
+            dup2(stdout, 1);
+            close(stdout);
+        }
+        launched.unwrap();
+        ended.unwrap();
+
+        let output = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let prefix = format!("{} z[", kernels::KERNEL_NAME);
+        let mut indices: Vec<usize> = output
+            .lines()
+            .filter_map(|line| line.strip_prefix(&prefix)?.split(']').next()?.parse().ok())
+            .collect();
+        indices.sort_unstable();
+        assert_eq!(indices, (0..items).collect::<Vec<_>>(), "{}", output);
+    }
+}
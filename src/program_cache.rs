@@ -0,0 +1,256 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+//! On-disk cache of program binaries
+//!
+//! After a successful build the `CL_PROGRAM_BINARIES` of every device are stored in [`dir`] under
+//! a hash of the source, the build options and the name and driver version of each device. A
+//! later build of the same program creates it from the binaries and skips compilation. Whatever
+//! goes wrong with a cache file (missing, truncated, written by another driver) only logs and
+//! makes the caller compile the source, a run never fails because of the cache.
+
+use std::{
+    env, fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    process,
+};
+
+use anyhow::Context as _;
+use opencl3::{context::Context, device::Device, program::Program, types::cl_device_id};
+
+/// Start of every cache file, the last byte is the version of the layout
+const MAGIC: &[u8; 8] = b"HOCLPRG\x01";
+
+const EXTENSION: &str = "bin";
+
+/// The directory the binaries are kept in: `$XDG_CACHE_HOME/hello-opencl3/programs`, falling back
+/// to the platform's cache directory, `None` when no home directory is known
+pub fn dir() -> Option<PathBuf> {
+    let base = if cfg!(windows) {
+        env::var_os("LOCALAPPDATA").map(PathBuf::from)
+    } else if let Some(cache) = env::var_os("XDG_CACHE_HOME").filter(|dir| !dir.is_empty()) {
+        Some(PathBuf::from(cache))
+    } else if cfg!(target_os = "macos") {
+        env::var_os("HOME").map(|home| Path::new(&home).join("Library/Caches"))
+    } else {
+        env::var_os("HOME").map(|home| Path::new(&home).join(".cache"))
+    };
+
+    base.map(|base| base.join("hello-opencl3").join("programs"))
+}
+
+/// The cache key of `source` built with `options` for `devices`
+///
+/// `None` when the program can't be cached: a device doesn't report its name or driver version,
+/// or the source includes headers, whose contents the key wouldn't cover
+pub fn key(source: &str, options: &str, devices: &[cl_device_id]) -> Option<u64> {
+    if source.contains("#include") {
+        tracing::debug!("Not caching a program that includes headers");
+        return None;
+    }
+
+    let mut hash = Fnv1a::default();
+    hash.field(source.as_bytes());
+    hash.field(options.as_bytes());
+    for &id in devices {
+        let device = Device::new(id);
+        hash.field(device.name().ok()?.as_bytes());
+        hash.field(device.driver_version().ok()?.as_bytes());
+    }
+
+    Some(hash.0)
+}
+
+/// Creates the program cached under `key` for the devices of `context` and builds it with
+/// `options`, `None` on a miss or when the cached binaries can't be used
+pub fn load(context: &Context, key: u64, options: &str) -> Option<Program> {
+    let path = dir()?.join(file_name(key));
+    let data = match fs::read(&path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == ErrorKind::NotFound => return None,
+        Err(err) => {
+            tracing::warn!("Failed to read cached program {}: {}", path.display(), err);
+            return None;
+        }
+    };
+
+    let devices = context.devices();
+    let binaries = match decode(&data) {
+        Some(binaries) if binaries.len() == devices.len() => binaries,
+        _ => {
+            tracing::warn!(
+                "Ignoring corrupted cached program {}, building from source",
+                path.display()
+            );
+            return None;
+        }
+    };
+
+    // SAFETY: the devices are the ones of `context`
+    let program = unsafe { Program::create_from_binary(context, devices, &binaries) };
+    let mut program = match program {
+        Ok(program) => program,
+        Err(err) => {
+            tracing::warn!(
+                "Cached program {} was rejected ({}), building from source",
+                path.display(),
+                err
+            );
+            return None;
+        }
+    };
+    if let Err(err) = program.build(devices, options) {
+        tracing::warn!(
+            "Failed to build cached program {} ({}), building from source",
+            path.display(),
+            err
+        );
+        return None;
+    }
+
+    Some(program)
+}
+
+/// Stores the binaries of the built `program` under `key`, failures are only logged
+pub fn store(program: &Program, key: u64) {
+    let Some(dir) = dir() else {
+        tracing::debug!("No cache directory, not caching the program");
+        return;
+    };
+
+    let binaries = match program.get_binaries() {
+        Ok(binaries) if binaries.iter().all(|binary| !binary.is_empty()) => binaries,
+        Ok(_) => {
+            tracing::debug!("The driver returned no program binary, not caching it");
+            return;
+        }
+        Err(err) => {
+            tracing::warn!("Failed to query the program binaries: {}", err);
+            return;
+        }
+    };
+
+    // Written next to the final name and renamed, so concurrent runs never see a partial file
+    let path = dir.join(file_name(key));
+    let partial = dir.join(format!("{:016x}.{}.tmp", key, process::id()));
+    let written = fs::create_dir_all(&dir)
+        .and_then(|()| fs::write(&partial, encode(&binaries)))
+        .and_then(|()| fs::rename(&partial, &path));
+    match written {
+        Ok(()) => tracing::debug!("Cached program binaries in {}", path.display()),
+        Err(err) => {
+            let _ = fs::remove_file(&partial);
+            tracing::warn!("Failed to cache program in {}: {}", path.display(), err);
+        }
+    }
+}
+
+/// `cache clear`: removes every cached program
+pub fn clear() -> anyhow::Result<()> {
+    let Some(dir) = dir() else {
+        anyhow::bail!("No cache directory, neither XDG_CACHE_HOME nor HOME is set");
+    };
+
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            println!("No cached programs in {}", dir.display());
+            return Ok(());
+        }
+        Err(err) => {
+            return Err(err).with_context(|| format!("Failed to list {}", dir.display()));
+        }
+    };
+
+    let mut removed = 0;
+    for entry in entries {
+        let path = entry
+            .with_context(|| format!("Failed to list {}", dir.display()))?
+            .path();
+        if path
+            .extension()
+            .is_some_and(|extension| extension == EXTENSION || extension == "tmp")
+        {
+            fs::remove_file(&path)
+                .with_context(|| format!("Failed to remove {}", path.display()))?;
+            removed += 1;
+        }
+    }
+
+    println!("Removed {} cached programs from {}", removed, dir.display());
+    Ok(())
+}
+
+fn file_name(key: u64) -> String {
+    format!("{:016x}.{}", key, EXTENSION)
+}
+
+/// The magic, the number of binaries as a little-endian `u64`, each binary preceded by its length
+/// as a little-endian `u64`, and the FNV-1a hash of everything before it
+fn encode(binaries: &[Vec<u8>]) -> Vec<u8> {
+    let mut data = MAGIC.to_vec();
+    data.extend_from_slice(&(binaries.len() as u64).to_le_bytes());
+    for binary in binaries {
+        data.extend_from_slice(&(binary.len() as u64).to_le_bytes());
+        data.extend_from_slice(binary);
+    }
+
+    let mut hash = Fnv1a::default();
+    hash.write(&data);
+    data.extend_from_slice(&hash.0.to_le_bytes());
+    data
+}
+
+/// The binaries of a file written by [`encode`], `None` if anything about it is off
+fn decode(data: &[u8]) -> Option<Vec<&[u8]>> {
+    let (body, checksum) = data.split_at(data.len().checked_sub(8)?);
+    let mut hash = Fnv1a::default();
+    hash.write(body);
+    if checksum != hash.0.to_le_bytes() {
+        return None;
+    }
+
+    let mut rest = body.strip_prefix(MAGIC)?;
+    let mut next = |len: usize| {
+        let current = rest;
+        let (head, tail) = (current.get(..len)?, current.get(len..)?);
+        rest = tail;
+        Some(head)
+    };
+    let read_len = |bytes: &[u8]| usize::try_from(u64::from_le_bytes(bytes.try_into().ok()?)).ok();
+
+    let count = read_len(next(8)?)?;
+    let mut binaries = Vec::new();
+    for _ in 0..count {
+        let len = read_len(next(8)?)?;
+        binaries.push(next(len)?);
+    }
+
+    Some(binaries)
+}
+
+/// 64-bit FNV-1a, unlike `DefaultHasher` it is stable across Rust versions, so keys computed by
+/// different builds agree
+pub struct Fnv1a(pub u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Fnv1a {
+    pub fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= u64::from(byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    /// Hashes `bytes` preceded by their length, so adjacent fields can't run into each other
+    fn field(&mut self, bytes: &[u8]) {
+        self.write(&(bytes.len() as u64).to_le_bytes());
+        self.write(bytes);
+    }
+}
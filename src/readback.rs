@@ -0,0 +1,86 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+//! Reading a result buffer back in chunks instead of in one piece
+
+use std::mem;
+
+use opencl3::{
+    command_queue::CommandQueue,
+    memory::{Buffer, ClMem},
+    types::cl_float,
+    types::CL_BLOCKING,
+};
+
+use crate::{error::ClContext, retry, trace};
+
+/// Iterator over `len` elements of a device buffer, read back `chunk_size` elements at a time
+///
+/// Each step is one blocking `enqueue_read_buffer` at the next offset, so no more than a chunk of
+/// host memory is needed at once and consumers can process the results while the rest is still on
+/// the device. Small chunks trade that for more transfers, each paying the enqueue and
+/// synchronization overhead, which dominates below a few hundred KiB on discrete GPUs.
+///
+/// The chunks are owned `Vec`s: an `Iterator` can't hand out slices of a buffer it reuses, and a
+/// chunk is only allocated once the previous one has been handed over.
+pub struct ResultChunks<'a> {
+    queue: &'a CommandQueue,
+    buffer: &'a Buffer<cl_float>,
+    len: usize,
+    offset: usize,
+    chunk_size: usize,
+}
+
+impl<'a> ResultChunks<'a> {
+    /// Reads the first `len` elements of `buffer`, whose producer has to have completed
+    pub fn new(
+        queue: &'a CommandQueue,
+        buffer: &'a Buffer<cl_float>,
+        len: usize,
+        chunk_size: usize,
+    ) -> Self {
+        Self {
+            queue,
+            buffer,
+            len,
+            offset: 0,
+            chunk_size: chunk_size.max(1),
+        }
+    }
+}
+
+impl Iterator for ResultChunks<'_> {
+    type Item = anyhow::Result<Vec<cl_float>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.len {
+            return None;
+        }
+
+        let mut chunk = vec![0.0; self.chunk_size.min(self.len - self.offset)];
+        // The offset is in bytes
+        let read = retry::call("clEnqueueReadBuffer", || unsafe {
+            self.queue.enqueue_read_buffer(
+                self.buffer,
+                CL_BLOCKING,
+                self.offset * mem::size_of::<cl_float>(),
+                &mut chunk,
+                &[],
+            )
+        });
+        if let Err(err) = read.context("Failed to read buffer chunk") {
+            // Stop after an error rather than retrying the same chunk forever
+            self.offset = self.len;
+            return Some(Err(err));
+        }
+
+        trace::read(self.buffer.get(), self.offset, &chunk);
+        self.offset += chunk.len();
+        Some(Ok(chunk))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let chunks = (self.len - self.offset).div_ceil(self.chunk_size);
+        (chunks, Some(chunks))
+    }
+}
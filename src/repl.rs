@@ -0,0 +1,404 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+//! The `repl` command: keeps a context and queue alive while kernels are edited and rerun
+//!
+//! Lines are read straight from stdin, so there is no line editing beyond what the terminal
+//! provides, but the session keeps its own history: `history` lists the lines entered so far and
+//! `!n` runs line `n` again. Every command reports its error and the session carries on.
+
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::{self, BufRead, Write},
+    ptr,
+    time::Instant,
+};
+
+use anyhow::{anyhow, bail, Context as _};
+use opencl3::{
+    command_queue::CommandQueue,
+    context::Context,
+    device::Device,
+    kernel::Kernel,
+    memory::{Buffer, CL_MEM_READ_WRITE},
+    program::Program,
+    types::{cl_float, cl_int, cl_uint, CL_BLOCKING},
+};
+
+use crate::{error::ClContext, kernels, launch::CheckedKernel, saxpy};
+
+const HELP: &str = "\
+Commands:
+    load <file.cl>                        Read kernel source from a file
+    build [options]                       Build the loaded source, printing the build log
+    alloc <name> <len> <float|int|uint>   Create a zeroed device buffer
+    fill <name> <value|iota>              Fill a buffer with a value or with 0, 1, 2, ...
+    set <arg-index> <buffer|scalar>       Bind a buffer or a scalar (1.5 is a float, 3 an int,
+                                          3u a uint) to a kernel argument
+    run <kernel> <global_size> [local_size]
+                                          Launch a kernel of the built program with the bound args
+    read <name> [count]                   Print the first count elements of a buffer [default: 16]
+    history                               List the lines entered so far
+    !<n>                                  Run line n of the history again
+    help                                  Print this help
+    quit                                  Leave the repl
+";
+
+/// A device buffer created by `alloc`
+enum ReplBuffer {
+    Float(Buffer<cl_float>),
+    Int(Buffer<cl_int>),
+    Uint(Buffer<cl_uint>),
+}
+
+/// A value bound to a kernel argument by `set`
+enum ArgValue {
+    Buffer(String),
+    Float(cl_float),
+    Int(cl_int),
+    Uint(cl_uint),
+}
+
+impl fmt::Display for ArgValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Buffer(name) => write!(f, "buffer {}", name),
+            Self::Float(value) => write!(f, "float {}", value),
+            Self::Int(value) => write!(f, "int {}", value),
+            Self::Uint(value) => write!(f, "uint {}", value),
+        }
+    }
+}
+
+struct Repl<'a> {
+    context: &'a Context,
+    queue: &'a CommandQueue,
+    device: &'a Device,
+    source: Option<String>,
+    program: Option<Program>,
+    /// The kernels of `program` by name, created once per build so `run` can switch between them
+    kernels: HashMap<String, Kernel>,
+    buffers: HashMap<String, (ReplBuffer, usize)>,
+    args: Vec<Option<ArgValue>>,
+}
+
+/// Reads commands from stdin until `quit` or the end of input
+pub fn run(context: &Context, queue: &CommandQueue, device: &Device) -> anyhow::Result<()> {
+    let mut repl = Repl {
+        context,
+        queue,
+        device,
+        source: None,
+        program: None,
+        kernels: HashMap::new(),
+        buffers: HashMap::new(),
+        args: Vec::new(),
+    };
+
+    println!("Type 'help' for the list of commands");
+
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    let mut history: Vec<String> = Vec::new();
+    loop {
+        print!("> ");
+        io::stdout().flush()?;
+
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => break,
+        };
+        let line = match recall(&history, &line) {
+            Ok(line) => line,
+            Err(err) => {
+                println!("error: {:#}", err);
+                continue;
+            }
+        };
+        if !line.trim().is_empty() {
+            history.push(line.clone());
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+
+        match words.as_slice() {
+            [] => {}
+            ["quit" | "exit"] => break,
+            ["history"] => {
+                for (i, line) in history.iter().enumerate() {
+                    println!("{:>4}  {}", i + 1, line);
+                }
+            }
+            words => {
+                if let Err(err) = repl.execute(words) {
+                    println!("error: {:#}", err);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+impl Repl<'_> {
+    fn execute(&mut self, words: &[&str]) -> anyhow::Result<()> {
+        match words {
+            ["help"] => print!("{}", HELP),
+            ["load", path] => {
+                let source =
+                    fs::read_to_string(path).with_context(|| format!("Failed to read {}", path))?;
+                println!("loaded {} ({} bytes)", path, source.len());
+                self.source = Some(source);
+            }
+            ["build", options @ ..] => self.build(&options.join(" "))?,
+            ["alloc", name, len, dtype] => self.alloc(name, parse(len)?, dtype)?,
+            ["fill", name, value] => self.fill(name, value)?,
+            ["set", index, value] => {
+                let index: usize = parse(index)?;
+                let value = self.parse_arg(value)?;
+                println!("arg {} = {}", index, value);
+
+                if self.args.len() <= index {
+                    self.args.resize_with(index + 1, || None);
+                }
+                self.args[index] = Some(value);
+            }
+            ["run", kernel, global] => self.launch(kernel, parse(global)?, None)?,
+            ["run", kernel, global, local] => {
+                self.launch(kernel, parse(global)?, Some(parse(local)?))?
+            }
+            ["read", name] => self.read(name, 16)?,
+            ["read", name, count] => self.read(name, parse(count)?)?,
+            _ => bail!("Unknown command '{}', type 'help'", words.join(" ")),
+        }
+
+        Ok(())
+    }
+
+    fn build(&mut self, options: &str) -> anyhow::Result<()> {
+        let source = self
+            .source
+            .as_deref()
+            .ok_or_else(|| anyhow!("Nothing loaded, use 'load <file.cl>' first"))?;
+
+        let start = Instant::now();
+        let mut program = Program::create_from_source(self.context, source)
+            .context("Program::create_from_source failed")?;
+        let built = program.build(self.context.devices(), options);
+        let elapsed = start.elapsed();
+
+        let log = program
+            .get_build_log(self.device.id())
+            .context("Failed to query CL_PROGRAM_BUILD_LOG")?;
+        if !log.trim().is_empty() {
+            println!("{}", log.trim_end());
+        }
+
+        built.context("Build failed")?;
+        self.kernels = kernels::program_kernels(&program)?;
+        let mut names: Vec<&str> = self.kernels.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        println!(
+            "built in {:.3} ms, kernels: {}",
+            elapsed.as_secs_f64() * 1e3,
+            names.join(", ")
+        );
+        self.program = Some(program);
+
+        Ok(())
+    }
+
+    fn alloc(&mut self, name: &str, len: usize, dtype: &str) -> anyhow::Result<()> {
+        let buffer = match dtype {
+            "float" => ReplBuffer::Float(create_buffer(self.context, len)?),
+            "int" => ReplBuffer::Int(create_buffer(self.context, len)?),
+            "uint" => ReplBuffer::Uint(create_buffer(self.context, len)?),
+            _ => bail!(
+                "Invalid dtype '{}', expected one of: float, int, uint",
+                dtype
+            ),
+        };
+
+        self.buffers.insert(name.to_owned(), (buffer, len));
+        self.fill(name, "0")
+    }
+
+    fn fill(&mut self, name: &str, value: &str) -> anyhow::Result<()> {
+        let (buffer, len) = self
+            .buffers
+            .get_mut(name)
+            .ok_or_else(|| anyhow!("No buffer called '{}'", name))?;
+        let len = *len;
+        let iota = value == "iota";
+
+        match buffer {
+            ReplBuffer::Float(buffer) => {
+                let value: cl_float = if iota { 0.0 } else { parse(value)? };
+                let data: Vec<cl_float> = (0..len)
+                    .map(|i| if iota { i as cl_float } else { value })
+                    .collect();
+                write_buffer(self.queue, buffer, &data)
+            }
+            ReplBuffer::Int(buffer) => {
+                let value: cl_int = if iota { 0 } else { parse(value)? };
+                let data: Vec<cl_int> = (0..len)
+                    .map(|i| if iota { i as cl_int } else { value })
+                    .collect();
+                write_buffer(self.queue, buffer, &data)
+            }
+            ReplBuffer::Uint(buffer) => {
+                let value: cl_uint = if iota { 0 } else { parse(value)? };
+                let data: Vec<cl_uint> = (0..len)
+                    .map(|i| if iota { i as cl_uint } else { value })
+                    .collect();
+                write_buffer(self.queue, buffer, &data)
+            }
+        }
+    }
+
+    fn parse_arg(&self, value: &str) -> anyhow::Result<ArgValue> {
+        if self.buffers.contains_key(value) {
+            return Ok(ArgValue::Buffer(value.to_owned()));
+        }
+
+        if let Some(value) = value.strip_suffix('u') {
+            Ok(ArgValue::Uint(parse(value)?))
+        } else if value.contains(['.', 'e']) || value.ends_with('f') {
+            Ok(ArgValue::Float(parse(value.trim_end_matches('f'))?))
+        } else if let Ok(value) = value.parse() {
+            Ok(ArgValue::Int(value))
+        } else {
+            bail!("'{}' is neither a buffer nor a number", value)
+        }
+    }
+
+    fn launch(&self, name: &str, global: usize, local: Option<usize>) -> anyhow::Result<()> {
+        if self.program.is_none() {
+            bail!("No program built, use 'build' first");
+        }
+        let Some(kernel) = self.kernels.get(name) else {
+            let mut names: Vec<&str> = self.kernels.keys().map(String::as_str).collect();
+            names.sort_unstable();
+            bail!(
+                "The program has no kernel called '{}', it defines: {}",
+                name,
+                names.join(", ")
+            );
+        };
+
+        // Bound through CheckedKernel, so mismatched types and a global size past the end of a
+        // buffer fail here instead of in the driver
+        let mut launch = CheckedKernel::new(kernel);
+        let num_args = kernel
+            .num_args()
+            .context("Failed to query CL_KERNEL_NUM_ARGS")? as usize;
+        for index in 0..num_args {
+            let arg = self
+                .args
+                .get(index)
+                .and_then(Option::as_ref)
+                .ok_or_else(|| anyhow!("Argument {} of {} isn't set", index, name))?;
+
+            unsafe {
+                match arg {
+                    ArgValue::Buffer(buffer) => {
+                        let (buffer, _) = self
+                            .buffers
+                            .get(buffer)
+                            .ok_or_else(|| anyhow!("No buffer called '{}'", buffer))?;
+                        match buffer {
+                            ReplBuffer::Float(buffer) => launch.set_buffer(buffer),
+                            ReplBuffer::Int(buffer) => launch.set_buffer(buffer),
+                            ReplBuffer::Uint(buffer) => launch.set_buffer(buffer),
+                        }
+                    }
+                    ArgValue::Float(value) => launch.set_scalar(value),
+                    ArgValue::Int(value) => launch.set_scalar(value),
+                    ArgValue::Uint(value) => launch.set_scalar(value),
+                };
+            }
+        }
+
+        launch.set_global_work_size(global);
+        if let Some(local) = local {
+            launch.set_local_work_size(local);
+        }
+        let event = unsafe { launch.enqueue_nd_range(self.queue) }?;
+        event.wait().context("Failed to wait for the kernel")?;
+
+        let kernel_ns = saxpy::profiled_ns(&event)?;
+        println!("{} ran in {:.3} us", name, kernel_ns as f64 / 1e3);
+
+        Ok(())
+    }
+
+    fn read(&self, name: &str, count: usize) -> anyhow::Result<()> {
+        let (buffer, len) = self
+            .buffers
+            .get(name)
+            .ok_or_else(|| anyhow!("No buffer called '{}'", name))?;
+        let count = count.min(*len);
+
+        let values: Vec<String> = match buffer {
+            ReplBuffer::Float(buffer) => read_buffer(self.queue, buffer, count)?
+                .iter()
+                .map(|v| v.to_string())
+                .collect(),
+            ReplBuffer::Int(buffer) => read_buffer(self.queue, buffer, count)?
+                .iter()
+                .map(|v| v.to_string())
+                .collect(),
+            ReplBuffer::Uint(buffer) => read_buffer(self.queue, buffer, count)?
+                .iter()
+                .map(|v| v.to_string())
+                .collect(),
+        };
+        println!("{}[..{}] = [{}]", name, count, values.join(", "));
+
+        Ok(())
+    }
+}
+
+/// `line`, or the line of `history` it recalls with `!n`, echoed as it runs
+fn recall(history: &[String], line: &str) -> anyhow::Result<String> {
+    let Some(n) = line.trim().strip_prefix('!') else {
+        return Ok(line.to_owned());
+    };
+    let recalled = parse::<usize>(n)?
+        .checked_sub(1)
+        .and_then(|i| history.get(i))
+        .ok_or_else(|| anyhow!("No line {} in the history, it has {}", n, history.len()))?;
+    println!("{}", recalled);
+
+    Ok(recalled.clone())
+}
+
+fn parse<T: std::str::FromStr>(value: &str) -> anyhow::Result<T> {
+    value
+        .parse()
+        .map_err(|_| anyhow!("Invalid number '{}'", value))
+}
+
+fn create_buffer<T>(context: &Context, len: usize) -> anyhow::Result<Buffer<T>> {
+    unsafe { Buffer::<T>::create(context, CL_MEM_READ_WRITE, len.max(1), ptr::null_mut()) }
+        .context("Failed to create buffer")
+}
+
+fn write_buffer<T>(queue: &CommandQueue, buffer: &mut Buffer<T>, data: &[T]) -> anyhow::Result<()> {
+    unsafe { queue.enqueue_write_buffer(buffer, CL_BLOCKING, 0, data, &[]) }
+        .context("Failed to write to buffer")?;
+
+    Ok(())
+}
+
+fn read_buffer<T: Clone + Default>(
+    queue: &CommandQueue,
+    buffer: &Buffer<T>,
+    count: usize,
+) -> anyhow::Result<Vec<T>> {
+    let mut data = vec![T::default(); count];
+    unsafe { queue.enqueue_read_buffer(buffer, CL_BLOCKING, 0, &mut data, &[]) }
+        .context("Failed to read buffer")?;
+
+    Ok(data)
+}
@@ -0,0 +1,125 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+//! What a built kernel needs on the device, and a rough occupancy estimate from it
+//!
+//! The limits come from `clGetKernelWorkGroupInfo` for the device the kernel runs on. The estimate
+//! compares two shares: the local size against `CL_KERNEL_WORK_GROUP_SIZE`, and the local memory of
+//! a work-group against `CL_DEVICE_LOCAL_MEM_SIZE`. When the second is larger, local memory caps the
+//! work-groups a compute unit can hold before their size does, which is what to look at when a
+//! kernel's tile size is tuned.
+
+use opencl3::{device::Device, kernel::Kernel};
+
+use crate::{error::ClContext, summary};
+
+/// The work-group info of a kernel on one device
+pub struct KernelResources {
+    /// `CL_KERNEL_WORK_GROUP_SIZE`, the largest local size the kernel can be launched with
+    pub work_group_size: usize,
+    /// `CL_KERNEL_PREFERRED_WORK_GROUP_SIZE_MULTIPLE`
+    pub preferred_multiple: usize,
+    /// `CL_KERNEL_LOCAL_MEM_SIZE`, local memory of one work-group in bytes
+    pub local_mem: u64,
+    /// `CL_KERNEL_PRIVATE_MEM_SIZE`, private memory of one work-item in bytes
+    pub private_mem: u64,
+    /// `CL_DEVICE_LOCAL_MEM_SIZE`
+    pub device_local_mem: u64,
+}
+
+impl KernelResources {
+    pub fn query(kernel: &Kernel, device: &Device) -> anyhow::Result<Self> {
+        let id = device.id();
+        Ok(Self {
+            work_group_size: kernel
+                .get_work_group_size(id)
+                .context("Failed to query CL_KERNEL_WORK_GROUP_SIZE")?,
+            preferred_multiple: kernel
+                .get_work_group_size_multiple(id)
+                .context("Failed to query CL_KERNEL_PREFERRED_WORK_GROUP_SIZE_MULTIPLE")?,
+            local_mem: kernel
+                .get_local_mem_size(id)
+                .context("Failed to query CL_KERNEL_LOCAL_MEM_SIZE")?,
+            private_mem: kernel
+                .get_private_mem_size(id)
+                .context("Failed to query CL_KERNEL_PRIVATE_MEM_SIZE")?,
+            device_local_mem: device
+                .local_mem_size()
+                .context("Failed to query CL_DEVICE_LOCAL_MEM_SIZE")?,
+        })
+    }
+
+    /// Share of the maximum work-group size a launch with `local_size` uses, `None` when the
+    /// driver picks the local size
+    pub fn work_group_share(&self, local_size: Option<usize>) -> Option<f64> {
+        local_size.map(|local| local as f64 / self.work_group_size.max(1) as f64)
+    }
+
+    /// Share of the device's local memory one work-group takes
+    pub fn local_mem_share(&self) -> f64 {
+        self.local_mem as f64 / self.device_local_mem.max(1) as f64
+    }
+
+    /// Whether local memory runs out before the work-group size does, see the module docs
+    ///
+    /// With the local size left to the driver the full work-group size is assumed
+    pub fn local_mem_limited(&self, local_size: Option<usize>) -> bool {
+        self.local_mem > 0
+            && self.local_mem_share() > self.work_group_share(local_size).unwrap_or(1.0)
+    }
+
+    /// Logs the resources and the occupancy estimate of a launch with `local_size`
+    pub fn log(&self, name: &str, local_size: Option<usize>) {
+        tracing::info!(
+            "{}: work-group size up to {} (preferred multiple {}), {} local memory per work-group, {} private memory per work-item",
+            name,
+            self.work_group_size,
+            self.preferred_multiple,
+            summary::bytes(self.local_mem),
+            summary::bytes(self.private_mem)
+        );
+        if self.local_mem_limited(local_size) {
+            tracing::warn!(
+                "{}: local memory is the limiting factor, a work-group takes {:.1}% of the device's {} of local memory",
+                name,
+                self.local_mem_share() * 100.0,
+                summary::bytes(self.device_local_mem)
+            );
+        }
+    }
+
+    /// The rows of the summary's resource section for a launch with `local_size`
+    pub fn rows(&self, local_size: Option<usize>) -> Vec<(&'static str, String)> {
+        let work_group = match (local_size, self.work_group_share(local_size)) {
+            (Some(local), Some(share)) => format!(
+                "{} of {} ({:.0}%), preferred multiple {}",
+                local,
+                self.work_group_size,
+                share * 100.0,
+                self.preferred_multiple
+            ),
+            _ => format!(
+                "driver's choice, up to {}, preferred multiple {}",
+                self.work_group_size, self.preferred_multiple
+            ),
+        };
+        let mut local_mem = format!(
+            "{} of {} ({:.1}%)",
+            summary::bytes(self.local_mem),
+            summary::bytes(self.device_local_mem),
+            self.local_mem_share() * 100.0
+        );
+        if self.local_mem_limited(local_size) {
+            local_mem.push_str(", the limiting factor");
+        }
+
+        vec![
+            ("work-group size", work_group),
+            ("local memory", local_mem),
+            (
+                "private memory",
+                format!("{} per work-item", summary::bytes(self.private_mem)),
+            ),
+        ]
+    }
+}
@@ -0,0 +1,109 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+//! `--retries N --retry-delay-ms M`: retrying enqueues and allocations that failed transiently
+//!
+//! When another process keeps the GPU busy, some drivers fail an enqueue or an allocation with
+//! `CL_OUT_OF_RESOURCES`, `CL_MEM_OBJECT_ALLOCATION_FAILURE` or `CL_DEVICE_NOT_AVAILABLE`, and the
+//! same call succeeds a moment later. [`call`] retries a call failing with one of those
+//! [`TRANSIENT`] codes up to N times, waiting M ms before the first retry and twice as long before
+//! each one after it, and logs every attempt. Every other error, such as `CL_INVALID_KERNEL_ARGS`,
+//! is deterministic and returned right away. Without `--retries` nothing is retried.
+//!
+//! The policy is process-wide, set once by [`configure`], and the retries are counted so `run`
+//! can show them in its summary and every command warns about them at the end.
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        OnceLock,
+    },
+    thread,
+    time::Duration,
+};
+
+use opencl3::error_codes::{
+    ClError, CL_DEVICE_NOT_AVAILABLE, CL_MEM_OBJECT_ALLOCATION_FAILURE, CL_OUT_OF_RESOURCES,
+};
+
+/// Delay before the first retry when `--retry-delay-ms` isn't given
+pub const DEFAULT_DELAY_MS: u64 = 100;
+
+/// The error codes worth retrying, see the module docs
+pub const TRANSIENT: &[i32] = &[
+    CL_OUT_OF_RESOURCES,
+    CL_MEM_OBJECT_ALLOCATION_FAILURE,
+    CL_DEVICE_NOT_AVAILABLE,
+];
+
+/// The doubling stops here, so a large `--retries` doesn't wait for hours
+const MAX_DOUBLINGS: u32 = 10;
+
+/// How often and how patiently calls are retried
+struct Policy {
+    retries: u32,
+    delay_ms: u64,
+}
+
+static POLICY: OnceLock<Policy> = OnceLock::new();
+
+/// Retries made by every [`call`] so far
+static RETRIES: AtomicUsize = AtomicUsize::new(0);
+
+/// Sets the policy for the rest of the process, `retries` of `None` keeps retrying off
+pub fn configure(retries: Option<u32>, delay_ms: Option<u64>) {
+    let _ = POLICY.set(Policy {
+        retries: retries.unwrap_or(0),
+        delay_ms: delay_ms.unwrap_or(DEFAULT_DELAY_MS),
+    });
+}
+
+/// Calls `f`, and again after a growing delay while it fails with a [`TRANSIENT`] code and the
+/// policy has retries left. `what` names the call in the log
+pub fn call<T>(what: &str, mut f: impl FnMut() -> Result<T, ClError>) -> Result<T, ClError> {
+    let (retries, delay_ms) = POLICY
+        .get()
+        .map_or((0, 0), |policy| (policy.retries, policy.delay_ms));
+
+    let mut attempt = 0;
+    loop {
+        match f() {
+            Err(err) if attempt < retries && TRANSIENT.contains(&err.0) => {
+                let delay = delay_ms.saturating_mul(1 << attempt.min(MAX_DOUBLINGS));
+                attempt += 1;
+                tracing::warn!(
+                    "{} failed with {}, retry {} of {} in {} ms",
+                    what,
+                    err,
+                    attempt,
+                    retries,
+                    delay
+                );
+                RETRIES.fetch_add(1, Ordering::Relaxed);
+                thread::sleep(Duration::from_millis(delay));
+            }
+            result => {
+                if attempt > 0 && result.is_ok() {
+                    tracing::info!("{} succeeded after {} retries", what, attempt);
+                }
+                return result;
+            }
+        }
+    }
+}
+
+/// How many retries were made so far
+pub fn count() -> usize {
+    RETRIES.load(Ordering::Relaxed)
+}
+
+/// Warns about the retries made, so a flaky environment shows in the log of every command
+pub fn report() {
+    let retries = count();
+    if retries > 0 {
+        tracing::warn!(
+            "Retried {} call(s) that failed with a transient error, the timings may include the waits",
+            retries
+        );
+    }
+}
@@ -0,0 +1,519 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+use std::{iter, mem, path::Path};
+
+use anyhow::bail;
+use opencl3::{
+    command_queue::CommandQueue, context::Context, device::Device, kernel::Kernel, types::cl_float,
+};
+
+use crate::{
+    batch::{self, BatchInput, Engine},
+    bench, build_time, cancel,
+    cli::{Args, Layout, Transfer},
+    error::ClContext,
+    fit, host_alloc, kernels, output, printf,
+    resources::KernelResources,
+    retry,
+    saxpy::{self, SaxpyBuffers},
+    stream,
+    summary::{self, Summary},
+    vector_file,
+};
+
+pub const ARRAY_SIZE: usize = 1024;
+
+/// The `x` and `y` inputs of the run, read from `--x-file` and `--y-file` or `--csv-input`, or
+/// `--size` generated values, [`ARRAY_SIZE`] by default
+pub fn inputs(args: &Args) -> anyhow::Result<(Vec<cl_float>, Vec<cl_float>)> {
+    if let Some(path) = &args.csv_input {
+        let (x, y) = vector_file::read_columns(path)?;
+        if x.is_empty() {
+            bail!("{} has no rows below the header", path.display());
+        }
+        return Ok((x, y));
+    }
+
+    let (Some(x_file), Some(y_file)) = (&args.x_file, &args.y_file) else {
+        let size = args.size.unwrap_or(ARRAY_SIZE);
+        let ones = vec![1.0; size];
+        let sums = (0..size).map(|i| 1.0 + 1.0 * i as cl_float).collect();
+        return Ok((ones, sums));
+    };
+
+    let x = vector_file::read(x_file)?;
+    let y = vector_file::read(y_file)?;
+    if x.len() != y.len() {
+        bail!(
+            "{} holds {} values but {} holds {}",
+            x_file.display(),
+            x.len(),
+            y_file.display(),
+            y.len()
+        );
+    }
+    if x.is_empty() {
+        bail!("{} is empty", x_file.display());
+    }
+
+    Ok((x, y))
+}
+
+/// The `run` command: saxpy over [`inputs`] in the mode selected by `args`
+pub fn run(
+    context: &Context,
+    queue: &CommandQueue,
+    device: &Device,
+    args: &Args,
+) -> anyhow::Result<()> {
+    if args.stdin {
+        return stream::run(context, queue, args);
+    }
+    if args.device_enqueue {
+        #[cfg(all(feature = "cl_2_0", not(target_os = "macos")))]
+        return crate::device_enqueue::run(context, queue, device, args);
+        #[cfg(any(not(feature = "cl_2_0"), target_os = "macos"))]
+        bail!("--device-enqueue needs the OpenCL 2.0 API, which this build doesn't use (built without the cl_2_0 feature or for macOS)");
+    }
+
+    if args.images {
+        return crate::images::run(context, queue, device, args);
+    }
+    if let Some(instance) = args.template {
+        return crate::template::run(context, queue, device, args, instance);
+    }
+
+    let (x, y) = inputs(args)?;
+    let size = x.len();
+
+    if args.no_verify {
+        tracing::warn!("--no-verify: the results aren't checked against the host computation");
+    } else if args.fast_math && args.max_error_threshold.is_none() {
+        tracing::warn!(
+            "--fast-math allows less accurate results, verification tolerates a relative error of {}",
+            args.max_error()
+        );
+    }
+
+    let a = args.op.scalar(args.scalar());
+
+    if args.compare_transfers {
+        let kernel = kernels::build_layout_kernel(context, args, args.layout)?;
+        let mut buffers = SaxpyBuffers::create(context, args.layout, size)?;
+
+        // Warm up so the first strategy doesn't pay for lazy allocation and kernel upload
+        buffers.run(queue, &kernel, Transfer::Events, &x, &y, a)?;
+
+        let mut slowest_ns = 0;
+        for transfer in Transfer::ALL {
+            let run = buffers.run(queue, &kernel, transfer, &x, &y, a)?;
+            slowest_ns = slowest_ns.max(run.kernel_ns);
+            println!(
+                "{:>8}: end-to-end {:>10} ns, kernel {:>10} ns",
+                transfer, run.wall_ns, run.kernel_ns
+            );
+            if args.dump_dag {
+                println!("{}", run.dag);
+            }
+        }
+
+        return saxpy::check_kernel_budget(
+            args.max_kernel_ms,
+            slowest_ns,
+            "the slowest transfer strategy",
+        );
+    }
+
+    if let Some(repeat) = args.repeat {
+        let kernel = kernels::build_layout_kernel(context, args, args.layout)?;
+        let mut engine = Engine::new(context, queue, &kernel, args.layout, args.op, args.transfer);
+        let device_name = device_name(device)?;
+        let input: BatchInput = (args.scalar(), x, y);
+
+        // The callback can't fail, the first error writing the profile ends the batch after it
+        let mut profile_error = None;
+        let mut slowest_ns = 0;
+        batch::run_batch(&mut engine, iter::repeat_n(&input, repeat), |i, run| {
+            slowest_ns = slowest_ns.max(run.kernel_ns);
+            println!(
+                "run {:>4}: end-to-end {:>10} ns, kernel {:>10} ns",
+                i, run.wall_ns, run.kernel_ns
+            );
+            if let (Some(path), None) = (&args.save_profile_csv, &profile_error) {
+                profile_error = output::append_profile_row(path, &device_name, size, run).err();
+            }
+        })?;
+        if let Some(err) = profile_error {
+            return Err(err);
+        }
+
+        tracing::info!(
+            "Buffer pool: {} allocations, {} reuses",
+            engine.pool.allocations(),
+            engine.pool.reuses()
+        );
+
+        return saxpy::check_kernel_budget(
+            args.max_kernel_ms,
+            slowest_ns,
+            &format!("the slowest of the {} runs", repeat),
+        );
+    }
+
+    match args.layout {
+        Layout::Soa | Layout::Aos => {
+            if let (Some(chunk_size), Some(path)) = (args.readback_chunk, &args.output) {
+                let kernel = kernels::build_layout_kernel(context, args, args.layout)?;
+                return write_in_chunks(context, queue, args, &kernel, &x, &y, chunk_size, path);
+            }
+            let guarded = kernels::uses_guarded_kernel(args, args.layout);
+            let kernel = if guarded {
+                kernels::build_guarded_kernel(context, args)?
+            } else {
+                kernels::build_layout_kernel(context, args, args.layout)?
+            };
+            let offset = args.offset.unwrap_or(0);
+            if offset > 0 && offset >= x.len() {
+                bail!(
+                    "--offset {} leaves none of the {} elements to run over",
+                    offset,
+                    x.len()
+                );
+            }
+            // Everything that depends on the size, retried with half of it by --fit-to-memory
+            let attempt = |size: usize| -> anyhow::Result<_> {
+                // Not an allocation failure, so halving stops here
+                let Some(items) = size.checked_sub(offset).filter(|&items| items > 0) else {
+                    bail!(
+                        "Only {} elements fit in the device memory, none past --offset {}",
+                        size,
+                        offset
+                    );
+                };
+                let (x, y) = (&x[..size], &y[..size]);
+                // --global brings its own local size, if any
+                let local_size = match args.global {
+                    Some(_) => None,
+                    None => saxpy::choose_local_size(
+                        &kernel,
+                        device,
+                        items,
+                        guarded,
+                        args.local_size.or(args.group_size),
+                    )?,
+                };
+                if let (Some(groups), Some(group_size)) = (args.num_groups, local_size) {
+                    let global = saxpy::groups_global_size(groups, group_size, items, guarded)?;
+                    tracing::info!(
+                        "{} work-groups of {}, global size {}",
+                        groups,
+                        group_size,
+                        global
+                    );
+                }
+                let mut buffers = if args.use_host_ptr {
+                    let align = host_alloc::host_ptr_alignment(device)?;
+                    tracing::debug!("Aligning host buffers to {} bytes", align);
+                    SaxpyBuffers::create_host_ptr(context, args.layout, size, align)?
+                } else {
+                    SaxpyBuffers::create(context, args.layout, size)?
+                };
+                buffers.set_bounds_guard(guarded);
+                buffers.set_local_work_size(local_size);
+                buffers.set_num_groups(args.num_groups);
+                buffers.set_range(args.range());
+                buffers.set_offset(offset);
+                buffers.set_detect_unwritten(args.detect_unwritten);
+                saxpy::log_allocation_summary(size, mem::size_of::<cl_float>(), device);
+
+                let pageable = if args.pinned_readback {
+                    let pageable = buffers.run(queue, &kernel, args.transfer, x, y, a)?;
+                    buffers.enable_pinned_readback(context)?;
+                    Some(pageable)
+                } else {
+                    None
+                };
+
+                let run = buffers.run(queue, &kernel, args.transfer, x, y, a)?;
+                Ok((local_size, pageable, run))
+            };
+            if args.enable_printf {
+                let items = args.range().map_or(size - offset, |range| range.items());
+                printf::begin(device, items)?;
+            }
+            let (size, (local_size, pageable, run)) =
+                fit::with_backoff(size, args.fit_to_memory, attempt)?;
+            if args.enable_printf {
+                printf::end(queue)?;
+            }
+            let (x, y) = (&x[..size], &y[..size]);
+            let resources = KernelResources::query(&kernel, device)?;
+            resources.log(
+                &kernel
+                    .function_name()
+                    .context("Failed to get kernel name")?,
+                local_size,
+            );
+
+            let show_results = if let Some(path) = &args.output {
+                output::write_results(path, &run.result, args.binary)?;
+                println!("wrote {} results to {}", run.result.len(), path.display());
+                args.show_results.unwrap_or(0)
+            } else if let Some(path) = &args.out_file {
+                vector_file::write(path, &run.result)?;
+                println!("wrote {} results to {}", run.result.len(), path.display());
+                args.show_results.unwrap_or(0)
+            } else {
+                args.show_results.unwrap_or(1)
+            };
+
+            tracing::info!("Kernel execution time (ns): {}", run.kernel_ns);
+
+            let name = match kernels::source_label(args) {
+                None if guarded => kernels::GUARDED_KERNEL_NAME.to_owned(),
+                None => kernels::layout_kernel(args.layout, args.op, args.kernel_iters)?
+                    .name
+                    .to_owned(),
+                Some(label) => format!(
+                    "{} from {}",
+                    kernel
+                        .function_name()
+                        .context("Failed to get kernel name")?,
+                    label
+                ),
+            };
+            let options = kernels::kernel_options(args, args.layout)?;
+            let mut timings = build_time::rows(&build_time::take());
+            timings.extend([
+                ("kernel", summary::duration(run.kernel_ns)),
+                (
+                    "kernel bandwidth",
+                    summary::bandwidth(saxpy::BYTES_PER_ELEMENT * (size - offset), run.kernel_ns),
+                ),
+                ("readback", summary::duration(run.read_ns)),
+                ("end-to-end", summary::duration(run.wall_ns)),
+            ]);
+            if let Some(peak) = args.peak_bandwidth {
+                timings.push((
+                    "of peak bandwidth",
+                    format!(
+                        "{:.1}% of {} GB/s",
+                        bench::peak_share(size - offset, run.kernel_ns, peak),
+                        peak
+                    ),
+                ));
+            }
+            if let Some(pageable) = &pageable {
+                let bytes = size * mem::size_of::<cl_float>();
+                timings.push((
+                    "pageable readback",
+                    summary::bandwidth(bytes, pageable.read_ns),
+                ));
+                timings.push(("pinned readback", summary::bandwidth(bytes, run.read_ns)));
+            }
+            if args.kernel_iters.is_some() {
+                let flops = kernels::flops_per_element(args.op, args.kernel_iters) * size as u64;
+                timings.push((
+                    "achieved",
+                    format!("{:.2} GFLOP/s", flops as f64 / run.kernel_ns.max(1) as f64),
+                ));
+            }
+
+            if let Some(path) = &args.save_profile_csv {
+                output::append_profile_row(path, &device_name(device)?, size, &run)?;
+            }
+
+            let summary = Summary {
+                device: device_name(device)?,
+                kernel: format!("{} ({})", name, args.layout),
+                size,
+                defines: kernels::defines(&options),
+                options,
+                verification: (!args.no_verify).then(|| {
+                    // The results before --offset have to be the fill, the rest the saxpy
+                    saxpy::check_untouched(&run.result[..offset])?;
+                    saxpy::verify(
+                        args.op,
+                        args.kernel_iters,
+                        args.max_error(),
+                        args.verify_stride.unwrap_or(1),
+                        a,
+                        &x[offset..],
+                        &y[offset..],
+                        &run.result[offset..],
+                    )
+                    .map(|checked| offset + checked)
+                    .map_err(|i| offset + i)
+                }),
+                retries: args.retries.map(|_| retry::count()),
+                timings,
+                resources: resources.rows(local_size),
+                results: summary::first_and_last(&run.result, show_results),
+            };
+            summary.print(summary::use_color(args));
+
+            if args.dump_dag {
+                println!("{}", run.dag);
+            }
+
+            // A more telling failure than the wrong values verification would report for them
+            if let Some((first, count)) = args
+                .detect_unwritten
+                .then(|| saxpy::find_unwritten(&run.result[offset..]))
+                .flatten()
+            {
+                bail!(
+                    "{} of the {} results were never written, the first at index {}: the launch doesn't cover every element, check the global size",
+                    count,
+                    size - offset,
+                    offset + first
+                );
+            }
+            if let Some(Err(i)) = summary.verification {
+                if i < offset {
+                    bail!(
+                        "Index {} before --offset {} was written, got {}: the driver doesn't honour the global work offset",
+                        i,
+                        offset,
+                        run.result[i]
+                    );
+                }
+                bail!(
+                    "Verification failed at index {}: got {}",
+                    i,
+                    run.result.get(i).copied().unwrap_or(cl_float::NAN)
+                );
+            }
+            saxpy::check_kernel_budget(args.max_kernel_ms, run.kernel_ns, "the run")?;
+        }
+        Layout::Both => {
+            let soa_kernel = kernels::build_layout_kernel(context, args, Layout::Soa)?;
+            let aos_kernel = kernels::build_layout_kernel(context, args, Layout::Aos)?;
+
+            let soa = SaxpyBuffers::create(context, Layout::Soa, size)?.run(
+                queue,
+                &soa_kernel,
+                args.transfer,
+                &x,
+                &y,
+                a,
+            )?;
+            let aos = SaxpyBuffers::create(context, Layout::Aos, size)?.run(
+                queue,
+                &aos_kernel,
+                args.transfer,
+                &x,
+                &y,
+                a,
+            )?;
+            for build in build_time::take() {
+                println!(
+                    "{} build time: {} ({})",
+                    build.what,
+                    summary::duration(build.ns),
+                    build.origin.name()
+                );
+            }
+            println!("soa kernel time (ns): {}", soa.kernel_ns);
+            println!("aos kernel time (ns): {}", aos.kernel_ns);
+            saxpy::check_kernel_budget(args.max_kernel_ms, soa.kernel_ns, "the soa layout")?;
+            saxpy::check_kernel_budget(args.max_kernel_ms, aos.kernel_ns, "the aos layout")?;
+
+            if let Err(i) = saxpy::compare_results(&soa.result, &aos.result) {
+                anyhow::bail!(
+                    "Layouts disagree at index {}: soa = {}, aos = {}",
+                    i,
+                    soa.result[i],
+                    aos.result[i]
+                );
+            }
+            println!("soa and aos results are identical");
+
+            if args.dump_dag {
+                println!("// soa\n{}\n// aos\n{}", soa.dag, aos.dag);
+            }
+
+            if let Some(path) = &args.output {
+                output::write_results(path, &soa.result, args.binary)?;
+                println!("wrote {} results to {}", soa.result.len(), path.display());
+            }
+            if let Some(path) = &args.out_file {
+                vector_file::write(path, &soa.result)?;
+                println!("wrote {} results to {}", soa.result.len(), path.display());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn device_name(device: &Device) -> anyhow::Result<String> {
+    device.name().context("Failed to query device name")
+}
+
+/// Runs the kernel once and streams the results to `path`, reading them back `chunk_size`
+/// elements at a time and checking each chunk against the host computation unless `--no-verify`
+#[allow(clippy::too_many_arguments)]
+fn write_in_chunks(
+    context: &Context,
+    queue: &CommandQueue,
+    args: &Args,
+    kernel: &Kernel,
+    x: &[cl_float],
+    y: &[cl_float],
+    chunk_size: usize,
+    path: &Path,
+) -> anyhow::Result<()> {
+    let a = args.op.scalar(args.scalar());
+    let mut buffers = SaxpyBuffers::create(context, args.layout, x.len())?;
+    let chunks = buffers.run_chunked(queue, kernel, args.transfer, x, y, a, chunk_size)?;
+
+    let mut writer = output::ResultWriter::create(path, x.len(), args.binary)?;
+    let mut offset = 0;
+    let mut checked = 0;
+    let mut mismatch = None;
+    for chunk in chunks {
+        // The file keeps the chunks written so far, a binary header still counts all of them
+        if cancel::requested() {
+            break;
+        }
+        let chunk = chunk?;
+        let range = offset..offset + chunk.len();
+        if mismatch.is_none() && !args.no_verify {
+            // The stride restarts at every chunk, which also checks the last result of each
+            match saxpy::verify(
+                args.op,
+                args.kernel_iters,
+                args.max_error(),
+                args.verify_stride.unwrap_or(1),
+                a,
+                &x[range.clone()],
+                &y[range],
+                &chunk,
+            ) {
+                Ok(count) => checked += count,
+                Err(i) => mismatch = Some((offset + i, chunk[i])),
+            }
+        }
+        writer.write(&chunk)?;
+        offset += chunk.len();
+    }
+    writer.finish()?;
+
+    println!(
+        "wrote {} results to {} in chunks of {}",
+        offset,
+        path.display(),
+        chunk_size
+    );
+    cancel::check(queue)?;
+    if let Some((i, value)) = mismatch {
+        bail!("Verification failed at index {}: got {}", i, value);
+    }
+    if !args.no_verify {
+        println!("verified {} of {} results", checked, offset);
+    }
+
+    Ok(())
+}
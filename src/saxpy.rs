@@ -0,0 +1,1256 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+use std::{borrow::Cow, mem, ptr, slice, time::Instant};
+
+use anyhow::bail;
+use opencl3::{
+    command_queue::CommandQueue,
+    context::Context,
+    device::Device,
+    event::{Event, CL_COMPLETE},
+    kernel::Kernel,
+    memory::{
+        Buffer, ClMem, CL_MAP_WRITE_INVALIDATE_REGION, CL_MEM_ALLOC_HOST_PTR, CL_MEM_READ_ONLY,
+        CL_MEM_READ_WRITE, CL_MEM_USE_HOST_PTR, CL_MEM_WRITE_ONLY,
+    },
+    types::{
+        cl_bool, cl_event, cl_float, cl_mem, cl_mem_flags, cl_uint, CL_BLOCKING, CL_NON_BLOCKING,
+    },
+};
+
+#[cfg(feature = "async")]
+use crate::event_future::{EventFuture, WaitOnDrop};
+use crate::{
+    cli::{Layout, Op, Transfer},
+    error::ClContext,
+    event_graph::{Dependency, EventGraph},
+    host_alloc::{aligned_host_vec, AlignedVec},
+    kernels,
+    launch::{CheckedKernel, NdRange},
+    pool::BufferPool,
+    readback::ResultChunks,
+    retry, summary, trace,
+};
+
+/// Bytes moved through global memory per element: `x` and `y` are read, `z` is written
+pub const BYTES_PER_ELEMENT: usize = 3 * mem::size_of::<cl_float>();
+
+/// Result of a single saxpy launch
+pub struct SaxpyRun {
+    pub result: Vec<cl_float>,
+    /// Kernel execution time (ns) from the profiling counters
+    pub kernel_ns: u64,
+    /// Upload time (ns) of the inputs from the profiling counters, summed over the writes
+    pub write_ns: u64,
+    /// Readback time (ns) of `z` from the profiling counters
+    pub read_ns: u64,
+    /// Host wall-clock time (ns) from the first upload until the result was read back
+    pub wall_ns: u64,
+    /// The commands that were enqueued and which ones waited on which
+    pub dag: EventGraph,
+}
+
+/// Input buffers in either layout
+enum InputBuffers {
+    /// `x` and `y` in separate buffers (struct-of-arrays)
+    Soa {
+        x: Buffer<cl_float>,
+        y: Buffer<cl_float>,
+    },
+    /// `x` and `y` interleaved into one `float2` buffer (array-of-structs)
+    Aos { xy: Buffer<cl_float> },
+}
+
+/// Device buffers for one saxpy launch
+///
+/// The buffers can be reused for any input length up to the `capacity` they were created with,
+/// the kernel is then launched over the first `len` elements only
+pub struct SaxpyBuffers {
+    inputs: InputBuffers,
+    z: Buffer<cl_float>,
+    /// Page-locked (`CL_MEM_ALLOC_HOST_PTR`) buffer that `z` is read into when pinned readback is enabled
+    staging: Option<Buffer<cl_float>>,
+    capacity: usize,
+    /// Work-group size of the launch, `None` leaves it to the driver
+    local_work_size: Option<usize>,
+    /// Bind the input length as the kernel's last argument and round the global size up
+    bounds_guard: bool,
+    /// Work-groups of the launch, the global size is then this many times the local work size
+    num_groups: Option<usize>,
+    /// The NDRange of the launch instead of one derived from the input length
+    range: Option<NdRange>,
+    /// The first element the kernel runs over, the ones before it keep [`UNTOUCHED`]
+    offset: usize,
+    /// Fill `z` with [`UNTOUCHED`] before every launch, for [`find_unwritten`]
+    detect_unwritten: bool,
+    /// Host memory backing `CL_MEM_USE_HOST_PTR` buffers, declared last so it outlives them
+    host_memory: Vec<AlignedVec>,
+}
+
+impl SaxpyBuffers {
+    pub fn create(context: &Context, layout: Layout, capacity: usize) -> anyhow::Result<Self> {
+        Self::create_with(layout, capacity, |flags, len| {
+            create_buffer(context, flags, len)
+        })
+    }
+
+    /// Like [`SaxpyBuffers::create`] but every buffer is `CL_MEM_USE_HOST_PTR` over host memory
+    /// aligned to `align` bytes, see [`crate::host_alloc`]
+    pub fn create_host_ptr(
+        context: &Context,
+        layout: Layout,
+        capacity: usize,
+        align: usize,
+    ) -> anyhow::Result<Self> {
+        let mut host_memory = Vec::new();
+        let mut buffers = Self::create_with(layout, capacity, |flags, len| {
+            let mut memory = aligned_host_vec(len, align);
+            let buffer = retry::call("clCreateBuffer", || unsafe {
+                Buffer::<cl_float>::create(
+                    context,
+                    flags | CL_MEM_USE_HOST_PTR,
+                    len,
+                    memory.as_mut_ptr().cast(),
+                )
+            })
+            .context("Failed to create buffer")?;
+
+            // Moving the `AlignedVec` doesn't move the memory the buffer points at
+            host_memory.push(memory);
+            Ok(buffer)
+        })?;
+        buffers.host_memory = host_memory;
+
+        Ok(buffers)
+    }
+
+    /// Like [`SaxpyBuffers::create`] but takes the buffers from `pool`
+    pub fn from_pool(
+        pool: &mut BufferPool,
+        context: &Context,
+        layout: Layout,
+        capacity: usize,
+    ) -> anyhow::Result<Self> {
+        Self::create_with(layout, capacity, |flags, len| {
+            pool.take(context, flags, len)
+        })
+    }
+
+    /// Gives the input and output buffers back to `pool`, the staging buffer is released
+    ///
+    /// Every [`SaxpyBuffers::run`] waits for its writes, kernel and readback before returning,
+    /// and the runs of [`SaxpyBuffers::enqueue`] have to be waited for before the buffers are
+    /// returned, so nothing can still be in flight here, which upholds the pool's safety invariant.
+    /// Buffers over host memory are released instead, the memory goes away with `self`.
+    pub fn return_to(self, pool: &mut BufferPool) {
+        if !self.host_memory.is_empty() {
+            return;
+        }
+
+        let capacity = self.capacity;
+        unsafe {
+            match self.inputs {
+                InputBuffers::Soa { x, y } => {
+                    pool.put(x, CL_MEM_READ_ONLY, capacity);
+                    pool.put(y, CL_MEM_READ_ONLY, capacity);
+                }
+                InputBuffers::Aos { xy } => pool.put(xy, CL_MEM_READ_ONLY, 2 * capacity),
+            }
+            pool.put(self.z, CL_MEM_WRITE_ONLY, capacity);
+        }
+    }
+
+    fn create_with(
+        layout: Layout,
+        capacity: usize,
+        mut create: impl FnMut(cl_mem_flags, usize) -> anyhow::Result<Buffer<cl_float>>,
+    ) -> anyhow::Result<Self> {
+        let mut create = |flags, len| {
+            let buffer = create(flags, len)?;
+            trace::alloc(buffer.get(), flags, len);
+            anyhow::Ok(buffer)
+        };
+        let inputs = match layout {
+            Layout::Soa => InputBuffers::Soa {
+                x: create(CL_MEM_READ_ONLY, capacity)?,
+                y: create(CL_MEM_READ_ONLY, capacity)?,
+            },
+            Layout::Aos => InputBuffers::Aos {
+                xy: create(CL_MEM_READ_ONLY, 2 * capacity)?,
+            },
+            Layout::Both => bail!("Buffers can only be created for a single layout"),
+        };
+
+        Ok(Self {
+            inputs,
+            z: create(CL_MEM_WRITE_ONLY, capacity)?,
+            staging: None,
+            capacity,
+            local_work_size: None,
+            bounds_guard: false,
+            num_groups: None,
+            range: None,
+            offset: 0,
+            detect_unwritten: false,
+            host_memory: Vec::new(),
+        })
+    }
+
+    /// Reads `z` back through a page-locked staging buffer instead of straight into pageable memory
+    ///
+    /// On discrete GPUs the driver can DMA directly into pinned memory, while a read into an
+    /// ordinary allocation is usually bounced through an internal pinned buffer first
+    pub fn enable_pinned_readback(&mut self, context: &Context) -> anyhow::Result<()> {
+        if self.staging.is_none() {
+            self.staging = Some(create_buffer(
+                context,
+                CL_MEM_READ_WRITE | CL_MEM_ALLOC_HOST_PTR,
+                self.capacity,
+            )?);
+        }
+
+        Ok(())
+    }
+
+    /// Launches with work-groups of `local_work_size` items, which has to divide the input length
+    /// unless the bounds guard is set
+    pub fn set_local_work_size(&mut self, local_work_size: Option<usize>) {
+        self.local_work_size = local_work_size;
+    }
+
+    /// Launches `num_groups` work-groups of the local work size, which has to be set, instead of
+    /// deriving the global size from the input length, see [`groups_global_size`]
+    pub fn set_num_groups(&mut self, num_groups: Option<usize>) {
+        self.num_groups = num_groups;
+    }
+
+    /// Launches over `range`, of up to three dimensions, instead of the input length, which its
+    /// work-items have to match. The local work size, work-groups and bounds guard don't apply then
+    pub fn set_range(&mut self, range: Option<NdRange>) {
+        self.range = range;
+    }
+
+    /// Launches over the elements from `offset` on through the global work offset, after filling
+    /// `z` with [`UNTOUCHED`] so [`check_untouched`] can tell whether the kernel wrote before it
+    pub fn set_offset(&mut self, offset: usize) {
+        self.offset = offset;
+    }
+
+    /// Fills `z` with [`UNTOUCHED`] before every launch, so [`find_unwritten`] finds the results
+    /// a launch didn't cover
+    pub fn set_detect_unwritten(&mut self, detect: bool) {
+        self.detect_unwritten = detect;
+    }
+
+    /// Binds the input length as the kernel's last argument `n` and rounds the global size up to a
+    /// multiple of the local work size, for kernels guarded by `if (i >= n) return;` such as
+    /// [`crate::kernels::GUARDED_PROGRAM_SOURCE`]
+    pub fn set_bounds_guard(&mut self, bounds_guard: bool) {
+        self.bounds_guard = bounds_guard;
+    }
+
+    /// Uploads `x` and `y`, runs the kernel over their length and reads `z` back
+    ///
+    /// The kernel is bound as `(z, x, y, a)` (or `(z, xy, a)`), without `a` when it is `None`, which
+    /// fits scalar-free kernels like `vadd_float`
+    pub fn run(
+        &mut self,
+        queue: &CommandQueue,
+        kernel: &Kernel,
+        transfer: Transfer,
+        x: &[cl_float],
+        y: &[cl_float],
+        a: Option<cl_float>,
+    ) -> anyhow::Result<SaxpyRun> {
+        let len = x.len();
+        let start = Instant::now();
+
+        let mut dag = EventGraph::default();
+        let (kernel_event, writes, kernel_node) =
+            self.launch(queue, kernel, transfer, x, y, a, &mut dag)?;
+
+        let read_dependency = match transfer {
+            Transfer::Events => Dependency::WaitList,
+            Transfer::Blocking | Transfer::Finish => Dependency::QueueOrder,
+        };
+        if self.staging.is_some() {
+            // The blocking map is enqueued behind the kernel and the read goes into the mapping
+            let map_node = dag.node("map staging");
+            let read_node = dag.node("read z");
+            let unmap_node = dag.node("unmap staging");
+            dag.edge(kernel_node, map_node, Dependency::QueueOrder);
+            dag.edge(map_node, read_node, Dependency::HostWait);
+            dag.edge(kernel_node, read_node, read_dependency);
+            dag.edge(read_node, unmap_node, Dependency::HostWait);
+        } else {
+            let read_node = dag.node("read z");
+            dag.edge(kernel_node, read_node, read_dependency);
+        }
+
+        let (result, read_event) = match &self.staging {
+            Some(staging) => {
+                read_back_pinned(queue, &self.z, staging, &kernel_event, transfer, len)?
+            }
+            None => {
+                let mut result = vec![0.0; len];
+                let read_event = read_back(queue, &self.z, &mut result, &kernel_event, transfer)?;
+                (result, read_event)
+            }
+        };
+
+        if cfg!(debug_assertions) {
+            check_completed_before_read(&kernel_event);
+        }
+        trace::read(self.z.get(), 0, &result);
+
+        // Everything has completed by now, this only releases the host inputs
+        let write_ns = writes
+            .events
+            .iter()
+            .map(profiled_ns)
+            .sum::<anyhow::Result<u64>>()?;
+        drop(writes);
+
+        Ok(SaxpyRun {
+            result,
+            kernel_ns: profiled_ns(&kernel_event)?,
+            write_ns,
+            read_ns: profiled_ns(&read_event)?,
+            wall_ns: start.elapsed().as_nanos() as u64,
+            dag,
+        })
+    }
+
+    /// Like [`SaxpyBuffers::run`] with [`Transfer::Events`], but instead of blocking the calling
+    /// thread the read of `z` is awaited as an [`EventFuture`]. The pinned staging buffer isn't
+    /// used
+    #[cfg(feature = "async")]
+    pub async fn run_async(
+        &mut self,
+        queue: &CommandQueue,
+        kernel: &Kernel,
+        x: &[cl_float],
+        y: &[cl_float],
+        a: Option<cl_float>,
+    ) -> anyhow::Result<SaxpyRun> {
+        let start = Instant::now();
+
+        let mut dag = EventGraph::default();
+        let (kernel_event, writes, kernel_node) =
+            self.launch(queue, kernel, Transfer::Events, x, y, a, &mut dag)?;
+        let read_node = dag.node("read z");
+        dag.edge(kernel_node, read_node, Dependency::WaitList);
+
+        let mut result = vec![0.0; x.len()];
+        let events = [kernel_event.get()];
+        let read_event = retry::call("clEnqueueReadBuffer", || unsafe {
+            queue.enqueue_read_buffer(&self.z, CL_NON_BLOCKING, 0, &mut result, &events)
+        })
+        .context("Failed to read buffer")?;
+        // Dropped before `result`, so a future dropped early doesn't free it under the read
+        let pending_read = WaitOnDrop(&read_event);
+        // Nothing but a flush makes the driver submit the commands, or the callback never comes
+        queue.flush().context("Failed to flush queue")?;
+
+        EventFuture::new(&read_event)
+            .await
+            .context("Failed to read back the results")?;
+        drop(pending_read);
+
+        if cfg!(debug_assertions) {
+            check_completed_before_read(&kernel_event);
+        }
+        trace::read(self.z.get(), 0, &result);
+
+        let write_ns = writes
+            .events
+            .iter()
+            .map(profiled_ns)
+            .sum::<anyhow::Result<u64>>()?;
+        drop(writes);
+
+        Ok(SaxpyRun {
+            result,
+            kernel_ns: profiled_ns(&kernel_event)?,
+            write_ns,
+            read_ns: profiled_ns(&read_event)?,
+            wall_ns: start.elapsed().as_nanos() as u64,
+            dag,
+        })
+    }
+
+    /// Like [`SaxpyBuffers::run`] with [`Transfer::Events`], but returns as soon as the read of `z`
+    /// is enqueued, without flushing the queue. The pinned staging buffer isn't used
+    ///
+    /// The buffers must neither be reused nor dropped before the returned run was waited for or
+    /// dropped, which waits as well
+    pub fn enqueue<'a>(
+        &mut self,
+        queue: &CommandQueue,
+        kernel: &Kernel,
+        x: &'a [cl_float],
+        y: &'a [cl_float],
+        a: Option<cl_float>,
+    ) -> anyhow::Result<PendingRun<'a>> {
+        let start = Instant::now();
+
+        let mut dag = EventGraph::default();
+        let (kernel_event, writes, kernel_node) =
+            self.launch(queue, kernel, Transfer::Events, x, y, a, &mut dag)?;
+        let read_node = dag.node("read z");
+        dag.edge(kernel_node, read_node, Dependency::WaitList);
+
+        let mut result = vec![0.0; x.len()];
+        let events = [kernel_event.get()];
+        // The allocation of `result` doesn't move with it, the pending run waits before freeing it
+        let read_event = retry::call("clEnqueueReadBuffer", || unsafe {
+            queue.enqueue_read_buffer(&self.z, CL_NON_BLOCKING, 0, &mut result, &events)
+        })
+        .context("Failed to read buffer")?;
+
+        Ok(PendingRun {
+            result,
+            z: self.z.get(),
+            read_event,
+            kernel_event,
+            writes,
+            start,
+            dag,
+        })
+    }
+
+    /// Like [`SaxpyBuffers::run`], but `z` isn't read back: the returned iterator reads it
+    /// `chunk_size` elements at a time instead, see [`ResultChunks`]. The pinned staging buffer
+    /// isn't used and no timings are taken
+    #[allow(clippy::too_many_arguments)]
+    pub fn run_chunked<'a>(
+        &'a mut self,
+        queue: &'a CommandQueue,
+        kernel: &Kernel,
+        transfer: Transfer,
+        x: &[cl_float],
+        y: &[cl_float],
+        a: Option<cl_float>,
+        chunk_size: usize,
+    ) -> anyhow::Result<ResultChunks<'a>> {
+        let mut dag = EventGraph::default();
+        let (kernel_event, writes, _) = self.launch(queue, kernel, transfer, x, y, a, &mut dag)?;
+        kernel_event.wait().context("Failed to wait for kernel")?;
+        drop(writes);
+
+        Ok(ResultChunks::new(queue, &self.z, x.len(), chunk_size))
+    }
+
+    /// Uploads `x` and `y` and enqueues the kernel over their length, recording both in `dag`
+    ///
+    /// Returns the kernel event, the writes that have to outlive it and the kernel's node
+    #[allow(clippy::too_many_arguments)]
+    fn launch<'a>(
+        &mut self,
+        queue: &CommandQueue,
+        kernel: &Kernel,
+        transfer: Transfer,
+        x: &'a [cl_float],
+        y: &'a [cl_float],
+        a: Option<cl_float>,
+        dag: &mut EventGraph,
+    ) -> anyhow::Result<(Event, InFlightWrites<'a>, usize)> {
+        let len = x.len();
+        if y.len() != len {
+            bail!("x and y differ in length ({} vs {})", len, y.len());
+        }
+        if len > self.capacity {
+            bail!(
+                "Input of {} elements exceeds the buffer capacity of {}",
+                len,
+                self.capacity
+            );
+        }
+        if let Some(range) = self.range.as_ref().filter(|range| range.items() != len) {
+            bail!(
+                "The range {} covers {} work-items but the input has {} elements",
+                range,
+                range.items(),
+                len
+            );
+        }
+        if self.offset > 0 && self.offset >= len {
+            bail!(
+                "An offset of {} leaves none of the {} elements to run over",
+                self.offset,
+                len
+            );
+        }
+        // The work-items from the offset on
+        let items = len - self.offset;
+        if let Some(local_work_size) = self.local_work_size.filter(|_| !self.bounds_guard) {
+            if !items.is_multiple_of(local_work_size) {
+                bail!(
+                    "Local work size {} doesn't divide the {} elements the kernel runs over",
+                    local_work_size,
+                    items
+                );
+            }
+        }
+
+        let mut write_nodes = Vec::new();
+        let mut writes = InFlightWrites::default();
+        let mut execute = CheckedKernel::new(kernel);
+        match &mut self.inputs {
+            InputBuffers::Soa {
+                x: x_buffer,
+                y: y_buffer,
+            } => {
+                writes.enqueue(queue, x_buffer, transfer, Cow::Borrowed(x))?;
+                writes.enqueue(queue, y_buffer, transfer, Cow::Borrowed(y))?;
+                write_nodes.extend([dag.node("write x"), dag.node("write y")]);
+
+                unsafe {
+                    execute
+                        .arg("z", &self.z)
+                        .arg("x", &*x_buffer)
+                        .arg("y", &*y_buffer);
+                }
+            }
+            InputBuffers::Aos { xy: xy_buffer } => {
+                let xy: Vec<cl_float> = x.iter().zip(y).flat_map(|(&x, &y)| [x, y]).collect();
+                writes.enqueue(queue, xy_buffer, transfer, Cow::Owned(xy))?;
+                write_nodes.push(dag.node("write xy"));
+
+                unsafe {
+                    execute.arg("z", &self.z).arg("xy", &*xy_buffer);
+                }
+            }
+        }
+        if let Some(a) = &a {
+            unsafe {
+                execute.arg("a", a);
+            }
+        }
+        let n = cl_uint::try_from(len)?;
+        if self.bounds_guard {
+            unsafe {
+                execute.arg("n", &n);
+            }
+        }
+
+        match (
+            &self.range,
+            self.local_work_size,
+            self.num_groups,
+            self.bounds_guard,
+        ) {
+            (Some(range), ..) => execute.set_range(range),
+            (None, Some(local_work_size), Some(num_groups), _) => {
+                execute.set_global_work_size(num_groups * local_work_size)
+            }
+            (None, Some(local_work_size), None, true) => {
+                execute.set_global_work_size(round_up(items, local_work_size))
+            }
+            _ => execute.set_global_work_size(items),
+        };
+        if let (None, Some(local_work_size)) = (&self.range, self.local_work_size) {
+            execute.set_local_work_size(local_work_size);
+        }
+        if self.offset > 0 {
+            execute.set_global_work_offset(self.offset);
+        }
+        if self.offset > 0 || self.detect_unwritten {
+            writes.fill(queue, &mut self.z, UNTOUCHED, len)?;
+            write_nodes.push(dag.node("fill z"));
+        }
+        if transfer == Transfer::Events {
+            execute.set_event_wait_list(&writes.event_ids());
+        }
+
+        let kernel_event = unsafe { execute.enqueue_nd_range(queue) }
+            .map_err(|err| err.context("Failed to execute kernel"))?;
+
+        let kernel_node = dag.node("kernel");
+        for write_node in write_nodes {
+            dag.edge(write_node, kernel_node, transfer.write_dependency());
+        }
+
+        Ok((kernel_event, writes, kernel_node))
+    }
+}
+
+/// Runs `kernel` once over `x` and `y` on `queue` in buffers of its own, awaiting the result as
+/// [`SaxpyBuffers::run_async`] does. Saxpys on separate queues run concurrently when driven
+/// together, by [`block_on_all`](crate::event_future::block_on_all) or any other executor
+///
+/// `run --threads --async` keeps its buffers across launches with [`SaxpyBuffers::run_async`]
+#[cfg(feature = "async")]
+#[cfg_attr(not(test), allow(dead_code))]
+pub async fn saxpy_async(
+    context: &Context,
+    queue: &CommandQueue,
+    kernel: &Kernel,
+    layout: Layout,
+    x: &[cl_float],
+    y: &[cl_float],
+    a: Option<cl_float>,
+) -> anyhow::Result<SaxpyRun> {
+    let mut buffers = SaxpyBuffers::create(context, layout, x.len())?;
+    buffers.run_async(queue, kernel, x, y, a).await
+}
+
+/// A launch from [`SaxpyBuffers::enqueue`] whose result may still be being read back
+///
+/// Dropping it waits for the read, so `result` is never freed under it
+pub struct PendingRun<'a> {
+    result: Vec<cl_float>,
+    /// The buffer `result` is read from, for [`trace`]
+    z: cl_mem,
+    read_event: Event,
+    kernel_event: Event,
+    writes: InFlightWrites<'a>,
+    start: Instant,
+    dag: EventGraph,
+}
+
+impl PendingRun<'_> {
+    /// Waits for the read of `z` and returns the run, timed from its first upload
+    pub fn wait(mut self) -> anyhow::Result<SaxpyRun> {
+        self.read_event
+            .wait()
+            .context("Failed to wait to read buffer")?;
+
+        if cfg!(debug_assertions) {
+            check_completed_before_read(&self.kernel_event);
+        }
+        trace::read(self.z, 0, &self.result);
+
+        let write_ns = self
+            .writes
+            .events
+            .iter()
+            .map(profiled_ns)
+            .sum::<anyhow::Result<u64>>()?;
+
+        Ok(SaxpyRun {
+            result: mem::take(&mut self.result),
+            kernel_ns: profiled_ns(&self.kernel_event)?,
+            write_ns,
+            read_ns: profiled_ns(&self.read_event)?,
+            wall_ns: self.start.elapsed().as_nanos() as u64,
+            dag: mem::take(&mut self.dag),
+        })
+    }
+}
+
+impl Drop for PendingRun<'_> {
+    fn drop(&mut self) {
+        if let Err(err) = self.read_event.wait() {
+            tracing::error!("Failed to wait for buffer read: {}", err);
+        }
+    }
+}
+
+impl Transfer {
+    fn blocking(self) -> cl_bool {
+        match self {
+            Transfer::Blocking => CL_BLOCKING,
+            Transfer::Events | Transfer::Finish => CL_NON_BLOCKING,
+        }
+    }
+
+    /// How the kernel is ordered after the uploads
+    fn write_dependency(self) -> Dependency {
+        match self {
+            Transfer::Blocking => Dependency::HostWait,
+            Transfer::Events => Dependency::WaitList,
+            Transfer::Finish => Dependency::QueueOrder,
+        }
+    }
+}
+
+/// Non-blocking writes that may still be in flight, together with the host data they read from
+///
+/// The runtime reads the host memory of a non-blocking write at some later point, so that memory
+/// must stay alive and unmodified until the write event completes. Keeping the data next to its
+/// events makes this explicit, and dropping the struct waits for every write to finish.
+#[derive(Default)]
+struct InFlightWrites<'a> {
+    data: Vec<Cow<'a, [cl_float]>>,
+    events: Vec<Event>,
+}
+
+impl<'a> InFlightWrites<'a> {
+    fn enqueue(
+        &mut self,
+        queue: &CommandQueue,
+        buffer: &mut Buffer<cl_float>,
+        transfer: Transfer,
+        data: Cow<'a, [cl_float]>,
+    ) -> anyhow::Result<()> {
+        // Store the data first, moving a `Cow::Owned` doesn't move its heap allocation
+        self.data.push(data);
+        let data = self.data.last().unwrap();
+
+        let event = retry::call("clEnqueueWriteBuffer", || unsafe {
+            queue.enqueue_write_buffer(buffer, transfer.blocking(), 0, data, &[])
+        })
+        .context("Failed to write to buffer")?;
+        trace::write(buffer.get(), 0, data);
+        self.events.push(event);
+
+        Ok(())
+    }
+
+    /// Fills the first `len` elements of `buffer` with `value`, the kernel waits for it like for
+    /// the writes
+    fn fill(
+        &mut self,
+        queue: &CommandQueue,
+        buffer: &mut Buffer<cl_float>,
+        value: cl_float,
+        len: usize,
+    ) -> anyhow::Result<()> {
+        let event = retry::call("clEnqueueFillBuffer", || unsafe {
+            queue.enqueue_fill_buffer(buffer, &[value], 0, len * mem::size_of::<cl_float>(), &[])
+        })
+        .context("Failed to fill buffer")?;
+        if trace::active() {
+            trace::write(buffer.get(), 0, &vec![value; len]);
+        }
+        self.events.push(event);
+
+        Ok(())
+    }
+
+    fn event_ids(&self) -> Vec<cl_event> {
+        self.events.iter().map(Event::get).collect()
+    }
+}
+
+impl Drop for InFlightWrites<'_> {
+    fn drop(&mut self) {
+        for event in &self.events {
+            if let Err(err) = event.wait() {
+                tracing::error!("Failed to wait for buffer write: {}", err);
+            }
+        }
+    }
+}
+
+/// What [`SaxpyBuffers::set_offset`] and [`SaxpyBuffers::set_detect_unwritten`] fill `z` with, a
+/// NaN whose payload the kernels don't produce
+pub const UNTOUCHED: cl_float = cl_float::from_bits(0x7fc0_dead);
+
+/// Whether `value` is [`UNTOUCHED`], comparing bits as NaNs never compare equal
+fn is_untouched(value: &cl_float) -> bool {
+    value.to_bits() == UNTOUCHED.to_bits()
+}
+
+/// Checks that `result` still holds [`UNTOUCHED`] throughout, returning the first index that
+/// doesn't
+pub fn check_untouched(result: &[cl_float]) -> Result<(), usize> {
+    match result.iter().position(|value| !is_untouched(value)) {
+        Some(i) => Err(i),
+        None => Ok(()),
+    }
+}
+
+/// The first index of `result` still holding [`UNTOUCHED`] and how many do, `None` when the kernel
+/// wrote every result
+pub fn find_unwritten(result: &[cl_float]) -> Option<(usize, usize)> {
+    let first = result.iter().position(is_untouched)?;
+    let count = result[first..]
+        .iter()
+        .filter(|value| is_untouched(value))
+        .count();
+
+    Some((first, count))
+}
+
+/// Recomputes the kernel on the host, returning the number of results checked or the first index
+/// where `result` differs from it
+///
+/// Only every `stride`-th result is checked, and the last one, so huge runs can sample instead of
+/// recomputing everything. The device may contract `a*x + y` into a fused multiply-add, so values
+/// only have to agree up to the relative error `max_error`, times the number of iterations, see
+/// [`Args::max_error`](crate::cli::Args::max_error)
+#[allow(clippy::too_many_arguments)]
+pub fn verify(
+    op: Op,
+    kernel_iters: Option<u32>,
+    max_error: f64,
+    stride: usize,
+    a: Option<cl_float>,
+    x: &[cl_float],
+    y: &[cl_float],
+    result: &[cl_float],
+) -> Result<usize, usize> {
+    let iters = kernel_iters.unwrap_or(1);
+    let tolerance = max_error as cl_float * iters as cl_float;
+
+    // Only saxpy repeats, every iteration feeds its result back in as `y`
+    let host = kernels::op_kernel(op).host;
+    let a = a.unwrap_or_default();
+    let expected = |i: usize| (0..iters).fold(y[i], |z, _| host(a, x[i], z));
+
+    let mut checked = 0;
+    match sample_indices(result.len(), stride)
+        .inspect(|_| checked += 1)
+        .find(|&i| {
+            let expected = expected(i);
+            (result[i] - expected).abs() > tolerance * expected.abs().max(1.0)
+        }) {
+        Some(i) => Err(i),
+        None if result.len() != x.len() => Err(result.len().min(x.len())),
+        None => Ok(checked),
+    }
+}
+
+/// Every `stride`-th index below `len` and the last one, the results [`verify`] checks
+pub fn sample_indices(len: usize, stride: usize) -> impl Iterator<Item = usize> {
+    let stride = stride.max(1);
+    let last = len
+        .checked_sub(1)
+        .filter(|last| !last.is_multiple_of(stride));
+    (0..len).step_by(stride).chain(last)
+}
+
+/// Checks that two runs produced bit-identical results, returning the first mismatching index
+pub fn compare_results(lhs: &[cl_float], rhs: &[cl_float]) -> Result<(), usize> {
+    match lhs
+        .iter()
+        .zip(rhs)
+        .position(|(l, r)| l.to_bits() != r.to_bits())
+    {
+        Some(i) => Err(i),
+        None if lhs.len() != rhs.len() => Err(lhs.len().min(rhs.len())),
+        None => Ok(()),
+    }
+}
+
+/// Creates an uninitialized buffer of `len` floats
+///
+/// `flags` is passed through, so callers pick the access per buffer, e.g. `CL_MEM_HOST_NO_ACCESS`
+/// for intermediates only kernels touch
+pub fn create_buffer(
+    context: &Context,
+    flags: cl_mem_flags,
+    len: usize,
+) -> anyhow::Result<Buffer<cl_float>> {
+    retry::call("clCreateBuffer", || unsafe {
+        Buffer::<cl_float>::create(context, flags, len, ptr::null_mut())
+    })
+    .context("Failed to create buffer")
+}
+
+/// The global size of `groups` work-groups of `group_size` items over `len` elements
+///
+/// Fewer work-items than elements would leave some undone, and more only work with a `guarded`
+/// kernel, which returns right away past the end of the data
+pub fn groups_global_size(
+    groups: usize,
+    group_size: usize,
+    len: usize,
+    guarded: bool,
+) -> anyhow::Result<usize> {
+    let Some(global) = groups.checked_mul(group_size) else {
+        bail!(
+            "--num-groups {} x --group-size {} overflows the global size",
+            groups,
+            group_size
+        );
+    };
+    if global < len {
+        bail!(
+            "--num-groups {} x --group-size {} is {} work-items, which leaves {} of the {} elements undone, use --num-groups {}",
+            groups,
+            group_size,
+            global,
+            len - global,
+            len,
+            len.div_ceil(group_size)
+        );
+    }
+    if global > len && !guarded {
+        bail!(
+            "--num-groups {} x --group-size {} is {} work-items for {} elements, and the kernel has no bounds guard to stop the {} past the end; use a group size dividing {} or the built-in saxpy over --layout soa, which is guarded",
+            groups,
+            group_size,
+            global,
+            len,
+            global - len,
+            len
+        );
+    }
+
+    Ok(global)
+}
+
+/// Rounds `global` up to the next multiple of `local`
+///
+/// The usual NDRange idiom for sizes `local` doesn't divide: the work-items past the end of the
+/// data return right away, see [`SaxpyBuffers::set_bounds_guard`]
+pub fn round_up(global: usize, local: usize) -> usize {
+    global.div_ceil(local) * local
+}
+
+/// The local size of a launch of `kernel` over `len` elements on `device`, logged with the global
+/// size it implies
+///
+/// `requested` (`--local-size`) is taken as is if the kernel allows it. Otherwise it is the largest
+/// multiple of `CL_KERNEL_PREFERRED_WORK_GROUP_SIZE_MULTIPLE` up to `CL_KERNEL_WORK_GROUP_SIZE`,
+/// and no larger than `len` rounded up to the multiple so small inputs don't launch a mostly idle
+/// work-group. Without `guarded` the global size can't be rounded up, so the local size also has to
+/// divide `len`, and `None` leaves the choice to the driver when no multiple does
+pub fn choose_local_size(
+    kernel: &Kernel,
+    device: &Device,
+    len: usize,
+    guarded: bool,
+    requested: Option<usize>,
+) -> anyhow::Result<Option<usize>> {
+    let max_local = kernel
+        .get_work_group_size(device.id())
+        .context("Failed to query CL_KERNEL_WORK_GROUP_SIZE")?;
+    let global = |local| if guarded { round_up(len, local) } else { len };
+
+    if let Some(local) = requested {
+        if local > max_local {
+            bail!(
+                "The local size {} exceeds the kernel's CL_KERNEL_WORK_GROUP_SIZE of {}",
+                local,
+                max_local
+            );
+        }
+        tracing::info!(
+            "Local size {} as requested, global size {}",
+            local,
+            global(local)
+        );
+        return Ok(Some(local));
+    }
+
+    let multiple = kernel
+        .get_work_group_size_multiple(device.id())
+        .context("Failed to query CL_KERNEL_PREFERRED_WORK_GROUP_SIZE_MULTIPLE")?
+        .clamp(1, max_local.max(1));
+    let largest = (max_local / multiple).min(len.div_ceil(multiple)) * multiple;
+    let local = (1..=largest / multiple)
+        .rev()
+        .map(|factor| factor * multiple)
+        .find(|&local| guarded || len.is_multiple_of(local));
+
+    match local {
+        Some(local) => tracing::info!(
+            "Local size {} (a multiple of the preferred {}, the kernel allows up to {}), global size {}",
+            local,
+            multiple,
+            max_local,
+            global(local)
+        ),
+        None => tracing::info!(
+            "No multiple of the preferred work-group size multiple {} divides {}, leaving the local size to the driver",
+            multiple,
+            len
+        ),
+    }
+
+    Ok(local)
+}
+
+/// Logs the device memory the three buffers of `size` elements of `elem_bytes` each take, and the
+/// share of `CL_DEVICE_GLOBAL_MEM_SIZE` that is, which shows how close a size comes to failing
+pub fn log_allocation_summary(size: usize, elem_bytes: usize, device: &Device) {
+    let allocated = 3 * size as u64 * elem_bytes as u64;
+    match device.global_mem_size() {
+        Ok(global) => tracing::info!(
+            "Allocated {} of device memory in 3 buffers, {:.2}% of the {} of global memory",
+            summary::bytes(allocated),
+            allocated as f64 / global.max(1) as f64 * 100.0,
+            summary::bytes(global)
+        ),
+        Err(err) => tracing::info!(
+            "Allocated {} of device memory in 3 buffers (CL_DEVICE_GLOBAL_MEM_SIZE unavailable: {})",
+            summary::bytes(allocated),
+            err
+        ),
+    }
+}
+
+/// Logs an error when the kernel hasn't completed after `z` was read back, which means the read
+/// wasn't ordered after it and may have raced with it
+///
+/// Only debug builds check, every transfer strategy orders the read after the kernel today and this
+/// is there to catch a refactoring that drops the kernel event from the read's wait list
+fn check_completed_before_read(kernel_event: &Event) {
+    match kernel_event.command_execution_status() {
+        Ok(status) if status.0 == CL_COMPLETE => {}
+        Ok(status) => tracing::error!(
+            "ORDERING BUG: the kernel is {} after its results were read back, the read isn't waiting for it and the results may be garbage",
+            status
+        ),
+        Err(err) => tracing::error!(
+            "Failed to query the kernel's status after the read back, can't check the read waited for it: {}",
+            err
+        ),
+    }
+}
+
+/// Reads `z` into `dst` according to `transfer` and waits for the read to complete
+fn read_back(
+    queue: &CommandQueue,
+    z_buffer: &Buffer<cl_float>,
+    dst: &mut [cl_float],
+    kernel_event: &Event,
+    transfer: Transfer,
+) -> anyhow::Result<Event> {
+    match transfer {
+        Transfer::Blocking => retry::call("clEnqueueReadBuffer", || unsafe {
+            queue.enqueue_read_buffer(z_buffer, CL_BLOCKING, 0, dst, &[])
+        })
+        .context("Failed to read buffer"),
+        Transfer::Events => {
+            let events: Vec<cl_event> = vec![kernel_event.get()];
+
+            let read_event = retry::call("clEnqueueReadBuffer", || unsafe {
+                queue.enqueue_read_buffer(z_buffer, CL_NON_BLOCKING, 0, dst, &events)
+            })
+            .context("Failed to read buffer")?;
+
+            read_event.wait().context("Failed to wait to read buffer")?;
+
+            Ok(read_event)
+        }
+        Transfer::Finish => {
+            // The queue is in-order, so the read runs after the kernel without a wait list
+            let read_event = retry::call("clEnqueueReadBuffer", || unsafe {
+                queue.enqueue_read_buffer(z_buffer, CL_NON_BLOCKING, 0, dst, &[])
+            })
+            .context("Failed to read buffer")?;
+
+            queue.finish().context("Failed to finish queue")?;
+
+            Ok(read_event)
+        }
+    }
+}
+
+/// Maps the pinned `staging` buffer, reads `z` into the mapping and copies the result out
+fn read_back_pinned(
+    queue: &CommandQueue,
+    z_buffer: &Buffer<cl_float>,
+    staging: &Buffer<cl_float>,
+    kernel_event: &Event,
+    transfer: Transfer,
+    len: usize,
+) -> anyhow::Result<(Vec<cl_float>, Event)> {
+    let mut mapped: cl_mem = ptr::null_mut();
+    retry::call("clEnqueueMapBuffer", || unsafe {
+        queue.enqueue_map_buffer(
+            staging,
+            CL_BLOCKING,
+            CL_MAP_WRITE_INVALIDATE_REGION,
+            0,
+            len * mem::size_of::<cl_float>(),
+            &mut mapped,
+            &[],
+        )
+    })
+    .context("Failed to map staging buffer")?;
+
+    // The mapping stays valid until it is unmapped below
+    let dst = unsafe { slice::from_raw_parts_mut(mapped as *mut cl_float, len) };
+    let read = read_back(queue, z_buffer, dst, kernel_event, transfer);
+    let result = dst.to_vec();
+
+    let unmap_event = unsafe { queue.enqueue_unmap_mem_object(staging.get(), mapped, &[]) }
+        .context("Failed to unmap staging buffer")?;
+    unmap_event
+        .wait()
+        .context("Failed to wait to unmap staging buffer")?;
+
+    Ok((result, read?))
+}
+
+/// Fails when the kernel took `kernel_ns`, more than the `max_kernel_ms` budget of
+/// `--max-kernel-ms`, so CI catches a change that makes the kernel slower. `what` names the launch
+/// in the message
+pub fn check_kernel_budget(
+    max_kernel_ms: Option<f64>,
+    kernel_ns: u64,
+    what: &str,
+) -> anyhow::Result<()> {
+    match max_kernel_ms {
+        Some(max_kernel_ms) if kernel_ns as f64 > max_kernel_ms * 1e6 => bail!(
+            "The kernel of {} took {}, over the --max-kernel-ms budget of {} ms",
+            what,
+            summary::duration(kernel_ns),
+            max_kernel_ms
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Duration (ns) between the start and end profiling counters of `event`
+///
+/// An end before the start, which some drivers report for commands they skip, counts as 0 with a
+/// warning
+pub fn profiled_ns(event: &Event) -> anyhow::Result<u64> {
+    let start_time = event
+        .profiling_command_start()
+        .context("Failed to start profiling command")?;
+
+    let end_time = event
+        .profiling_command_end()
+        .context("Failed to end profiling command")?;
+
+    if end_time < start_time {
+        tracing::warn!(
+            "Profiling counters out of order: start {}, end {}",
+            start_time,
+            end_time
+        );
+    }
+
+    Ok(end_time.saturating_sub(start_time))
+}
+
+#[cfg(test)]
+mod tests {
+    use opencl3::{
+        event::Event,
+        memory::{CL_MEM_READ_ONLY, CL_MEM_WRITE_ONLY},
+        types::{cl_float, CL_NON_BLOCKING},
+    };
+
+    use super::*;
+    use crate::{launch::launch, test_device};
+
+    /// `queued <= submit <= start <= end`, and the duration is the last two apart
+    fn assert_counters_ordered(event: &Event) {
+        event.wait().unwrap();
+        let queued = event.profiling_command_queued().unwrap();
+        let submit = event.profiling_command_submit().unwrap();
+        let start = event.profiling_command_start().unwrap();
+        let end = event.profiling_command_end().unwrap();
+
+        assert!(
+            queued <= submit && submit <= start && start <= end,
+            "queued {}, submit {}, start {}, end {}",
+            queued,
+            submit,
+            start,
+            end
+        );
+        assert_eq!(profiled_ns(event).unwrap(), end - start);
+    }
+
+    #[test]
+    fn profiling_counters_are_ordered() {
+        let Some(test) = test_device::get() else {
+            return;
+        };
+        eprintln!("Profiling on {}", test.device.name().unwrap());
+        let len = 1024;
+        let x: Vec<cl_float> = (0..len).map(|i| i as cl_float).collect();
+        let mut x_buffer = create_buffer(&test.context, CL_MEM_READ_ONLY, len).unwrap();
+        let mut y_buffer = create_buffer(&test.context, CL_MEM_READ_ONLY, len).unwrap();
+        let z_buffer = create_buffer(&test.context, CL_MEM_WRITE_ONLY, len).unwrap();
+
+        let write = unsafe {
+            test.queue
+                .enqueue_write_buffer(&mut x_buffer, CL_NON_BLOCKING, 0, &x, &[])
+        }
+        .unwrap();
+        assert_counters_ordered(&write);
+        let write = unsafe {
+            test.queue
+                .enqueue_write_buffer(&mut y_buffer, CL_NON_BLOCKING, 0, &x, &[])
+        }
+        .unwrap();
+        assert_counters_ordered(&write);
+
+        let kernel = test_device::saxpy_kernel(&test);
+        let a: cl_float = 2.0;
+        let launch = unsafe {
+            launch!(&kernel, global = len; z: &z_buffer, x: &x_buffer, y: &y_buffer, a: &a)
+                .enqueue_nd_range(&test.queue)
+        }
+        .unwrap();
+        assert_counters_ordered(&launch);
+    }
+
+    #[test]
+    fn offset_leaves_the_front_untouched() {
+        let Some(test) = test_device::get() else {
+            return;
+        };
+        let kernel = test_device::saxpy_kernel(&test);
+        let (x, y) = test_device::inputs(1024);
+        let offset = 100;
+
+        let mut buffers = SaxpyBuffers::create(&test.context, Layout::Soa, x.len()).unwrap();
+        buffers.set_offset(offset);
+        let run = buffers
+            .run(&test.queue, &kernel, Transfer::default(), &x, &y, Some(2.0))
+            .unwrap();
+
+        assert_eq!(check_untouched(&run.result[..offset]), Ok(()));
+        assert_eq!(
+            verify(
+                Op::Saxpy,
+                None,
+                1e-6,
+                1,
+                Some(2.0),
+                &x[offset..],
+                &y[offset..],
+                &run.result[offset..]
+            ),
+            Ok(x.len() - offset)
+        );
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn concurrent_async_saxpys() {
+        let Some(test) = test_device::get() else {
+            return;
+        };
+        let second_queue = crate::create_queue(&test.context).unwrap();
+        let (first_kernel, second_kernel) = (
+            test_device::saxpy_kernel(&test),
+            test_device::saxpy_kernel(&test),
+        );
+        let (x, y) = test_device::inputs(4096);
+
+        let runs = crate::event_future::block_on_all(vec![
+            saxpy_async(
+                &test.context,
+                &test.queue,
+                &first_kernel,
+                Layout::Soa,
+                &x,
+                &y,
+                Some(2.0),
+            ),
+            saxpy_async(
+                &test.context,
+                &second_queue,
+                &second_kernel,
+                Layout::Soa,
+                &x,
+                &y,
+                Some(3.0),
+            ),
+        ]);
+
+        for (run, a) in runs.into_iter().zip([2.0, 3.0]) {
+            let run = run.unwrap();
+            assert_eq!(
+                verify(Op::Saxpy, None, 1e-6, 1, Some(a), &x, &y, &run.result),
+                Ok(x.len()),
+                "a = {}",
+                a
+            );
+        }
+    }
+}
@@ -0,0 +1,607 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+//! The `serve` and `client` commands: keeping the context and programs warm for short-lived scripts
+//!
+//! `serve --socket PATH` builds the element-wise programs of both layouts once and answers requests
+//! on a Unix socket at PATH (on Windows a TCP address on localhost, such as `127.0.0.1:7878`).
+//! Every request runs through [`SaxpyBuffers`] like `run` does, with the buffers taken from one
+//! [`BufferPool`] across requests. Connections are served one at a time, a connection may send any
+//! number of requests and gets the replies in order.
+//!
+//! Every message is a frame: its length as a little-endian `u32`, then that many bytes, encoded as
+//! described in [`crate::wire`].
+//!
+//! - A request holds its id (`u64`), the kernel name (`u32` length and UTF-8), the scalar arguments
+//!   (`u32` count and `f32`s) and the input arrays (`u32` count, then per array a `u32` length and
+//!   `f32`s), `x` and `y` for the built-in kernels.
+//! - A reply holds the id of its request (`u64`) and a status byte. [`STATUS_OK`] is followed by the
+//!   output array (`u32` length and `f32`s), [`STATUS_INVALID`] (a malformed request or one the
+//!   kernel can't take) and [`STATUS_FAILED`] (the kernel failed to run) by the message (`u32`
+//!   length and UTF-8).
+//!
+//! The id lets connections be served concurrently later, with replies out of order.
+
+#[cfg(windows)]
+use std::net::{TcpListener as Listener, TcpStream as Stream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener as Listener, UnixStream as Stream};
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    time::Instant,
+};
+
+use anyhow::{anyhow, bail, Context as _};
+use opencl3::{command_queue::CommandQueue, context::Context, kernel::Kernel, types::cl_float};
+
+use crate::{
+    cli::{Args, Layout},
+    kernels::{self, BuildFlags, BuiltinKernel},
+    pool::BufferPool,
+    run,
+    saxpy::{self, SaxpyBuffers},
+    summary, vector_file,
+    wire::{put_floats, put_len, put_str, put_u64, put_u8, Reader},
+};
+
+/// Where `serve` listens and `client` connects when `--socket` isn't given
+#[cfg(unix)]
+pub const DEFAULT_SOCKET: &str = "/tmp/hello-opencl3.sock";
+#[cfg(windows)]
+pub const DEFAULT_SOCKET: &str = "127.0.0.1:7878";
+
+pub const STATUS_OK: u8 = 0;
+pub const STATUS_INVALID: u8 = 1;
+pub const STATUS_FAILED: u8 = 2;
+
+/// Frames above this are refused, which keeps a corrupt length from allocating gigabytes
+const MAX_FRAME: usize = 1 << 30;
+
+/// A kernel to run and its arguments, see the module docs
+pub struct Request {
+    pub id: u64,
+    pub kernel: String,
+    pub scalars: Vec<cl_float>,
+    pub arrays: Vec<Vec<cl_float>>,
+}
+
+/// The answer to the request of the same id
+pub struct Reply {
+    pub id: u64,
+    pub outcome: Outcome,
+}
+
+pub enum Outcome {
+    Output(Vec<cl_float>),
+    /// The request was malformed or doesn't fit its kernel
+    Invalid(String),
+    /// The kernel failed to run
+    Failed(String),
+}
+
+impl Request {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        put_u64(&mut bytes, self.id);
+        put_str(&mut bytes, &self.kernel);
+        put_floats(&mut bytes, &self.scalars);
+        put_len(&mut bytes, self.arrays.len());
+        for array in &self.arrays {
+            put_floats(&mut bytes, array);
+        }
+
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> anyhow::Result<Self> {
+        let mut reader = Reader::new(bytes);
+        let id = reader.u64()?;
+        let kernel = reader.str()?;
+        let scalars = reader.floats()?;
+        let count = reader.len()?;
+        let arrays = (0..count)
+            .map(|_| reader.floats())
+            .collect::<anyhow::Result<_>>()?;
+        reader.end()?;
+
+        Ok(Self {
+            id,
+            kernel,
+            scalars,
+            arrays,
+        })
+    }
+}
+
+impl Reply {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        put_u64(&mut bytes, self.id);
+        match &self.outcome {
+            Outcome::Output(values) => {
+                put_u8(&mut bytes, STATUS_OK);
+                put_floats(&mut bytes, values);
+            }
+            Outcome::Invalid(message) => {
+                put_u8(&mut bytes, STATUS_INVALID);
+                put_str(&mut bytes, message);
+            }
+            Outcome::Failed(message) => {
+                put_u8(&mut bytes, STATUS_FAILED);
+                put_str(&mut bytes, message);
+            }
+        }
+
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> anyhow::Result<Self> {
+        let mut reader = Reader::new(bytes);
+        let id = reader.u64()?;
+        let outcome = match reader.u8()? {
+            STATUS_OK => Outcome::Output(reader.floats()?),
+            STATUS_INVALID => Outcome::Invalid(reader.str()?),
+            STATUS_FAILED => Outcome::Failed(reader.str()?),
+            status => bail!("Unknown reply status {}", status),
+        };
+        reader.end()?;
+
+        Ok(Self { id, outcome })
+    }
+}
+
+/// Reads the next frame, `None` when the peer closed the connection between frames
+fn read_frame(stream: &mut impl Read) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    match stream.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err).context("Failed to read a frame length"),
+    }
+
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME {
+        bail!(
+            "A frame of {} bytes exceeds the limit of {}",
+            len,
+            MAX_FRAME
+        );
+    }
+    let mut frame = vec![0; len];
+    stream
+        .read_exact(&mut frame)
+        .context("The connection closed in the middle of a frame")?;
+
+    Ok(Some(frame))
+}
+
+fn write_frame(stream: &mut impl Write, frame: &[u8]) -> anyhow::Result<()> {
+    if frame.len() > MAX_FRAME {
+        bail!(
+            "A frame of {} bytes exceeds the limit of {}",
+            frame.len(),
+            MAX_FRAME
+        );
+    }
+    stream.write_all(&(frame.len() as u32).to_le_bytes())?;
+    stream.write_all(frame)?;
+    stream.flush()?;
+
+    Ok(())
+}
+
+/// A request that fits its kernel
+struct Launch<'r> {
+    layout: Layout,
+    x: &'r [cl_float],
+    y: &'r [cl_float],
+    a: Option<cl_float>,
+}
+
+/// What `serve` keeps across requests
+struct Server<'a> {
+    context: &'a Context,
+    queue: &'a CommandQueue,
+    args: &'a Args,
+    /// The kernels of both built-in programs by name
+    kernels: HashMap<String, Kernel>,
+    pool: BufferPool,
+}
+
+/// The `serve` command: answers requests on `socket` until the process is stopped
+pub fn serve(
+    context: &Context,
+    queue: &CommandQueue,
+    args: &Args,
+    socket: &str,
+) -> anyhow::Result<()> {
+    let mut kernels = HashMap::new();
+    for layout in [Layout::Soa, Layout::Aos] {
+        let program = kernels::build_program(
+            context,
+            kernels::program_source(layout),
+            &kernels::kernel_options(args, layout)?,
+            &format!("the built-in {} program", layout),
+            BuildFlags::from_args(args),
+        )?;
+        kernels.extend(kernels::program_kernels(&program)?);
+    }
+
+    let listener = listen(socket)?;
+    let mut names: Vec<&str> = kernels.keys().map(String::as_str).collect();
+    names.sort_unstable();
+    println!("serving {} on {}", names.join(", "), socket);
+
+    let mut server = Server {
+        context,
+        queue,
+        args,
+        kernels,
+        pool: BufferPool::default(),
+    };
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                tracing::warn!("Failed to accept a connection: {}", err);
+                continue;
+            }
+        };
+        // A broken connection only ends itself, the server carries on with the next one
+        if let Err(err) = server.connection(stream) {
+            tracing::warn!("Connection ended: {:#}", err);
+        }
+    }
+
+    Ok(())
+}
+
+/// Binds `socket`, replacing the socket file a server that is gone left behind
+#[cfg(unix)]
+fn listen(socket: &str) -> anyhow::Result<Listener> {
+    if std::path::Path::new(socket).exists() {
+        if Stream::connect(socket).is_ok() {
+            bail!("Another server is listening on {} already", socket);
+        }
+        std::fs::remove_file(socket)
+            .with_context(|| format!("Failed to remove the stale socket {}", socket))?;
+    }
+
+    Listener::bind(socket).with_context(|| format!("Failed to listen on {}", socket))
+}
+
+/// Binds `socket`, which has to be an address on localhost
+#[cfg(windows)]
+fn listen(socket: &str) -> anyhow::Result<Listener> {
+    let address: std::net::SocketAddr = socket
+        .parse()
+        .with_context(|| format!("'{}' isn't an address such as 127.0.0.1:7878", socket))?;
+    if !address.ip().is_loopback() {
+        bail!(
+            "{} isn't on localhost, the server only listens locally",
+            socket
+        );
+    }
+
+    Listener::bind(address).with_context(|| format!("Failed to listen on {}", socket))
+}
+
+impl Server<'_> {
+    fn connection(&mut self, mut stream: Stream) -> anyhow::Result<()> {
+        while let Some(frame) = read_frame(&mut stream)? {
+            let reply = match Request::decode(&frame) {
+                Ok(request) => Reply {
+                    id: request.id,
+                    outcome: self.handle(&request),
+                },
+                // Without a request there is no id to answer to, the frame is at least 8 bytes
+                // when only what follows the id is malformed
+                Err(err) => Reply {
+                    id: frame
+                        .get(..8)
+                        .map_or(0, |id| u64::from_le_bytes(id.try_into().unwrap())),
+                    outcome: Outcome::Invalid(format!("Malformed request: {:#}", err)),
+                },
+            };
+            write_frame(&mut stream, &reply.encode()).context("Failed to send a reply")?;
+        }
+
+        Ok(())
+    }
+
+    fn handle(&mut self, request: &Request) -> Outcome {
+        let start = Instant::now();
+        let launch = match self.check(request) {
+            Ok(launch) => launch,
+            Err(err) => {
+                tracing::warn!("Request {}: {:#}", request.id, err);
+                return Outcome::Invalid(format!("{:#}", err));
+            }
+        };
+
+        match self.launch(&request.kernel, &launch) {
+            Ok(result) => {
+                tracing::debug!(
+                    "Request {}: {} over {} elements in {}",
+                    request.id,
+                    request.kernel,
+                    launch.x.len(),
+                    summary::duration(start.elapsed().as_nanos() as u64)
+                );
+                Outcome::Output(result)
+            }
+            Err(err) => {
+                tracing::warn!("Request {} failed: {:#}", request.id, err);
+                Outcome::Failed(format!("{:#}", err))
+            }
+        }
+    }
+
+    /// The launch `request` asks for, or why its kernel can't take it
+    fn check<'r>(&self, request: &'r Request) -> anyhow::Result<Launch<'r>> {
+        let served = request
+            .kernel
+            .parse::<BuiltinKernel>()
+            .ok()
+            .and_then(BuiltinKernel::layout_op)
+            .filter(|_| self.kernels.contains_key(&request.kernel));
+        let Some((layout, op)) = served else {
+            let mut names: Vec<&str> = self.kernels.keys().map(String::as_str).collect();
+            names.sort_unstable();
+            bail!(
+                "'{}' isn't a kernel the server runs, expected one of: {}",
+                request.kernel,
+                names.join(", ")
+            );
+        };
+
+        let a = match (kernels::op_kernel(op).scalar, request.scalars.as_slice()) {
+            (true, &[a]) => Some(a),
+            (false, []) => None,
+            (true, scalars) => bail!(
+                "{} takes the scalar a, the request has {} scalars",
+                request.kernel,
+                scalars.len()
+            ),
+            (false, scalars) => bail!(
+                "{} takes no scalar, the request has {}",
+                request.kernel,
+                scalars.len()
+            ),
+        };
+        let [x, y] = request.arrays.as_slice() else {
+            bail!(
+                "{} takes the arrays x and y, the request has {} arrays",
+                request.kernel,
+                request.arrays.len()
+            );
+        };
+        if x.len() != y.len() {
+            bail!("x and y differ in length ({} vs {})", x.len(), y.len());
+        }
+        if x.is_empty() {
+            bail!("x and y are empty");
+        }
+
+        Ok(Launch { layout, x, y, a })
+    }
+
+    fn launch(&mut self, name: &str, launch: &Launch) -> anyhow::Result<Vec<cl_float>> {
+        let kernel = &self.kernels[name];
+        let mut buffers =
+            SaxpyBuffers::from_pool(&mut self.pool, self.context, launch.layout, launch.x.len())?;
+        let run = buffers.run(
+            self.queue,
+            kernel,
+            self.args.transfer,
+            launch.x,
+            launch.y,
+            launch.a,
+        )?;
+        // `run` waited for the readback, nothing is in flight on the buffers anymore
+        buffers.return_to(&mut self.pool);
+
+        Ok(run.result)
+    }
+}
+
+/// The `client` command: sends the kernel of `--op` and `--layout` over the inputs of `run` to the
+/// server on `socket`, checks the reply and prints it
+pub fn client(args: &Args, socket: &str) -> anyhow::Result<()> {
+    let (x, y) = run::inputs(args)?;
+    let a = args.op.scalar(args.scalar());
+    let request = Request {
+        id: 1,
+        kernel: kernels::op_kernel(args.op)
+            .kernel_name(args.layout)
+            .to_owned(),
+        scalars: a.into_iter().collect(),
+        arrays: vec![x, y],
+    };
+
+    let start = Instant::now();
+    let mut stream =
+        Stream::connect(socket).with_context(|| format!("Failed to connect to {}", socket))?;
+    write_frame(&mut stream, &request.encode()).context("Failed to send the request")?;
+    let frame = read_frame(&mut stream)?
+        .ok_or_else(|| anyhow!("The server closed the connection without replying"))?;
+    let reply = Reply::decode(&frame).context("Malformed reply")?;
+    let round_trip_ns = start.elapsed().as_nanos() as u64;
+
+    if reply.id != request.id {
+        bail!(
+            "The reply is for request {}, expected {}",
+            reply.id,
+            request.id
+        );
+    }
+    let result = match reply.outcome {
+        Outcome::Output(result) => result,
+        Outcome::Invalid(message) => bail!("The server rejected the request: {}", message),
+        Outcome::Failed(message) => bail!("The server failed to run the request: {}", message),
+    };
+
+    let [x, y] = &request.arrays[..] else {
+        unreachable!("the request holds x and y")
+    };
+    if result.len() != x.len() {
+        bail!(
+            "The server returned {} values for {} inputs",
+            result.len(),
+            x.len()
+        );
+    }
+    let checked = match saxpy::verify(args.op, None, args.max_error(), 1, a, x, y, &result) {
+        Ok(checked) => checked,
+        Err(i) => bail!("Verification failed at index {}: got {}", i, result[i]),
+    };
+
+    if let Some(path) = &args.out_file {
+        vector_file::write(path, &result)?;
+        println!("wrote {} results to {}", result.len(), path.display());
+    } else {
+        for (i, value) in summary::first_and_last(&result, 1) {
+            println!("z[{}] = {}", i, value);
+        }
+    }
+    println!(
+        "{} over {} elements via {}, verified {} results, round trip {}",
+        request.kernel,
+        result.len(),
+        socket,
+        checked,
+        summary::duration(round_trip_ns)
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn request() -> Request {
+        Request {
+            id: 42,
+            kernel: "saxpy_float".to_owned(),
+            scalars: vec![2.0],
+            arrays: vec![vec![1.0, 2.0, 3.0], vec![-0.5, cl_float::NAN, 1e-40]],
+        }
+    }
+
+    fn err<T>(result: anyhow::Result<T>) -> String {
+        match result {
+            Ok(_) => panic!("decoded a malformed frame"),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    fn bits(values: &[cl_float]) -> Vec<u32> {
+        values.iter().map(|value| value.to_bits()).collect()
+    }
+
+    #[test]
+    fn request_round_trip() {
+        let sent = request();
+        let received = Request::decode(&sent.encode()).unwrap();
+
+        assert_eq!(received.id, 42);
+        assert_eq!(received.kernel, "saxpy_float");
+        assert_eq!(bits(&received.scalars), bits(&sent.scalars));
+        assert_eq!(received.arrays.len(), 2);
+        for (received, sent) in received.arrays.iter().zip(&sent.arrays) {
+            assert_eq!(bits(received), bits(sent));
+        }
+    }
+
+    #[test]
+    fn reply_round_trip() {
+        let outcomes = [
+            Outcome::Output(vec![4.0, 6.5]),
+            Outcome::Invalid("no kernel 'nope'".to_owned()),
+            Outcome::Failed("CL_OUT_OF_RESOURCES".to_owned()),
+        ];
+        for (id, outcome) in (7..).zip(outcomes) {
+            let sent = Reply { id, outcome };
+            let received = Reply::decode(&sent.encode()).unwrap();
+
+            assert_eq!(received.id, id);
+            match (&received.outcome, &sent.outcome) {
+                (Outcome::Output(received), Outcome::Output(sent)) => {
+                    assert_eq!(bits(received), bits(sent))
+                }
+                (Outcome::Invalid(received), Outcome::Invalid(sent))
+                | (Outcome::Failed(received), Outcome::Failed(sent)) => {
+                    assert_eq!(received, sent)
+                }
+                _ => panic!("reply {} decoded to another outcome", id),
+            }
+        }
+    }
+
+    #[test]
+    fn malformed_messages() {
+        let bytes = request().encode();
+        for len in [0, 7, 12, bytes.len() - 1] {
+            assert_eq!(
+                err(Request::decode(&bytes[..len])),
+                "The message ends early",
+                "{} bytes",
+                len
+            );
+        }
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert_eq!(
+            err(Request::decode(&longer)),
+            "1 bytes left over at the end of the message"
+        );
+
+        let mut reply = Reply {
+            id: 1,
+            outcome: Outcome::Output(vec![1.0]),
+        }
+        .encode();
+        reply[8] = 9;
+        assert_eq!(err(Reply::decode(&reply)), "Unknown reply status 9");
+        assert_eq!(err(Reply::decode(&reply[..8])), "The message ends early");
+    }
+
+    #[test]
+    fn frames() {
+        let mut stream = Vec::new();
+        write_frame(&mut stream, b"first").unwrap();
+        write_frame(&mut stream, b"").unwrap();
+
+        let mut reader = Cursor::new(stream);
+        assert_eq!(read_frame(&mut reader).unwrap().unwrap(), b"first");
+        assert_eq!(read_frame(&mut reader).unwrap().unwrap(), b"");
+        // Closed between frames
+        assert!(read_frame(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn malformed_frames() {
+        let too_long = (MAX_FRAME as u32 + 1).to_le_bytes();
+        assert_eq!(
+            err(read_frame(&mut Cursor::new(too_long))),
+            format!(
+                "A frame of {} bytes exceeds the limit of {}",
+                MAX_FRAME + 1,
+                MAX_FRAME
+            )
+        );
+
+        let mut truncated = 10u32.to_le_bytes().to_vec();
+        truncated.extend(b"short");
+        assert_eq!(
+            err(read_frame(&mut Cursor::new(truncated))),
+            "The connection closed in the middle of a frame"
+        );
+        // Part of a length isn't a frame boundary either way, it reads as a closed connection
+        assert!(read_frame(&mut Cursor::new([1, 0])).unwrap().is_none());
+    }
+}
@@ -0,0 +1,372 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+//! `run --threads`: saxpy from several host threads sharing one context and program
+//!
+//! OpenCL allows calling the API from any thread, with one exception: setting the arguments of a
+//! kernel (`clSetKernelArg`) isn't safe while another thread uses the same `cl_kernel`. opencl3
+//! reflects that, `Context`, `Program` and `CommandQueue` are `Send` and `Sync` while `Kernel` is
+//! only `Send`. So [`SharedEngine`] shares the context and the built program between threads, and
+//! every thread creates its own kernel and command queue from them with [`SharedEngine::kernel`]
+//! and [`SharedEngine::queue`]. No `unsafe impl` is needed, the engine is `Send` and `Sync` through
+//! its fields, which the assertion below keeps true. A kernel can't end up shared by accident
+//! either, `thread::scope` only lets a thread borrow what is `Sync`.
+//!
+//! With `--repeat N` every thread runs N launches on its queue and kernel, and checks each result,
+//! so `run --threads 8 --repeat 100 --size 4K` stresses concurrent launches on one context.
+
+use std::{sync::Arc, thread};
+
+use anyhow::bail;
+use opencl3::{
+    command_queue::CommandQueue, context::Context, kernel::Kernel, program::Program,
+    types::cl_float,
+};
+
+use crate::{
+    cancel,
+    cli::{Args, Layout, Transfer},
+    error::ClContext,
+    kernels::{self, BuildFlags},
+    run,
+    saxpy::{self, SaxpyBuffers, SaxpyRun},
+};
+
+/// A built saxpy program that threads can share, see the module docs
+#[derive(Clone)]
+pub struct SharedEngine {
+    context: Arc<Context>,
+    program: Arc<Program>,
+    kernel_name: &'static str,
+    layout: Layout,
+    transfer: Transfer,
+}
+
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<SharedEngine>();
+};
+
+impl SharedEngine {
+    /// Builds the built-in kernel `args` ask for over inputs in `layout` into a program on `context`
+    pub fn new(context: Arc<Context>, args: &Args, layout: Layout) -> anyhow::Result<Self> {
+        let spec = kernels::layout_kernel(layout, args.op, args.kernel_iters)?;
+        let options = kernels::kernel_options(args, layout)?;
+        let program = kernels::build_program(
+            &context,
+            spec.source,
+            &options,
+            spec.name,
+            BuildFlags::from_args(args),
+        )?;
+        // Checks the signature once, the kernels of the threads are created from the same program
+        kernels::program_kernel(
+            &program,
+            Some(spec.name),
+            spec.name,
+            &options,
+            layout,
+            args.op,
+        )?;
+
+        Ok(Self {
+            context,
+            program: Arc::new(program),
+            kernel_name: spec.name,
+            layout,
+            transfer: args.transfer,
+        })
+    }
+
+    /// A new profiling queue on the device of the context, for the calling thread
+    pub fn queue(&self) -> anyhow::Result<CommandQueue> {
+        crate::create_queue(&self.context).context("Failed to create queue")
+    }
+
+    /// A new kernel object from the shared program, for the calling thread
+    pub fn kernel(&self) -> anyhow::Result<Kernel> {
+        Kernel::create(&self.program, self.kernel_name).context("Failed to create kernel")
+    }
+
+    /// Runs the kernel `launches` times over `x` and `y` on a queue, kernel and buffers of the
+    /// calling thread, handing every run to `on_run`. Stops early after Ctrl-C
+    pub fn run(
+        &self,
+        x: &[cl_float],
+        y: &[cl_float],
+        a: Option<cl_float>,
+        launches: usize,
+        mut on_run: impl FnMut(&SaxpyRun),
+    ) -> anyhow::Result<()> {
+        let queue = self.queue()?;
+        let kernel = self.kernel()?;
+        let mut buffers = SaxpyBuffers::create(&self.context, self.layout, x.len())?;
+
+        for _ in 0..launches {
+            if cancel::requested() {
+                break;
+            }
+            on_run(&buffers.run(&queue, &kernel, self.transfer, x, y, a)?);
+        }
+
+        Ok(())
+    }
+
+    /// Like [`SharedEngine::run`], but every launch is awaited instead of blocking the calling
+    /// thread, so several of these futures can share one thread
+    #[cfg(feature = "async")]
+    pub async fn run_async(
+        &self,
+        x: &[cl_float],
+        y: &[cl_float],
+        a: Option<cl_float>,
+        launches: usize,
+        mut on_run: impl FnMut(&SaxpyRun),
+    ) -> anyhow::Result<()> {
+        let queue = self.queue()?;
+        let kernel = self.kernel()?;
+        let mut buffers = SaxpyBuffers::create(&self.context, self.layout, x.len())?;
+
+        for _ in 0..launches {
+            if cancel::requested() {
+                break;
+            }
+            on_run(&buffers.run_async(&queue, &kernel, x, y, a).await?);
+        }
+
+        Ok(())
+    }
+}
+
+/// What one thread of [`run_threads`] ran
+#[derive(Default)]
+struct ThreadReport {
+    launches: usize,
+    wall_ns: u64,
+    kernel_ns: u64,
+    /// The longest kernel time of a single launch, for `--max-kernel-ms`
+    slowest_kernel_ns: u64,
+    /// Results verified over all launches
+    checked: usize,
+    /// The first launch that failed verification, the index and the value there
+    mismatch: Option<(usize, usize, cl_float)>,
+}
+
+impl ThreadReport {
+    /// Adds `run` to the report, checking its result with `verify`
+    fn record(&mut self, run: &SaxpyRun, verify: impl Fn(&[cl_float]) -> Result<usize, usize>) {
+        self.wall_ns += run.wall_ns;
+        self.kernel_ns += run.kernel_ns;
+        self.slowest_kernel_ns = self.slowest_kernel_ns.max(run.kernel_ns);
+        match verify(&run.result) {
+            Ok(checked) => self.checked += checked,
+            Err(index) => {
+                let value = run.result.get(index).copied().unwrap_or(cl_float::NAN);
+                self.mismatch.get_or_insert((self.launches, index, value));
+            }
+        }
+        self.launches += 1;
+    }
+}
+
+/// Runs saxpy over the inputs of `args` from `threads` threads at once, each with its own queue,
+/// `--repeat` times per thread, and checks every result
+///
+/// With `--async` the "threads" are futures on one host thread instead, see [`crate::event_future`]
+pub fn run_threads(context: Arc<Context>, args: &Args, threads: usize) -> anyhow::Result<()> {
+    let engine = SharedEngine::new(context, args, args.layout)?;
+    let (x, y) = run::inputs(args)?;
+    let a = args.op.scalar(args.scalar());
+    let launches = args.repeat.unwrap_or(1);
+
+    let verify = |result: &[cl_float]| {
+        if args.no_verify {
+            return Ok(0);
+        }
+        saxpy::verify(
+            args.op,
+            args.kernel_iters,
+            args.max_error(),
+            args.verify_stride.unwrap_or(1),
+            a,
+            &x,
+            &y,
+            result,
+        )
+    };
+    let reports: Vec<anyhow::Result<ThreadReport>> = if args.async_tasks {
+        #[cfg(feature = "async")]
+        {
+            let (engine, x, y, verify) = (&engine, &x, &y, &verify);
+            let tasks = (0..threads)
+                .map(|_| async move {
+                    let mut report = ThreadReport::default();
+                    engine
+                        .run_async(x, y, a, launches, |run| report.record(run, verify))
+                        .await?;
+                    Ok(report)
+                })
+                .collect();
+            crate::event_future::block_on_all(tasks)
+        }
+        #[cfg(not(feature = "async"))]
+        bail!("--async needs the async feature, build with --features async")
+    } else {
+        thread::scope(|scope| {
+            let handles: Vec<_> = (0..threads)
+                .map(|_| {
+                    let engine = engine.clone();
+                    let (x, y, verify) = (&x, &y, &verify);
+                    scope.spawn(move || {
+                        let mut report = ThreadReport::default();
+                        engine.run(x, y, a, launches, |run| report.record(run, verify))?;
+                        Ok(report)
+                    })
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|handle| match handle.join() {
+                    Ok(report) => report,
+                    Err(_) => bail!("A saxpy thread panicked"),
+                })
+                .collect()
+        })
+    };
+
+    let unit = if args.async_tasks { "task" } else { "thread" };
+    let mut failed = None;
+    let mut slowest = (0, 0);
+    for (i, report) in reports.into_iter().enumerate() {
+        let report =
+            report.map_err(|err| err.context(format!("The saxpy {} {} failed", unit, i)))?;
+        if let Some((launch, index, value)) = report.mismatch {
+            println!(
+                "{} {:>3}: verification failed in launch {} at index {}: got {}",
+                unit, i, launch, index, value
+            );
+            failed.get_or_insert(i);
+            continue;
+        }
+        if report.slowest_kernel_ns > slowest.1 {
+            slowest = (i, report.slowest_kernel_ns);
+        }
+
+        let runs = report.launches.max(1) as u64;
+        let verified = if args.no_verify {
+            "not verified".to_owned()
+        } else {
+            format!(
+                "verified {} of {} results",
+                report.checked,
+                x.len() * report.launches
+            )
+        };
+        println!(
+            "{} {:>3}: end-to-end {:>10} ns, kernel {:>10} ns (mean of {} launches), {}",
+            unit,
+            i,
+            report.wall_ns / runs,
+            report.kernel_ns / runs,
+            report.launches,
+            verified
+        );
+    }
+
+    if let Some(i) = failed {
+        bail!("Verification failed in {} {}", unit, i);
+    }
+    saxpy::check_kernel_budget(
+        args.max_kernel_ms,
+        slowest.1,
+        &format!("the slowest launch ({} {})", unit, slowest.0),
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_device;
+
+    /// Runs `launches` saxpys over 4K elements from each of `threads` threads sharing one engine,
+    /// verifying every result, or `None` without a device
+    fn run_shared(threads: usize, launches: usize) -> Option<Vec<ThreadReport>> {
+        let test = test_device::get()?;
+        let args = Args::parse_from(["run".to_owned()]).unwrap();
+        let engine = SharedEngine::new(Arc::new(test.context), &args, Layout::Soa).unwrap();
+        let (x, y) = test_device::inputs(4096);
+        let a = args.op.scalar(args.scalar());
+        let verify = |result: &[cl_float]| {
+            saxpy::verify(
+                args.op,
+                args.kernel_iters,
+                args.max_error(),
+                1,
+                a,
+                &x,
+                &y,
+                result,
+            )
+        };
+
+        let reports = thread::scope(|scope| {
+            let handles: Vec<_> = (0..threads)
+                .map(|_| {
+                    let engine = engine.clone();
+                    let (x, y, verify) = (&x, &y, &verify);
+                    scope.spawn(move || {
+                        let mut report = ThreadReport::default();
+                        engine
+                            .run(x, y, a, launches, |run| report.record(run, verify))
+                            .unwrap();
+                        report
+                    })
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect()
+        });
+
+        Some(reports)
+    }
+
+    fn assert_all_verified(reports: &[ThreadReport], launches: usize) {
+        for (i, report) in reports.iter().enumerate() {
+            assert_eq!(
+                report.mismatch, None,
+                "thread {}: (launch, index, value) of the first wrong result",
+                i
+            );
+            assert_eq!(report.launches, launches, "thread {}", i);
+            assert_eq!(report.checked, 4096 * launches, "thread {}", i);
+        }
+    }
+
+    #[test]
+    fn engine_shared_between_threads() {
+        let Some(reports) = run_shared(4, 3) else {
+            return;
+        };
+
+        assert_eq!(reports.len(), 4);
+        assert_all_verified(&reports, 3);
+    }
+
+    /// `run --threads 8 --repeat 100` as a test, `cargo test -- --ignored` runs it
+    #[test]
+    #[ignore = "stress test, 800 launches"]
+    fn stress_8_threads_100_launches() {
+        let Some(reports) = run_shared(8, 100) else {
+            return;
+        };
+
+        assert_eq!(reports.len(), 8);
+        assert_all_verified(&reports, 100);
+    }
+}
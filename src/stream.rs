@@ -0,0 +1,115 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+//! `run --stdin`: raw `f32` inputs from standard input, raw `f32` results to standard output
+
+use std::io::{self, BufWriter, Read, Write};
+
+use anyhow::{bail, Context as _};
+use opencl3::{command_queue::CommandQueue, context::Context, types::cl_float};
+
+use crate::{
+    cancel,
+    cli::{Args, StdinLayout},
+    kernels,
+    saxpy::SaxpyBuffers,
+};
+
+/// Elements per launch when `--chunk-size` isn't given
+pub const DEFAULT_CHUNK_SIZE: usize = 1 << 20;
+
+/// Runs the kernel over chunks of standard input as they arrive until EOF
+///
+/// Only the results go to stdout, everything else is logged to stderr
+pub fn run(context: &Context, queue: &CommandQueue, args: &Args) -> anyhow::Result<()> {
+    let chunk_size = args.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE);
+    let kernel = kernels::build_layout_kernel(context, args, args.layout)?;
+    let mut buffers = SaxpyBuffers::create(context, args.layout, chunk_size)?;
+    if args.pinned_readback {
+        buffers.enable_pinned_readback(context)?;
+    }
+    let a = args.op.scalar(args.scalar());
+
+    let mut input = io::stdin().lock();
+    let mut output = BufWriter::new(io::stdout().lock());
+    let mut process = |x: &[cl_float], y: &[cl_float]| -> anyhow::Result<()> {
+        let run = buffers.run(queue, &kernel, args.transfer, x, y, a)?;
+        for value in &run.result {
+            output
+                .write_all(&value.to_le_bytes())
+                .context("Failed to write to stdout")?;
+        }
+        tracing::debug!(
+            "Processed a chunk of {} elements in {} ns",
+            x.len(),
+            run.kernel_ns
+        );
+        Ok(())
+    };
+
+    let mut elements = 0;
+    let mut chunks = 0;
+    match args.stdin_layout.unwrap_or_default() {
+        StdinLayout::Interleaved => loop {
+            if cancel::requested() {
+                break;
+            }
+            let pairs = read_values(&mut input, 2 * chunk_size)?;
+            if pairs.is_empty() {
+                break;
+            }
+            if !pairs.len().is_multiple_of(2) {
+                bail!("Standard input ended in the middle of an x/y pair");
+            }
+
+            let (x, y): (Vec<cl_float>, Vec<cl_float>) =
+                pairs.chunks_exact(2).map(|pair| (pair[0], pair[1])).unzip();
+            process(&x, &y)?;
+            elements += x.len();
+            chunks += 1;
+        },
+        StdinLayout::Concatenated => {
+            // All of x comes before y, so nothing can run before EOF
+            let values = read_values(&mut input, usize::MAX)?;
+            if !values.len().is_multiple_of(2) {
+                bail!(
+                    "Read {} values, concatenated x and y have to be of the same length",
+                    values.len()
+                );
+            }
+
+            let (x, y) = values.split_at(values.len() / 2);
+            for (x, y) in x.chunks(chunk_size).zip(y.chunks(chunk_size)) {
+                if cancel::requested() {
+                    break;
+                }
+                process(x, y)?;
+                elements += x.len();
+                chunks += 1;
+            }
+        }
+    }
+
+    output.flush().context("Failed to write to stdout")?;
+    tracing::info!("Streamed {} elements in {} chunks", elements, chunks);
+
+    cancel::check(queue)
+}
+
+/// Reads up to `max` little-endian `f32` values, fewer only at EOF
+fn read_values(input: &mut impl Read, max: usize) -> anyhow::Result<Vec<cl_float>> {
+    let mut bytes = Vec::new();
+    input
+        .take(max.saturating_mul(4) as u64)
+        .read_to_end(&mut bytes)
+        .context("Failed to read from stdin")?;
+
+    if !bytes.len().is_multiple_of(4) {
+        bail!("Standard input ended in the middle of an f32 value");
+    }
+
+    Ok(bytes
+        .chunks_exact(4)
+        .map(|chunk| cl_float::from_le_bytes(chunk.try_into().unwrap()))
+        .collect())
+}
@@ -0,0 +1,151 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+//! The summary block printed after a `run`
+
+use std::io::IsTerminal;
+
+use opencl3::types::cl_float;
+
+use crate::cli::Args;
+
+const GREEN: &str = "\x1b[32m";
+const RED: &str = "\x1b[31m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+/// Everything the summary shows, the timing rows are label and formatted value
+pub struct Summary {
+    pub device: String,
+    pub kernel: String,
+    pub size: usize,
+    pub options: String,
+    /// The macros the kernel was built with, `NAME=VALUE`, see [`crate::kernels::defines`]
+    pub defines: Vec<String>,
+    /// The number of results checked, `Err` holds the first index that didn't match the host
+    /// computation, `None` with `--no-verify`
+    pub verification: Option<Result<usize, usize>>,
+    /// Calls retried after a transient error, `None` without `--retries`
+    pub retries: Option<usize>,
+    pub timings: Vec<(&'static str, String)>,
+    /// What the kernel needs on the device, see [`crate::resources::KernelResources::rows`]
+    pub resources: Vec<(&'static str, String)>,
+    /// Indices and values of the results to show
+    pub results: Vec<(usize, cl_float)>,
+}
+
+impl Summary {
+    /// Prints the summary, with ANSI colors if `color` is set
+    pub fn print(&self, color: bool) {
+        let paint = |code: &str, text: &str| {
+            if color {
+                format!("{}{}{}", code, text, RESET)
+            } else {
+                text.to_owned()
+            }
+        };
+
+        let options = if self.options.is_empty() {
+            "(none)"
+        } else {
+            &self.options
+        };
+        let verification = match self.verification {
+            None => "skipped (--no-verify)".to_owned(),
+            Some(Ok(checked)) if checked < self.size => format!(
+                "{} ({} of {} results checked)",
+                paint(GREEN, "PASS"),
+                checked,
+                self.size
+            ),
+            Some(Ok(_)) => paint(GREEN, "PASS"),
+            Some(Err(i)) => format!("{} (first mismatch at index {})", paint(RED, "FAIL"), i),
+        };
+
+        println!("{:<14}{}", "Device", self.device);
+        println!("{:<14}{}", "Kernel", self.kernel);
+        println!("{:<14}{} elements", "Size", self.size);
+        println!("{:<14}{}", "Options", options);
+        if !self.defines.is_empty() {
+            println!("{:<14}{}", "Defines", self.defines.join(", "));
+        }
+        println!("{:<14}{}", "Verification", verification);
+        if let Some(retries) = self.retries {
+            println!("{:<14}{}", "Retries", retries);
+        }
+
+        println!("{}", paint(BOLD, "Timing"));
+        for (label, value) in &self.timings {
+            println!("  {:<20}{:>14}", label, value);
+        }
+
+        if !self.resources.is_empty() {
+            println!("{}", paint(BOLD, "Resources"));
+            for (label, value) in &self.resources {
+                println!("  {:<20}{}", label, value);
+            }
+        }
+
+        if !self.results.is_empty() {
+            println!("{}", paint(BOLD, "Results"));
+            let width = self.results.last().map_or(1, |(i, _)| i.to_string().len());
+            for (i, value) in &self.results {
+                println!("  z[{:>width$}] = {}", i, value, width = width);
+            }
+        }
+    }
+}
+
+/// Whether the summary should be colored: stdout is a terminal, `NO_COLOR` isn't set and the
+/// output wasn't turned down with `--quiet` or `--log-level`
+pub fn use_color(args: &Args) -> bool {
+    std::io::stdout().is_terminal()
+        && std::env::var_os("NO_COLOR").is_none()
+        && args
+            .log_level
+            .is_none_or(|level| level >= tracing::Level::INFO)
+}
+
+/// The first and last `n` indices of `result` with their values, each shown once
+pub fn first_and_last(result: &[cl_float], n: usize) -> Vec<(usize, cl_float)> {
+    let head = n.min(result.len());
+    let tail = result.len().saturating_sub(n).max(head);
+
+    (0..head)
+        .chain(tail..result.len())
+        .map(|i| (i, result[i]))
+        .collect()
+}
+
+/// Formats nanoseconds with the largest unit that keeps the value at or above 1
+pub fn duration(ns: u64) -> String {
+    match ns {
+        0..=999 => format!("{} ns", ns),
+        1_000..=999_999 => format!("{:.2} µs", ns as f64 / 1e3),
+        1_000_000..=999_999_999 => format!("{:.2} ms", ns as f64 / 1e6),
+        _ => format!("{:.3} s", ns as f64 / 1e9),
+    }
+}
+
+/// Formats `bytes` with the largest binary unit that keeps the value at or above 1
+pub fn bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1 << 10 {
+        return format!("{} B", bytes);
+    }
+
+    let exponent = ((63 - bytes.leading_zeros()) / 10).min(UNITS.len() as u32);
+    format!(
+        "{:.2} {}",
+        bytes as f64 / (1u64 << (10 * exponent)) as f64,
+        UNITS[exponent as usize - 1]
+    )
+}
+
+/// Formats `bytes` moved in `ns` as GiB/s
+pub fn bandwidth(bytes: usize, ns: u64) -> String {
+    format!(
+        "{:.2} GiB/s",
+        bytes as f64 / (ns.max(1) as f64 / 1e9) / (1u64 << 30) as f64
+    )
+}
@@ -0,0 +1,337 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+use std::{fmt::Write as _, fs, mem};
+
+use anyhow::{bail, Context as _};
+use opencl3::{
+    command_queue::CommandQueue, context::Context, device::Device, kernel::Kernel, types::cl_float,
+};
+
+use crate::{
+    cancel,
+    cli::{Args, Layout, SweepArgs, Transfer},
+    error::ClContext,
+    kernels,
+    saxpy::{SaxpyBuffers, BYTES_PER_ELEMENT},
+};
+
+/// One row of the sweep table
+struct SweepRow {
+    size: usize,
+    kernel_ns: u64,
+    flops_per_element: u64,
+}
+
+impl SweepRow {
+    /// Achieved bandwidth in GB/s (bytes per nanosecond)
+    fn bandwidth(&self) -> f64 {
+        (BYTES_PER_ELEMENT * self.size) as f64 / self.kernel_ns.max(1) as f64
+    }
+
+    /// Achieved throughput in GFLOP/s (flops per nanosecond)
+    fn gflops(&self) -> f64 {
+        (self.flops_per_element * self.size as u64) as f64 / self.kernel_ns.max(1) as f64
+    }
+}
+
+/// Runs the selected kernel over the geometric range of sizes described by `sweep`
+///
+/// Unless `--realloc-each` is given the buffers are created once at the largest size that fits
+/// on the device and smaller sizes only shrink the global work size. With `--compare-transfers`
+/// every size is timed end-to-end with each transfer strategy instead, see [`TransferRow`]
+pub fn run(
+    context: &Context,
+    queue: &CommandQueue,
+    device: &Device,
+    args: &Args,
+    sweep: &SweepArgs,
+) -> anyhow::Result<()> {
+    let layout = args.layout;
+    if layout == Layout::Both {
+        bail!("sweep runs a single kernel, pick --layout soa or --layout aos");
+    }
+
+    let kernel = kernels::build_layout_kernel(context, args, layout)?;
+    let flops_per_element = kernels::flops_per_element(args.op, args.kernel_iters);
+
+    let (sizes, skipped) = fitting_sizes(device, layout, &sweep.sizes())?;
+    for note in skipped {
+        println!("note: {}", note);
+    }
+
+    let largest = match sizes.last() {
+        Some(&largest) => largest,
+        None => bail!("None of the requested sizes fit in device memory"),
+    };
+
+    let ones: Vec<cl_float> = vec![1.0; largest];
+    let sums: Vec<cl_float> = (0..largest).map(|i| 1.0 + 1.0 * i as cl_float).collect();
+    let a = args.op.scalar(args.scalar());
+
+    let mut shared_buffers = if sweep.realloc_each {
+        None
+    } else {
+        Some(create_buffers(context, args, largest)?)
+    };
+
+    if args.compare_transfers {
+        return compare_transfers(
+            context,
+            queue,
+            args,
+            sweep,
+            &kernel,
+            &sizes,
+            (&ones, &sums),
+            shared_buffers,
+        );
+    }
+
+    println!(
+        "{:>12} {:>18} {:>18} {:>12}",
+        "elements", "kernel time (us)", "bandwidth (GB/s)", "GFLOP/s"
+    );
+
+    let mut rows = Vec::with_capacity(sizes.len());
+    for size in sizes {
+        if cancel::requested() {
+            break;
+        }
+        let mut owned_buffers;
+        let buffers = match shared_buffers.as_mut() {
+            Some(buffers) => buffers,
+            None => {
+                owned_buffers = create_buffers(context, args, size)?;
+                &mut owned_buffers
+            }
+        };
+
+        let mut total_ns = 0;
+        let mut iterations = 0;
+        for _ in 0..sweep.iterations {
+            if cancel::requested() {
+                break;
+            }
+            let run = buffers.run(
+                queue,
+                &kernel,
+                args.transfer,
+                &ones[..size],
+                &sums[..size],
+                a,
+            )?;
+            total_ns += run.kernel_ns;
+            iterations += 1;
+        }
+        // An interrupted size is left out rather than averaged over fewer iterations
+        if iterations < sweep.iterations {
+            break;
+        }
+
+        let row = SweepRow {
+            size,
+            kernel_ns: total_ns / iterations as u64,
+            flops_per_element,
+        };
+        println!(
+            "{:>12} {:>18.3} {:>18.3} {:>12.3}",
+            row.size,
+            row.kernel_ns as f64 / 1e3,
+            row.bandwidth(),
+            row.gflops()
+        );
+
+        rows.push(row);
+    }
+
+    if let Some(path) = &sweep.csv {
+        let mut csv = String::from("elements,kernel_ns,bandwidth_gbs,gflops\n");
+        for row in &rows {
+            writeln!(
+                csv,
+                "{},{},{},{}",
+                row.size,
+                row.kernel_ns,
+                row.bandwidth(),
+                row.gflops()
+            )?;
+        }
+
+        fs::write(path, csv).with_context(|| format!("Failed to write {}", path.display()))?;
+        tracing::info!("Wrote sweep results to {}", path.display());
+    }
+
+    cancel::check(queue)
+}
+
+/// One row of `sweep --compare-transfers`: the mean end-to-end time of every strategy at a size
+struct TransferRow {
+    size: usize,
+    /// In the order of [`Transfer::ALL`]
+    wall_ns: [u64; Transfer::ALL.len()],
+}
+
+impl TransferRow {
+    /// The strategy with the shortest end-to-end time
+    fn fastest(&self) -> Transfer {
+        let (i, _) = self
+            .wall_ns
+            .iter()
+            .enumerate()
+            .min_by_key(|&(_, ns)| ns)
+            .unwrap();
+        Transfer::ALL[i]
+    }
+}
+
+/// Times every size end-to-end with each transfer strategy, blocking writes and read against
+/// writes and read chained to the kernel by events (or a `clFinish`), and prints which one wins
+#[allow(clippy::too_many_arguments)]
+fn compare_transfers(
+    context: &Context,
+    queue: &CommandQueue,
+    args: &Args,
+    sweep: &SweepArgs,
+    kernel: &Kernel,
+    sizes: &[usize],
+    (ones, sums): (&[cl_float], &[cl_float]),
+    mut shared_buffers: Option<SaxpyBuffers>,
+) -> anyhow::Result<()> {
+    let a = args.op.scalar(args.scalar());
+
+    print!("{:>12}", "elements");
+    for transfer in Transfer::ALL {
+        print!(" {:>14}", format!("{} (us)", transfer));
+    }
+    println!(" {:>10}", "fastest");
+
+    let mut rows = Vec::with_capacity(sizes.len());
+    'sizes: for &size in sizes {
+        let mut owned_buffers;
+        let buffers = match shared_buffers.as_mut() {
+            Some(buffers) => buffers,
+            None => {
+                owned_buffers = create_buffers(context, args, size)?;
+                &mut owned_buffers
+            }
+        };
+        let (x, y) = (&ones[..size], &sums[..size]);
+
+        // Warm up so the first strategy doesn't pay for lazy allocation at this size
+        buffers.run(queue, kernel, Transfer::Events, x, y, a)?;
+
+        let mut row = TransferRow {
+            size,
+            wall_ns: [0; Transfer::ALL.len()],
+        };
+        for (i, transfer) in Transfer::ALL.into_iter().enumerate() {
+            let mut total_ns = 0;
+            for _ in 0..sweep.iterations {
+                // An interrupted size is left out rather than averaged over fewer iterations
+                if cancel::requested() {
+                    break 'sizes;
+                }
+                total_ns += buffers.run(queue, kernel, transfer, x, y, a)?.wall_ns;
+            }
+            row.wall_ns[i] = total_ns / sweep.iterations as u64;
+        }
+
+        print!("{:>12}", row.size);
+        for ns in row.wall_ns {
+            print!(" {:>14.3}", ns as f64 / 1e3);
+        }
+        println!(" {:>10}", row.fastest());
+        rows.push(row);
+    }
+
+    if let Some(path) = &sweep.csv {
+        let mut csv = String::from("elements");
+        for transfer in Transfer::ALL {
+            write!(csv, ",{}_ns", transfer)?;
+        }
+        csv.push_str(",fastest\n");
+        for row in &rows {
+            write!(csv, "{}", row.size)?;
+            for ns in row.wall_ns {
+                write!(csv, ",{}", ns)?;
+            }
+            writeln!(csv, ",{}", row.fastest())?;
+        }
+
+        fs::write(path, csv).with_context(|| format!("Failed to write {}", path.display()))?;
+        tracing::info!("Wrote sweep results to {}", path.display());
+    }
+
+    for transfer in Transfer::ALL {
+        let wins: Vec<usize> = rows
+            .iter()
+            .filter(|row| row.fastest() == transfer)
+            .map(|row| row.size)
+            .collect();
+        if let (Some(first), Some(last)) = (wins.first(), wins.last()) {
+            println!(
+                "{} is fastest at {} of {} sizes, from {} to {} elements",
+                transfer,
+                wins.len(),
+                rows.len(),
+                first,
+                last
+            );
+        }
+    }
+
+    cancel::check(queue)
+}
+
+/// Splits `sizes` into those whose buffers fit on `device` and notes on the ones that don't
+pub fn fitting_sizes(
+    device: &Device,
+    layout: Layout,
+    sizes: &[usize],
+) -> anyhow::Result<(Vec<usize>, Vec<String>)> {
+    let global_mem_size = device
+        .global_mem_size()
+        .context("Failed to query global memory size")?;
+    let max_alloc_size = device
+        .max_mem_alloc_size()
+        .context("Failed to query max allocation size")?;
+
+    // The aos layout packs both inputs into a single allocation
+    let largest_buffer_elements = match layout {
+        Layout::Aos => 2,
+        _ => 1,
+    };
+
+    let mut fitting = Vec::new();
+    let mut skipped = Vec::new();
+    for &size in sizes {
+        let total_bytes = (BYTES_PER_ELEMENT * size) as u64;
+        let largest_buffer_bytes =
+            (largest_buffer_elements * size * mem::size_of::<cl_float>()) as u64;
+
+        if total_bytes > global_mem_size || largest_buffer_bytes > max_alloc_size {
+            skipped.push(format!(
+                "skipping {} elements, needs {} MiB ({} MiB in one buffer) but the device has {} MiB ({} MiB max allocation)",
+                size,
+                total_bytes >> 20,
+                largest_buffer_bytes >> 20,
+                global_mem_size >> 20,
+                max_alloc_size >> 20
+            ));
+        } else {
+            fitting.push(size);
+        }
+    }
+
+    Ok((fitting, skipped))
+}
+
+fn create_buffers(context: &Context, args: &Args, capacity: usize) -> anyhow::Result<SaxpyBuffers> {
+    let mut buffers = SaxpyBuffers::create(context, args.layout, capacity)?;
+    if args.pinned_readback {
+        buffers.enable_pinned_readback(context)?;
+    }
+
+    Ok(buffers)
+}
@@ -0,0 +1,421 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+//! Kernel templates: built-in kernels written once over an element type `{T}`
+//!
+//! A template lists the element types it may be instantiated with. Asking for `saxpy<f64>` renders
+//! the source with `double` for `{T}`, puts the `#pragma OPENCL EXTENSION` the type needs in front
+//! and builds it, after checking that the device reports the extension. Rendered sources are kept
+//! per instantiation for the rest of the process, the built programs go through the program cache
+//! like every other kernel. `run --template` runs an instantiation over host data of its type.
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Write as _},
+    ops::{Add, Mul},
+    ptr,
+    str::FromStr,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use anyhow::bail;
+use opencl3::{
+    command_queue::CommandQueue,
+    context::Context,
+    device::Device,
+    memory::{Buffer, CL_MEM_READ_ONLY, CL_MEM_WRITE_ONLY},
+    types::{cl_double, cl_float, cl_int, cl_mem_flags, CL_BLOCKING},
+};
+
+use crate::{
+    cli::{Args, Layout, Op},
+    error::ClContext,
+    kernels::{self, BuiltinKernel},
+    launch::{launch, ArgType},
+    run, saxpy,
+};
+
+/// An element type templates are instantiated with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ElementType {
+    F32,
+    F64,
+    I32,
+}
+
+impl ElementType {
+    /// The name in instantiations, as in `saxpy<f64>`
+    pub const fn host_name(self) -> &'static str {
+        match self {
+            Self::F32 => "f32",
+            Self::F64 => "f64",
+            Self::I32 => "i32",
+        }
+    }
+
+    /// The OpenCL C type substituted for `{T}`
+    pub const fn cl_name(self) -> &'static str {
+        match self {
+            Self::F32 => "float",
+            Self::F64 => "double",
+            Self::I32 => "int",
+        }
+    }
+
+    /// The extension the device has to report for kernels using the type
+    pub const fn extension(self) -> Option<&'static str> {
+        match self {
+            Self::F64 => Some("cl_khr_fp64"),
+            Self::F32 | Self::I32 => None,
+        }
+    }
+}
+
+impl fmt::Display for ElementType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.host_name())
+    }
+}
+
+/// A built-in kernel written over `{T}`, the kernel function is called `<name>_{T}`
+#[derive(Debug)]
+pub struct KernelTemplate {
+    pub name: &'static str,
+    /// The registry entry the `f32` instantiation renders
+    pub kernel: BuiltinKernel,
+    pub source: &'static str,
+    pub types: &'static [ElementType],
+    /// Whether the kernel takes the scalar `a` after the buffers
+    pub scalar: bool,
+}
+
+pub static TEMPLATES: [KernelTemplate; 2] = [
+    KernelTemplate {
+        name: "saxpy",
+        kernel: BuiltinKernel::Elementwise(Op::Saxpy, Layout::Soa),
+        source: r#"
+kernel void saxpy_{T} (global {T}* z,
+    global {T} const* x,
+    global {T} const* y,
+    {T} a)
+{
+    const size_t i = get_global_id(0);
+    z[i] = a*x[i] + y[i];
+}"#,
+        types: &[ElementType::F32, ElementType::F64, ElementType::I32],
+        scalar: true,
+    },
+    KernelTemplate {
+        name: "vadd",
+        kernel: BuiltinKernel::Elementwise(Op::Add, Layout::Soa),
+        source: r#"
+kernel void vadd_{T} (global {T}* z,
+    global {T} const* x,
+    global {T} const* y)
+{
+    const size_t i = get_global_id(0);
+    z[i] = x[i] + y[i];
+}"#,
+        types: &[ElementType::F32, ElementType::F64, ElementType::I32],
+        scalar: false,
+    },
+];
+
+/// A template together with the element type to render it with, `saxpy<f64>`
+#[derive(Debug, Clone, Copy)]
+pub struct Instance {
+    pub template: &'static KernelTemplate,
+    pub ty: ElementType,
+}
+
+/// Rendered sources by template name and element type
+type Rendered = HashMap<(&'static str, ElementType), Arc<str>>;
+
+static RENDERED: OnceLock<Mutex<Rendered>> = OnceLock::new();
+
+impl Instance {
+    /// The name of the kernel function, `saxpy_double` for `saxpy<f64>`
+    pub fn kernel_name(&self) -> String {
+        format!("{}_{}", self.template.name, self.ty.cl_name())
+    }
+
+    /// The source with `{T}` replaced and the pragma of the type's extension in front, rendered
+    /// once per instantiation
+    pub fn source(&self) -> Arc<str> {
+        let mut rendered = RENDERED
+            .get_or_init(Default::default)
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        Arc::clone(
+            rendered
+                .entry((self.template.name, self.ty))
+                .or_insert_with(|| {
+                    let mut source = String::new();
+                    if let Some(extension) = self.ty.extension() {
+                        writeln!(source, "#pragma OPENCL EXTENSION {} : enable", extension)
+                            .unwrap();
+                    }
+                    source.push_str(&self.template.source.replace("{T}", self.ty.cl_name()));
+                    source.into()
+                }),
+        )
+    }
+
+    /// The extension the device lacks for this instantiation, if any
+    pub fn missing_extension(&self, extensions: &str) -> Option<&'static str> {
+        self.ty
+            .extension()
+            .filter(|&extension| !extensions.split_whitespace().any(|ext| ext == extension))
+    }
+
+    /// Fails naming the missing extension when `device` can't build this instantiation
+    pub fn check(&self, device: &Device) -> anyhow::Result<()> {
+        let extensions = device
+            .extensions()
+            .context("Failed to query CL_DEVICE_EXTENSIONS")?;
+        if let Some(extension) = self.missing_extension(&extensions) {
+            bail!(
+                "{} needs the {} extension, which {} doesn't report",
+                self,
+                extension,
+                device.name().context("Failed to query device name")?
+            );
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Instance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}<{}>", self.template.name, self.ty)
+    }
+}
+
+impl FromStr for Instance {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((name, ty_name)) = s.strip_suffix('>').and_then(|s| s.split_once('<')) else {
+            bail!(
+                "'{}' isn't a template instantiation, expected NAME<TYPE> such as saxpy<f64>",
+                s
+            );
+        };
+        let Some(template) = TEMPLATES.iter().find(|template| template.name == name) else {
+            bail!(
+                "'{}' isn't a kernel template, expected one of: {}",
+                name,
+                TEMPLATES
+                    .each_ref()
+                    .map(|template| template.name)
+                    .join(", ")
+            );
+        };
+        let Some(&ty) = template.types.iter().find(|ty| ty.host_name() == ty_name) else {
+            bail!(
+                "{} can't be instantiated with '{}', expected one of: {}",
+                name,
+                ty_name,
+                template
+                    .types
+                    .iter()
+                    .map(|ty| ty.host_name())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        };
+
+        Ok(Self { template, ty })
+    }
+}
+
+/// The section of `--help` listing the templates and the types they take
+pub fn help() -> String {
+    let mut help = String::from("\nKernel templates (run --template NAME<TYPE>):\n");
+    for template in &TEMPLATES {
+        let types: Vec<String> = template
+            .types
+            .iter()
+            .map(|ty| match ty.extension() {
+                Some(extension) => format!("{} (needs {})", ty, extension),
+                None => ty.to_string(),
+            })
+            .collect();
+        writeln!(
+            help,
+            "    {:<27}{}, {}<f32> is {}",
+            format!("{}<T>", template.name),
+            types.join(", "),
+            template.name,
+            template.kernel
+        )
+        .unwrap();
+    }
+
+    help
+}
+
+/// Every instantiation and whether `device` can build it, for `device-info`
+pub fn print_instances(device: &Device) -> anyhow::Result<()> {
+    let extensions = device
+        .extensions()
+        .context("Failed to query CL_DEVICE_EXTENSIONS")?;
+    println!("Kernel templates:");
+    for template in &TEMPLATES {
+        for &ty in template.types {
+            let instance = Instance { template, ty };
+            match instance.missing_extension(&extensions) {
+                None => println!("  {:<17}available", instance.to_string()),
+                Some(extension) => println!("  {:<17}needs {}", instance.to_string(), extension),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Host types of the element types
+trait Element:
+    ArgType + Copy + Default + fmt::Display + Add<Output = Self> + Mul<Output = Self>
+{
+    fn from_f32(value: cl_float) -> Self;
+
+    /// The relative error verification allows, see [`Args::max_error`]
+    fn max_error(args: &Args) -> f64 {
+        args.max_error()
+    }
+
+    /// Whether `result` is within the relative error `max_error` of the host computation `expected`
+    fn matches(result: Self, expected: Self, max_error: f64) -> bool;
+}
+
+impl Element for cl_float {
+    fn from_f32(value: cl_float) -> Self {
+        value
+    }
+
+    fn matches(result: Self, expected: Self, max_error: f64) -> bool {
+        (result - expected).abs() <= max_error as cl_float * expected.abs().max(1.0)
+    }
+}
+
+impl Element for cl_double {
+    fn from_f32(value: cl_float) -> Self {
+        value.into()
+    }
+
+    /// Doubles are held to a much smaller error unless `--max-error-threshold` says otherwise
+    fn max_error(args: &Args) -> f64 {
+        args.max_error_threshold
+            .unwrap_or(if args.fast_math { 1e-9 } else { 1e-12 })
+    }
+
+    fn matches(result: Self, expected: Self, max_error: f64) -> bool {
+        (result - expected).abs() <= max_error * expected.abs().max(1.0)
+    }
+}
+
+impl Element for cl_int {
+    fn from_f32(value: cl_float) -> Self {
+        value as cl_int
+    }
+
+    fn matches(result: Self, expected: Self, _: f64) -> bool {
+        result == expected
+    }
+}
+
+/// `run --template`: builds `instance` and runs it over the inputs of `args` converted to its type
+pub fn run(
+    context: &Context,
+    queue: &CommandQueue,
+    device: &Device,
+    args: &Args,
+    instance: Instance,
+) -> anyhow::Result<()> {
+    match instance.ty {
+        ElementType::F32 => run_typed::<cl_float>(context, queue, device, args, instance),
+        ElementType::F64 => run_typed::<cl_double>(context, queue, device, args, instance),
+        ElementType::I32 => run_typed::<cl_int>(context, queue, device, args, instance),
+    }
+}
+
+fn run_typed<T: Element>(
+    context: &Context,
+    queue: &CommandQueue,
+    device: &Device,
+    args: &Args,
+    instance: Instance,
+) -> anyhow::Result<()> {
+    instance.check(device)?;
+
+    let name = instance.kernel_name();
+    let kernel = kernels::build_kernel(
+        context,
+        &instance.source(),
+        &name,
+        &kernels::arg_info_options(args),
+        kernels::BuildFlags::from_args(args),
+    )?;
+
+    let (x, y) = run::inputs(args)?;
+    let x: Vec<T> = x.into_iter().map(T::from_f32).collect();
+    let y: Vec<T> = y.into_iter().map(T::from_f32).collect();
+    let size = x.len();
+    let a = T::from_f32(args.scalar());
+
+    let mut x_buffer = create_buffer::<T>(context, CL_MEM_READ_ONLY, size)?;
+    let mut y_buffer = create_buffer::<T>(context, CL_MEM_READ_ONLY, size)?;
+    let z_buffer = create_buffer::<T>(context, CL_MEM_WRITE_ONLY, size)?;
+    unsafe { queue.enqueue_write_buffer(&mut x_buffer, CL_BLOCKING, 0, &x, &[]) }
+        .context("Failed to write to buffer")?;
+    unsafe { queue.enqueue_write_buffer(&mut y_buffer, CL_BLOCKING, 0, &y, &[]) }
+        .context("Failed to write to buffer")?;
+
+    let kernel_event = unsafe {
+        let mut launch = launch!(&kernel, global = size; z: &z_buffer, x: &x_buffer, y: &y_buffer);
+        if instance.template.scalar {
+            launch.arg("a", &a);
+        }
+        launch.enqueue_nd_range(queue)
+    }
+    .map_err(|err| err.context("Failed to execute kernel"))?;
+
+    let mut z = vec![T::default(); size];
+    unsafe { queue.enqueue_read_buffer(&z_buffer, CL_BLOCKING, 0, &mut z, &[]) }
+        .context("Failed to read buffer")?;
+
+    let max_error = T::max_error(args);
+    let mut checked = 0;
+    for i in saxpy::sample_indices(size, args.verify_stride.unwrap_or(1)) {
+        let expected = if instance.template.scalar {
+            a * x[i] + y[i]
+        } else {
+            x[i] + y[i]
+        };
+        if !T::matches(z[i], expected, max_error) {
+            bail!("Verification failed at index {}: got {}", i, z[i]);
+        }
+        checked += 1;
+    }
+
+    println!("kernel: {} ({})", name, instance);
+    println!("verified {} of {} results", checked, size);
+    println!(
+        "{} kernel time (ns): {}",
+        instance,
+        saxpy::profiled_ns(&kernel_event)?
+    );
+
+    Ok(())
+}
+
+fn create_buffer<T>(
+    context: &Context,
+    flags: cl_mem_flags,
+    len: usize,
+) -> anyhow::Result<Buffer<T>> {
+    unsafe { Buffer::<T>::create(context, flags, len, ptr::null_mut()) }
+        .context("Failed to create buffer")
+}
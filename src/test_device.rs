@@ -0,0 +1,86 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+//! The OpenCL device the tests that need one run on
+//!
+//! [`get`] gives `None` on machines without an OpenCL runtime or device, and those tests return
+//! early, so `cargo test` passes everywhere and exercises the device where there is one.
+
+use std::sync::OnceLock;
+
+use opencl3::{
+    command_queue::CommandQueue,
+    context::Context,
+    device::{get_all_devices, Device, CL_DEVICE_TYPE_ALL},
+    kernel::Kernel,
+    types::cl_float,
+};
+
+use crate::{cli::Layout, kernels};
+
+pub struct TestDevice {
+    pub device: Device,
+    pub context: Context,
+    pub queue: CommandQueue,
+}
+
+/// Whether the OpenCL library could be loaded, tried once per test binary
+fn runtime() -> bool {
+    static LOADED: OnceLock<bool> = OnceLock::new();
+
+    *LOADED.get_or_init(|| {
+        #[cfg(feature = "dynamic")]
+        if let Err(err) = crate::dynamic::load_opencl() {
+            eprintln!("{:#}", err);
+            return false;
+        }
+        true
+    })
+}
+
+/// The first device of any type, with a context and a profiling queue of its own, or `None` with
+/// a note on stderr when there isn't one
+pub fn get() -> Option<TestDevice> {
+    let found = runtime()
+        .then(|| get_all_devices(CL_DEVICE_TYPE_ALL).ok())
+        .flatten()
+        .and_then(|ids| ids.first().copied());
+    let Some(id) = found else {
+        eprintln!("No OpenCL device, skipped");
+        return None;
+    };
+
+    let device = Device::new(id);
+    let context = Context::from_device(&device).ok()?;
+    let queue = crate::create_queue(&context).ok()?;
+
+    Some(TestDevice {
+        device,
+        context,
+        queue,
+    })
+}
+
+/// The saxpy kernel of the SoA program, built without options
+pub fn saxpy_kernel(test: &TestDevice) -> Kernel {
+    saxpy_kernel_with(test, "")
+}
+
+/// The saxpy kernel of the SoA program, built with `options`
+pub fn saxpy_kernel_with(test: &TestDevice, options: &str) -> Kernel {
+    kernels::build_kernel(
+        &test.context,
+        kernels::program_source(Layout::Soa),
+        kernels::KERNEL_NAME,
+        options,
+        kernels::BuildFlags::default(),
+    )
+    .unwrap()
+}
+
+/// `x` and `y` of `n` elements, `i % 1000` and `i % 7` so every value is exact in a float
+pub fn inputs(n: usize) -> (Vec<cl_float>, Vec<cl_float>) {
+    let x = (0..n).map(|i| (i % 1000) as cl_float).collect();
+    let y = (0..n).map(|i| (i % 7) as cl_float).collect();
+    (x, y)
+}
@@ -0,0 +1,1136 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+//! `--record trace.bin` and `replay trace.bin`: capturing the commands of a run and running them
+//! again elsewhere
+//!
+//! While recording, every buffer allocation, upload, kernel launch and readback of `run` goes
+//! through this module: [`SaxpyBuffers`](crate::saxpy::SaxpyBuffers) reports its buffers, writes
+//! and reads, [`CheckedKernel`](crate::launch::CheckedKernel) its launches with their arguments and
+//! NDRange, and [`kernels::build_program`] the source and options of every program, written to the
+//! trace when one of its kernels is first launched. The trace is a stream of operations in the
+//! order they were enqueued, encoded with [`crate::wire`].
+//!
+//! Uploads are recognized as a constant, an arithmetic sequence (the generated inputs) or anything
+//! else, which is stored as a hash and regenerated from it as a seed on replay, so traces stay
+//! small. `--record-data` stores the uploads and readbacks as they were instead. Every readback is
+//! stored as a hash of its values, `replay` runs the operations on the selected device and compares
+//! the hashes, and with the recorded data the values as well, up to `--max-error-threshold`, so a
+//! result a device rounds differently is told apart from a wrong one. Reads depending on
+//! regenerated data can't be compared and are only counted.
+//!
+//! Programs not built from source (`--spirv`, `--load-binary`, `--link-helpers`) and images can't
+//! be replayed, recording stops at the first of them and [`finish`] fails naming it.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{self, BufWriter, Write as _},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, PoisonError,
+    },
+};
+
+use anyhow::{anyhow, bail, Context as _};
+use opencl3::{
+    command_queue::CommandQueue,
+    context::Context,
+    device::Device,
+    kernel::{ExecuteKernel, Kernel},
+    memory::{Buffer, CL_MEM_ALLOC_HOST_PTR, CL_MEM_COPY_HOST_PTR, CL_MEM_USE_HOST_PTR},
+    program::Program,
+    types::{cl_double, cl_float, cl_int, cl_mem, cl_mem_flags, cl_program, cl_uint, CL_BLOCKING},
+};
+
+use crate::{
+    cli::Args,
+    error::ClContext,
+    kernels::{self, BuildFlags},
+    program_cache::Fnv1a,
+    wire::{put_bytes, put_f32, put_floats, put_len, put_str, put_u32, put_u64, put_u8, Reader},
+};
+
+const MAGIC: &[u8] = b"HOCL-TRACE";
+const VERSION: u32 = 2;
+
+const OP_PROGRAM: u8 = 1;
+const OP_KERNEL: u8 = 2;
+const OP_ALLOC: u8 = 3;
+const OP_WRITE: u8 = 4;
+const OP_LAUNCH: u8 = 5;
+const OP_READ: u8 = 6;
+
+const ARG_BUFFER: u8 = 0;
+const ARG_SCALAR: u8 = 1;
+
+const DATA_VALUES: u8 = 0;
+const DATA_CONSTANT: u8 = 1;
+const DATA_SEQUENCE: u8 = 2;
+const DATA_SEEDED: u8 = 3;
+
+/// The most floats a replayed buffer may hold, 1 GiB of them, so a corrupt length fails instead of
+/// aborting on the allocation
+const MAX_LEN: usize = (1 << 30) / std::mem::size_of::<cl_float>();
+
+/// A kernel argument of a recorded launch
+pub enum Arg {
+    Buffer(cl_mem),
+    /// A scalar of the OpenCL C type `cl_name`, as its little-endian bytes
+    Scalar {
+        cl_name: &'static str,
+        bytes: Vec<u8>,
+    },
+    /// A memory object recordings don't hold, such as "an image", which stops the recording
+    Unsupported(&'static str),
+}
+
+/// The trace being written
+struct Recorder {
+    path: PathBuf,
+    out: BufWriter<File>,
+    with_data: bool,
+    ops: usize,
+    /// Trace ids of the live buffers by `cl_mem`
+    buffers: HashMap<usize, u32>,
+    next_buffer: u32,
+    /// Source and options of the programs built from source by `cl_program`
+    sources: HashMap<usize, (String, String)>,
+    /// Trace ids of the programs written so far by `cl_program`
+    programs: HashMap<usize, u32>,
+    /// Trace ids of the kernels written so far by `cl_program` and name
+    kernels: HashMap<(usize, String), u32>,
+    /// The first operation that couldn't be recorded, nothing after it is
+    unsupported: Option<String>,
+    /// The first error writing the trace
+    error: Option<io::Error>,
+}
+
+/// Set while recording, so the hooks cost one load otherwise
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+static RECORDER: Mutex<Option<Recorder>> = Mutex::new(None);
+
+/// Starts recording to `path`, with the uploaded and read back values when `with_data` is set
+pub fn start(path: &Path, with_data: bool) -> anyhow::Result<()> {
+    let file =
+        File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    let mut out = BufWriter::new(file);
+    let mut header = MAGIC.to_vec();
+    put_u32(&mut header, VERSION);
+    out.write_all(&header)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+
+    *RECORDER.lock().unwrap_or_else(PoisonError::into_inner) = Some(Recorder {
+        path: path.to_owned(),
+        out,
+        with_data,
+        ops: 0,
+        buffers: HashMap::new(),
+        next_buffer: 0,
+        sources: HashMap::new(),
+        programs: HashMap::new(),
+        kernels: HashMap::new(),
+        unsupported: None,
+        error: None,
+    });
+    ACTIVE.store(true, Ordering::Relaxed);
+
+    Ok(())
+}
+
+/// Whether a trace is being recorded
+pub fn active() -> bool {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+fn with(record: impl FnOnce(&mut Recorder)) {
+    if !active() {
+        return;
+    }
+    let mut recorder = RECORDER.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some(recorder) = recorder.as_mut().filter(|r| r.unsupported.is_none()) {
+        record(recorder);
+    }
+}
+
+/// Remembers the source and options `program` was built from
+pub fn program(program: cl_program, source: &str, options: &str) {
+    with(|recorder| {
+        // A new program may reuse the handle of a released one
+        let key = program as usize;
+        recorder.programs.remove(&key);
+        recorder.kernels.retain(|(program, _), _| *program != key);
+        recorder
+            .sources
+            .insert(key, (source.to_owned(), options.to_owned()));
+    });
+}
+
+/// Records the creation of a buffer of `len` floats
+pub fn alloc(buffer: cl_mem, flags: cl_mem_flags, len: usize) {
+    with(|recorder| {
+        let id = recorder.next_buffer;
+        recorder.next_buffer += 1;
+        // A pooled buffer handed out again counts as a new one
+        recorder.buffers.insert(buffer as usize, id);
+
+        let mut op = vec![OP_ALLOC];
+        put_u32(&mut op, id);
+        put_u64(&mut op, flags);
+        put_u64(&mut op, len as u64);
+        recorder.emit(&op);
+    });
+}
+
+/// Records an upload of `data` to `buffer` at element `offset`
+pub fn write(buffer: cl_mem, offset: usize, data: &[cl_float]) {
+    with(|recorder| {
+        let Some(id) = recorder.buffer(buffer) else {
+            return;
+        };
+        let mut op = vec![OP_WRITE];
+        put_u32(&mut op, id);
+        put_u64(&mut op, offset as u64);
+        if recorder.with_data {
+            put_u8(&mut op, DATA_VALUES);
+            put_floats(&mut op, data);
+        } else {
+            Payload::of(data).put(&mut op);
+        }
+        recorder.emit(&op);
+    });
+}
+
+/// Records a launch of `kernel` with `args` over `global` work-items in groups of `local`, which
+/// is empty when the driver picks it, with the global ids offset by `offset`, empty for none
+pub fn launch(kernel: &Kernel, args: &[Arg], global: &[usize], local: &[usize], offset: &[usize]) {
+    with(|recorder| {
+        let Some(kernel) = recorder.kernel(kernel) else {
+            return;
+        };
+        let mut op = vec![OP_LAUNCH];
+        put_u32(&mut op, kernel);
+        put_len(&mut op, args.len());
+        for arg in args {
+            match arg {
+                Arg::Buffer(buffer) => {
+                    let Some(id) = recorder.buffer(*buffer) else {
+                        return;
+                    };
+                    put_u8(&mut op, ARG_BUFFER);
+                    put_u32(&mut op, id);
+                }
+                Arg::Scalar { cl_name, bytes } => {
+                    put_u8(&mut op, ARG_SCALAR);
+                    put_str(&mut op, cl_name);
+                    put_bytes(&mut op, bytes);
+                }
+                Arg::Unsupported(what) => {
+                    recorder.stop(&format!("a launch with {} argument", what));
+                    return;
+                }
+            }
+        }
+        for sizes in [global, local, offset] {
+            put_len(&mut op, sizes.len());
+            for &size in sizes {
+                put_u64(&mut op, size as u64);
+            }
+        }
+        recorder.emit(&op);
+    });
+}
+
+/// Records that `data` was read back from `buffer` at element `offset`
+pub fn read(buffer: cl_mem, offset: usize, data: &[cl_float]) {
+    with(|recorder| {
+        let Some(id) = recorder.buffer(buffer) else {
+            return;
+        };
+        let mut op = vec![OP_READ];
+        put_u32(&mut op, id);
+        put_u64(&mut op, offset as u64);
+        put_u64(&mut op, data.len() as u64);
+        put_u64(&mut op, hash(data));
+        put_u8(&mut op, recorder.with_data as u8);
+        if recorder.with_data {
+            put_floats(&mut op, data);
+        }
+        recorder.emit(&op);
+    });
+}
+
+/// Stops recording and flushes the trace, failing when something couldn't be recorded
+pub fn finish() -> anyhow::Result<()> {
+    ACTIVE.store(false, Ordering::Relaxed);
+    let Some(mut recorder) = RECORDER
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .take()
+    else {
+        return Ok(());
+    };
+
+    let path = recorder.path.display().to_string();
+    if let Some(err) = recorder.error.take() {
+        return Err(err).with_context(|| format!("Failed to write {}", path));
+    }
+    recorder
+        .out
+        .flush()
+        .with_context(|| format!("Failed to write {}", path))?;
+    if let Some(what) = &recorder.unsupported {
+        bail!(
+            "Recorded {} operations to {} but stopped at {}, which can't be replayed",
+            recorder.ops,
+            path,
+            what
+        );
+    }
+    tracing::info!("Recorded {} operations to {}", recorder.ops, path);
+
+    Ok(())
+}
+
+impl Recorder {
+    fn emit(&mut self, op: &[u8]) {
+        if self.error.is_some() {
+            return;
+        }
+        match self.out.write_all(op) {
+            Ok(()) => self.ops += 1,
+            Err(err) => self.error = Some(err),
+        }
+    }
+
+    fn stop(&mut self, what: &str) {
+        tracing::warn!("Stopped recording at {}, which can't be replayed", what);
+        self.unsupported = Some(what.to_owned());
+    }
+
+    fn buffer(&mut self, buffer: cl_mem) -> Option<u32> {
+        let id = self.buffers.get(&(buffer as usize)).copied();
+        if id.is_none() {
+            self.stop("a buffer created outside of the recording");
+        }
+        id
+    }
+
+    /// The trace id of `kernel`, writing it and its program to the trace the first time
+    fn kernel(&mut self, kernel: &Kernel) -> Option<u32> {
+        let (Ok(program), Ok(name)) = (kernel.program(), kernel.function_name()) else {
+            self.stop("a kernel whose program or name couldn't be queried");
+            return None;
+        };
+        let program = program as usize;
+        let name = name.trim_end_matches('\0').to_owned();
+        if let Some(&id) = self.kernels.get(&(program, name.clone())) {
+            return Some(id);
+        }
+
+        let program_id = match self.programs.get(&program) {
+            Some(&id) => id,
+            None => {
+                let Some((source, options)) = self.sources.get(&program) else {
+                    self.stop(&format!("{}, whose program wasn't built from source", name));
+                    return None;
+                };
+                let id = self.programs.len() as u32;
+                let mut op = vec![OP_PROGRAM];
+                put_u32(&mut op, id);
+                put_str(&mut op, source);
+                put_str(&mut op, options);
+                self.emit(&op);
+                self.programs.insert(program, id);
+                id
+            }
+        };
+
+        let id = self.kernels.len() as u32;
+        let mut op = vec![OP_KERNEL];
+        put_u32(&mut op, id);
+        put_u32(&mut op, program_id);
+        put_str(&mut op, &name);
+        self.emit(&op);
+        self.kernels.insert((program, name), id);
+
+        Some(id)
+    }
+}
+
+/// How an upload is stored without `--record-data`
+enum Payload {
+    Constant {
+        len: usize,
+        value: cl_float,
+    },
+    /// `start + step * i`, exactly as the host computed it
+    Sequence {
+        len: usize,
+        start: cl_float,
+        step: cl_float,
+    },
+    /// Regenerated from the hash of the values
+    Seeded {
+        len: usize,
+        seed: u64,
+    },
+}
+
+impl Payload {
+    fn of(data: &[cl_float]) -> Self {
+        let len = data.len();
+        let Some(&start) = data.first() else {
+            return Payload::Constant { len, value: 0.0 };
+        };
+        if data.iter().all(|value| value.to_bits() == start.to_bits()) {
+            return Payload::Constant { len, value: start };
+        }
+        let step = data[1] - start;
+        let sequence = |i: usize| start + step * i as cl_float;
+        if data
+            .iter()
+            .enumerate()
+            .all(|(i, value)| value.to_bits() == sequence(i).to_bits())
+        {
+            return Payload::Sequence { len, start, step };
+        }
+
+        Payload::Seeded {
+            len,
+            seed: hash(data),
+        }
+    }
+
+    fn put(&self, op: &mut Vec<u8>) {
+        match *self {
+            Payload::Constant { len, value } => {
+                put_u8(op, DATA_CONSTANT);
+                put_u64(op, len as u64);
+                put_f32(op, value);
+            }
+            Payload::Sequence { len, start, step } => {
+                put_u8(op, DATA_SEQUENCE);
+                put_u64(op, len as u64);
+                put_f32(op, start);
+                put_f32(op, step);
+            }
+            Payload::Seeded { len, seed } => {
+                put_u8(op, DATA_SEEDED);
+                put_u64(op, len as u64);
+                put_u64(op, seed);
+            }
+        }
+    }
+}
+
+/// `len` floats of room, or an error when they can't be allocated
+fn floats_with_capacity(len: usize) -> anyhow::Result<Vec<cl_float>> {
+    let mut values = Vec::new();
+    values
+        .try_reserve_exact(len)
+        .with_context(|| format!("Failed to allocate {} floats", len))?;
+    Ok(values)
+}
+
+/// Reads the data of an upload of at most `max` floats, and whether it was regenerated from a seed
+fn read_payload(reader: &mut Reader, max: usize) -> anyhow::Result<(Vec<cl_float>, bool)> {
+    let too_long = |len| anyhow!("An upload of {} floats where at most {} fit", len, max);
+    let kind = reader.u8()?;
+    if kind == DATA_VALUES {
+        // Backed by the bytes of the trace
+        let values = reader.floats()?;
+        if values.len() > max {
+            return Err(too_long(values.len() as u64));
+        }
+        return Ok((values, false));
+    }
+    let len = reader.u64()?;
+    if len > max as u64 {
+        return Err(too_long(len));
+    }
+    let mut values = floats_with_capacity(len as usize)?;
+    let len = len as usize;
+    let seeded = match kind {
+        DATA_CONSTANT => {
+            values.resize(len, reader.f32()?);
+            false
+        }
+        DATA_SEQUENCE => {
+            let (start, step) = (reader.f32()?, reader.f32()?);
+            values.extend((0..len).map(|i| start + step * i as cl_float));
+            false
+        }
+        DATA_SEEDED => {
+            // splitmix64, mapped to [-1, 1)
+            let mut state = reader.u64()?;
+            values.extend((0..len).map(|_| {
+                state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+                let mut z = state;
+                z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+                z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+                z ^= z >> 31;
+                (z >> 40) as cl_float / (1 << 23) as cl_float - 1.0
+            }));
+            true
+        }
+        kind => bail!("Unknown upload kind {}", kind),
+    };
+    Ok((values, seeded))
+}
+
+/// A recorded kernel argument
+enum TracedArg<'a> {
+    Buffer(u32),
+    Scalar { cl_name: String, bytes: &'a [u8] },
+}
+
+/// A recorded operation, with its lengths checked against the buffers allocated before it
+enum Op<'a> {
+    Program {
+        id: u32,
+        source: String,
+        options: String,
+    },
+    Kernel {
+        id: u32,
+        program: u32,
+        name: String,
+    },
+    Alloc {
+        id: u32,
+        flags: cl_mem_flags,
+        len: usize,
+    },
+    Write {
+        id: u32,
+        offset: usize,
+        data: Vec<cl_float>,
+        seeded: bool,
+    },
+    Launch {
+        kernel: u32,
+        args: Vec<TracedArg<'a>>,
+        global: Vec<usize>,
+        local: Vec<usize>,
+        offset: Vec<usize>,
+    },
+    Read {
+        id: u32,
+        offset: usize,
+        len: usize,
+        hash: u64,
+        recorded: Option<Vec<cl_float>>,
+    },
+}
+
+/// The operations of a trace, decoded one at a time
+struct Ops<'a> {
+    reader: Reader<'a>,
+    /// Lengths of the buffers allocated so far by trace id
+    lens: HashMap<u32, usize>,
+    /// Operations decoded so far
+    count: usize,
+}
+
+impl<'a> Ops<'a> {
+    /// Checks the header of the trace read from `path`
+    fn new(bytes: &'a [u8], path: &Path) -> anyhow::Result<Self> {
+        let Some(ops) = bytes.strip_prefix(MAGIC) else {
+            bail!("{} isn't a trace recorded with --record", path.display());
+        };
+        let mut reader = Reader::new(ops);
+        let version = reader.u32()?;
+        if version != VERSION {
+            bail!(
+                "{} is a version {} trace, this build replays version {}",
+                path.display(),
+                version,
+                VERSION
+            );
+        }
+
+        Ok(Ops {
+            reader,
+            lens: HashMap::new(),
+            count: 0,
+        })
+    }
+
+    /// The next operation, or `None` at the end of the trace
+    fn next(&mut self) -> anyhow::Result<Option<Op<'a>>> {
+        if self.reader.is_empty() {
+            return Ok(None);
+        }
+        self.count += 1;
+        let count = self.count;
+        self.decode()
+            .with_context(|| format!("Operation {}", count))
+            .map(Some)
+    }
+
+    fn decode(&mut self) -> anyhow::Result<Op<'a>> {
+        let reader = &mut self.reader;
+        Ok(match reader.u8()? {
+            OP_PROGRAM => Op::Program {
+                id: reader.u32()?,
+                source: reader.str()?,
+                options: reader.str()?,
+            },
+            OP_KERNEL => Op::Kernel {
+                id: reader.u32()?,
+                program: reader.u32()?,
+                name: reader.str()?,
+            },
+            OP_ALLOC => {
+                let (id, flags, len) = (reader.u32()?, reader.u64()?, reader.u64()?);
+                if len > MAX_LEN as u64 {
+                    bail!(
+                        "A buffer of {} floats exceeds the limit of {}",
+                        len,
+                        MAX_LEN
+                    );
+                }
+                let len = len as usize;
+                self.lens.insert(id, len);
+                Op::Alloc { id, flags, len }
+            }
+            OP_WRITE => {
+                let (id, offset) = (reader.u32()?, reader.u64()?);
+                let Some(&len) = self.lens.get(&id) else {
+                    bail!("Write to unknown buffer {}", id);
+                };
+                let offset = bounded_offset(id, offset, len)?;
+                let (data, seeded) = read_payload(reader, len - offset)?;
+                Op::Write {
+                    id,
+                    offset,
+                    data,
+                    seeded,
+                }
+            }
+            OP_LAUNCH => {
+                let kernel = reader.u32()?;
+                let mut args = Vec::new();
+                for _ in 0..reader.len()? {
+                    args.push(match reader.u8()? {
+                        ARG_BUFFER => TracedArg::Buffer(reader.u32()?),
+                        ARG_SCALAR => TracedArg::Scalar {
+                            cl_name: reader.str()?,
+                            bytes: reader.bytes()?,
+                        },
+                        kind => bail!("Unknown argument kind {}", kind),
+                    });
+                }
+                let mut sizes = || -> anyhow::Result<Vec<usize>> {
+                    (0..reader.len()?)
+                        .map(|_| Ok(reader.u64()? as usize))
+                        .collect()
+                };
+                Op::Launch {
+                    kernel,
+                    args,
+                    global: sizes()?,
+                    local: sizes()?,
+                    offset: sizes()?,
+                }
+            }
+            OP_READ => {
+                let (id, offset) = (reader.u32()?, reader.u64()?);
+                let (len, hash) = (reader.u64()?, reader.u64()?);
+                let Some(&buffer_len) = self.lens.get(&id) else {
+                    bail!("Read of unknown buffer {}", id);
+                };
+                let offset = bounded_offset(id, offset, buffer_len)?;
+                if len > (buffer_len - offset) as u64 {
+                    bail!(
+                        "A read of {} floats at {} from buffer {} of {}",
+                        len,
+                        offset,
+                        id,
+                        buffer_len
+                    );
+                }
+                let recorded = match reader.u8()? {
+                    0 => None,
+                    _ => Some(reader.floats()?),
+                };
+                Op::Read {
+                    id,
+                    offset,
+                    len: len as usize,
+                    hash,
+                    recorded,
+                }
+            }
+            op => bail!("Unknown operation {}", op),
+        })
+    }
+}
+
+/// `offset` into buffer `id` of `len` floats, when it isn't past the end
+fn bounded_offset(id: u32, offset: u64, len: usize) -> anyhow::Result<usize> {
+    if offset > len as u64 {
+        bail!("Offset {} past the end of buffer {} of {}", offset, id, len);
+    }
+    Ok(offset as usize)
+}
+
+/// FNV-1a of the bits of `values`
+fn hash(values: &[cl_float]) -> u64 {
+    let mut hash = Fnv1a::default();
+    for value in values {
+        hash.write(&value.to_le_bytes());
+    }
+    hash.0
+}
+
+/// How the reads of a replay compared with the recording
+#[derive(Default)]
+struct Tally {
+    identical: usize,
+    within_error: usize,
+    /// Reads depending on uploads regenerated from a seed
+    regenerated: usize,
+    differing: usize,
+}
+
+/// The `replay` command: runs the operations recorded in `path` on `device` and compares what they
+/// read back with the recording
+pub fn replay(
+    context: &Context,
+    queue: &CommandQueue,
+    device: &Device,
+    args: &Args,
+    path: &Path,
+) -> anyhow::Result<()> {
+    let bytes = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let mut ops = Ops::new(&bytes, path)?;
+
+    let mut programs: HashMap<u32, Program> = HashMap::new();
+    let mut kernels: HashMap<u32, Kernel> = HashMap::new();
+    let mut buffers: HashMap<u32, Buffer<cl_float>> = HashMap::new();
+    // Buffers whose contents depend on regenerated uploads
+    let mut regenerated: HashSet<u32> = HashSet::new();
+    let mut tally = Tally::default();
+    let max_error = args.max_error();
+
+    while let Some(op) = ops.next()? {
+        let count = ops.count;
+        match op {
+            Op::Program {
+                id,
+                source,
+                options,
+            } => {
+                let program = kernels::build_program(
+                    context,
+                    &source,
+                    &options,
+                    &format!("recorded program {}", id),
+                    BuildFlags::from_args(args),
+                )?;
+                programs.insert(id, program);
+            }
+            Op::Kernel { id, program, name } => {
+                let Some(program) = programs.get(&program) else {
+                    bail!("Operation {}: kernel {} of an unknown program", count, name);
+                };
+                let kernel = Kernel::create(program, &name).context("Failed to create kernel")?;
+                kernels.insert(id, kernel);
+            }
+            Op::Alloc { id, flags, len } => {
+                // The host memory of the recording isn't in the trace
+                let flags =
+                    flags & !(CL_MEM_USE_HOST_PTR | CL_MEM_ALLOC_HOST_PTR | CL_MEM_COPY_HOST_PTR);
+                let buffer = crate::saxpy::create_buffer(context, flags, len)?;
+                buffers.insert(id, buffer);
+                regenerated.remove(&id);
+            }
+            Op::Write {
+                id,
+                offset,
+                data,
+                seeded,
+            } => {
+                let buffer = buffers.get_mut(&id).expect("decoded after its allocation");
+                unsafe {
+                    queue.enqueue_write_buffer(
+                        buffer,
+                        CL_BLOCKING,
+                        offset * std::mem::size_of::<cl_float>(),
+                        &data,
+                        &[],
+                    )
+                }
+                .context("Failed to write to buffer")?;
+                if seeded {
+                    regenerated.insert(id);
+                }
+            }
+            Op::Launch {
+                kernel,
+                args,
+                global,
+                local,
+                offset,
+            } => {
+                let Some(kernel) = kernels.get(&kernel) else {
+                    bail!("Operation {}: launch of unknown kernel {}", count, kernel);
+                };
+                let mut execute = ExecuteKernel::new(kernel);
+                let mut bound = Vec::new();
+                for arg in args {
+                    match arg {
+                        TracedArg::Buffer(id) => {
+                            let Some(buffer) = buffers.get(&id) else {
+                                bail!("Operation {}: launch with unknown buffer {}", count, id);
+                            };
+                            unsafe { execute.set_arg(buffer) };
+                            bound.push(id);
+                        }
+                        TracedArg::Scalar { cl_name, bytes } => {
+                            set_scalar(&mut execute, &cl_name, bytes)
+                                .with_context(|| format!("Operation {}", count))?;
+                        }
+                    }
+                }
+                execute.set_global_work_sizes(&global);
+                if !local.is_empty() {
+                    execute.set_local_work_sizes(&local);
+                }
+                if !offset.is_empty() {
+                    execute.set_global_work_offsets(&offset);
+                }
+                unsafe { execute.enqueue_nd_range(queue) }
+                    .context("clEnqueueNDRangeKernel failed")?;
+
+                // Any buffer of the launch may be written from a regenerated one
+                if bound.iter().any(|id| regenerated.contains(id)) {
+                    regenerated.extend(bound);
+                }
+            }
+            Op::Read {
+                id,
+                offset,
+                len,
+                hash: recorded_hash,
+                recorded,
+            } => {
+                let buffer = buffers.get(&id).expect("decoded after its allocation");
+                let mut data = floats_with_capacity(len)?;
+                data.resize(len, 0.0);
+                unsafe {
+                    queue.enqueue_read_buffer(
+                        buffer,
+                        CL_BLOCKING,
+                        offset * std::mem::size_of::<cl_float>(),
+                        &mut data,
+                        &[],
+                    )
+                }
+                .context("Failed to read buffer")?;
+
+                if regenerated.contains(&id) {
+                    tally.regenerated += 1;
+                } else if hash(&data) == recorded_hash {
+                    tally.identical += 1;
+                } else if let Some(recorded) = recorded {
+                    match data.iter().zip(&recorded).position(|(&value, &expected)| {
+                        (value as f64 - expected as f64).abs()
+                            > max_error * (expected as f64).abs().max(1.0)
+                    }) {
+                        None => tally.within_error += 1,
+                        Some(i) => {
+                            tally.differing += 1;
+                            println!(
+                                "operation {}: buffer {} at index {} is {} but was recorded as {}",
+                                count,
+                                id,
+                                offset + i,
+                                data[i],
+                                recorded[i]
+                            );
+                        }
+                    }
+                } else {
+                    tally.differing += 1;
+                    println!(
+                        "operation {}: the {} values read from buffer {} differ from the recording",
+                        count, len, id
+                    );
+                }
+            }
+        }
+    }
+
+    let reads = tally.identical + tally.within_error + tally.regenerated + tally.differing;
+    println!(
+        "replayed {} operations from {} on {}: {} of {} reads identical, {} within a relative error of {}, {} not comparable (regenerated inputs)",
+        ops.count,
+        path.display(),
+        device.name().context("Failed to query device name")?,
+        tally.identical,
+        reads,
+        tally.within_error,
+        max_error,
+        tally.regenerated
+    );
+    if tally.differing > 0 {
+        bail!(
+            "{} of {} reads differ from the recording",
+            tally.differing,
+            reads
+        );
+    }
+
+    Ok(())
+}
+
+/// Binds a recorded scalar argument as the type it was bound with
+fn set_scalar(execute: &mut ExecuteKernel, cl_name: &str, bytes: &[u8]) -> anyhow::Result<()> {
+    fn value<const N: usize>(bytes: &[u8]) -> anyhow::Result<[u8; N]> {
+        bytes.try_into().with_context(|| {
+            format!(
+                "A scalar of {} bytes where {} were expected",
+                bytes.len(),
+                N
+            )
+        })
+    }
+
+    unsafe {
+        match cl_name {
+            "float" => execute.set_arg(&cl_float::from_le_bytes(value(bytes)?)),
+            "double" => execute.set_arg(&cl_double::from_le_bytes(value(bytes)?)),
+            "int" => execute.set_arg(&cl_int::from_le_bytes(value(bytes)?)),
+            "uint" => execute.set_arg(&cl_uint::from_le_bytes(value(bytes)?)),
+            _ => bail!("Unknown scalar type {}", cl_name),
+        };
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn err<T>(result: anyhow::Result<T>) -> String {
+        match result {
+            Ok(_) => panic!("read a malformed trace"),
+            Err(err) => format!("{:#}", err),
+        }
+    }
+
+    /// Writes `data` as a recording without `--record-data` does and reads it back
+    fn round_trip(data: &[cl_float]) -> (Vec<cl_float>, bool) {
+        let mut bytes = Vec::new();
+        Payload::of(data).put(&mut bytes);
+        let mut reader = Reader::new(&bytes);
+        let payload = read_payload(&mut reader, data.len()).unwrap();
+        reader.end().unwrap();
+        payload
+    }
+
+    fn bits(values: &[cl_float]) -> Vec<u32> {
+        values.iter().map(|value| value.to_bits()).collect()
+    }
+
+    /// A trace of the operations `ops` appends
+    fn trace(ops: impl FnOnce(&mut Vec<u8>)) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        put_u32(&mut bytes, VERSION);
+        ops(&mut bytes);
+        bytes
+    }
+
+    fn alloc(bytes: &mut Vec<u8>, id: u32, len: u64) {
+        put_u8(bytes, OP_ALLOC);
+        put_u32(bytes, id);
+        put_u64(bytes, 0);
+        put_u64(bytes, len);
+    }
+
+    /// Decodes all of `bytes`
+    fn decode(bytes: &[u8]) -> anyhow::Result<usize> {
+        let mut ops = Ops::new(bytes, Path::new("trace.bin"))?;
+        while ops.next()?.is_some() {}
+        Ok(ops.count)
+    }
+
+    #[test]
+    fn payloads_round_trip() {
+        let constant = [2.5; 100];
+        assert!(matches!(
+            Payload::of(&constant),
+            Payload::Constant { len: 100, .. }
+        ));
+        assert_eq!(round_trip(&constant), (constant.to_vec(), false));
+
+        let sequence: Vec<cl_float> = (0..1000).map(|i| 0.5 + 0.25 * i as cl_float).collect();
+        assert!(matches!(
+            Payload::of(&sequence),
+            Payload::Sequence { len: 1000, .. }
+        ));
+        let (values, seeded) = round_trip(&sequence);
+        assert_eq!(bits(&values), bits(&sequence));
+        assert!(!seeded);
+
+        // Regenerated rather than restored, the same way every time
+        let other: Vec<cl_float> = (0..1000).map(|i| (i % 7) as cl_float).collect();
+        assert!(matches!(
+            Payload::of(&other),
+            Payload::Seeded { len: 1000, .. }
+        ));
+        let (values, seeded) = round_trip(&other);
+        assert!(seeded);
+        assert_eq!(values.len(), other.len());
+        assert!(values.iter().all(|value| (-1.0..1.0).contains(value)));
+        assert_eq!(bits(&round_trip(&other).0), bits(&values));
+
+        assert_eq!(round_trip(&[]), (Vec::new(), false));
+        assert_eq!(round_trip(&[-0.0]).0[0].to_bits(), (-0.0f32).to_bits());
+    }
+
+    #[test]
+    fn payloads_longer_than_the_buffer_are_rejected() {
+        let mut bytes = Vec::new();
+        Payload::of(&[1.0; 10]).put(&mut bytes);
+        assert_eq!(
+            err(read_payload(&mut Reader::new(&bytes), 9)),
+            "An upload of 10 floats where at most 9 fit"
+        );
+
+        let mut bytes = Vec::new();
+        put_u8(&mut bytes, DATA_SEEDED);
+        put_u64(&mut bytes, u64::MAX);
+        put_u64(&mut bytes, 0);
+        assert_eq!(
+            err(read_payload(&mut Reader::new(&bytes), MAX_LEN)),
+            format!(
+                "An upload of {} floats where at most {} fit",
+                u64::MAX,
+                MAX_LEN
+            )
+        );
+    }
+
+    #[test]
+    fn malformed_traces_are_rejected() {
+        assert_eq!(
+            err(decode(b"HOCL-TRACF")),
+            "trace.bin isn't a trace recorded with --record"
+        );
+
+        let mut bytes = MAGIC.to_vec();
+        put_u32(&mut bytes, VERSION + 1);
+        assert_eq!(
+            err(decode(&bytes)),
+            format!(
+                "trace.bin is a version {} trace, this build replays version {}",
+                VERSION + 1,
+                VERSION
+            )
+        );
+
+        assert_eq!(decode(&trace(|_| {})).unwrap(), 0);
+        assert_eq!(
+            err(decode(&trace(|bytes| {
+                alloc(bytes, 0, 16);
+                put_u8(bytes, 42);
+            }))),
+            "Operation 2: Unknown operation 42"
+        );
+        assert_eq!(
+            err(decode(&trace(|bytes| put_u8(bytes, OP_ALLOC)))),
+            "Operation 1: The message ends early"
+        );
+    }
+
+    #[test]
+    fn lengths_are_bounded_by_the_allocation() {
+        assert_eq!(
+            err(decode(&trace(|bytes| alloc(bytes, 0, u64::MAX)))),
+            format!(
+                "Operation 1: A buffer of {} floats exceeds the limit of {}",
+                u64::MAX,
+                MAX_LEN
+            )
+        );
+
+        let write = |offset: u64, len: usize| {
+            trace(|bytes| {
+                alloc(bytes, 0, 16);
+                put_u8(bytes, OP_WRITE);
+                put_u32(bytes, 0);
+                put_u64(bytes, offset);
+                Payload::of(&vec![1.0; len]).put(bytes);
+            })
+        };
+        assert_eq!(decode(&write(4, 12)).unwrap(), 2);
+        assert_eq!(
+            err(decode(&write(4, 13))),
+            "Operation 2: An upload of 13 floats where at most 12 fit"
+        );
+        assert_eq!(
+            err(decode(&write(17, 0))),
+            "Operation 2: Offset 17 past the end of buffer 0 of 16"
+        );
+
+        let read = |id: u32, len: u64| {
+            trace(|bytes| {
+                alloc(bytes, 0, 16);
+                put_u8(bytes, OP_READ);
+                put_u32(bytes, id);
+                put_u64(bytes, 0);
+                put_u64(bytes, len);
+                put_u64(bytes, 0);
+                put_u8(bytes, 0);
+            })
+        };
+        assert_eq!(decode(&read(0, 16)).unwrap(), 2);
+        assert_eq!(
+            err(decode(&read(0, u64::MAX))),
+            format!(
+                "Operation 2: A read of {} floats at 0 from buffer 0 of 16",
+                u64::MAX
+            )
+        );
+        assert_eq!(
+            err(decode(&read(1, 16))),
+            "Operation 2: Read of unknown buffer 1"
+        );
+    }
+
+    #[test]
+    fn recorded_saxpy_replays() {
+        use crate::{
+            cli::{Layout, Transfer},
+            saxpy::SaxpyBuffers,
+            test_device,
+        };
+
+        let Some(test) = test_device::get() else {
+            return;
+        };
+        let path = std::env::temp_dir().join(format!("hello-opencl3-{}.trace", std::process::id()));
+        let (x, y) = test_device::inputs(1024);
+
+        start(&path, true).unwrap();
+        // Built while recording, so its source is in the trace
+        let kernel = test_device::saxpy_kernel(&test);
+        let mut buffers = SaxpyBuffers::create(&test.context, Layout::Soa, x.len()).unwrap();
+        let run = buffers.run(&test.queue, &kernel, Transfer::Blocking, &x, &y, Some(2.0));
+        let finished = finish();
+        run.unwrap();
+        finished.unwrap();
+
+        let args = Args::parse_from(["replay".to_owned(), path.display().to_string()]).unwrap();
+        let replayed = replay(&test.context, &test.queue, &test.device, &args, &path);
+        fs::remove_file(&path).unwrap();
+        replayed.unwrap();
+    }
+}
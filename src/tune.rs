@@ -0,0 +1,119 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+use anyhow::bail;
+use opencl3::{command_queue::CommandQueue, context::Context, device::Device, types::cl_float};
+
+use crate::{
+    bench, cancel,
+    cli::{Args, Layout, TuneArgs},
+    error::ClContext,
+    kernels,
+    resources::KernelResources,
+    saxpy::SaxpyBuffers,
+};
+
+/// The `tune` command: times every power-of-two local work size the kernel allows
+///
+/// Only sizes dividing `tune.size` are tried since OpenCL 1.x requires the global size to be a
+/// multiple of the local size, the driver's own choice is timed as well for comparison. With
+/// `tune.guarded` the kernel skips the work-items past the end and every size is tried, the global
+/// size rounded up to a multiple of it
+pub fn run(
+    context: &Context,
+    queue: &CommandQueue,
+    device: &Device,
+    args: &Args,
+    tune: &TuneArgs,
+) -> anyhow::Result<()> {
+    if args.layout == Layout::Both {
+        bail!("tune runs a single kernel, pick --layout soa or --layout aos");
+    }
+
+    let kernel = if tune.guarded {
+        kernels::build_guarded_kernel(context, args)?
+    } else {
+        kernels::build_layout_kernel(context, args, args.layout)?
+    };
+    let resources = KernelResources::query(&kernel, device)?;
+    resources.log(
+        &kernel
+            .function_name()
+            .context("Failed to get kernel name")?,
+        None,
+    );
+    let max_local = resources.work_group_size;
+
+    let candidates = local_size_candidates(tune.size, max_local, tune.guarded);
+
+    let mut buffers = SaxpyBuffers::create(context, args.layout, tune.size)?;
+    buffers.set_bounds_guard(tune.guarded);
+    let ones: Vec<cl_float> = vec![1.0; tune.size];
+    let sums: Vec<cl_float> = (0..tune.size).map(|i| 1.0 + 1.0 * i as cl_float).collect();
+    let a = args.op.scalar(args.scalar());
+
+    println!(
+        "{:>12} {:>18} {:>18}",
+        "local size", "kernel time (us)", "bandwidth (GB/s)"
+    );
+
+    let mut best: Option<(Option<usize>, u64)> = None;
+    for local in candidates {
+        buffers.set_local_work_size(local);
+
+        let mut fastest = u64::MAX;
+        for _ in 0..tune.iterations {
+            if cancel::requested() {
+                break;
+            }
+            let run = buffers.run(queue, &kernel, args.transfer, &ones, &sums, a)?;
+            fastest = fastest.min(run.kernel_ns);
+        }
+        if fastest == u64::MAX {
+            break;
+        }
+
+        println!(
+            "{:>12} {:>18.3} {:>18.3}",
+            local_label(local),
+            fastest as f64 / 1e3,
+            bench::bandwidth(tune.size, fastest)
+        );
+
+        if best.is_none_or(|(_, ns)| fastest < ns) {
+            best = Some((local, fastest));
+        }
+    }
+
+    if let Some((local, ns)) = best {
+        println!(
+            "fastest: local size {} at {:.3} us",
+            local_label(local),
+            ns as f64 / 1e3
+        );
+    }
+
+    cancel::check(queue)
+}
+
+/// The driver's choice (`None`) followed by every power of two up to `max_local` dividing `size`,
+/// or all of them for a `guarded` kernel
+pub fn local_size_candidates(size: usize, max_local: usize, guarded: bool) -> Vec<Option<usize>> {
+    let mut candidates = vec![None];
+    let mut local = 1;
+    while local <= max_local {
+        if guarded || size.is_multiple_of(local) {
+            candidates.push(Some(local));
+        }
+        local *= 2;
+    }
+
+    candidates
+}
+
+pub fn local_label(local: Option<usize>) -> String {
+    match local {
+        Some(local) => local.to_string(),
+        None => "driver".to_owned(),
+    }
+}
@@ -0,0 +1,590 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+//! Reading and writing one-dimensional `f32` vectors as raw binaries, CSV or `.npy` files
+//!
+//! The format follows from the file extension. `.npy` files are read with a minimal parser of the
+//! [NPY format](https://numpy.org/doc/stable/reference/generated/numpy.lib.format.html) that
+//! accepts `f4` and `f8` arrays in C order (`f8` is narrowed to `f32`, the precision the kernels
+//! compute in) and writes `<f4` arrays.
+//!
+//! [`read_columns`] reads both inputs from one CSV file whose header row names an `x` and a `y`
+//! column, in any order and next to other columns. Its records follow RFC 4180: fields may be
+//! quoted, with `""` for a quote and line breaks inside the quotes.
+
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::{anyhow, bail, Context};
+use opencl3::types::cl_float;
+
+const NPY_MAGIC: &[u8; 6] = b"\x93NUMPY";
+
+/// File formats a vector can be stored in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Little-endian `f32` values back to back, no header
+    Raw,
+    /// One value per line
+    Csv,
+    /// NumPy's `.npy`
+    Npy,
+}
+
+impl Format {
+    /// Picks the format from the extension of `path`
+    pub fn of(path: &Path) -> anyhow::Result<Self> {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase);
+
+        match extension.as_deref() {
+            Some("bin" | "raw" | "f32") => Ok(Self::Raw),
+            Some("csv") => Ok(Self::Csv),
+            Some("npy") => Ok(Self::Npy),
+            _ => bail!(
+                "Can't tell the format of {}, expected a .bin, .raw, .f32, .csv or .npy extension",
+                path.display()
+            ),
+        }
+    }
+}
+
+/// Reads the vector stored in `path`
+pub fn read(path: &Path) -> anyhow::Result<Vec<cl_float>> {
+    let format = Format::of(path)?;
+    let bytes = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+
+    let values = match format {
+        Format::Raw => parse_raw(&bytes),
+        Format::Csv => parse_csv(&bytes),
+        Format::Npy => parse_npy(&bytes),
+    }
+    .with_context(|| format!("Failed to parse {}", path.display()))?;
+
+    tracing::debug!("Read {} values from {}", values.len(), path.display());
+    Ok(values)
+}
+
+/// Reads the columns named `x` and `y` in the header row of the CSV file `path`
+pub fn read_columns(path: &Path) -> anyhow::Result<(Vec<cl_float>, Vec<cl_float>)> {
+    let bytes = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let columns =
+        parse_columns(&bytes).with_context(|| format!("Failed to parse {}", path.display()))?;
+
+    tracing::debug!(
+        "Read {} rows of x and y from {}",
+        columns.0.len(),
+        path.display()
+    );
+    Ok(columns)
+}
+
+/// Writes `values` to `path` in the format its extension names
+pub fn write(path: &Path, values: &[cl_float]) -> anyhow::Result<()> {
+    let format = Format::of(path)?;
+    let file =
+        File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    let mut writer = BufWriter::new(file);
+
+    match format {
+        Format::Raw => {}
+        Format::Csv => {
+            for value in values {
+                writeln!(writer, "{}", value)?;
+            }
+        }
+        Format::Npy => writer.write_all(&npy_header(values.len()))?,
+    }
+    if format != Format::Csv {
+        for value in values {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+    }
+
+    writer
+        .flush()
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+fn parse_raw(bytes: &[u8]) -> anyhow::Result<Vec<cl_float>> {
+    if !bytes.len().is_multiple_of(4) {
+        bail!(
+            "{} bytes isn't a whole number of 4 byte f32 values",
+            bytes.len()
+        );
+    }
+
+    Ok(bytes
+        .chunks_exact(4)
+        .map(|chunk| cl_float::from_le_bytes(chunk.try_into().unwrap()))
+        .collect())
+}
+
+fn parse_csv(bytes: &[u8]) -> anyhow::Result<Vec<cl_float>> {
+    let text = std::str::from_utf8(bytes).context("Not valid UTF-8")?;
+
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            line.trim()
+                .parse()
+                .map_err(|_| anyhow!("Invalid value '{}' on line {}", line.trim(), i + 1))
+        })
+        .collect()
+}
+
+fn parse_columns(bytes: &[u8]) -> anyhow::Result<(Vec<cl_float>, Vec<cl_float>)> {
+    let text = std::str::from_utf8(bytes).context("Not valid UTF-8")?;
+    let mut records = csv_records(text)?.into_iter();
+
+    let Some((_, header)) = records.next() else {
+        bail!("Empty file, expected a header row naming the x and y columns");
+    };
+    let column = |name: &str| {
+        let mut found = header
+            .iter()
+            .enumerate()
+            .filter(|(_, field)| field.trim() == name);
+        match (found.next(), found.next()) {
+            (Some((i, _)), None) => Ok(i),
+            (Some(_), Some(_)) => bail!("The header names the column '{}' more than once", name),
+            (None, _) => bail!(
+                "The header has no '{}' column, it names: {}",
+                name,
+                header.join(", ")
+            ),
+        }
+    };
+    let (x_column, y_column) = (column("x")?, column("y")?);
+
+    let mut x = Vec::new();
+    let mut y = Vec::new();
+    for (row, (line, record)) in records.enumerate() {
+        let cell = |column: usize, name: &str| -> anyhow::Result<cl_float> {
+            let field = record
+                .get(column)
+                .map(|field| field.trim())
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Row {} (line {}) has no {} value, only {} field(s)",
+                        row + 1,
+                        line,
+                        name,
+                        record.len()
+                    )
+                })?;
+            field.parse().map_err(|_| {
+                anyhow!(
+                    "Invalid {} value '{}' in row {} (line {})",
+                    name,
+                    field,
+                    row + 1,
+                    line
+                )
+            })
+        };
+        x.push(cell(x_column, "x")?);
+        y.push(cell(y_column, "y")?);
+    }
+
+    Ok((x, y))
+}
+
+/// The records of the CSV `text` with the line each starts on, blank lines are skipped
+fn csv_records(text: &str) -> anyhow::Result<Vec<(usize, Vec<String>)>> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut line = 1;
+    let mut start = 1;
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted => {
+                if chars.next_if_eq(&'"').is_some() {
+                    field.push('"');
+                } else {
+                    quoted = false;
+                }
+            }
+            '"' if field.trim().is_empty() => {
+                field.clear();
+                quoted = true;
+            }
+            '\n' if quoted => {
+                field.push(c);
+                line += 1;
+            }
+            ',' if !quoted => record.push(std::mem::take(&mut field)),
+            '\r' if !quoted && chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                record.push(std::mem::take(&mut field));
+                if record.len() > 1 || !record[0].trim().is_empty() {
+                    records.push((start, std::mem::take(&mut record)));
+                }
+                record.clear();
+                line += 1;
+                start = line;
+            }
+            c => field.push(c),
+        }
+    }
+    if quoted {
+        bail!(
+            "A quoted field of the record on line {} isn't closed",
+            start
+        );
+    }
+    if !record.is_empty() || !field.trim().is_empty() {
+        record.push(field);
+        records.push((start, record));
+    }
+
+    Ok(records)
+}
+
+fn parse_npy(bytes: &[u8]) -> anyhow::Result<Vec<cl_float>> {
+    if bytes.len() < 10 || &bytes[..6] != NPY_MAGIC {
+        bail!("Not an .npy file");
+    }
+
+    // Version 1 stores the header length in 2 bytes, versions 2 and 3 in 4
+    let (header_len, header_start) = match bytes[6] {
+        1 => (u16::from_le_bytes([bytes[8], bytes[9]]) as usize, 10),
+        2 | 3 if bytes.len() >= 12 => (
+            u32::from_le_bytes(bytes[8..12].try_into().unwrap()) as usize,
+            12,
+        ),
+        version => bail!("Unsupported .npy version {}", version),
+    };
+    let data_start = header_start + header_len;
+    if bytes.len() < data_start {
+        bail!("Truncated .npy header");
+    }
+    let header = std::str::from_utf8(&bytes[header_start..data_start])
+        .context("The .npy header isn't valid text")?;
+
+    let descr = header_value(header, "descr")?;
+    let fortran_order = header_value(header, "fortran_order")?;
+    let shape = header_value(header, "shape")?;
+
+    let dims = shape
+        .trim_start_matches('(')
+        .trim_end_matches(')')
+        .split(',')
+        .map(str::trim)
+        .filter(|dim| !dim.is_empty())
+        .map(|dim| {
+            dim.parse::<usize>()
+                .map_err(|_| anyhow!("Invalid shape {}", shape))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if dims.iter().filter(|&&dim| dim != 1).count() > 1 {
+        bail!("Expected a one-dimensional array, found shape {}", shape);
+    }
+    if fortran_order == "True" && dims.len() > 1 {
+        bail!("Fortran-ordered arrays aren't supported");
+    }
+    let len = dims
+        .iter()
+        .try_fold(1usize, |len, &dim| len.checked_mul(dim))
+        .ok_or_else(|| {
+            anyhow!(
+                "The shape {} has more elements than can be addressed",
+                shape
+            )
+        })?;
+
+    let descr = descr.trim_matches(|c| c == '\'' || c == '"');
+    let (big_endian, width) = match descr {
+        "<f4" | "=f4" | "|f4" => (false, 4),
+        ">f4" => (true, 4),
+        "<f8" | "=f8" | "|f8" => (false, 8),
+        ">f8" => (true, 8),
+        _ => bail!("Unsupported dtype '{}', expected f4 or f8", descr),
+    };
+
+    let data = &bytes[data_start..];
+    let expected = len
+        .checked_mul(width)
+        .ok_or_else(|| anyhow!("The shape {} has more bytes than can be addressed", shape))?;
+    if data.len() != expected {
+        bail!(
+            "Expected {} bytes of data for {} elements, found {}",
+            expected,
+            len,
+            data.len()
+        );
+    }
+
+    if width == 8 {
+        tracing::warn!("Narrowing f8 data to f32");
+    }
+    Ok(data
+        .chunks_exact(width)
+        .map(|chunk| match (width, big_endian) {
+            (4, false) => cl_float::from_le_bytes(chunk.try_into().unwrap()),
+            (4, true) => cl_float::from_be_bytes(chunk.try_into().unwrap()),
+            (_, false) => f64::from_le_bytes(chunk.try_into().unwrap()) as cl_float,
+            (_, true) => f64::from_be_bytes(chunk.try_into().unwrap()) as cl_float,
+        })
+        .collect())
+}
+
+/// Extracts the text of `key`'s value from the Python dict literal of an .npy header
+fn header_value<'a>(header: &'a str, key: &str) -> anyhow::Result<&'a str> {
+    let start = header
+        .find(&format!("'{}'", key))
+        .and_then(|i| header[i..].find(':').map(|colon| i + colon + 1))
+        .ok_or_else(|| anyhow!("The .npy header has no '{}'", key))?;
+    let rest = header[start..].trim_start();
+
+    // The shape is a tuple containing commas, the other values end at the next comma
+    let end = if rest.starts_with('(') {
+        rest.find(')').map(|i| i + 1)
+    } else {
+        rest.find([',', '}'])
+    }
+    .ok_or_else(|| anyhow!("Malformed '{}' in the .npy header", key))?;
+
+    Ok(rest[..end].trim())
+}
+
+/// A version 1.0 header for a one-dimensional `<f4` array of `len` elements
+fn npy_header(len: usize) -> Vec<u8> {
+    let mut dict = format!(
+        "{{'descr': '<f4', 'fortran_order': False, 'shape': ({},), }}",
+        len
+    );
+    // Magic, version and length take 10 bytes, the whole header is padded to a multiple of 64
+    // and ends in a newline
+    let unpadded = 10 + dict.len() + 1;
+    dict.extend(std::iter::repeat_n(
+        ' ',
+        unpadded.next_multiple_of(64) - unpadded,
+    ));
+    dict.push('\n');
+
+    let mut header = NPY_MAGIC.to_vec();
+    header.extend([1, 0]);
+    header.extend((dict.len() as u16).to_le_bytes());
+    header.extend(dict.into_bytes());
+
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An .npy file with the header dict `dict`, padded as numpy does, followed by `data`
+    fn npy(dict: &str, data: &[u8]) -> Vec<u8> {
+        let mut dict = dict.to_owned();
+        let unpadded = 10 + dict.len() + 1;
+        dict.extend(std::iter::repeat_n(
+            ' ',
+            unpadded.next_multiple_of(64) - unpadded,
+        ));
+        dict.push('\n');
+
+        let mut bytes = NPY_MAGIC.to_vec();
+        bytes.extend([1, 0]);
+        bytes.extend((dict.len() as u16).to_le_bytes());
+        bytes.extend(dict.into_bytes());
+        bytes.extend(data);
+        bytes
+    }
+
+    fn le_bytes(values: &[cl_float]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn npy_round_trip() {
+        let values = [0.0, -1.5, 3.25, f32::MAX, f32::MIN_POSITIVE];
+        let mut bytes = npy_header(values.len());
+        assert_eq!(bytes.len() % 64, 0);
+        bytes.extend(le_bytes(&values));
+
+        assert_eq!(parse_npy(&bytes).unwrap(), values);
+    }
+
+    #[test]
+    fn npy_empty_round_trip() {
+        assert_eq!(parse_npy(&npy_header(0)).unwrap(), Vec::<cl_float>::new());
+    }
+
+    #[test]
+    fn npy_big_endian_and_f8() {
+        let be: Vec<u8> = [1.0f32, 2.0].iter().flat_map(|v| v.to_be_bytes()).collect();
+        let bytes = npy(
+            "{'descr': '>f4', 'fortran_order': False, 'shape': (2,), }",
+            &be,
+        );
+        assert_eq!(parse_npy(&bytes).unwrap(), [1.0, 2.0]);
+
+        let f8: Vec<u8> = [0.5f64, -4.0]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+        let bytes = npy(
+            "{'descr': '<f8', 'fortran_order': False, 'shape': (2,), }",
+            &f8,
+        );
+        assert_eq!(parse_npy(&bytes).unwrap(), [0.5, -4.0]);
+    }
+
+    #[test]
+    fn npy_truncated() {
+        let mut bytes = npy_header(4);
+        bytes.extend(le_bytes(&[1.0, 2.0, 3.0]));
+        let err = parse_npy(&bytes).unwrap_err().to_string();
+        assert!(
+            err.contains("Expected 16 bytes of data for 4 elements, found 12"),
+            "{}",
+            err
+        );
+
+        let header = npy_header(4);
+        let err = parse_npy(&header[..20]).unwrap_err().to_string();
+        assert!(err.contains("Truncated .npy header"), "{}", err);
+
+        assert!(parse_npy(&header[..8]).is_err());
+    }
+
+    #[test]
+    fn npy_wrong_dtype() {
+        let bytes = npy(
+            "{'descr': '<i4', 'fortran_order': False, 'shape': (1,), }",
+            &[0; 4],
+        );
+        let err = parse_npy(&bytes).unwrap_err().to_string();
+        assert!(err.contains("Unsupported dtype '<i4'"), "{}", err);
+    }
+
+    #[test]
+    fn npy_overflowing_shape() {
+        let bytes = npy(
+            "{'descr': '<f8', 'fortran_order': False, 'shape': (4611686018427387904,), }",
+            &[],
+        );
+        let err = parse_npy(&bytes).unwrap_err().to_string();
+        assert!(err.contains("more bytes than can be addressed"), "{}", err);
+
+        let bytes = npy(
+            "{'descr': '<f4', 'fortran_order': False, 'shape': (4294967296, 4294967296), }",
+            &[],
+        );
+        assert!(parse_npy(&bytes).is_err());
+
+        let bytes = npy(
+            "{'descr': '<f4', 'fortran_order': False, 'shape': (1, 18446744073709551615, 1), }",
+            &[],
+        );
+        let err = parse_npy(&bytes).unwrap_err().to_string();
+        assert!(err.contains("more bytes than can be addressed"), "{}", err);
+    }
+
+    #[test]
+    fn npy_not_one_dimensional() {
+        let bytes = npy(
+            "{'descr': '<f4', 'fortran_order': False, 'shape': (2, 3), }",
+            &[0; 24],
+        );
+        let err = parse_npy(&bytes).unwrap_err().to_string();
+        assert!(err.contains("one-dimensional"), "{}", err);
+    }
+
+    fn records(text: &str) -> Vec<(usize, Vec<String>)> {
+        csv_records(text).unwrap()
+    }
+
+    fn record(line: usize, fields: &[&str]) -> (usize, Vec<String>) {
+        (line, fields.iter().map(|field| field.to_string()).collect())
+    }
+
+    fn columns_error(text: &str) -> String {
+        format!("{:#}", parse_columns(text.as_bytes()).unwrap_err())
+    }
+
+    #[test]
+    fn csv_quoted_fields() {
+        assert_eq!(
+            records("a,\"b, \"\"c\"\"\",\"\"\nd,e,f\n"),
+            [
+                record(1, &["a", "b, \"c\"", ""]),
+                record(2, &["d", "e", "f"])
+            ]
+        );
+        // The record keeps the line it starts on, the next one counts the embedded newline
+        assert_eq!(
+            records("\"two\nlines\",1\nnext,2\n"),
+            [record(1, &["two\nlines", "1"]), record(3, &["next", "2"])]
+        );
+        assert_eq!(
+            records("x,y\r\n1,\"2\r\n3\"\r\n\r\n4,5"),
+            [
+                record(1, &["x", "y"]),
+                record(2, &["1", "2\r\n3"]),
+                record(5, &["4", "5"])
+            ]
+        );
+        assert_eq!(
+            csv_records("x,y\n\"1,2\n").unwrap_err().to_string(),
+            "A quoted field of the record on line 2 isn't closed"
+        );
+    }
+
+    #[test]
+    fn csv_columns() {
+        assert_eq!(
+            parse_columns(b"x,y\r\n1,2\r\n3.5,-4\r\n").unwrap(),
+            (vec![1.0, 3.5], vec![2.0, -4.0])
+        );
+        assert_eq!(
+            parse_columns(b"label,y, x \n\"a, b\",2,1\n\nc,4,3\n").unwrap(),
+            (vec![1.0, 3.0], vec![2.0, 4.0])
+        );
+        assert_eq!(parse_columns(b"x,y\n").unwrap(), (Vec::new(), Vec::new()));
+    }
+
+    #[test]
+    fn csv_missing_columns() {
+        assert_eq!(
+            columns_error("x,z\n1,2\n"),
+            "The header has no 'y' column, it names: x, z"
+        );
+        assert_eq!(
+            columns_error("y,x,y\n1,2,3\n"),
+            "The header names the column 'y' more than once"
+        );
+        assert_eq!(
+            columns_error("x,y\n1,2\n3\n"),
+            "Row 2 (line 3) has no y value, only 1 field(s)"
+        );
+        assert!(columns_error("").starts_with("Empty file"));
+    }
+
+    #[test]
+    fn csv_invalid_cell_names_the_row() {
+        assert_eq!(
+            columns_error("x,y\n1,2\n\n3,abc\n"),
+            "Invalid y value 'abc' in row 2 (line 4)"
+        );
+        assert_eq!(
+            columns_error("x,note,y\n1,\"two\nlines\",2\nnan?,z,3\n"),
+            "Invalid x value 'nan?' in row 2 (line 4)"
+        );
+    }
+}
@@ -0,0 +1,192 @@
+// Copyright 2023 Canvas02 <Canvas02@protonmail.com>.
+// SPDX-License-Identifier: MIT
+
+//! The little-endian encoding of the `serve` messages and the `--record` traces
+//!
+//! Lengths and counts are `u32`s, followed by the elements. Strings are UTF-8 and `f32`s are
+//! stored as their bits, so values round-trip exactly.
+
+use anyhow::{bail, Context as _};
+use opencl3::types::cl_float;
+
+pub fn put_u8(bytes: &mut Vec<u8>, value: u8) {
+    bytes.push(value);
+}
+
+pub fn put_u32(bytes: &mut Vec<u8>, value: u32) {
+    bytes.extend(value.to_le_bytes());
+}
+
+pub fn put_u64(bytes: &mut Vec<u8>, value: u64) {
+    bytes.extend(value.to_le_bytes());
+}
+
+pub fn put_f32(bytes: &mut Vec<u8>, value: cl_float) {
+    bytes.extend(value.to_le_bytes());
+}
+
+/// Writes a length or count
+///
+/// # Panics
+///
+/// When `len` doesn't fit in a `u32`, messages and traces are far from that size
+pub fn put_len(bytes: &mut Vec<u8>, len: usize) {
+    put_u32(bytes, u32::try_from(len).expect("lengths fit in a u32"));
+}
+
+pub fn put_str(bytes: &mut Vec<u8>, s: &str) {
+    put_bytes(bytes, s.as_bytes());
+}
+
+pub fn put_bytes(bytes: &mut Vec<u8>, data: &[u8]) {
+    put_len(bytes, data.len());
+    bytes.extend(data);
+}
+
+pub fn put_floats(bytes: &mut Vec<u8>, values: &[cl_float]) {
+    put_len(bytes, values.len());
+    bytes.extend(values.iter().flat_map(|value| value.to_le_bytes()));
+}
+
+/// Reads the fields of a message front to back
+pub struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self(bytes)
+    }
+
+    fn take<const N: usize>(&mut self) -> anyhow::Result<[u8; N]> {
+        Ok(self.raw(N)?.try_into().unwrap())
+    }
+
+    fn raw(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        if self.0.len() < len {
+            bail!("The message ends early");
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> anyhow::Result<u8> {
+        Ok(u8::from_le_bytes(self.take()?))
+    }
+
+    pub fn u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    pub fn u64(&mut self) -> anyhow::Result<u64> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+
+    pub fn f32(&mut self) -> anyhow::Result<cl_float> {
+        Ok(cl_float::from_le_bytes(self.take()?))
+    }
+
+    pub fn len(&mut self) -> anyhow::Result<usize> {
+        Ok(self.u32()? as usize)
+    }
+
+    pub fn str(&mut self) -> anyhow::Result<String> {
+        String::from_utf8(self.bytes()?.to_vec()).context("A string isn't valid UTF-8")
+    }
+
+    pub fn bytes(&mut self) -> anyhow::Result<&'a [u8]> {
+        let len = self.len()?;
+        self.raw(len)
+    }
+
+    pub fn floats(&mut self) -> anyhow::Result<Vec<cl_float>> {
+        let len = self.len()?;
+        let bytes = self.raw(len.saturating_mul(4))?;
+        Ok(bytes
+            .chunks_exact(4)
+            .map(|chunk| cl_float::from_le_bytes(chunk.try_into().unwrap()))
+            .collect())
+    }
+
+    /// Whether everything has been read
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn end(&self) -> anyhow::Result<()> {
+        if !self.0.is_empty() {
+            bail!("{} bytes left over at the end of the message", self.0.len());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn err<T>(result: anyhow::Result<T>) -> String {
+        match result {
+            Ok(_) => panic!("read a malformed message"),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut bytes = Vec::new();
+        put_u8(&mut bytes, 7);
+        put_u32(&mut bytes, 0xdead_beef);
+        put_u64(&mut bytes, u64::MAX);
+        put_f32(&mut bytes, -0.0);
+        put_str(&mut bytes, "saxpy_float ✓");
+        put_bytes(&mut bytes, &[]);
+        put_floats(
+            &mut bytes,
+            &[1.5, cl_float::NAN, cl_float::MIN_POSITIVE / 2.0],
+        );
+
+        let mut reader = Reader::new(&bytes);
+        assert_eq!(reader.u8().unwrap(), 7);
+        assert_eq!(reader.u32().unwrap(), 0xdead_beef);
+        assert_eq!(reader.u64().unwrap(), u64::MAX);
+        assert_eq!(reader.f32().unwrap().to_bits(), (-0.0f32).to_bits());
+        assert_eq!(reader.str().unwrap(), "saxpy_float ✓");
+        assert_eq!(reader.bytes().unwrap(), &[] as &[u8]);
+        let floats = reader.floats().unwrap();
+        assert_eq!(floats.len(), 3);
+        assert_eq!(floats[0], 1.5);
+        // NaN payloads and subnormals keep their bits
+        assert_eq!(floats[1].to_bits(), cl_float::NAN.to_bits());
+        assert_eq!(floats[2], cl_float::MIN_POSITIVE / 2.0);
+        assert!(reader.is_empty());
+        reader.end().unwrap();
+    }
+
+    #[test]
+    fn malformed() {
+        let mut bytes = Vec::new();
+        put_str(&mut bytes, "saxpy");
+
+        assert_eq!(
+            err(Reader::new(&bytes[..6]).str()),
+            "The message ends early"
+        );
+        assert_eq!(err(Reader::new(&[1, 2, 3]).u32()), "The message ends early");
+        // A count that can't be backed by the bytes left, not an allocation of it
+        assert_eq!(
+            err(Reader::new(&u32::MAX.to_le_bytes()).floats()),
+            "The message ends early"
+        );
+        assert_eq!(
+            err(Reader::new(&[1, 0, 0, 0, 0xff]).str()),
+            "A string isn't valid UTF-8"
+        );
+
+        let mut reader = Reader::new(&bytes);
+        reader.u32().unwrap();
+        assert_eq!(
+            err(reader.end()),
+            "5 bytes left over at the end of the message"
+        );
+    }
+}